@@ -0,0 +1,172 @@
+use std::fs::{self, OpenOptions, Permissions};
+use std::io;
+use std::path::Path;
+
+use anyhow::Result;
+
+use patch_types::error::{FileError, PatchError, Remedy};
+
+use crate::antivirus;
+
+pub(crate) const PROBE_NAME: &str = ".patch_access_probe";
+
+/// Clears the read-only attribute on an existing target so it can be replaced or removed.
+pub fn clear_readonly(path: &Path) -> Result<()> {
+    let Ok(meta) = fs::metadata(path) else {
+        return Ok(());
+    };
+    let mut perms = meta.permissions();
+    if !perms.readonly() {
+        return Ok(());
+    }
+    make_writable(&mut perms);
+    fs::set_permissions(path, perms)
+        .map_err(|e| explain(e, path, "clearing the read-only attribute on"))
+}
+
+#[cfg(unix)]
+fn make_writable(perms: &mut Permissions) {
+    use std::os::unix::fs::PermissionsExt;
+    perms.set_mode(perms.mode() | 0o200);
+}
+
+#[cfg(not(unix))]
+fn make_writable(perms: &mut Permissions) {
+    #[allow(clippy::permissions_set_readonly_false)]
+    perms.set_readonly(false);
+}
+
+/// What the user can do about `err` on `path`, if anything.
+fn remedy_for(err: &io::Error, path: &Path) -> Option<Remedy> {
+    if antivirus::suspect(err, path) {
+        return Some(Remedy::Antivirus);
+    }
+    if in_use(err) {
+        return Some(Remedy::CloseProgram);
+    }
+    match err.kind() {
+        io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => {
+            Some(Remedy::RunAsAdmin)
+        }
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => Some(Remedy::FreeSpace),
+        io::ErrorKind::NotFound => Some(Remedy::Repair),
+        _ => None,
+    }
+}
+
+/// Turns an I/O error on `path` into a [`PatchError::Io`]. Access-denied errors name the
+/// permission that is missing and the account the patcher is running as, instead of just
+/// "os error 5". Errors that point at an antivirus program say so.
+pub fn explain(err: io::Error, path: &Path, action: &str) -> anyhow::Error {
+    let message = if antivirus::suspect(&err, path) {
+        format!(
+            "{action} {}: {err}; an antivirus program is probably scanning or quarantining it",
+            path.display()
+        )
+    } else if err.kind() == io::ErrorKind::PermissionDenied && !in_use(&err) {
+        let code = err
+            .raw_os_error()
+            .map(|code| format!(" (os error {code})"))
+            .unwrap_or_default();
+        format!(
+            "Access denied while {action} {}: {} is missing {}{code}",
+            path.display(),
+            current_user(),
+            missing_permission(path),
+        )
+    } else {
+        format!("{action} {}: {err}", path.display())
+    };
+    anyhow::Error::new(PatchError::Io(FileError::new(
+        path.to_path_buf(),
+        action,
+        err.raw_os_error(),
+        remedy_for(&err, path),
+        message,
+    )))
+}
+
+/// Whether the error means another program has the file open.
+#[cfg(windows)]
+pub(crate) fn in_use(err: &io::Error) -> bool {
+    use windows_sys::Win32::Foundation::{ERROR_LOCK_VIOLATION, ERROR_SHARING_VIOLATION};
+    matches!(err.raw_os_error(), Some(code)
+        if code == ERROR_SHARING_VIOLATION as i32 || code == ERROR_LOCK_VIOLATION as i32)
+}
+
+#[cfg(unix)]
+pub(crate) fn in_use(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::ETXTBSY | libc::EBUSY))
+}
+
+/// Whether the error is likely to pass if the operation is tried again shortly: a dropped or
+/// slow network connection, or a file briefly held open by a scanner or the file server.
+pub(crate) fn transient(err: &io::Error) -> bool {
+    use io::ErrorKind::*;
+    in_use(err)
+        || lost_connection(err)
+        || matches!(
+            err.kind(),
+            TimedOut
+                | Interrupted
+                | WouldBlock
+                | ConnectionReset
+                | ConnectionAborted
+                | NetworkDown
+                | NetworkUnreachable
+                | HostUnreachable
+        )
+}
+
+#[cfg(windows)]
+fn lost_connection(err: &io::Error) -> bool {
+    use windows_sys::Win32::Foundation::{
+        ERROR_NETNAME_DELETED, ERROR_SEM_TIMEOUT, ERROR_UNEXP_NET_ERR,
+    };
+    matches!(err.raw_os_error(), Some(code)
+        if [ERROR_NETNAME_DELETED, ERROR_SEM_TIMEOUT, ERROR_UNEXP_NET_ERR].contains(&(code as u32)))
+}
+
+#[cfg(unix)]
+fn lost_connection(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::ESTALE | libc::EIO))
+}
+
+/// Probes the folder and the file to work out which access check failed.
+fn missing_permission(path: &Path) -> String {
+    if let Some(dir) = path.parent() {
+        let probe = dir.join(PROBE_NAME);
+        match OpenOptions::new().write(true).create_new(true).open(&probe) {
+            Ok(_) => {
+                let _ = fs::remove_file(&probe);
+            }
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                return format!(
+                    "write (create files) permission on the folder {}",
+                    dir.display()
+                );
+            }
+            Err(_) => {}
+        }
+    }
+
+    if path.exists() {
+        let writable = OpenOptions::new().write(true).open(path);
+        if matches!(writable, Err(e) if e.kind() == io::ErrorKind::PermissionDenied) {
+            return format!("write permission on the file {}", path.display());
+        }
+        return format!(
+            "delete permission on the file {} (needed to replace it)",
+            path.display()
+        );
+    }
+
+    format!("access to {}", path.display())
+}
+
+fn current_user() -> String {
+    std::env::var("USERNAME")
+        .or_else(|_| std::env::var("USER"))
+        .map(|name| format!("user '{name}'"))
+        .unwrap_or_else(|_| "the current user".to_string())
+}
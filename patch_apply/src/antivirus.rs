@@ -0,0 +1,54 @@
+//! Signs of an antivirus program interfering with the files the patcher writes. Scanners open
+//! a freshly written file to check it, holding it for a moment, and may quarantine it, which
+//! happens most to executables.
+
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use patch_types::error::{FileError, PatchError, Remedy};
+
+use crate::access;
+use crate::apply::STAGING_DIR;
+use crate::segments::SEGMENTS_DIR;
+
+/// How long `--av-safe` lets a scanner finish with the files just written before they are
+/// checked and moved.
+pub(crate) const SETTLE: Duration = Duration::from_secs(2);
+
+/// Whether `err` on `path` looks like a scanner at work: Windows refusing a file as infected,
+/// or one of the patcher's staged files, which no other program has reason to open, held open
+/// or gone.
+pub(crate) fn suspect(err: &io::Error, path: &Path) -> bool {
+    blocked(err)
+        || (path
+            .components()
+            .any(|part| part.as_os_str() == STAGING_DIR || part.as_os_str() == SEGMENTS_DIR)
+            && (access::in_use(err) || err.kind() == io::ErrorKind::NotFound))
+}
+
+#[cfg(windows)]
+fn blocked(err: &io::Error) -> bool {
+    use windows_sys::Win32::Foundation::{ERROR_VIRUS_DELETED, ERROR_VIRUS_INFECTED};
+    matches!(err.raw_os_error(), Some(code)
+        if code == ERROR_VIRUS_INFECTED as i32 || code == ERROR_VIRUS_DELETED as i32)
+}
+
+#[cfg(not(windows))]
+fn blocked(_err: &io::Error) -> bool {
+    false
+}
+
+/// Error for a file of the installation that was gone moments after the patcher wrote it.
+pub(crate) fn vanished(path: &Path, rel: &str) -> anyhow::Error {
+    let message = format!(
+        "{rel} disappeared right after it was written; an antivirus program has probably quarantined it"
+    );
+    anyhow::Error::new(PatchError::Io(FileError::new(
+        path.to_path_buf(),
+        "writing",
+        None,
+        Some(Remedy::Antivirus),
+        message,
+    )))
+}
@@ -0,0 +1,1333 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use rayon::current_thread_index;
+
+use patch_progress::throttle::Ticker;
+use patch_types::buffers;
+use patch_types::error::PatchError;
+use patch_types::normalize::Normalization;
+use patch_types::schedule::{self, largest_first};
+use patch_types::{CHUNK_SIZE, FileEntry, Manifest, PatchData, PatchKind, run_filter};
+
+use crate::memory::MemoryBudget;
+use crate::observer::{PatchObserver, Stage};
+use crate::prefetch::{self, Prefetch};
+use crate::source::BundleSource;
+use crate::telemetry::Telemetry;
+use crate::verify::Verification;
+use crate::{
+    ApplyOptions, access, antivirus, chunks, disk, hash_file_counted, segments, selfexe, throttle,
+};
+
+/// Folder inside the install where new and patched files are decoded before being committed.
+pub(crate) const STAGING_DIR: &str = ".patch_staging";
+/// Workers staging files at once when the installation is on a network share, where more
+/// only queue up behind each other on the link.
+const NETWORK_WORKERS: usize = 2;
+/// Times a failed commit on a network share or in `--av-safe` mode is tried again, first after `NETWORK_RETRY_PAUSE`
+/// and then after twice the previous pause (about 30 seconds in all).
+const NETWORK_RETRIES: u32 = 5;
+const NETWORK_RETRY_PAUSE: Duration = Duration::from_secs(1);
+
+/// Creates the new version's empty directories and removes the old version's, once files are done.
+/// A directory to remove that is no longer empty is kept, since it holds files the patch does not own.
+pub(crate) fn apply_directories(manifest: &Manifest, cwd: &Path) -> Result<()> {
+    for dir in &manifest.created_dirs {
+        let path = cwd.join(dir);
+        fs::create_dir_all(&path).map_err(|e| access::explain(e, &path, "creating"))?;
+    }
+    for dir in &manifest.deleted_dirs {
+        let path = cwd.join(dir);
+        if path.is_dir() && fs::read_dir(&path)?.next().is_none() {
+            fs::remove_dir(&path).map_err(|e| access::explain(e, &path, "removing"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Loads and decompresses the full fallback copy of `path`.
+fn load_fallback(entries: &Prefetch, idx: usize, path: &str) -> Result<Vec<u8>> {
+    match entries.read(idx)? {
+        PatchData::CompressedFull(compressed) => Ok(zstd::decode_all(compressed.as_slice())?),
+        _ => Err(decode_error(
+            path,
+            "its fallback entry is not a compressed full copy".into(),
+        )
+        .into()),
+    }
+}
+
+fn decode_error(path: &str, reason: String) -> PatchError {
+    PatchError::Decode {
+        path: path.to_string(),
+        reason,
+    }
+}
+
+/// Creates a temp file sized up front, so the filesystem can allocate it in one piece. A
+/// `sparse` file is marked so first, so the size is a hole until written.
+fn create_output(tmp: &Path, len: u64, sparse: bool) -> Result<File> {
+    let out = File::create(tmp).map_err(|e| access::explain(e, tmp, "creating"))?;
+    if sparse {
+        disk::mark_sparse(&out);
+    }
+    out.set_len(len)
+        .with_context(|| format!("Allocating {len} bytes for {}", tmp.display()))?;
+    Ok(out)
+}
+
+/// Writes the next `chunk` of a file created by `create_output`. In a sparse file, a chunk of
+/// zeros is skipped instead, leaving a hole.
+fn write_chunk(out: &mut File, chunk: &[u8], sparse: bool) -> io::Result<()> {
+    if sparse && chunk.iter().all(|&byte| byte == 0) {
+        out.seek(SeekFrom::Current(chunk.len() as i64))?;
+        Ok(())
+    } else {
+        out.write_all(chunk)
+    }
+}
+
+/// Hashes a staged file from the chunks written to it, the way [`hash_file_counted`] hashes it
+/// from disk, so phase 1 can verify it without reading it back.
+struct WriteHasher {
+    hasher: blake3::Hasher,
+    normalization: Normalization,
+    /// Start of the file, held until it is as long as the first chunk normalized on reading
+    head: Option<Vec<u8>>,
+    head_len: usize,
+    large: bool,
+}
+
+impl WriteHasher {
+    fn new(file: &FileEntry) -> Self {
+        let head_len = buffers::head_len(file.new_size);
+        WriteHasher {
+            hasher: blake3::Hasher::new(),
+            normalization: file.normalization,
+            head: Some(Vec::with_capacity(
+                file.new_size.min(head_len as u64) as usize
+            )),
+            head_len,
+            large: file.new_size >= schedule::LARGE_FILE,
+        }
+    }
+
+    fn update(&mut self, mut chunk: &[u8]) {
+        if let Some(head) = &mut self.head {
+            let take = (self.head_len - head.len()).min(chunk.len());
+            head.extend_from_slice(&chunk[..take]);
+            chunk = &chunk[take..];
+            if head.len() < self.head_len {
+                return;
+            }
+            self.flush_head();
+        }
+        if self.large {
+            // Lets workers that ran out of files help with the last big ones
+            self.hasher.update_rayon(chunk);
+        } else {
+            self.hasher.update(chunk);
+        }
+    }
+
+    fn flush_head(&mut self) {
+        if let Some(mut head) = self.head.take() {
+            self.normalization.apply(&mut head);
+            self.hasher.update(&head);
+        }
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        self.flush_head();
+        *self.hasher.finalize().as_bytes()
+    }
+}
+
+/// Applies the modification time recorded by the builder. Set on the staged file, since the
+/// commit rename keeps it.
+fn set_mtime(out: &File, file: &FileEntry) -> Result<()> {
+    if let Some(nanos) = file.mtime {
+        out.set_modified(UNIX_EPOCH + Duration::from_nanos(nanos))
+            .with_context(|| format!("Setting modification time of {}", file.path))?;
+    }
+    Ok(())
+}
+
+/// Gives files the POSIX permission bits recorded by the builder, including files whose content
+/// was already up to date, so a rerun fixes binaries that lost their executable bit. Skipped on
+/// filesystems that keep no permissions.
+#[cfg(unix)]
+pub(crate) fn restore_modes(
+    manifest: &Manifest,
+    verification: &Verification,
+    cwd: &Path,
+) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    if !verification.filesystem.permissions {
+        return Ok(());
+    }
+    for (i, file) in manifest.files.iter().enumerate() {
+        let Some(mode) = file.mode else {
+            continue;
+        };
+        if matches!(file.kind, PatchKind::Deleted)
+            || verification.skipped.contains(&i)
+            || verification.left_out.contains(&i)
+            || verification.unselected.contains(&i)
+        {
+            continue;
+        }
+        let path = cwd.join(&file.path);
+        let meta = fs::metadata(&path).map_err(|e| access::explain(e, &path, "reading"))?;
+        if meta.permissions().mode() & 0o7777 != mode {
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode))
+                .map_err(|e| access::explain(e, &path, "setting permissions on"))?;
+        }
+    }
+    Ok(())
+}
+
+// Windows files have no mode bits; only the read-only attribute, which patching clears anyway.
+#[cfg(not(unix))]
+pub(crate) fn restore_modes(
+    _manifest: &Manifest,
+    _verification: &Verification,
+    _cwd: &Path,
+) -> Result<()> {
+    Ok(())
+}
+
+/// Fails early when the volume cannot hold the patched files.
+pub(crate) fn check_disk_space(
+    manifest: &Manifest,
+    verification: &Verification,
+    cwd: &Path,
+) -> Result<()> {
+    let Some(free) = disk::free_space(cwd) else {
+        return Ok(());
+    };
+    let needed = verification.space_needed(manifest);
+    if needed > free {
+        anyhow::bail!(
+            "Not enough disk space in {}: patching needs {} but only {} is free",
+            cwd.display(),
+            indicatif::HumanBytes(needed),
+            indicatif::HumanBytes(free)
+        );
+    }
+    Ok(())
+}
+
+/// Moves a staged file over its target. In durable mode the staged data was flushed when it
+/// was written and the directory entry is flushed here, so a power loss cannot leave a
+/// truncated file.
+fn commit_file(
+    staged: &Path,
+    target: &Path,
+    options: &ApplyOptions,
+    running_exe: Option<&Path>,
+    network: bool,
+) -> Result<()> {
+    access::clear_readonly(target)?;
+    if selfexe::is_running_exe(target, running_exe) {
+        selfexe::replace_running(staged, target)
+    } else {
+        retrying(network || options.av_safe, || {
+            move_file(staged, target, network, options.durable)
+        })
+    }
+    .map_err(|e| {
+        if e.kind() == io::ErrorKind::NotFound && !staged.exists() {
+            access::explain(e, staged, "moving into place")
+        } else {
+            access::explain(e, target, "replacing")
+        }
+    })?;
+    if options.durable {
+        sync_parent(target)?;
+    }
+    Ok(())
+}
+
+/// Renames `from` to `to`, copying instead where a rename does not work: across mount points,
+/// and on network shares, whose servers often refuse to rename over an existing file.
+fn move_file(from: &Path, to: &Path, network: bool, durable: bool) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if network || e.kind() == io::ErrorKind::CrossesDevices => {
+            copy_into_place(from, to, durable)
+        }
+        result => result,
+    }
+}
+
+/// Replaces `to` with a copy of `from`, keeping its modification time, then removes `from`.
+/// Unlike a rename this is not atomic, so `from` is only removed once the copy is complete.
+fn copy_into_place(from: &Path, to: &Path, durable: bool) -> io::Result<()> {
+    fs::copy(from, to)?;
+    let out = OpenOptions::new().write(true).open(to)?;
+    out.set_modified(fs::metadata(from)?.modified()?)?;
+    if durable {
+        out.sync_all()?;
+    }
+    fs::remove_file(from)
+}
+
+/// Runs `op`, and with `retry` (on a network share, or in `--av-safe` mode) tries it again a
+/// few times with growing pauses while it fails in a way a dropped connection, a busy server or
+/// a scanner holding the file would explain.
+fn retrying<T>(retry: bool, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut pause = NETWORK_RETRY_PAUSE;
+    for _ in 0..if retry { NETWORK_RETRIES } else { 0 } {
+        match op() {
+            Err(e) if access::transient(&e) => {
+                std::thread::sleep(pause);
+                pause *= 2;
+            }
+            result => return result,
+        }
+    }
+    op()
+}
+
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// NTFS journals directory metadata itself; directories cannot be flushed through std on Windows.
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Fails once the observer asks to cancel.
+fn check_cancelled<O: PatchObserver>(observer: &Mutex<&mut O>) -> Result<()> {
+    if observer.lock().unwrap().cancelled() {
+        anyhow::bail!("Patching cancelled");
+    }
+    Ok(())
+}
+
+/// Phase 1: decodes new and patched files into the staging folder and verifies them. Nothing
+/// in the installation changes, and the staging folder is removed again if this fails.
+pub(crate) fn stage_files<O: PatchObserver>(
+    manifest: &Manifest,
+    source: &BundleSource,
+    verification: &Verification,
+    cwd: &Path,
+    options: &ApplyOptions,
+    telemetry: &Telemetry,
+    observer: &mut O,
+) -> Result<()> {
+    // Progress is weighted by the bytes each file reads and fetches, so rate and ETA stay honest
+    let weights: Vec<u64> = manifest
+        .files
+        .iter()
+        .enumerate()
+        .map(|(i, file)| match file.kind {
+            _ if verification.untouched(i) || verification.in_place.contains(&i) => 0,
+            PatchKind::Added { idx } => source.entry_len(idx),
+            PatchKind::Unchanged | PatchKind::Moved { .. } | PatchKind::Copied { .. }
+                if verification.use_fallback.contains(&i) =>
+            {
+                file.full_copy.map_or(0, |idx| source.entry_len(idx))
+            }
+            PatchKind::Patched { idx, fallback, .. } => match fallback {
+                _ if verification.use_fallback.contains(&i) => {
+                    match manifest.chunked_file(&file.path) {
+                        Some(chunked) if !chunked.chunk_entries.is_empty() => chunked
+                            .chunk_entries
+                            .iter()
+                            .map(|&entry| source.entry_len(entry))
+                            .sum(),
+                        _ => fallback.map_or(0, |fallback| source.entry_len(fallback)),
+                    }
+                }
+                _ => file.old_size + source.entry_len(idx),
+            },
+            PatchKind::Copied { .. } | PatchKind::Streamed { .. } | PatchKind::Segmented { .. } => {
+                file.new_size
+            }
+            PatchKind::Unchanged | PatchKind::Deleted | PatchKind::Moved { .. } => 0,
+        })
+        .collect();
+
+    observer.totals(manifest.files.len() as u64, weights.iter().sum());
+    let budget = options.max_memory.map(MemoryBudget::new);
+    let network = disk::is_network(cwd);
+    if network {
+        observer.notice(&format!(
+            "{} is on a network share: patching will be slower, with {NETWORK_WORKERS} files at a time \
+             copied into place",
+            cwd.display()
+        ));
+    }
+    let observer = Mutex::new(observer);
+
+    let files = &manifest.files;
+    let ahead = options.max_memory.map_or(prefetch::AHEAD_BYTES, |max| {
+        prefetch::AHEAD_BYTES.min(max / 2)
+    });
+    let entries = Prefetch::new(source, entry_order(manifest, verification), ahead);
+    let staging = cwd.join(STAGING_DIR);
+    if staging.exists() {
+        // Left behind by an interrupted run; nothing in it was committed
+        fs::remove_dir_all(&staging).map_err(|e| access::explain(e, &staging, "removing"))?;
+    }
+    fs::create_dir(&staging).map_err(|e| access::explain(e, &staging, "creating"))?;
+
+    let stage_file = |(i, file): (usize, &FileEntry)| {
+        check_cancelled(&observer)?;
+        let worker = current_thread_index().unwrap_or(0);
+        // Shared by the threads of a segmented file, which report as this file's worker
+        let ticker = Ticker::from_now();
+        let progress = |done| {
+            if ticker.due() {
+                observer.lock().unwrap().file_progress(worker, done);
+            }
+        };
+        let file_started = Instant::now();
+
+        let target = cwd.join(&file.path);
+        let staged = staged_path(&staging, i, file);
+
+        // Whether the file's base did not verify, so it is written from its full copy
+        let restore = verification.use_fallback.contains(&i);
+        // Hash of what was written, for verify_staged
+        let written = match file.kind {
+            _ if verification.untouched(i) => None,
+            // Patched where it is once everything else is staged
+            PatchKind::Streamed { .. } if verification.in_place.contains(&i) => None,
+            // Renames and deletions wait for phase 2
+            PatchKind::Deleted => None,
+            PatchKind::Unchanged | PatchKind::Moved { .. } if !restore => None,
+            PatchKind::Copied { ref from } if !restore => {
+                observer
+                    .lock()
+                    .unwrap()
+                    .file_started(worker, &file.path, file.new_size);
+                let source_path = cwd.join(from);
+                let mut source_file = File::open(&source_path)
+                    .map_err(|e| access::explain(e, &source_path, "reading"))?;
+                let mut out = create_output(&staged, file.new_size, file.sparse)?;
+                let mut hasher = WriteHasher::new(file);
+
+                let write_started = Instant::now();
+                let mut buffer = vec![0u8; buffers::buffer_len(file.new_size)];
+                let mut copied: u64 = 0;
+                loop {
+                    let n = source_file
+                        .read(&mut buffer)
+                        .map_err(|e| access::explain(e, &source_path, "reading"))?;
+                    if n == 0 {
+                        break;
+                    }
+                    if let Some(chaos) = options.chaos {
+                        chaos
+                            .write_error(i)
+                            .with_context(|| format!("Writing {}", file.path))?;
+                    }
+                    write_chunk(&mut out, &buffer[..n], file.sparse)
+                        .map_err(|e| access::explain(e, &target, "writing"))?;
+                    hasher.update(&buffer[..n]);
+                    throttle::io(n as u64);
+                    copied += n as u64;
+                    progress(copied);
+                }
+                set_mtime(&out, file)?;
+                if options.durable {
+                    out.sync_all()
+                        .with_context(|| format!("Syncing {}", file.path))?;
+                }
+                telemetry.add_read(copied);
+                telemetry.add_write(write_started.elapsed());
+                telemetry.add_written(copied);
+                Some(hasher.finish())
+            }
+            // A new file, or one restored from the full copy the patch carries of it
+            PatchKind::Added { .. }
+            | PatchKind::Unchanged
+            | PatchKind::Moved { .. }
+            | PatchKind::Copied { .. } => {
+                let _memory = budget
+                    .as_ref()
+                    .map(|budget| budget.reserve(if restore { 2 } else { 1 } * file.new_size));
+                observer
+                    .lock()
+                    .unwrap()
+                    .file_started(worker, &file.path, file.new_size);
+                let bytes = match (&file.kind, file.full_copy) {
+                    (&PatchKind::Added { idx }, _) => {
+                        match entries
+                            .read(idx)
+                            .with_context(|| format!("Loading entry for {}", file.path))?
+                        {
+                            PatchData::Full(bytes) => bytes,
+                            _ => {
+                                return Err(decode_error(
+                                    &file.path,
+                                    "its entry is not a full copy".into(),
+                                )
+                                .into());
+                            }
+                        }
+                    }
+                    (_, Some(idx)) => load_fallback(&entries, idx, &file.path)
+                        .with_context(|| format!("Restoring {} from its full copy", file.path))?,
+                    (_, None) => {
+                        return Err(decode_error(
+                            &file.path,
+                            "the patch stores no full copy of it".into(),
+                        )
+                        .into());
+                    }
+                };
+                let total = bytes.len() as u64;
+                if total != file.new_size {
+                    let reason = format!(
+                        "the stored copy is {total} bytes, expected {}",
+                        file.new_size
+                    );
+                    return Err(decode_error(&file.path, reason).into());
+                }
+                let mut out = create_output(&staged, file.new_size, file.sparse)?;
+                let mut hasher = WriteHasher::new(file);
+
+                let write_started = Instant::now();
+                let mut written: u64 = 0;
+                for chunk in bytes.chunks(buffers::buffer_len(file.new_size)) {
+                    if let Some(chaos) = options.chaos {
+                        chaos
+                            .write_error(i)
+                            .with_context(|| format!("Writing {}", file.path))?;
+                    }
+                    write_chunk(&mut out, chunk, file.sparse)
+                        .map_err(|e| access::explain(e, &target, "writing"))?;
+                    hasher.update(chunk);
+                    throttle::io(chunk.len() as u64);
+                    written += chunk.len() as u64;
+                    progress(written);
+                }
+                set_mtime(&out, file)?;
+                if options.durable {
+                    out.sync_all()
+                        .with_context(|| format!("Syncing {}", file.path))?;
+                }
+                telemetry.add_write(write_started.elapsed());
+                telemetry.add_written(written);
+                Some(hasher.finish())
+            }
+            // Too large to hold in memory, so written as its chunks come
+            PatchKind::Streamed { ref chunks } => {
+                let chunked = manifest.chunked_file(&file.path).ok_or_else(|| {
+                    decode_error(&file.path, "the patch lists no chunks for it".into())
+                })?;
+                let _memory = budget.as_ref().map(|budget| budget.reserve(2 * CHUNK_SIZE));
+                observer
+                    .lock()
+                    .unwrap()
+                    .file_started(worker, &file.path, file.new_size);
+                let mut out = create_output(&staged, file.new_size, file.sparse)?;
+                let mut hasher = WriteHasher::new(file);
+
+                let write_started = Instant::now();
+                let mut written: u64 = 0;
+                chunks::stream(source, chunked, chunks, &target, telemetry, |chunk| {
+                    if let Some(chaos) = options.chaos {
+                        chaos
+                            .write_error(i)
+                            .with_context(|| format!("Writing {}", file.path))?;
+                    }
+                    write_chunk(&mut out, chunk, file.sparse)
+                        .map_err(|e| access::explain(e, &target, "writing"))?;
+                    hasher.update(chunk);
+                    throttle::io(chunk.len() as u64);
+                    written += chunk.len() as u64;
+                    progress(written);
+                    Ok(())
+                })
+                .with_context(|| format!("Writing {} chunk by chunk", file.path))?;
+                if written != file.new_size {
+                    let reason = format!(
+                        "its chunks add up to {written} bytes, expected {}",
+                        file.new_size
+                    );
+                    return Err(decode_error(&file.path, reason).into());
+                }
+                set_mtime(&out, file)?;
+                if options.durable {
+                    out.sync_all()
+                        .with_context(|| format!("Syncing {}", file.path))?;
+                }
+                telemetry.add_write(write_started.elapsed());
+                telemetry.add_written(written);
+                Some(hasher.finish())
+            }
+            // Decoded segment by segment in parallel, into a file the next apply resumes from
+            PatchKind::Segmented { ref segments } => {
+                let chunked = manifest.chunked_file(&file.path).ok_or_else(|| {
+                    decode_error(&file.path, "the patch lists no chunks for it".into())
+                })?;
+                observer
+                    .lock()
+                    .unwrap()
+                    .file_started(worker, &file.path, file.new_size);
+                let done = segments::stage(
+                    source,
+                    chunked,
+                    segments,
+                    file,
+                    i,
+                    &target,
+                    &staged,
+                    budget.as_ref(),
+                    options.chaos,
+                    telemetry,
+                    &progress,
+                )
+                .with_context(|| format!("Writing {} segment by segment", file.path))?;
+                let out = OpenOptions::new()
+                    .write(true)
+                    .open(&staged)
+                    .map_err(|e| access::explain(e, &staged, "opening"))?;
+                set_mtime(&out, file)?;
+                if options.durable {
+                    out.sync_all()
+                        .with_context(|| format!("Syncing {}", file.path))?;
+                }
+                if done.resumed > 0 {
+                    observer.lock().unwrap().notice(&format!(
+                        "Resumed {}: {} of its {} segments were written by an earlier apply, {} decoded now",
+                        file.path,
+                        done.resumed,
+                        segments.len(),
+                        done.decoded
+                    ));
+                }
+                // Every segment was checked against its hash as it was written
+                Some(file.new_hash)
+            }
+            PatchKind::Patched {
+                idx,
+                fallback,
+                transform,
+            } => {
+                let use_fallback = restore;
+                let chunked = manifest
+                    .chunked_file(&file.path)
+                    .filter(|chunked| !chunked.chunk_entries.is_empty());
+                let total = if use_fallback { 0 } else { file.old_size } + file.new_size;
+                let _memory = budget.as_ref().map(|budget| {
+                    budget.reserve(peak_memory(file, use_fallback, source.entry_len(idx)))
+                });
+                observer
+                    .lock()
+                    .unwrap()
+                    .file_started(worker, &file.path, total);
+
+                let mut read_total: u64 = 0;
+                let data = if use_fallback {
+                    None
+                } else {
+                    let data = entries
+                        .read(idx)
+                        .with_context(|| format!("Loading entry for {}", file.path))?;
+                    Some(data)
+                };
+                let decoded = match data {
+                    None => None,
+                    // Changed from or to an empty file: the entry is the new content, and the
+                    // original is not read
+                    Some(PatchData::Full(bytes)) => Some(bytes),
+                    Some(PatchData::Xdelta(patch)) => {
+                        let mut org_bytes =
+                            Vec::with_capacity(buffers::memory_len(file.old_size, || {
+                                file.path.clone()
+                            })?);
+                        let mut org_file = File::open(&target)
+                            .map_err(|e| access::explain(e, &target, "reading"))?;
+                        let mut buffer = vec![0u8; buffers::buffer_len(file.old_size)];
+
+                        loop {
+                            let n = org_file
+                                .read(&mut buffer)
+                                .map_err(|e| access::explain(e, &target, "reading"))?;
+                            if n == 0 {
+                                break;
+                            }
+                            org_bytes.extend_from_slice(&buffer[..n]);
+                            throttle::io(n as u64);
+                            read_total += n as u64;
+                            progress(read_total);
+                        }
+                        telemetry.add_read(read_total);
+
+                        let transform = transform
+                            .map(|t| {
+                                manifest
+                                    .transforms
+                                    .get(t)
+                                    .ok_or(PatchError::InvalidIndex {
+                                        what: "transform",
+                                        index: t,
+                                    })
+                                    .with_context(|| format!("Patching {}", file.path))
+                            })
+                            .transpose()?;
+                        if let Some(transform) = transform {
+                            org_bytes = run_filter(&transform.normalize, &org_bytes)
+                                .with_context(|| format!("Normalizing {}", file.path))?;
+                        }
+
+                        let decode_started = Instant::now();
+                        let decoded = xdelta3::decode(&patch, &org_bytes);
+                        telemetry.add_decode(decode_started.elapsed());
+                        if decoded.is_none() && fallback.is_none() && chunked.is_none() {
+                            return Err(decode_error(
+                                &file.path,
+                                "xdelta could not apply the delta".into(),
+                            )
+                            .into());
+                        }
+                        match (decoded, transform) {
+                            (Some(decoded), Some(transform)) => Some(
+                                run_filter(&transform.restore, &decoded)
+                                    .with_context(|| format!("Restoring {}", file.path))?,
+                            ),
+                            (decoded, _) => decoded,
+                        }
+                    }
+                    Some(_) => {
+                        return Err(decode_error(
+                            &file.path,
+                            "its entry is neither a delta nor a full copy".into(),
+                        )
+                        .into());
+                    }
+                };
+
+                let new_bytes = match (decoded, chunked) {
+                    (Some(bytes), _) => bytes,
+                    (None, Some(chunked)) => {
+                        let (bytes, fetched) =
+                            chunks::restore(source, chunked, &target, file.new_size, telemetry)
+                                .with_context(|| {
+                                    format!("Restoring {} from its chunks", file.path)
+                                })?;
+                        observer.lock().unwrap().notice(&format!(
+                            "Repaired {} with {fetched} of its {} chunks from the patch",
+                            file.path,
+                            chunked.new_chunks.len()
+                        ));
+                        bytes
+                    }
+                    (None, None) => {
+                        let fallback = fallback.ok_or_else(|| {
+                            decode_error(&file.path, "the patch stores no full copy of it".into())
+                        })?;
+                        load_fallback(&entries, fallback, &file.path).with_context(|| {
+                            format!("Restoring {} from its full copy", file.path)
+                        })?
+                    }
+                };
+
+                let new_len = new_bytes.len() as u64;
+                if new_len != file.new_size {
+                    let reason = format!(
+                        "patching produced {new_len} bytes, expected {}",
+                        file.new_size
+                    );
+                    return Err(decode_error(&file.path, reason).into());
+                }
+                let mut pos = read_total;
+
+                let mut out = create_output(&staged, new_len, file.sparse)?;
+                let mut hasher = WriteHasher::new(file);
+
+                let write_started = Instant::now();
+                for chunk in new_bytes.chunks(buffers::buffer_len(new_len)) {
+                    if let Some(chaos) = options.chaos {
+                        chaos
+                            .write_error(i)
+                            .with_context(|| format!("Writing {}", file.path))?;
+                    }
+                    write_chunk(&mut out, chunk, file.sparse)
+                        .map_err(|e| access::explain(e, &target, "writing"))?;
+                    hasher.update(chunk);
+                    throttle::io(chunk.len() as u64);
+                    pos += chunk.len() as u64;
+                    progress(pos);
+                }
+                set_mtime(&out, file)?;
+                if options.durable {
+                    out.sync_all()
+                        .with_context(|| format!("Syncing {}", file.path))?;
+                }
+                telemetry.add_write(write_started.elapsed());
+                telemetry.add_written(new_len);
+                Some(hasher.finish())
+            }
+        };
+
+        telemetry.record_file(&file.path, file_started.elapsed());
+        observer
+            .lock()
+            .unwrap()
+            .file_finished(&file.path, weights[i]);
+        Ok::<Option<[u8; 32]>, anyhow::Error>(written)
+    };
+
+    // Phase 1 writes only inside the staging folder, so failing here leaves the install untouched
+    // Biggest files first, so a huge file is not left to one core at the end
+    // In download mode, later entries download meanwhile
+    let stage_all = || {
+        entries.alongside(|| {
+            largest_first(
+                files,
+                |file| file.old_size + file.new_size,
+                |i, file| stage_file((i, file)),
+            )
+        })
+    };
+    let staged = if network {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(NETWORK_WORKERS)
+            .build()
+            .map_err(anyhow::Error::from)
+            .and_then(|pool| pool.install(stage_all))
+    } else {
+        stage_all()
+    }
+    .and_then(|written| {
+        if options.av_safe {
+            std::thread::sleep(antivirus::SETTLE);
+        }
+        observer.lock().unwrap().stage(Stage::VerifyingOutput);
+        // Read back only when the disk itself is in doubt
+        let written = if options.paranoid {
+            Vec::new()
+        } else {
+            written
+        };
+        verify_staged(manifest, verification, &staging, &written, telemetry)
+    })
+    .and_then(|_| check_cancelled(&observer));
+    if staged.is_err() {
+        discard_staged(cwd);
+    }
+    staged
+}
+
+/// Phase 2 for the files [`stage_files`] staged, removing the staging folder once done.
+pub(crate) fn commit_staged(
+    manifest: &Manifest,
+    verification: &Verification,
+    cwd: &Path,
+    options: &ApplyOptions,
+    observer: &mut impl PatchObserver,
+) -> Result<()> {
+    observer.stage(Stage::Committing);
+    let staging = cwd.join(STAGING_DIR);
+    // The running patcher: never deleted, and replaced only after every other file
+    let running_exe = selfexe::running_exe();
+    commit_files(
+        manifest,
+        verification,
+        cwd,
+        &staging,
+        options,
+        running_exe.as_deref(),
+        observer,
+    )?;
+    fs::remove_dir_all(&staging).map_err(|e| access::explain(e, &staging, "removing"))?;
+    // Holds only segmented files written for this patch, which are in place now
+    let segments = cwd.join(segments::SEGMENTS_DIR);
+    if segments.exists() {
+        fs::remove_dir_all(&segments).map_err(|e| access::explain(e, &segments, "removing"))?;
+    }
+    Ok(())
+}
+
+/// Removes what [`stage_files`] staged without committing any of it.
+pub(crate) fn discard_staged(cwd: &Path) {
+    let _ = fs::remove_dir_all(cwd.join(STAGING_DIR));
+}
+
+/// The entries staging reads, in the order `largest_first` takes their files: deltas, the
+/// full copies of files that need one and are not repaired chunk by chunk, and new files.
+fn entry_order(manifest: &Manifest, verification: &Verification) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..manifest.files.len()).collect();
+    order.sort_by_key(|&i| {
+        std::cmp::Reverse(manifest.files[i].old_size + manifest.files[i].new_size)
+    });
+    let mut seen = HashSet::new();
+    order
+        .into_iter()
+        .filter(|&i| !verification.untouched(i))
+        .filter_map(|i| {
+            let file = &manifest.files[i];
+            match file.kind {
+                PatchKind::Added { idx } => Some(idx),
+                PatchKind::Patched { idx, .. } if !verification.use_fallback.contains(&i) => {
+                    Some(idx)
+                }
+                PatchKind::Unchanged | PatchKind::Moved { .. } | PatchKind::Copied { .. }
+                    if verification.use_fallback.contains(&i) =>
+                {
+                    file.full_copy
+                }
+                PatchKind::Patched { fallback, .. } => match manifest.chunked_file(&file.path) {
+                    Some(chunked) if !chunked.chunk_entries.is_empty() => None,
+                    _ => fallback,
+                },
+                _ => None,
+            }
+        })
+        .filter(|&idx| seen.insert(idx))
+        .collect()
+}
+
+/// Most memory staging a patched file takes: the original (twice while a transform
+/// normalizes it), the delta and the decoded file, or the full copy and its decompressed bytes.
+fn peak_memory(file: &FileEntry, use_fallback: bool, delta_len: u64) -> u64 {
+    if use_fallback {
+        return 2 * file.new_size;
+    }
+    let PatchKind::Patched { transform, .. } = file.kind else {
+        return file.new_size;
+    };
+    let original = if transform.is_some() {
+        2 * file.old_size
+    } else {
+        file.old_size
+    };
+    original + delta_len + file.new_size
+}
+
+/// Path a file is decoded to during phase 1: in the staging folder, or for a segmented file
+/// beside it in [`segments::SEGMENTS_DIR`], where it outlives a failed apply.
+fn staged_path(staging: &Path, index: usize, file: &FileEntry) -> PathBuf {
+    match (&file.kind, staging.parent()) {
+        (PatchKind::Segmented { .. }, Some(cwd)) => segments::staged_path(cwd, file),
+        _ => staging.join(index.to_string()),
+    }
+}
+
+/// Hashes every staged file against its new hash before anything in the install changes.
+/// `written` holds the hashes taken as the files were written, by index; a file without one
+/// is read back from the staging folder.
+fn verify_staged(
+    manifest: &Manifest,
+    verification: &Verification,
+    staging: &Path,
+    written: &[Option<[u8; 32]>],
+    telemetry: &Telemetry,
+) -> Result<()> {
+    let staged: Vec<(usize, &FileEntry)> = manifest
+        .files
+        .iter()
+        .enumerate()
+        .filter(|&(i, file)| verification.is_staged(i, file))
+        .collect();
+    largest_first(
+        &staged,
+        |(_, file)| file.new_size,
+        |_, &(i, file)| {
+            let hash = match written.get(i).copied().flatten() {
+                Some(hash) => hash,
+                None => hash_file_counted(
+                    &staged_path(staging, i, file),
+                    file.normalization,
+                    telemetry,
+                )
+                .with_context(|| format!("Hashing staged {}", file.path))?,
+            };
+            if hash != file.new_hash {
+                let mismatch = PatchError::HashMismatch {
+                    path: file.path.clone(),
+                    expected: file.new_hash,
+                    actual: Some(hash),
+                };
+                return Err(mismatch).with_context(|| {
+                    format!("Patched {} does not match the new version", file.path)
+                });
+            }
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
+
+/// Step of the commit a file's change runs in. Moves free old paths first and new content goes
+/// in next; deletions come last, so nothing is gone before its replacement is in place. A
+/// deletion in the way of a new file runs before everything else: a file where the new version
+/// has a folder of the same name, or a file inside a folder the new version replaces with a file.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum CommitStep {
+    Unblock,
+    Move,
+    Write,
+    Delete,
+}
+
+/// Folders above a slash-separated relative path, innermost first.
+fn parent_dirs(rel: &str) -> impl Iterator<Item = &str> {
+    rel.rmatch_indices('/').map(move |(i, _)| &rel[..i])
+}
+
+fn commit_steps(files: &[FileEntry]) -> Vec<CommitStep> {
+    let written: HashSet<&str> = files
+        .iter()
+        .filter(|file| !matches!(file.kind, PatchKind::Unchanged | PatchKind::Deleted))
+        .map(|file| file.path.as_str())
+        .collect();
+    let written_dirs: HashSet<&str> = written.iter().flat_map(|path| parent_dirs(path)).collect();
+    files
+        .iter()
+        .map(|file| match file.kind {
+            PatchKind::Deleted
+                if written_dirs.contains(file.path.as_str())
+                    || parent_dirs(&file.path).any(|dir| written.contains(dir)) =>
+            {
+                CommitStep::Unblock
+            }
+            PatchKind::Deleted => CommitStep::Delete,
+            PatchKind::Moved { .. } => CommitStep::Move,
+            PatchKind::Unchanged
+            | PatchKind::Added { .. }
+            | PatchKind::Patched { .. }
+            | PatchKind::Copied { .. }
+            | PatchKind::Streamed { .. }
+            | PatchKind::Segmented { .. } => CommitStep::Write,
+        })
+        .collect()
+}
+
+/// Deleted files in the way of a new file or folder, which `--no-delete` cannot keep.
+pub(crate) fn blocking_deletions(files: &[FileEntry]) -> HashSet<usize> {
+    commit_steps(files)
+        .into_iter()
+        .enumerate()
+        .filter(|&(_, step)| step == CommitStep::Unblock)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Removes `dir` and the folders above it up to `cwd` while they are empty.
+fn prune_empty_dirs(cwd: &Path, rel_dir: &str) {
+    for dir in std::iter::once(rel_dir).chain(parent_dirs(rel_dir)) {
+        // Fails on the first folder that still holds something, which then stays with its parents
+        if fs::remove_dir(cwd.join(dir)).is_err() {
+            break;
+        }
+    }
+}
+
+/// Removes the folder `dir` and the empty folders inside it, failing if any file is left.
+fn remove_empty_tree(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_empty_tree(&entry.path())?;
+        }
+    }
+    fs::remove_dir(dir)
+}
+
+/// Phase 2: moves staged files into place and performs renames and deletions, in the order
+/// of [`CommitStep`]. Only metadata operations remain, so the window in which an interruption
+/// leaves a mixed install is short. Folders emptied by deletions and moves are removed last.
+fn commit_files(
+    manifest: &Manifest,
+    verification: &Verification,
+    cwd: &Path,
+    staging: &Path,
+    options: &ApplyOptions,
+    running_exe: Option<&Path>,
+    observer: &mut impl PatchObserver,
+) -> Result<()> {
+    let files = &manifest.files;
+    let network = disk::is_network(cwd);
+
+    // The running patcher is replaced last, once nothing else can fail
+    let deferred = files
+        .iter()
+        .position(|file| selfexe::is_running_exe(&cwd.join(&file.path), running_exe));
+    let steps = commit_steps(files);
+    let mut order: Vec<usize> = (0..files.len())
+        .filter(|&i| Some(i) != deferred && !verification.untouched(i))
+        .collect();
+    order.sort_by_key(|&i| steps[i]);
+    order.extend(deferred.filter(|&i| !verification.untouched(i)));
+    // Folders that lost a file, pruned once every file is in place
+    let mut emptied: BTreeSet<&str> = BTreeSet::new();
+
+    for i in order {
+        let file = &files[i];
+        let target = cwd.join(&file.path);
+        match file.kind {
+            PatchKind::Unchanged if !verification.use_fallback.contains(&i) => {}
+            // Already patched where it is
+            PatchKind::Streamed { .. } if verification.in_place.contains(&i) => {}
+            PatchKind::Deleted => {
+                if Some(i) == deferred {
+                    observer.notice(&format!("Keeping {}: it is the running patcher", file.path));
+                } else if target.exists() {
+                    access::clear_readonly(&target)?;
+                    fs::remove_file(&target)
+                        .map_err(|e| access::explain(e, &target, "removing"))?;
+                    if options.durable {
+                        sync_parent(&target).with_context(|| format!("Syncing {}", file.path))?;
+                    }
+                }
+                emptied.extend(parent_dirs(&file.path).next());
+            }
+            PatchKind::Moved { ref from } if !verification.use_fallback.contains(&i) => {
+                let source_path = cwd.join(from);
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)
+                        .with_context(|| format!("Creating dir for {}", file.path))?;
+                }
+                access::clear_readonly(&target)?;
+                retrying(network || options.av_safe, || {
+                    move_file(&source_path, &target, network, options.durable)
+                })
+                .map_err(|e| access::explain(e, &target, "moving a file to"))?;
+                if options.durable {
+                    sync_parent(&target).with_context(|| format!("Syncing {}", file.path))?;
+                    sync_parent(&source_path).with_context(|| format!("Syncing {from}"))?;
+                }
+                emptied.extend(parent_dirs(from).next());
+            }
+            PatchKind::Unchanged
+            | PatchKind::Moved { .. }
+            | PatchKind::Added { .. }
+            | PatchKind::Patched { .. }
+            | PatchKind::Copied { .. }
+            | PatchKind::Streamed { .. }
+            | PatchKind::Segmented { .. } => {
+                if let Some(chaos) = options.chaos {
+                    chaos.power_loss("before replacing a file", Some(i));
+                }
+                if target.is_dir() {
+                    // A folder of the old version, emptied by the deletions that ran first
+                    remove_empty_tree(&target)
+                        .map_err(|e| access::explain(e, &target, "removing the old folder"))?;
+                }
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)
+                        .with_context(|| format!("Creating dir for {}", file.path))?;
+                }
+                commit_file(
+                    &staged_path(staging, i, file),
+                    &target,
+                    options,
+                    running_exe,
+                    network,
+                )
+                .with_context(|| format!("Renaming {}", file.path))?;
+                // Restored from its full copy, so whatever is left at its old path goes as the
+                // move would have taken it
+                if let PatchKind::Moved { ref from } = file.kind {
+                    let source_path = cwd.join(from);
+                    if source_path.is_file() {
+                        access::clear_readonly(&source_path)?;
+                        fs::remove_file(&source_path)
+                            .map_err(|e| access::explain(e, &source_path, "removing"))?;
+                    }
+                    emptied.extend(parent_dirs(from).next());
+                }
+            }
+        }
+    }
+    // Deepest first, so a parent is tried once its children are gone
+    for dir in emptied.into_iter().rev() {
+        prune_empty_dirs(cwd, dir);
+    }
+    check_committed(manifest, verification, cwd, options)
+}
+
+/// Fails naming the first file put in place that is gone again, as a scanner quarantining it
+/// would leave things. In `--av-safe` mode scanners first get time to finish.
+fn check_committed(
+    manifest: &Manifest,
+    verification: &Verification,
+    cwd: &Path,
+    options: &ApplyOptions,
+) -> Result<()> {
+    if options.av_safe {
+        std::thread::sleep(antivirus::SETTLE);
+    }
+    let written = manifest.files.iter().enumerate().filter(|&(i, file)| {
+        !verification.untouched(i)
+            && (!matches!(file.kind, PatchKind::Unchanged | PatchKind::Deleted)
+                || verification.use_fallback.contains(&i))
+    });
+    for (_, file) in written {
+        let target = cwd.join(&file.path);
+        if !target.exists() {
+            return Err(antivirus::vanished(&target, &file.path));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, kind: PatchKind) -> FileEntry {
+        FileEntry {
+            path: path.to_string(),
+            kind,
+            original_hash: [0; 32],
+            new_hash: [0; 32],
+            old_size: 0,
+            new_size: 0,
+            mtime: None,
+            normalization: Normalization::None,
+            mode: None,
+            sparse: false,
+            full_copy: None,
+        }
+    }
+
+    fn added(path: &str) -> FileEntry {
+        entry(path, PatchKind::Added { idx: 0 })
+    }
+
+    fn deleted(path: &str) -> FileEntry {
+        entry(path, PatchKind::Deleted)
+    }
+
+    /// Paths of `files` in the order the commit handles them.
+    fn commit_order(files: &[FileEntry]) -> Vec<&str> {
+        let steps = commit_steps(files);
+        let mut order: Vec<usize> = (0..files.len()).collect();
+        order.sort_by_key(|&i| steps[i]);
+        order.into_iter().map(|i| files[i].path.as_str()).collect()
+    }
+
+    #[test]
+    fn moved_file_into_a_folder_replacing_a_deleted_file() {
+        // The old version has a file `data`; the new one has a folder there holding the file
+        // moved from `save.dat`
+        let files = [
+            entry(
+                "data/save.dat",
+                PatchKind::Moved {
+                    from: "save.dat".to_string(),
+                },
+            ),
+            deleted("data"),
+            deleted("old.log"),
+        ];
+        assert_eq!(
+            commit_steps(&files),
+            [CommitStep::Move, CommitStep::Unblock, CommitStep::Delete]
+        );
+        assert_eq!(commit_order(&files), ["data", "data/save.dat", "old.log"]);
+        assert_eq!(blocking_deletions(&files), HashSet::from([1]));
+    }
+
+    #[test]
+    fn moved_file_out_of_a_folder_replaced_by_a_file() {
+        // `cfg/app.ini` moves out, and `cfg` becomes a file once its other file is deleted
+        let files = [
+            added("cfg"),
+            entry(
+                "app.ini",
+                PatchKind::Moved {
+                    from: "cfg/app.ini".to_string(),
+                },
+            ),
+            deleted("cfg/old.ini"),
+        ];
+        assert_eq!(commit_order(&files), ["cfg/old.ini", "app.ini", "cfg"]);
+    }
+
+    #[test]
+    fn file_replacing_a_folder() {
+        let files = [
+            deleted("plugins/a.dll"),
+            deleted("plugins/sub/b.dll"),
+            added("plugins"),
+        ];
+        assert_eq!(
+            commit_steps(&files),
+            [CommitStep::Unblock, CommitStep::Unblock, CommitStep::Write]
+        );
+        assert_eq!(blocking_deletions(&files), HashSet::from([0, 1]));
+    }
+
+    #[test]
+    fn folder_replacing_a_file() {
+        let files = [
+            added("plugins/a.dll"),
+            entry(
+                "plugins/sub/b.dll",
+                PatchKind::Patched {
+                    idx: 1,
+                    fallback: None,
+                    transform: None,
+                },
+            ),
+            deleted("plugins"),
+        ];
+        assert_eq!(
+            commit_order(&files),
+            ["plugins", "plugins/a.dll", "plugins/sub/b.dll"]
+        );
+        assert_eq!(blocking_deletions(&files), HashSet::from([2]));
+    }
+
+    #[test]
+    fn copied_file_outlives_its_deleted_source() {
+        // Copies are staged before the commit starts, so their source may go in the same patch;
+        // it is deleted only once the copies are in place
+        let files = [
+            deleted("a.pak"),
+            entry(
+                "b.pak",
+                PatchKind::Copied {
+                    from: "a.pak".to_string(),
+                },
+            ),
+            entry(
+                "c/a.pak",
+                PatchKind::Copied {
+                    from: "a.pak".to_string(),
+                },
+            ),
+        ];
+        assert_eq!(
+            commit_steps(&files),
+            [CommitStep::Delete, CommitStep::Write, CommitStep::Write]
+        );
+        assert_eq!(commit_order(&files), ["b.pak", "c/a.pak", "a.pak"]);
+        assert!(blocking_deletions(&files).is_empty());
+    }
+
+    #[test]
+    fn copied_file_into_a_folder_replacing_its_source() {
+        let files = [
+            deleted("a"),
+            entry(
+                "a/b",
+                PatchKind::Copied {
+                    from: "a".to_string(),
+                },
+            ),
+        ];
+        assert_eq!(commit_order(&files), ["a", "a/b"]);
+    }
+}
@@ -0,0 +1,73 @@
+use std::io;
+
+/// Points where `--chaos` can inject a failure.
+#[derive(Clone, Copy)]
+enum Fault {
+    /// A base file reads back with the wrong hash
+    HashMismatch,
+    /// Writing a temp file fails
+    WriteError,
+    /// The process dies, as on power loss: after a temp file is written but before it replaces
+    /// its target, or between the verify / apply / marker stages
+    PowerLoss,
+}
+
+/// Roughly one in this many fault points fires.
+const FAULT_RATE: u64 = 8;
+
+/// Deterministic failure injection for testing recovery against a real filesystem.
+///
+/// Each decision depends only on the seed, the fault and the file index, so a seed replays the
+/// same failures regardless of how the work was scheduled across threads.
+#[derive(Clone, Copy)]
+pub struct Chaos {
+    seed: u64,
+}
+
+impl Chaos {
+    pub fn new(seed: u64) -> Self {
+        Chaos { seed }
+    }
+
+    /// Whether `fault` fires at `point` (a file index, or a hash of a stage name).
+    fn fires(&self, fault: Fault, point: u64) -> bool {
+        splitmix64(self.seed ^ splitmix64(fault as u64) ^ splitmix64(point))
+            .is_multiple_of(FAULT_RATE)
+    }
+
+    /// Whether the base file at `index` should be treated as corrupt.
+    pub fn hash_mismatch(&self, index: usize) -> bool {
+        self.fires(Fault::HashMismatch, index as u64)
+    }
+
+    pub fn write_error(&self, index: usize) -> io::Result<()> {
+        if self.fires(Fault::WriteError, index as u64) {
+            return Err(io::Error::other("chaos: injected write failure"));
+        }
+        Ok(())
+    }
+
+    /// Terminates without unwinding or flushing anything, leaving the filesystem as it is.
+    /// `index` is the file being written, or `None` at a boundary between stages.
+    pub fn power_loss(&self, stage: &str, index: Option<usize>) {
+        let point = index.map_or_else(
+            || {
+                stage
+                    .bytes()
+                    .fold(u64::MAX, |h, b| splitmix64(h ^ b as u64))
+            },
+            |i| i as u64,
+        );
+        if self.fires(Fault::PowerLoss, point) {
+            eprintln!("chaos: simulated power loss {stage}");
+            std::process::abort();
+        }
+    }
+}
+
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
@@ -0,0 +1,171 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::Result;
+
+use patch_types::buffers;
+use patch_types::error::PatchError;
+use patch_types::{CHUNK_SIZE, ChunkedFile, PatchData};
+
+use crate::source::BundleSource;
+use crate::telemetry::Telemetry;
+use crate::{access, throttle};
+
+/// Reads chunk `index` of `file`, shorter at the end of the file.
+fn read_chunk(file: &mut File, index: u64, path: &Path) -> Result<Vec<u8>> {
+    let reading = |e| access::explain(e, path, "reading");
+    file.seek(SeekFrom::Start(index * CHUNK_SIZE))
+        .map_err(reading)?;
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE as usize);
+    file.take(CHUNK_SIZE)
+        .read_to_end(&mut chunk)
+        .map_err(reading)?;
+    throttle::io(chunk.len() as u64);
+    Ok(chunk)
+}
+
+/// Chunks of the file at `path` whose hashes differ from `expected`, including those past
+/// its end.
+pub(crate) fn differing(
+    path: &Path,
+    expected: &[[u8; 32]],
+    telemetry: &Telemetry,
+) -> Result<Vec<u64>> {
+    let Ok(mut file) = File::open(path) else {
+        return Ok((0..expected.len() as u64).collect());
+    };
+    let mut differ = Vec::new();
+    for (index, hash) in (0..).zip(expected) {
+        let chunk = read_chunk(&mut file, index, path)?;
+        telemetry.add_read(chunk.len() as u64);
+        if blake3::hash(&chunk).as_bytes() != hash {
+            differ.push(index);
+        }
+    }
+    Ok(differ)
+}
+
+/// Rebuilds the new version of a chunked file, keeping the chunks of the file at `path` that
+/// already hold the right content and fetching only the others from the patch. Returns the
+/// content and the number of chunks fetched.
+pub(crate) fn restore(
+    source: &BundleSource,
+    chunked: &ChunkedFile,
+    path: &Path,
+    new_size: u64,
+    telemetry: &Telemetry,
+) -> Result<(Vec<u8>, usize)> {
+    let mut local = File::open(path).ok();
+    let mut content = Vec::with_capacity(buffers::memory_len(new_size, || chunked.path.clone())?);
+    let mut fetched = 0;
+    for (index, (hash, &entry)) in (0..).zip(chunked.new_chunks.iter().zip(&chunked.chunk_entries))
+    {
+        if let Some(file) = local.as_mut() {
+            let chunk = read_chunk(file, index, path)?;
+            telemetry.add_read(chunk.len() as u64);
+            if blake3::hash(&chunk).as_bytes() == hash {
+                content.extend_from_slice(&chunk);
+                continue;
+            }
+        }
+        let chunk = fetch(source, chunked, index, entry)?;
+        content.extend_from_slice(&chunk);
+        fetched += 1;
+    }
+    if content.len() as u64 != new_size {
+        let reason = format!(
+            "its chunks add up to {} bytes, expected {new_size}",
+            content.len()
+        );
+        return Err(PatchError::Decode {
+            path: chunked.path.clone(),
+            reason,
+        }
+        .into());
+    }
+    Ok((content, fetched))
+}
+
+/// Writes the new version of a streamed file to `write` chunk by chunk, taking each chunk from
+/// the same place in the file at `path` when it already holds it and from its entry in
+/// `entries` otherwise, so the file is never held whole in memory. Returns the number of
+/// chunks fetched from the patch.
+pub(crate) fn stream(
+    source: &BundleSource,
+    chunked: &ChunkedFile,
+    entries: &[Option<usize>],
+    path: &Path,
+    telemetry: &Telemetry,
+    mut write: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<usize> {
+    if entries.len() != chunked.new_chunks.len() {
+        let reason = format!(
+            "{} chunk entries for {} chunks",
+            entries.len(),
+            chunked.new_chunks.len()
+        );
+        return Err(PatchError::Decode {
+            path: chunked.path.clone(),
+            reason,
+        }
+        .into());
+    }
+    let mut local = File::open(path).ok();
+    let mut fetched = 0;
+    for (index, (hash, &entry)) in (0..).zip(chunked.new_chunks.iter().zip(entries)) {
+        if let Some(file) = local.as_mut() {
+            let chunk = read_chunk(file, index, path)?;
+            telemetry.add_read(chunk.len() as u64);
+            if blake3::hash(&chunk).as_bytes() == hash {
+                write(&chunk)?;
+                continue;
+            }
+        }
+        let Some(entry) = entry else {
+            return Err(decode_error(
+                &chunked.path,
+                index,
+                "the file on disk does not hold it and the patch stores no copy",
+            )
+            .into());
+        };
+        let chunk = fetch(source, chunked, index, entry)?;
+        write(&chunk)?;
+        fetched += 1;
+    }
+    Ok(fetched)
+}
+
+/// Loads chunk `index` of `chunked` from its entry, checked against the chunk's new hash.
+pub(crate) fn fetch(
+    source: &BundleSource,
+    chunked: &ChunkedFile,
+    index: u64,
+    entry: usize,
+) -> Result<Vec<u8>> {
+    let chunk = match source.read_entry(entry)? {
+        PatchData::CompressedFull(compressed) => zstd::decode_all(compressed.as_slice())?,
+        _ => {
+            return Err(
+                decode_error(&chunked.path, index, "its entry is not a compressed copy").into(),
+            );
+        }
+    };
+    if Some(blake3::hash(&chunk).as_bytes()) != chunked.new_chunks.get(index as usize) {
+        return Err(decode_error(
+            &chunked.path,
+            index,
+            "its stored copy does not match its hash",
+        )
+        .into());
+    }
+    Ok(chunk)
+}
+
+pub(crate) fn decode_error(path: &str, index: u64, reason: &str) -> PatchError {
+    PatchError::Decode {
+        path: path.to_string(),
+        reason: format!("chunk {index}: {reason}"),
+    }
+}
@@ -0,0 +1,177 @@
+use std::path::Path;
+
+/// Bytes available to this user on the volume holding `path`, if the platform reports it.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: c_path is NUL-terminated and stat is only read after statvfs fills it in.
+    if unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let stat = unsafe { stat.assume_init() };
+    // The field widths differ between Unix flavours
+    #[allow(clippy::unnecessary_cast)]
+    let free = stat.f_bavail as u64 * stat.f_frsize as u64;
+    Some(free)
+}
+
+#[cfg(windows)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut available = 0u64;
+    // SAFETY: wide is NUL-terminated; the unused outputs may be null.
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            wide.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    (ok != 0).then_some(available)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Whether `path` is on a network share (NFS, SMB), where renames and parallel writes are
+/// unreliable and slow.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn is_network(path: &Path) -> bool {
+    // NFS, SMB, CIFS and SMB2, from linux/magic.h
+    const NETWORK_MAGICS: [i64; 4] = [0x6969, 0x517B, 0xFF53_4D42, 0xFE53_4D42];
+    fs_magic(path).is_some_and(|kind| NETWORK_MAGICS.contains(&kind))
+}
+
+/// Magic number of the filesystem holding `path`, as in linux/magic.h.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn fs_magic(path: &Path) -> Option<i64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: c_path is NUL-terminated and stat is only read after statfs fills it in.
+    if unsafe { libc::statfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let stat = unsafe { stat.assume_init() };
+    // f_type is signed on some architectures, so the 32-bit magic numbers are compared unsigned
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_type as i64 & 0xFFFF_FFFF)
+}
+
+#[cfg(windows)]
+pub fn is_network(path: &Path) -> bool {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::{GetDriveTypeW, GetVolumePathNameW};
+    use windows_sys::Win32::System::WindowsProgramming::DRIVE_REMOTE;
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut root = [0u16; 261];
+    // SAFETY: wide is NUL-terminated and root is as long as the length passed.
+    if unsafe { GetVolumePathNameW(wide.as_ptr(), root.as_mut_ptr(), root.len() as u32) } == 0 {
+        // UNC paths name their share even when the volume cannot be looked up
+        let path = path.as_os_str().to_string_lossy();
+        return path.starts_with(r"\\?\UNC\")
+            || (path.starts_with(r"\\") && !path.starts_with(r"\\?\"));
+    }
+    // SAFETY: GetVolumePathNameW NUL-terminated root.
+    unsafe { GetDriveTypeW(root.as_ptr()) == DRIVE_REMOTE }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+pub fn is_network(_path: &Path) -> bool {
+    false
+}
+
+/// Name of the filesystem holding `path` where it has limits that matter to patching, such
+/// as `FAT` or `exFAT`; `None` for the rest and where it cannot be told.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn fs_name(path: &Path) -> Option<String> {
+    // From linux/magic.h and the exFAT driver
+    const MSDOS_SUPER_MAGIC: i64 = 0x4D44;
+    const EXFAT_SUPER_MAGIC: i64 = 0x2011_BAB0;
+    match fs_magic(path)? {
+        MSDOS_SUPER_MAGIC => Some("FAT".to_string()),
+        EXFAT_SUPER_MAGIC => Some("exFAT".to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(windows)]
+pub fn fs_name(path: &Path) -> Option<String> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::{GetVolumeInformationW, GetVolumePathNameW};
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut root = [0u16; 261];
+    // SAFETY: wide is NUL-terminated and root is as long as the length passed.
+    if unsafe { GetVolumePathNameW(wide.as_ptr(), root.as_mut_ptr(), root.len() as u32) } == 0 {
+        return None;
+    }
+    let mut name = [0u16; 261];
+    // SAFETY: root was NUL-terminated by GetVolumePathNameW, name is as long as the length
+    // passed, and the unused outputs may be null.
+    let ok = unsafe {
+        GetVolumeInformationW(
+            root.as_ptr(),
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            name.as_mut_ptr(),
+            name.len() as u32,
+        )
+    };
+    if ok == 0 {
+        return None;
+    }
+    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    let name = String::from_utf16_lossy(&name[..len]);
+    // FAT12, FAT16 and FAT32 share the limits
+    matches!(name.as_str(), "FAT" | "FAT32" | "exFAT").then_some(name)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+pub fn fs_name(_path: &Path) -> Option<String> {
+    None
+}
+
+/// Marks `file` sparse, so the regions it skips stay unallocated. NTFS needs this before a
+/// file can have holes; where it fails (FAT, network shares) the file is written in full.
+#[cfg(windows)]
+pub fn mark_sparse(file: &std::fs::File) {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+    use windows_sys::Win32::System::Ioctl::FSCTL_SET_SPARSE;
+
+    let mut returned = 0u32;
+    // SAFETY: the handle is open for writing; FSCTL_SET_SPARSE takes no buffers.
+    unsafe {
+        DeviceIoControl(
+            file.as_raw_handle(),
+            FSCTL_SET_SPARSE,
+            std::ptr::null(),
+            0,
+            std::ptr::null_mut(),
+            0,
+            &mut returned,
+            std::ptr::null_mut(),
+        );
+    }
+}
+
+/// Unix filesystems leave whatever a file skips unallocated without being asked.
+#[cfg(not(windows))]
+pub fn mark_sparse(_file: &std::fs::File) {}
@@ -0,0 +1,136 @@
+use std::fs::{self, File};
+use std::path::Path;
+
+use anyhow::Result;
+use indicatif::HumanBytes;
+use serde::Serialize;
+
+use patch_types::{Manifest, PatchKind};
+
+use crate::observer::PatchObserver;
+use crate::{disk, verify};
+
+/// Largest file FAT can hold: its sizes are 32-bit.
+const FAT_MAX_FILE: u64 = (4 << 30) - 1;
+
+/// What the filesystem holding the target folder can do, probed before patching. Whatever
+/// could not be probed is assumed to work.
+#[derive(Clone, Debug, Serialize)]
+pub struct Filesystem {
+    /// Name of the filesystem when it has limits, e.g. `FAT32` or `exFAT`
+    pub kind: Option<String>,
+    /// Largest file it can hold
+    pub max_file_size: Option<u64>,
+    pub case_sensitive: bool,
+    /// Whether symbolic links can be created in the folder
+    pub symlinks: bool,
+    /// Whether it keeps POSIX permission bits
+    pub permissions: bool,
+    /// Whether a file replaced by renaming over it is never lost to a power cut midway
+    pub atomic_rename: bool,
+}
+
+impl Default for Filesystem {
+    fn default() -> Self {
+        Filesystem {
+            kind: None,
+            max_file_size: None,
+            case_sensitive: true,
+            symlinks: true,
+            permissions: true,
+            atomic_rename: true,
+        }
+    }
+}
+
+impl Filesystem {
+    /// Probes the filesystem holding `dir` with short-lived files in it.
+    pub fn probe(dir: &Path) -> Self {
+        let kind = disk::fs_name(dir);
+        // FAT and exFAT keep no journal, so a rename over a file is not safe from a power cut
+        let fat = kind.is_some();
+        let probe = dir.join(format!(".patch_fs_probe_{}", std::process::id()));
+        let probed = File::create(&probe).is_ok();
+        let filesystem = Filesystem {
+            max_file_size: kind
+                .as_deref()
+                .filter(|kind| kind.starts_with("FAT"))
+                .map(|_| FAT_MAX_FILE),
+            case_sensitive: !verify::is_case_insensitive(dir).unwrap_or(false),
+            symlinks: !probed || links(&probe),
+            permissions: !probed || keeps_modes(&probe),
+            atomic_rename: !fat,
+            kind,
+        };
+        let _ = fs::remove_file(&probe);
+        filesystem
+    }
+
+    /// Fails naming the files the patch writes that this filesystem cannot hold, and tells
+    /// the user about the limits that do not stop the patch.
+    pub(crate) fn check(
+        &self,
+        manifest: &Manifest,
+        observer: &mut impl PatchObserver,
+    ) -> Result<()> {
+        let name = self.kind.as_deref().unwrap_or("this filesystem");
+        if let Some(max) = self.max_file_size {
+            let too_large: Vec<String> = manifest
+                .files
+                .iter()
+                .filter(|file| !matches!(file.kind, PatchKind::Deleted) && file.new_size > max)
+                .map(|file| format!("  {} ({})", file.path, HumanBytes(file.new_size)))
+                .collect();
+            if !too_large.is_empty() {
+                anyhow::bail!(
+                    "The target folder is on {name}, which cannot hold files over {}, but the patch \
+                     needs these:\n{}\nInstall to a drive formatted as NTFS or exFAT, or reformat this one",
+                    HumanBytes(max + 1),
+                    too_large.join("\n")
+                );
+            }
+        }
+        if !self.permissions && manifest.files.iter().any(|file| file.mode.is_some()) {
+            observer.notice(&format!(
+                "The target folder is on {name}, which keeps no file permissions; they are left as the drive sets them"
+            ));
+        }
+        if !self.atomic_rename {
+            observer.notice(&format!(
+                "The target folder is on {name}, where files are not replaced atomically; keep the drive \
+                 connected and the computer on until patching is done"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Whether a symbolic link to `probe` can be created beside it.
+fn links(probe: &Path) -> bool {
+    let link = probe.with_extension("link");
+    #[cfg(unix)]
+    let linked = std::os::unix::fs::symlink(probe, &link).is_ok();
+    #[cfg(windows)]
+    let linked = std::os::windows::fs::symlink_file(probe, &link).is_ok();
+    #[cfg(not(any(unix, windows)))]
+    let linked = false;
+    let _ = fs::remove_file(&link);
+    linked
+}
+
+/// Whether permission bits set on `probe` read back as set. FAT and exFAT mounts give every
+/// file the same mode and refuse or ignore changes to it.
+#[cfg(unix)]
+fn keeps_modes(probe: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    [0o600, 0o644].iter().all(|&mode| {
+        fs::set_permissions(probe, fs::Permissions::from_mode(mode)).is_ok()
+            && fs::metadata(probe).is_ok_and(|meta| meta.permissions().mode() & 0o777 == mode)
+    })
+}
+
+// Windows files have no mode bits to keep, and patches restore none there
+#[cfg(not(unix))]
+fn keeps_modes(_probe: &Path) -> bool {
+    true
+}
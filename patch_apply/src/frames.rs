@@ -0,0 +1,70 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+
+use anyhow::Result;
+
+/// Decompressed frames kept for the entries still to be read from them, at most. Entries are
+/// read in about the order the builder packed them, so a few frames cover every worker.
+const CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Frames of small entries compressed together, decompressed once and shared by the workers
+/// that read their entries. The frame a worker waits on is fetched by whichever asked first.
+#[derive(Default)]
+pub(crate) struct FrameCache {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    frames: HashMap<usize, Slot>,
+    /// Ready frames, oldest first, for eviction
+    order: VecDeque<usize>,
+    held: usize,
+}
+
+enum Slot {
+    Fetching,
+    Ready(Arc<Vec<u8>>),
+}
+
+impl FrameCache {
+    /// The decompressed frame `idx`, from the cache or from `fetch`. A failed fetch is not
+    /// cached, so the next reader tries again.
+    pub fn get(&self, idx: usize, fetch: impl FnOnce() -> Result<Vec<u8>>) -> Result<Arc<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            match state.frames.get(&idx) {
+                Some(Slot::Ready(frame)) => return Ok(frame.clone()),
+                Some(Slot::Fetching) => state = self.changed.wait(state).unwrap(),
+                None => break,
+            }
+        }
+        state.frames.insert(idx, Slot::Fetching);
+        drop(state);
+
+        let fetched = fetch();
+        let mut state = self.state.lock().unwrap();
+        let frame = match fetched {
+            Ok(frame) => Arc::new(frame),
+            Err(e) => {
+                state.frames.remove(&idx);
+                self.changed.notify_all();
+                return Err(e);
+            }
+        };
+        // The newest frame stays, however large
+        while state.held + frame.len() > CACHE_BYTES
+            && let Some(oldest) = state.order.pop_front()
+        {
+            if let Some(Slot::Ready(evicted)) = state.frames.remove(&oldest) {
+                state.held -= evicted.len();
+            }
+        }
+        state.held += frame.len();
+        state.order.push_back(idx);
+        state.frames.insert(idx, Slot::Ready(frame.clone()));
+        self.changed.notify_all();
+        Ok(frame)
+    }
+}
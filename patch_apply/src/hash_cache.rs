@@ -0,0 +1,137 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use patch_types::hex_hash;
+use patch_types::normalize::Normalization;
+use patch_types::{Manifest, PatchKind};
+
+use crate::telemetry::Telemetry;
+use crate::verify::Verification;
+use crate::{access, hash_file_counted};
+
+/// Hashes of the installation's files, kept in the target folder between patches.
+pub(crate) const CACHE_FILE: &str = ".patch_hashes.json";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    size: u64,
+    modified: u64,
+}
+
+impl Stamp {
+    fn of(path: &Path) -> Option<Stamp> {
+        let meta = fs::metadata(path).ok()?;
+        let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Stamp {
+            size: meta.len(),
+            modified: modified.as_nanos().try_into().ok()?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Known {
+    #[serde(flatten)]
+    stamp: Stamp,
+    normalization: Normalization,
+    #[serde(with = "hex_hash")]
+    hash: [u8; 32],
+}
+
+/// Hashes of files as they were when last read or written, so a later patch can skip
+/// rehashing a file whose size and modification time have not changed since.
+#[derive(Default)]
+pub(crate) struct HashCache {
+    path: PathBuf,
+    files: Mutex<HashMap<String, Known>>,
+}
+
+impl HashCache {
+    /// Loads the cache of `target`, or starts an empty one when `trust` is false (`--paranoid`)
+    /// or there is none. A cache that cannot be read is ignored.
+    pub fn load(target: &Path, trust: bool) -> Self {
+        let path = target.join(CACHE_FILE);
+        let files = trust
+            .then(|| fs::read(&path).ok())
+            .flatten()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        HashCache {
+            path,
+            files: Mutex::new(files),
+        }
+    }
+
+    /// Hash of `rel` under `cwd`, from the cache when the file is unchanged since it was
+    /// recorded, otherwise read from disk and recorded.
+    pub fn hash(
+        &self,
+        cwd: &Path,
+        rel: &str,
+        normalization: Normalization,
+        telemetry: &Telemetry,
+    ) -> Result<[u8; 32]> {
+        let path = cwd.join(rel);
+        let stamp = Stamp::of(&path);
+        if let Some(known) = self.files.lock().unwrap().get(rel)
+            && Some(known.stamp) == stamp
+            && known.normalization == normalization
+        {
+            return Ok(known.hash);
+        }
+        let hash = hash_file_counted(&path, normalization, telemetry)?;
+        if let Some(stamp) = stamp {
+            self.files.lock().unwrap().insert(
+                rel.to_string(),
+                Known {
+                    stamp,
+                    normalization,
+                    hash,
+                },
+            );
+        }
+        Ok(hash)
+    }
+
+    /// Records the files the apply wrote or moved at their new hashes, drops paths the patch
+    /// removed, and writes the cache to the target folder.
+    pub fn save(&self, manifest: &Manifest, verification: &Verification, cwd: &Path) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        for (i, file) in manifest.files.iter().enumerate() {
+            if let PatchKind::Moved { from } = &file.kind {
+                files.remove(from);
+            }
+            let written = !verification.untouched(i)
+                && !matches!(file.kind, PatchKind::Unchanged | PatchKind::Deleted);
+            if written && let Some(stamp) = Stamp::of(&cwd.join(&file.path)) {
+                let known = Known {
+                    stamp,
+                    normalization: file.normalization,
+                    hash: file.new_hash,
+                };
+                files.insert(file.path.clone(), known);
+            }
+        }
+        // Only paths of the new version are kept, so the cache does not grow across patches
+        let current: HashSet<&str> = manifest
+            .files
+            .iter()
+            .filter(|file| !matches!(file.kind, PatchKind::Deleted))
+            .map(|file| file.path.as_str())
+            .collect();
+        files.retain(|rel, _| current.contains(rel.as_str()));
+
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(&*files)?)
+            .map_err(|e| access::explain(e, &tmp, "writing"))?;
+        fs::rename(&tmp, &self.path)
+            .with_context(|| format!("Replacing {}", self.path.display()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use patch_types::error::PatchError;
+use patch_types::{FORMAT_VERSION, Manifest};
+
+/// Log of every apply to the folder, successful or not, one JSON object per line. Lines are
+/// only ever appended, so it shows how the installation got into its current state.
+pub const HISTORY_FILE: &str = "patch_history.json";
+
+/// One apply, as recorded in the history.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub product: String,
+    pub from_version: String,
+    pub to_version: String,
+    /// When the apply started, in seconds since the Unix epoch
+    pub started_at: u64,
+    /// How long it took, in milliseconds
+    pub duration_ms: u64,
+    pub outcome: Outcome,
+    /// Why it failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Kind of the [`PatchError`] behind the failure, such as `hash_mismatch`, when there is one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<String>,
+    /// Version of the patcher that applied it, and the newest patch format it reads
+    pub patcher_version: String,
+    pub format_version: u32,
+}
+
+/// How an apply ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// Every file was patched, and the installation is at `to_version`
+    Complete,
+    /// Only the files `--only` and `--skip` picked were patched
+    Partial,
+    /// Patched except for conflicting files the user chose to skip
+    SkippedConflicts,
+    /// The installation was already at `to_version`; nothing changed
+    UpToDate,
+    /// Nothing changed, unless it failed while moving files into place
+    Failed,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Complete => "complete",
+            Outcome::Partial => "partial",
+            Outcome::SkippedConflicts => "conflicts skipped",
+            Outcome::UpToDate => "already up to date",
+            Outcome::Failed => "failed",
+        })
+    }
+}
+
+impl HistoryEntry {
+    /// `started_at` as `YYYY-MM-DD HH:MM:SS UTC`.
+    pub fn started_at_utc(&self) -> String {
+        let (days, rem) = (self.started_at / 86400, self.started_at % 86400);
+        // Civil-from-days (Howard Hinnant), valid for any date after the epoch
+        let z = days as i64 + 719_468;
+        let era = z / 146_097;
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+        format!(
+            "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+            rem / 3600,
+            rem % 3600 / 60,
+            rem % 60
+        )
+    }
+}
+
+/// Appends the apply of `manifest` to the history in `target`: when it started, how long it
+/// took and how it ended, with the error for a failure.
+pub(crate) fn record(
+    manifest: &Manifest,
+    target: &Path,
+    started_at: SystemTime,
+    duration: Duration,
+    outcome: Outcome,
+    error: Option<&anyhow::Error>,
+) -> Result<()> {
+    let entry = HistoryEntry {
+        product: manifest.product.clone(),
+        from_version: manifest.from_version.clone(),
+        to_version: manifest.to_version.clone(),
+        started_at: started_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        duration_ms: duration.as_millis().try_into().unwrap_or(u64::MAX),
+        outcome,
+        error: error.map(|e| format!("{e:#}")),
+        error_kind: error
+            .and_then(|e| e.chain().find_map(|e| e.downcast_ref::<PatchError>()))
+            .map(|e| e.kind().to_string()),
+        patcher_version: env!("CARGO_PKG_VERSION").to_string(),
+        format_version: FORMAT_VERSION,
+    };
+    let mut line = serde_json::to_vec(&entry)?;
+    line.push(b'\n');
+    let path = target.join(HISTORY_FILE);
+    // One write in append mode, so a second patcher's line never lands inside this one
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(&line))
+        .with_context(|| format!("Appending to {}", path.display()))
+}
+
+/// The applies recorded in `target`, oldest first; none when it has no history. A line that
+/// does not parse, such as one cut short by a crash, is left out.
+pub fn read(target: &Path) -> Result<Vec<HistoryEntry>> {
+    let path = target.join(HISTORY_FILE);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Reading {}", path.display())),
+    };
+    Ok(text
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
@@ -0,0 +1,34 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use patch_types::Manifest;
+use patch_types::normalize::Normalization;
+
+use crate::hash_file;
+
+/// The release in the manifest's fingerprints whose sentinel files all match `target`, if
+/// exactly one does.
+pub(crate) fn installed_version<'m>(manifest: &'m Manifest, target: &Path) -> Option<&'m str> {
+    let mut hashes = HashMap::new();
+    let mut hash = |rel: &str| {
+        *hashes.entry(rel.to_string()).or_insert_with(|| {
+            let path = target.join(rel);
+            if path.is_file() {
+                hash_file(&path, Normalization::None).ok()
+            } else {
+                Some([0u8; 32])
+            }
+        })
+    };
+    let mut matches = manifest.fingerprints.iter().filter(|fp| {
+        !fp.files.is_empty()
+            && fp
+                .files
+                .iter()
+                .all(|file| hash(&file.path) == Some(file.hash))
+    });
+    match (matches.next(), matches.next()) {
+        (Some(only), None) => Some(&only.version),
+        _ => None,
+    }
+}
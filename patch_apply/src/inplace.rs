@@ -0,0 +1,341 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use bincode::{Decode, Encode};
+
+use patch_types::error::PatchError;
+use patch_types::{CHUNK_SIZE, FileEntry, Manifest, PatchKind};
+
+use crate::observer::PatchObserver;
+use crate::source::BundleSource;
+use crate::telemetry::Telemetry;
+use crate::verify::Verification;
+use crate::{ApplyOptions, access, chunks, hash_file_counted, throttle};
+
+/// Journal in the target folder holding the chunk an in-place patch is about to write, so a
+/// write torn by a crash or power loss is finished on the next apply.
+pub(crate) const JOURNAL_FILE: &str = ".patch_inplace.journal";
+
+/// A chunk on its way into a file patched in place.
+#[derive(Encode, Decode)]
+struct Journal {
+    /// Path of the file, relative to the target folder
+    path: String,
+    chunk: u64,
+    hash: [u8; 32],
+    content: Vec<u8>,
+}
+
+impl Journal {
+    /// Writes the journal and flushes it to disk, before the chunk itself is written.
+    fn write(&self, cwd: &Path) -> Result<()> {
+        let path = cwd.join(JOURNAL_FILE);
+        let bytes = bincode::encode_to_vec(self, bincode::config::standard())?;
+        let mut file = File::create(&path).map_err(|e| access::explain(e, &path, "creating"))?;
+        file.write_all(&bytes)
+            .map_err(|e| access::explain(e, &path, "writing"))?;
+        file.sync_all()
+            .with_context(|| format!("Syncing {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Reads the journal in `cwd`. One cut short while it was written is ignored: its chunk
+    /// was not written yet.
+    fn read(cwd: &Path) -> Option<Journal> {
+        let bytes = fs::read(cwd.join(JOURNAL_FILE)).ok()?;
+        let (journal, _): (Journal, _) =
+            bincode::decode_from_slice(&bytes, bincode::config::standard()).ok()?;
+        (blake3::hash(&journal.content).as_bytes() == &journal.hash).then_some(journal)
+    }
+}
+
+/// Finishes the chunk write an interrupted in-place patch left in its journal, so the file
+/// is back to chunks of its old and new versions that patching can resume from.
+pub(crate) fn recover(
+    manifest: &Manifest,
+    cwd: &Path,
+    observer: &mut impl PatchObserver,
+) -> Result<()> {
+    let path = cwd.join(JOURNAL_FILE);
+    if !path.exists() {
+        return Ok(());
+    }
+    if let Some(journal) = Journal::read(cwd)
+        && manifest.files.iter().any(|file| file.path == journal.path)
+    {
+        let target = cwd.join(&journal.path);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&target)
+            .map_err(|e| access::explain(e, &target, "opening"))?;
+        file.seek(SeekFrom::Start(journal.chunk * CHUNK_SIZE))
+            .and_then(|_| file.write_all(&journal.content))
+            .and_then(|_| file.sync_data())
+            .map_err(|e| access::explain(e, &target, "writing"))?;
+        observer.notice(&format!(
+            "Finished writing chunk {} of {}, which an interrupted in-place patch left half done",
+            journal.chunk, journal.path
+        ));
+    }
+    fs::remove_file(&path).map_err(|e| access::explain(e, &path, "removing"))?;
+    Ok(())
+}
+
+/// Patches the files in `verification.in_place` where they are, chunk by chunk, instead of
+/// staging a copy. Each chunk is journaled before it is written and every file is hashed once
+/// done. A failure leaves the file part old, part new, which the next apply picks up from.
+pub(crate) fn patch_files(
+    manifest: &Manifest,
+    source: &BundleSource,
+    verification: &Verification,
+    cwd: &Path,
+    options: &ApplyOptions,
+    telemetry: &Telemetry,
+    observer: &mut impl PatchObserver,
+) -> Result<()> {
+    for (i, file) in manifest.files.iter().enumerate() {
+        if !verification.in_place.contains(&i) || verification.untouched(i) {
+            continue;
+        }
+        let PatchKind::Streamed {
+            chunks: ref entries,
+        } = file.kind
+        else {
+            continue;
+        };
+        observer.notice(&format!(
+            "Patching {} in place; do not turn off the computer until it is done",
+            file.path
+        ));
+        let file_started = Instant::now();
+        patch_file(
+            manifest, source, file, i, entries, cwd, options, telemetry, observer,
+        )
+        .with_context(|| {
+            format!(
+                "Patching {} in place stopped partway; run the patcher again to finish it",
+                file.path
+            )
+        })?;
+        telemetry.record_file(&file.path, file_started.elapsed());
+        observer.file_finished(&file.path, 0);
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn patch_file(
+    manifest: &Manifest,
+    source: &BundleSource,
+    file: &FileEntry,
+    index: usize,
+    entries: &[Option<usize>],
+    cwd: &Path,
+    options: &ApplyOptions,
+    telemetry: &Telemetry,
+    observer: &mut impl PatchObserver,
+) -> Result<()> {
+    let chunked = manifest
+        .chunked_file(&file.path)
+        .ok_or_else(|| chunks::decode_error(&file.path, 0, "the patch lists no chunks for it"))?;
+    if entries.len() != chunked.new_chunks.len() {
+        let reason = format!(
+            "{} chunk entries for {} chunks",
+            entries.len(),
+            chunked.new_chunks.len()
+        );
+        return Err(PatchError::Decode {
+            path: file.path.clone(),
+            reason,
+        }
+        .into());
+    }
+    let target = cwd.join(&file.path);
+    access::clear_readonly(&target)?;
+    let mut out = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&target)
+        .map_err(|e| access::explain(e, &target, "opening"))?;
+    let len = out
+        .metadata()
+        .map_err(|e| access::explain(e, &target, "reading"))?
+        .len();
+    // Grown up front, so running out of space stops the patch before any chunk changes
+    if file.new_size > len {
+        out.set_len(file.new_size)
+            .with_context(|| format!("Growing {} to {} bytes", file.path, file.new_size))?;
+    }
+    observer.file_started(0, &file.path, file.new_size);
+
+    let write_started = Instant::now();
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE as usize);
+    let mut fetched = 0;
+    for (chunk_index, (hash, &entry)) in (0..).zip(chunked.new_chunks.iter().zip(entries)) {
+        let offset = chunk_index * CHUNK_SIZE;
+        chunk.clear();
+        out.seek(SeekFrom::Start(offset))
+            .and_then(|_| {
+                (&mut out)
+                    .take(CHUNK_SIZE.min(file.new_size - offset))
+                    .read_to_end(&mut chunk)
+            })
+            .map_err(|e| access::explain(e, &target, "reading"))?;
+        telemetry.add_read(chunk.len() as u64);
+        throttle::io(chunk.len() as u64);
+        if blake3::hash(&chunk).as_bytes() != hash {
+            let Some(entry) = entry else {
+                let reason = "the file on disk does not hold it and the patch stores no copy";
+                return Err(chunks::decode_error(&file.path, chunk_index, reason).into());
+            };
+            let content = chunks::fetch(source, chunked, chunk_index, entry)?;
+            let journal = Journal {
+                path: file.path.clone(),
+                chunk: chunk_index,
+                hash: *hash,
+                content,
+            };
+            journal.write(cwd)?;
+            if let Some(chaos) = options.chaos {
+                chaos.power_loss("while patching a file in place", Some(index));
+                chaos
+                    .write_error(index)
+                    .with_context(|| format!("Writing {}", file.path))?;
+            }
+            out.seek(SeekFrom::Start(offset))
+                .and_then(|_| out.write_all(&journal.content))
+                .and_then(|_| out.sync_data())
+                .map_err(|e| access::explain(e, &target, "writing"))?;
+            let journal_path = cwd.join(JOURNAL_FILE);
+            fs::remove_file(&journal_path)
+                .map_err(|e| access::explain(e, &journal_path, "removing"))?;
+            throttle::io(journal.content.len() as u64);
+            telemetry.add_written(journal.content.len() as u64);
+            fetched += 1;
+        }
+        observer.file_progress(0, offset + chunk.len() as u64);
+    }
+    out.set_len(file.new_size)
+        .with_context(|| format!("Truncating {} to {} bytes", file.path, file.new_size))?;
+    if let Some(nanos) = file.mtime {
+        out.set_modified(UNIX_EPOCH + Duration::from_nanos(nanos))
+            .with_context(|| format!("Setting modification time of {}", file.path))?;
+    }
+    out.sync_all()
+        .with_context(|| format!("Syncing {}", file.path))?;
+    telemetry.add_write(write_started.elapsed());
+    drop(out);
+
+    let hash = hash_file_counted(&target, file.normalization, telemetry)
+        .with_context(|| format!("Hashing {}", file.path))?;
+    if hash != file.new_hash {
+        let mismatch = PatchError::HashMismatch {
+            path: file.path.clone(),
+            expected: file.new_hash,
+            actual: Some(hash),
+        };
+        return Err(mismatch)
+            .with_context(|| format!("Patched {} does not match the new version", file.path));
+    }
+    observer.notice(&format!(
+        "Patched {} in place with {fetched} of its {} chunks from the patch",
+        file.path,
+        chunked.new_chunks.len()
+    ));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use patch_types::normalize::Normalization;
+
+    use super::*;
+
+    /// A manifest patching `path` in place, as far as recovery looks at it.
+    fn manifest(path: &str) -> Manifest {
+        let file = FileEntry {
+            path: path.to_string(),
+            kind: PatchKind::Streamed {
+                chunks: vec![None, Some(0)],
+            },
+            original_hash: [0; 32],
+            new_hash: [0; 32],
+            old_size: 0,
+            new_size: 0,
+            mtime: None,
+            normalization: Normalization::None,
+            mode: None,
+            sparse: false,
+            full_copy: None,
+        };
+        crate::testing::manifest(vec![file])
+    }
+
+    /// A folder holding a file of a whole old chunk and part of another, with the second
+    /// chunk's new content journaled and only its first bytes written, as a crash leaves it.
+    fn torn_write(name: &str) -> (std::path::PathBuf, Vec<u8>) {
+        let cwd = std::env::temp_dir().join(format!("patch_inplace_{name}_{}", std::process::id()));
+        fs::create_dir_all(&cwd).unwrap();
+        let mut old = vec![0x11; CHUNK_SIZE as usize + 1000];
+        let content = vec![0x22; 1000];
+        Journal {
+            path: "big.bin".to_string(),
+            chunk: 1,
+            hash: *blake3::hash(&content).as_bytes(),
+            content: content.clone(),
+        }
+        .write(&cwd)
+        .unwrap();
+        old[CHUNK_SIZE as usize..][..300].copy_from_slice(&content[..300]);
+        fs::write(cwd.join("big.bin"), &old).unwrap();
+        (cwd, old)
+    }
+
+    #[test]
+    fn recovery_finishes_a_torn_chunk_write() {
+        let (cwd, _) = torn_write("torn");
+        let result = recover(&manifest("big.bin"), &cwd, &mut ());
+        let file = fs::read(cwd.join("big.bin")).unwrap();
+        let journal_left = cwd.join(JOURNAL_FILE).exists();
+        fs::remove_dir_all(&cwd).unwrap();
+        result.unwrap();
+        assert!(file[..CHUNK_SIZE as usize].iter().all(|&b| b == 0x11));
+        assert!(file[CHUNK_SIZE as usize..].iter().all(|&b| b == 0x22));
+        assert!(!journal_left);
+    }
+
+    #[test]
+    fn recovery_drops_a_journal_it_cannot_use() {
+        // Cut short while it was written, so its chunk was never started
+        let (cwd, old) = torn_write("short");
+        let journal = cwd.join(JOURNAL_FILE);
+        let len = fs::metadata(&journal).unwrap().len();
+        File::options()
+            .write(true)
+            .open(&journal)
+            .unwrap()
+            .set_len(len / 2)
+            .unwrap();
+        let short = recover(&manifest("big.bin"), &cwd, &mut ());
+        let short_file = fs::read(cwd.join("big.bin")).unwrap();
+        let short_left = journal.exists();
+        fs::remove_dir_all(&cwd).unwrap();
+
+        // For a file this patch does not change
+        let (cwd, _) = torn_write("other");
+        let other = recover(&manifest("other.bin"), &cwd, &mut ());
+        let other_file = fs::read(cwd.join("big.bin")).unwrap();
+        let other_left = cwd.join(JOURNAL_FILE).exists();
+        fs::remove_dir_all(&cwd).unwrap();
+
+        short.unwrap();
+        other.unwrap();
+        assert!(short_file == old && other_file == old);
+        assert!(!short_left && !other_left);
+    }
+}
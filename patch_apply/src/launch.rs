@@ -0,0 +1,98 @@
+//! The product's main executable: starting it once patched, and keeping Windows shortcuts to
+//! it working when a patch renames it.
+
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result};
+
+use patch_types::{MainExecutable, Manifest};
+
+/// Starts the manifest's main executable from `target` without waiting for it to exit.
+pub fn start(manifest: &Manifest, target: &Path) -> Result<()> {
+    let main = manifest
+        .main_exe
+        .as_ref()
+        .with_context(|| format!("{} names no executable to start", manifest.product))?;
+    let exe = std::path::absolute(target.join(&main.path))?;
+    Command::new(&exe)
+        .current_dir(exe.parent().unwrap_or(target))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Starting {}", exe.display()))?;
+    Ok(())
+}
+
+/// Finds the `.lnk` files in the Start Menu and on the desktop, of this user and of all users,
+/// whose target is `$env:PATCH_OLD_EXE`, points them at `$env:PATCH_NEW_EXE` and prints how many
+/// were changed. Shortcuts that cannot be read or saved, e.g. all-users ones without admin
+/// rights, are left alone.
+#[cfg(windows)]
+const SHORTCUT_SCRIPT: &str = r#"
+$shell = New-Object -ComObject WScript.Shell
+$old = $env:PATCH_OLD_EXE; $new = $env:PATCH_NEW_EXE
+$oldDir = Split-Path $old; $newDir = Split-Path $new
+$count = 0
+foreach ($folder in 'StartMenu', 'CommonStartMenu', 'Desktop', 'CommonDesktopDirectory') {
+    $dir = [Environment]::GetFolderPath($folder)
+    if (-not $dir) { continue }
+    foreach ($file in Get-ChildItem -LiteralPath $dir -Filter *.lnk -Recurse -ErrorAction SilentlyContinue) {
+        try {
+            $link = $shell.CreateShortcut($file.FullName)
+            if ($link.TargetPath -ine $old) { continue }
+            $link.TargetPath = $new
+            if ($link.WorkingDirectory -ieq $oldDir) { $link.WorkingDirectory = $newDir }
+            if ($link.IconLocation.StartsWith("$old,", 'OrdinalIgnoreCase')) {
+                $link.IconLocation = $new + $link.IconLocation.Substring($old.Length)
+            }
+            $link.Save()
+            $count++
+        } catch {}
+    }
+}
+$count
+"#;
+
+/// Points shortcuts to the main executable's previous path at its new one, returning how many
+/// were changed.
+#[cfg(windows)]
+pub(crate) fn update_shortcuts(main: &MainExecutable, target: &Path) -> Result<usize> {
+    let Some(previous) = &main.previous else {
+        return Ok(0);
+    };
+    let target = std::path::absolute(target)?;
+    let path = |rel: &str| target.join(rel.replace('/', "\\"));
+    let output = Command::new("powershell")
+        .args([
+            "-NoProfile",
+            "-NonInteractive",
+            "-ExecutionPolicy",
+            "Bypass",
+            "-Command",
+            SHORTCUT_SCRIPT,
+        ])
+        .env("PATCH_OLD_EXE", path(previous))
+        .env("PATCH_NEW_EXE", path(&main.path))
+        .stdin(Stdio::null())
+        .output()
+        .context("Running PowerShell")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "PowerShell failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let count = String::from_utf8_lossy(&output.stdout);
+    count
+        .trim()
+        .parse()
+        .with_context(|| format!("Unexpected PowerShell output {:?}", count.trim()))
+}
+
+#[cfg(not(windows))]
+pub(crate) fn update_shortcuts(_main: &MainExecutable, _target: &Path) -> Result<usize> {
+    Ok(0)
+}
@@ -0,0 +1,697 @@
+//! The apply engine behind the patch stub, for driving patches from another program.
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! let bundle = patch_apply::Bundle::open("update.zip".as_ref())?;
+//! patch_apply::apply_bundle_with(&bundle, "C:/Games/Example".as_ref(), &mut ())?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod access;
+mod antivirus;
+mod apply;
+pub mod chaos;
+mod chunks;
+pub mod disk;
+mod filesystem;
+mod frames;
+mod hash_cache;
+pub mod history;
+mod identify;
+mod inplace;
+pub mod launch;
+mod lock;
+mod markers;
+mod memory;
+mod mirrors;
+pub mod net;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+mod observer;
+pub mod plan;
+mod prefetch;
+pub mod probe;
+mod purge;
+mod receipt;
+mod segments;
+mod select;
+pub mod selfexe;
+pub mod snapshot;
+mod source;
+pub mod telemetry;
+#[cfg(test)]
+mod testing;
+pub mod throttle;
+pub mod update;
+mod verify;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use ureq::Agent;
+
+use patch_types::normalize::Normalization;
+use patch_types::{Manifest, PatchData, PatchKind};
+use patch_types::{buffers, schedule};
+
+pub use crate::filesystem::Filesystem;
+pub use crate::mirrors::MirrorOrder;
+pub use crate::observer::{Conflict, ConflictAction, PatchObserver, Stage};
+pub use crate::plan::Plan;
+pub use crate::receipt::{EulaAcceptance, RECEIPT_FILE};
+pub use crate::select::Selection;
+pub use crate::telemetry::Summary;
+pub use crate::verify::VerifyMode;
+pub use patch_types::error::{FileError, PatchError, Remedy};
+
+use crate::chaos::Chaos;
+use crate::hash_cache::HashCache;
+use crate::history::Outcome;
+use crate::lock::ApplyLock;
+use crate::receipt::Status;
+use crate::source::BundleSource;
+use crate::telemetry::Telemetry;
+use crate::verify::{
+    Verification, check_case_collisions, check_case_renames, verify_changed, verify_unchanged,
+};
+
+/// An opened patch: its manifest, and where its entries are read from.
+pub struct Bundle {
+    source: BundleSource,
+    manifest: Manifest,
+}
+
+impl Bundle {
+    /// Opens a patcher executable, a bare payload, or a zip-format patch.
+    pub fn open(path: &Path) -> Result<Self> {
+        let (source, manifest) = if is_zip(path)? {
+            BundleSource::open_zip(path)?
+        } else {
+            BundleSource::open_local(path)?
+        };
+        Ok(Bundle { source, manifest })
+    }
+
+    /// Opens a patcher hosted over HTTP, fetching entries with range requests as they are needed.
+    /// `urls` are copies of the same patcher, tried in turn along with the mirrors the patch
+    /// names, when one fails or sends an entry that does not match its hash.
+    pub fn open_remote(agent: Agent, urls: &[String], order: MirrorOrder) -> Result<Self> {
+        let (source, manifest) = BundleSource::open_remote(agent, urls, order)?;
+        Ok(Bundle { source, manifest })
+    }
+
+    /// Downloads a patcher hosted over HTTP to `path` and checks that every entry in it
+    /// decodes and every stored file matches its hash, so it can be applied later with
+    /// [`Bundle::open`] without a connection. A corrupt download is deleted. A mirror that fails
+    /// partway is left for the next, as in [`Bundle::open_remote`].
+    pub fn download(
+        agent: &Agent,
+        urls: &[String],
+        order: MirrorOrder,
+        path: &Path,
+        observer: &mut impl PatchObserver,
+    ) -> Result<Self> {
+        source::download_payload(agent.clone(), urls, order, path, |done, total| {
+            observer.downloaded(done, total)
+        })?;
+        let checked = Bundle::open(path).and_then(|bundle| {
+            bundle.check_entries()?;
+            Ok(bundle)
+        });
+        if checked.is_err() {
+            let _ = std::fs::remove_file(path);
+        }
+        checked.with_context(|| format!("Checking the download from {}", urls.join(", ")))
+    }
+
+    /// Decodes every entry, hashing full copies against the files they restore.
+    fn check_entries(&self) -> Result<()> {
+        let mut hashes = HashMap::new();
+        for file in &self.manifest.files {
+            match file.kind {
+                PatchKind::Added { idx } => {
+                    hashes.insert(idx, file);
+                }
+                // Deltas are skipped below, so only a file's full copy is hashed against it
+                PatchKind::Patched { idx, fallback, .. } => {
+                    hashes.insert(idx, file);
+                    if let Some(fallback) = fallback {
+                        hashes.insert(fallback, file);
+                    }
+                }
+                _ => {}
+            }
+            if let Some(full_copy) = file.full_copy {
+                hashes.insert(full_copy, file);
+            }
+        }
+        let mut chunk_hashes = HashMap::new();
+        for chunked in &self.manifest.chunked {
+            for (&idx, hash) in chunked.chunk_entries.iter().zip(&chunked.new_chunks) {
+                chunk_hashes.insert(idx, (chunked, hash));
+            }
+        }
+        for file in &self.manifest.files {
+            if let PatchKind::Streamed { chunks } = &file.kind
+                && let Some(chunked) = self.manifest.chunked_file(&file.path)
+            {
+                for (&idx, hash) in chunks.iter().zip(&chunked.new_chunks) {
+                    if let Some(idx) = idx {
+                        chunk_hashes.insert(idx, (chunked, hash));
+                    }
+                }
+            }
+        }
+        for idx in 0..self.manifest.entries.len() {
+            let mut bytes = match self.source.read_entry(idx)? {
+                PatchData::Full(bytes) => bytes,
+                PatchData::CompressedFull(compressed) => zstd::decode_all(compressed.as_slice())?,
+                PatchData::Xdelta(_) => continue,
+            };
+            if let Some(file) = hashes.get(&idx) {
+                file.normalization.apply(&mut bytes);
+                let hash = *blake3::hash(&bytes).as_bytes();
+                if hash != file.new_hash {
+                    let mismatch = PatchError::HashMismatch {
+                        path: file.path.clone(),
+                        expected: file.new_hash,
+                        actual: Some(hash),
+                    };
+                    return Err(mismatch)
+                        .with_context(|| format!("Stored copy of {} is corrupt", file.path));
+                }
+            }
+            if let Some((chunked, hash)) = chunk_hashes.get(&idx)
+                && blake3::hash(&bytes).as_bytes() != *hash
+            {
+                anyhow::bail!("Stored chunk of {} is corrupt", chunked.path);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    pub fn into_manifest(self) -> Manifest {
+        self.manifest
+    }
+}
+
+/// Settings controlling how a bundle is verified and written.
+#[derive(Clone, Default)]
+pub struct ApplyOptions {
+    /// Flush every written file and its directory to disk before moving on
+    pub durable: bool,
+    /// Give antivirus scanners time with each written file: wait before checking and moving
+    /// staged files, retry ones a scanner holds, and check they are still there afterwards
+    pub av_safe: bool,
+    pub verify: VerifyMode,
+    /// Inject failures from this seed (testing only)
+    pub chaos: Option<Chaos>,
+    /// Most bytes of file data decoded in memory at once; workers wait for room instead of
+    /// decoding in parallel past it
+    pub max_memory: Option<u64>,
+    /// Hash every file, ignoring the hashes cached in the target by earlier patches, and check
+    /// staged files by reading them back rather than by the hashes taken as they were written
+    pub paranoid: bool,
+    /// How the user accepted the patch's license; a patch with one is not applied without it
+    pub eula: Option<EulaAcceptance>,
+    /// Ids of the wizard components to install, all of them when `None`. Files of the others
+    /// are not installed unless they already are
+    pub components: Option<Vec<String>>,
+    /// Apply a patch to an older release than the receipt in the target records
+    pub allow_downgrade: bool,
+    /// Snapshot the filesystem holding the target before changing it, and fail if it cannot be
+    pub snapshot: bool,
+    /// Apply only these files of the patch, recording the apply as partial in the receipt
+    pub selection: Option<Selection>,
+    /// Leave the files the patch deletes in place, listing them in the summary
+    pub keep_deleted: bool,
+    /// Once patched, remove the files in the target that the patch does not know under any path
+    pub purge_unknown: bool,
+    /// Patch streamed files where they are, a journaled chunk at a time, instead of staging a
+    /// copy, so they need no free space beyond what they grow by. Risky: the installation is
+    /// changed before the commit, and a file cut off partway is only whole again once a later
+    /// apply finishes it
+    pub in_place: bool,
+}
+
+/// Applies `bundle` to the installation in `target` with default options, reporting progress
+/// to `observer`.
+pub fn apply_bundle_with(
+    bundle: &Bundle,
+    target: &Path,
+    observer: &mut impl PatchObserver,
+) -> Result<Summary> {
+    apply_bundle_with_options(bundle, target, &ApplyOptions::default(), observer)
+}
+
+/// Applies `bundle` to the installation in `target`.
+///
+/// New and patched files are decoded into a staging folder and verified before anything in
+/// the installation changes, so a failure or cancellation before the commit leaves it as it
+/// was. A lock file in `target` makes a second apply to the same folder fail until this one
+/// returns. A patch with a license (`Manifest::eula`) needs `options.eula`, and its acceptance
+/// is recorded in the receipt written to `target`. Once the lock is held, the apply and how it
+/// ended are appended to the history in `target` ([`history::HISTORY_FILE`]).
+pub fn apply_bundle_with_options(
+    bundle: &Bundle,
+    target: &Path,
+    options: &ApplyOptions,
+    observer: &mut impl PatchObserver,
+) -> Result<Summary> {
+    let started = Instant::now();
+    let manifest = &bundle.manifest;
+    if manifest.eula.is_some() && options.eula.is_none() {
+        anyhow::bail!(
+            "{} {} requires accepting its license agreement before patching",
+            manifest.product,
+            manifest.to_version
+        );
+    }
+    selfexe::remove_leftover();
+    let _lock = ApplyLock::acquire(target)?;
+    let started_at = SystemTime::now();
+    let result = apply_locked(bundle, target, options, observer, started);
+    let (outcome, error) = match &result {
+        Ok((_, outcome)) => (*outcome, None),
+        Err(e) => (Outcome::Failed, Some(e)),
+    };
+    // The history is for support; a folder it cannot be written to does not fail the apply
+    if let Err(e) = history::record(
+        manifest,
+        target,
+        started_at,
+        started.elapsed(),
+        outcome,
+        error,
+    ) {
+        observer.notice(&format!(
+            "Warning: could not record the apply in the history: {e:#}"
+        ));
+    }
+    result.map(|(summary, _)| summary)
+}
+
+/// [`apply_bundle_with_options`] once the folder is locked, returning how the apply ended.
+fn apply_locked(
+    bundle: &Bundle,
+    target: &Path,
+    options: &ApplyOptions,
+    observer: &mut impl PatchObserver,
+    started: Instant,
+) -> Result<(Summary, Outcome)> {
+    let manifest = &bundle.manifest;
+    let telemetry = Telemetry::default();
+
+    inplace::recover(manifest, target, observer)?;
+    let (mut verification, verify_time) = verify(bundle, target, options, &telemetry, observer)?;
+    check_installed_version(manifest, target, &verification)?;
+    verification.resolve_conflicts(observer)?;
+    if let Some(chaos) = options.chaos {
+        chaos.power_loss("after verification", None);
+    }
+    // Unchanged files a full install restores from their copies have to be known before
+    // staging, as does whether an installation with nothing else to do checks out
+    let unchanged_first = verification.nothing_to_do(manifest, target)
+        || manifest
+            .files
+            .iter()
+            .any(|file| matches!(file.kind, PatchKind::Unchanged) && file.full_copy.is_some());
+    if unchanged_first {
+        let unchanged = verify_unchanged(
+            manifest,
+            target,
+            &verification.hashes,
+            &telemetry,
+            options.chaos,
+            options.verify,
+            &AtomicBool::new(false),
+        )?;
+        add_unchanged(manifest, target, options, &mut verification, unchanged)?;
+        verification.resolve_conflicts(observer)?;
+    }
+    if verification.nothing_to_do(manifest, target) {
+        apply::restore_modes(manifest, &verification, target)?;
+        verification.hashes.save(manifest, &verification, target)?;
+        let outcome = if verification.skipped.is_empty() {
+            Outcome::UpToDate
+        } else {
+            Outcome::SkippedConflicts
+        };
+        if !verification.skipped.is_empty() {
+            observer.notice("Nothing to do besides the skipped files");
+        } else if verification.partial(manifest) {
+            observer.notice("The selected files are already up to date, nothing to do");
+        } else {
+            observer.notice(&format!(
+                "Installation already at {} {}, nothing to do",
+                manifest.product, manifest.to_version
+            ));
+        }
+        let mut summary = telemetry.summary(started.elapsed(), verify_time);
+        finish_deletions(
+            manifest,
+            target,
+            options,
+            &verification,
+            observer,
+            &mut summary,
+        )?;
+        return Ok((summary, outcome));
+    }
+
+    apply::check_disk_space(manifest, &verification, target)?;
+    let snapshot = if options.snapshot {
+        let snapshot = snapshot::create(target)?;
+        observer.notice(&format!(
+            "Took {} snapshot {} before patching; to roll back: {}",
+            snapshot.kind, snapshot.id, snapshot.restore
+        ));
+        Some(snapshot)
+    } else {
+        None
+    };
+
+    observer.stage(Stage::Applying);
+    // Nothing is patched from unchanged files, so they are hashed while the rest is staged and
+    // only have to check out before the commit
+    let stop = AtomicBool::new(false);
+    let (staged, unchanged) = std::thread::scope(|scope| {
+        let unchanged = (!unchanged_first).then(|| {
+            scope.spawn(|| {
+                verify_unchanged(
+                    manifest,
+                    target,
+                    &verification.hashes,
+                    &telemetry,
+                    options.chaos,
+                    options.verify,
+                    &stop,
+                )
+            })
+        });
+        let staged = apply::stage_files(
+            manifest,
+            &bundle.source,
+            &verification,
+            target,
+            options,
+            &telemetry,
+            observer,
+        );
+        stop.store(staged.is_err(), Ordering::Relaxed);
+        (
+            staged,
+            unchanged.map(|unchanged| {
+                unchanged
+                    .join()
+                    .expect("verifying unchanged files panicked")
+            }),
+        )
+    });
+    staged?;
+    if let Some(unchanged) = unchanged
+        && let Err(e) = unchanged.and_then(|unchanged| {
+            add_unchanged(manifest, target, options, &mut verification, unchanged)?;
+            verification.resolve_conflicts(observer)
+        })
+    {
+        apply::discard_staged(target);
+        return Err(e);
+    }
+    if let Err(e) = inplace::patch_files(
+        manifest,
+        &bundle.source,
+        &verification,
+        target,
+        options,
+        &telemetry,
+        observer,
+    ) {
+        apply::discard_staged(target);
+        return Err(e);
+    }
+    apply::commit_staged(manifest, &verification, target, options, observer)?;
+    apply::apply_directories(manifest, target)?;
+    apply::restore_modes(manifest, &verification, target)?;
+    verification.hashes.save(manifest, &verification, target)?;
+    if let Some(chaos) = options.chaos {
+        chaos.power_loss("before writing version markers", None);
+    }
+    let outcome = if !verification.skipped.is_empty() {
+        observer.notice(&format!(
+            "{} conflicting files were skipped, so the installation is not marked as {}",
+            verification.skipped.len(),
+            manifest.to_version
+        ));
+        Outcome::SkippedConflicts
+    } else if verification.partial(manifest) {
+        receipt::write_receipt(
+            manifest,
+            target,
+            options,
+            Status::Partial,
+            snapshot.as_ref(),
+        )?;
+        observer.notice(&format!(
+            "Only the selected files were patched, so the installation is marked as partially at {}",
+            manifest.to_version
+        ));
+        Outcome::Partial
+    } else {
+        markers::write_version_markers(manifest, target, observer)?;
+        receipt::write_receipt(
+            manifest,
+            target,
+            options,
+            Status::Complete,
+            snapshot.as_ref(),
+        )?;
+        if let Some(main) = &manifest.main_exe {
+            // Shortcuts are a convenience; a patched installation is not failed over them
+            match launch::update_shortcuts(main, target) {
+                Ok(0) => {}
+                Ok(count) => {
+                    observer.notice(&format!("Updated {count} shortcuts to {}", main.path))
+                }
+                Err(e) => observer.notice(&format!(
+                    "Warning: could not update shortcuts to {}: {e:#}",
+                    main.path
+                )),
+            }
+        }
+        Outcome::Complete
+    };
+
+    let mut summary = telemetry.summary(started.elapsed(), verify_time);
+    finish_deletions(
+        manifest,
+        target,
+        options,
+        &verification,
+        observer,
+        &mut summary,
+    )?;
+    Ok((summary, outcome))
+}
+
+/// Lists the files `--no-delete` left in place in `summary`, and for `--purge-unknown` removes
+/// the files the patch does not know.
+fn finish_deletions(
+    manifest: &Manifest,
+    target: &Path,
+    options: &ApplyOptions,
+    verification: &Verification,
+    observer: &mut impl PatchObserver,
+    summary: &mut Summary,
+) -> Result<()> {
+    summary.kept = manifest
+        .files
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| verification.kept.contains(i))
+        .map(|(_, file)| file.path.clone())
+        .collect();
+    if !summary.kept.is_empty() {
+        observer.notice(&format!(
+            "Left {} files the patch deletes in place",
+            summary.kept.len()
+        ));
+    }
+    if options.purge_unknown {
+        summary.purged = purge::purge_unknown(manifest, target, |rel, e| {
+            observer.notice(&format!("Warning: could not remove {rel}: {e:#}"));
+        })?;
+        observer.notice(&format!(
+            "Removed {} files the patch does not know",
+            summary.purged.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Verifies the installation in `target` and reports what applying `bundle` would do,
+/// without changing anything.
+pub fn plan(
+    bundle: &Bundle,
+    target: &Path,
+    options: &ApplyOptions,
+    observer: &mut impl PatchObserver,
+) -> Result<Plan> {
+    let telemetry = Telemetry::default();
+    let (mut verification, _) = verify(bundle, target, options, &telemetry, observer)?;
+    let unchanged = verify_unchanged(
+        &bundle.manifest,
+        target,
+        &verification.hashes,
+        &telemetry,
+        options.chaos,
+        options.verify,
+        &AtomicBool::new(false),
+    )?;
+    add_unchanged(
+        &bundle.manifest,
+        target,
+        options,
+        &mut verification,
+        unchanged,
+    )?;
+    let mut plan = Plan::new(&bundle.manifest, &verification, target);
+    if options.purge_unknown {
+        plan.unknown = purge::unknown_files(&bundle.manifest, target)?;
+    }
+    Ok(plan)
+}
+
+/// Verifies the files the patch changes, deletes, moves or copies from. Unchanged files are
+/// left to [`verify_unchanged`], whose conflicts go through [`add_unchanged`].
+fn verify(
+    bundle: &Bundle,
+    target: &Path,
+    options: &ApplyOptions,
+    telemetry: &Telemetry,
+    observer: &mut impl PatchObserver,
+) -> Result<(Verification, std::time::Duration)> {
+    let filesystem = Filesystem::probe(target);
+    filesystem.check(&bundle.manifest, observer)?;
+    check_case_collisions(&bundle.manifest, target)?;
+    check_case_renames(&bundle.manifest, target)?;
+    if !options.allow_downgrade {
+        receipt::check_downgrade(&bundle.manifest, target, |message| observer.notice(message))?;
+    }
+    observer.stage(Stage::Verifying);
+    let started = Instant::now();
+    let manifest = &bundle.manifest;
+    let hashes = HashCache::load(target, !options.paranoid);
+    let mut verification = verify_changed(manifest, target, hashes, telemetry, options.chaos)?;
+    verification.filesystem = filesystem;
+    if let Some(chosen) = &options.components {
+        verification.leave_out(manifest, target, chosen);
+    }
+    if let Some(selection) = &options.selection {
+        let unselected = selection.left_out(manifest, |path, from| {
+            observer.notice(&format!(
+                "Also patching {path}, which needs {from} before the patch changes it"
+            ));
+        })?;
+        verification.deselect(unselected);
+    }
+    if options.keep_deleted {
+        for i in verification.keep_deleted(manifest) {
+            let path = &manifest.files[i].path;
+            observer.notice(&format!(
+                "Deleting {path} all the same: a new file or folder takes its place"
+            ));
+        }
+    }
+    if options.in_place {
+        verification.patch_in_place(manifest);
+    }
+    Ok((verification, started.elapsed()))
+}
+
+/// Adds the conflicts [`verify_unchanged`] found to `verification`, less those of components
+/// left out.
+fn add_unchanged(
+    manifest: &Manifest,
+    target: &Path,
+    options: &ApplyOptions,
+    verification: &mut Verification,
+    unchanged: Vec<Conflict>,
+) -> Result<()> {
+    verification.add_conflicts(manifest, unchanged);
+    if let Some(chosen) = &options.components {
+        verification.leave_out(manifest, target, chosen);
+    }
+    check_installed_version(manifest, target, verification)
+}
+
+/// Fails when files conflict because another release than the patch's is installed, rather
+/// than asking about each file.
+fn check_installed_version(
+    manifest: &Manifest,
+    target: &Path,
+    verification: &Verification,
+) -> Result<()> {
+    if !verification.conflicts.is_empty()
+        && let Some(version) = identify::installed_version(manifest, target)
+        && version != manifest.from_version
+        && version != manifest.to_version
+    {
+        anyhow::bail!(
+            "You have {} {version} installed; this patch updates {} to {}",
+            manifest.product,
+            manifest.from_version,
+            manifest.to_version
+        );
+    }
+    Ok(())
+}
+
+/// BLAKE3 hash of a file after `normalization`, as stored in the manifest.
+pub fn hash_file(path: &Path, normalization: Normalization) -> Result<[u8; 32]> {
+    hash_file_counted(path, normalization, &Telemetry::default())
+}
+
+fn hash_file_counted(
+    path: &Path,
+    normalization: Normalization,
+    telemetry: &Telemetry,
+) -> Result<[u8; 32]> {
+    let reading = |e| access::explain(e, path, "reading");
+    let mut hasher = blake3::Hasher::new();
+    let mut file = File::open(path).map_err(reading)?;
+    let len = file.metadata().map_err(reading)?.len();
+    let mut buffer = vec![0u8; buffers::buffer_len(len)];
+    let mut n = normalization
+        .read_first(&mut file, &mut buffer[..buffers::head_len(len)])
+        .map_err(reading)?;
+    while n > 0 {
+        if len >= schedule::LARGE_FILE {
+            // Lets workers that ran out of files help with the last big ones
+            hasher.update_rayon(&buffer[..n]);
+        } else {
+            hasher.update(&buffer[..n]);
+        }
+        telemetry.add_read(n as u64);
+        throttle::io(n as u64);
+        n = file.read(&mut buffer).map_err(reading)?;
+    }
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// Zip archives start with a local file header signature.
+fn is_zip(path: &Path) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    let mut file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    Ok(file.read_exact(&mut magic).is_ok() && magic == *b"PK\x03\x04")
+}
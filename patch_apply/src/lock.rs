@@ -0,0 +1,107 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// Lock file in the target folder, holding the id and path of the patcher applying to it.
+pub(crate) const LOCK_FILE: &str = ".patch.lock";
+
+/// Exclusive hold on a target folder for the length of an apply, released when dropped.
+pub(crate) struct ApplyLock {
+    path: PathBuf,
+}
+
+impl ApplyLock {
+    /// Creates the lock file in `target`. A lock left by a patcher that is no longer running,
+    /// e.g. after a crash or power loss, is taken over.
+    pub fn acquire(target: &Path) -> Result<Self> {
+        let path = target.join(LOCK_FILE);
+        // A second attempt follows removing a stale lock; if that also fails, another
+        // patcher took the lock in between
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    let exe = std::env::current_exe().unwrap_or_default();
+                    // One write, so another patcher never reads a lock without its id
+                    file.write_all(
+                        format!("{}\n{}\n", std::process::id(), exe.display()).as_bytes(),
+                    )
+                    .with_context(|| format!("Writing {}", path.display()))?;
+                    return Ok(ApplyLock { path });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    if let Some((pid, exe)) = holder(target) {
+                        anyhow::bail!(
+                            "{} is being patched by another process ({exe}, pid {pid}); wait for it to \
+                             finish. If no patcher is running, delete {}",
+                            target.display(),
+                            path.display()
+                        );
+                    }
+                    match fs::remove_file(&path) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                            return Err(e).with_context(|| {
+                                format!("Removing stale lock {}", path.display())
+                            });
+                        }
+                        _ => {}
+                    }
+                }
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Creating lock file {}", path.display()));
+                }
+            }
+        }
+        anyhow::bail!("{} is being patched by another process", target.display())
+    }
+}
+
+/// Process id and executable of the patcher holding the lock on `target`, if one is running.
+pub(crate) fn holder(target: &Path) -> Option<(u32, String)> {
+    let lock = fs::read_to_string(target.join(LOCK_FILE)).ok()?;
+    let mut lines = lock.lines();
+    let pid = lines
+        .next()
+        .and_then(|line| line.trim().parse::<u32>().ok())?;
+    let exe = lines.next().unwrap_or("unknown patcher").to_string();
+    is_running(pid).then_some((pid, exe))
+}
+
+impl Drop for ApplyLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks that the process exists; EPERM means it does but belongs to another user
+    // SAFETY: kill with signal 0 sends nothing.
+    let exists = unsafe { libc::kill(pid, 0) } == 0;
+    exists || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+fn is_running(pid: u32) -> bool {
+    use windows_sys::Win32::Foundation::{CloseHandle, ERROR_ACCESS_DENIED, STILL_ACTIVE};
+    use windows_sys::Win32::System::Threading::{
+        GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+    };
+
+    // SAFETY: the handle is checked before use and closed once the exit code is read.
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if handle.is_null() {
+            return io::Error::last_os_error().raw_os_error() == Some(ERROR_ACCESS_DENIED as i32);
+        }
+        let mut code = 0u32;
+        let ok = GetExitCodeProcess(handle, &mut code);
+        CloseHandle(handle);
+        ok != 0 && code == STILL_ACTIVE as u32
+    }
+}
@@ -0,0 +1,144 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use patch_types::{Manifest, RegistryMarker, UninstallEntry};
+
+use crate::observer::PatchObserver;
+
+/// Records `to_version` in the version file and registry value configured by the builder.
+/// Markers this platform has no place for are reported to `observer`.
+pub fn write_version_markers(
+    manifest: &Manifest,
+    cwd: &Path,
+    observer: &mut impl PatchObserver,
+) -> Result<()> {
+    let markers = &manifest.markers;
+
+    if let Some(rel) = &markers.version_file {
+        let path = cwd.join(rel);
+        fs::write(&path, &manifest.to_version)
+            .with_context(|| format!("Writing version marker {}", path.display()))?;
+    }
+
+    if let Some(registry) = &markers.registry {
+        write_registry(registry, &manifest.to_version, observer)?;
+    }
+
+    if let Some(entry) = &markers.uninstall {
+        write_uninstall_entry(entry, manifest, cwd, observer)?;
+    }
+
+    Ok(())
+}
+
+/// Parent of every Add/Remove Programs entry.
+#[cfg(windows)]
+const UNINSTALL_KEY: &str = r"Software\Microsoft\Windows\CurrentVersion\Uninstall";
+
+#[cfg(windows)]
+fn hive_key(hive: patch_types::RegistryHive) -> winreg::RegKey {
+    use patch_types::RegistryHive;
+    use winreg::RegKey;
+    use winreg::enums::{HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE};
+
+    RegKey::predef(match hive {
+        RegistryHive::CurrentUser => HKEY_CURRENT_USER,
+        RegistryHive::LocalMachine => HKEY_LOCAL_MACHINE,
+    })
+}
+
+#[cfg(windows)]
+fn write_registry(
+    marker: &RegistryMarker,
+    version: &str,
+    _observer: &mut impl PatchObserver,
+) -> Result<()> {
+    let (key, _) = hive_key(marker.hive)
+        .create_subkey(&marker.key)
+        .with_context(|| format!("Opening registry key {}", marker.key))?;
+    key.set_value(&marker.value, &version)
+        .with_context(|| format!("Setting registry value {}\\{}", marker.key, marker.value))?;
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn write_registry(
+    marker: &RegistryMarker,
+    _version: &str,
+    observer: &mut impl PatchObserver,
+) -> Result<()> {
+    observer.notice(&format!(
+        "Registry value {}\\{} is only updated on Windows",
+        marker.key, marker.value
+    ));
+    Ok(())
+}
+
+/// Registers the product in Add/Remove Programs with the version just installed, or removes
+/// the entry when this patch is the uninstaller reverting it.
+#[cfg(windows)]
+fn write_uninstall_entry(
+    entry: &UninstallEntry,
+    manifest: &Manifest,
+    cwd: &Path,
+    _observer: &mut impl PatchObserver,
+) -> Result<()> {
+    let path = format!(r"{UNINSTALL_KEY}\{}", entry.name);
+    let Some(uninstaller) = &entry.uninstaller else {
+        return match hive_key(entry.hive).delete_subkey_all(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Removing uninstall entry {}", entry.name))
+            }
+            _ => Ok(()),
+        };
+    };
+
+    let location = std::path::absolute(cwd)?;
+    let command = format!(
+        "\"{}\" --target \"{}\"",
+        location.join(uninstaller).display(),
+        location.display()
+    );
+    // In KiB, as Add/Remove Programs expects
+    let size = manifest.files.iter().map(|file| file.new_size).sum::<u64>() / 1024;
+
+    let (key, _) = hive_key(entry.hive)
+        .create_subkey(&path)
+        .with_context(|| format!("Opening registry key {path}"))?;
+    let set = |name: &str, value: &str| {
+        key.set_value(name, &value)
+            .with_context(|| format!("Setting registry value {path}\\{name}"))
+    };
+    set("DisplayName", &entry.display_name)?;
+    set("DisplayVersion", &manifest.to_version)?;
+    set("InstallLocation", &location.display().to_string())?;
+    set("UninstallString", &command)?;
+    if let Some(publisher) = &entry.publisher {
+        set("Publisher", publisher)?;
+    }
+    for (name, value) in [
+        ("EstimatedSize", u32::try_from(size).unwrap_or(u32::MAX)),
+        ("NoModify", 1),
+        ("NoRepair", 1),
+    ] {
+        key.set_value(name, &value)
+            .with_context(|| format!("Setting registry value {path}\\{name}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn write_uninstall_entry(
+    entry: &UninstallEntry,
+    _manifest: &Manifest,
+    _cwd: &Path,
+    observer: &mut impl PatchObserver,
+) -> Result<()> {
+    observer.notice(&format!(
+        "Uninstall entry {} is only registered on Windows",
+        entry.name
+    ));
+    Ok(())
+}
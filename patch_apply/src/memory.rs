@@ -0,0 +1,57 @@
+use std::sync::{Condvar, Mutex};
+
+/// Bytes of file data the workers may hold in memory at once. Workers wait their turn for room
+/// before loading a file; a file bigger than the whole budget waits until it can run alone.
+pub(crate) struct MemoryBudget {
+    limit: u64,
+    state: Mutex<State>,
+    freed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    used: u64,
+    /// Turns are handed out in order, so small files cannot keep a big one waiting forever
+    next_turn: u64,
+    serving: u64,
+}
+
+/// Memory held by one file until dropped.
+pub(crate) struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: u64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> Self {
+        MemoryBudget {
+            limit,
+            state: Mutex::new(State::default()),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Blocks until `bytes` fit in the budget, or nothing else holds any of it.
+    pub fn reserve(&self, bytes: u64) -> Reservation<'_> {
+        let mut state = self.state.lock().unwrap();
+        let turn = state.next_turn;
+        state.next_turn += 1;
+        while state.serving != turn || (state.used > 0 && state.used + bytes > self.limit) {
+            state = self.freed.wait(state).unwrap();
+        }
+        state.serving += 1;
+        state.used += bytes;
+        self.freed.notify_all();
+        Reservation {
+            budget: self,
+            bytes,
+        }
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.budget.state.lock().unwrap().used -= self.bytes;
+        self.budget.freed.notify_all();
+    }
+}
@@ -0,0 +1,313 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use ureq::Agent;
+
+use patch_types::buffers;
+use patch_types::{FOOTER_LEN, Footer, authenticode};
+
+use crate::net::explain;
+use crate::throttle::DownloadReader;
+
+/// Order in which the hosted copies of a patch are tried.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MirrorOrder {
+    /// As listed: the URLs given, then the mirrors named in the patch
+    #[default]
+    Listed,
+    /// Fastest to answer first, measured with one small request to each
+    Latency,
+}
+
+/// Hosted copies of one patcher. Requests go to the mirror in use and move on to the next
+/// when it fails or sends bytes that do not match their hash; a mirror is only used once it
+/// serves the same file as the first.
+pub(crate) struct Mirrors {
+    agent: Agent,
+    urls: Vec<String>,
+    /// Index in `urls` of the mirror requests go to first
+    current: AtomicUsize,
+    /// Length of the file up to the end of its footer, and the footer, from the mirror it was
+    /// opened from
+    len: u64,
+    footer: [u8; FOOTER_LEN as usize],
+    /// Whether each mirror was found to serve the same file, once checked
+    same_file: Mutex<Vec<Option<bool>>>,
+}
+
+impl Mirrors {
+    /// Fetches the footer from the first of `urls` that answers.
+    pub fn open(agent: Agent, urls: &[String]) -> Result<(Mirrors, Footer)> {
+        let mut failures = Vec::new();
+        for (i, url) in urls.iter().enumerate() {
+            match remote_footer(&agent, url) {
+                Ok((footer, len)) => {
+                    let mut same_file = vec![None; urls.len()];
+                    same_file[i] = Some(true);
+                    let mirrors = Mirrors {
+                        agent,
+                        urls: urls.to_vec(),
+                        current: AtomicUsize::new(i),
+                        len,
+                        footer,
+                        same_file: Mutex::new(same_file),
+                    };
+                    return Ok((mirrors, Footer::from_bytes(&footer)));
+                }
+                Err(e) => failures.push(e),
+            }
+        }
+        Err(all_failed(
+            failures,
+            "No mirror of the patch could be reached",
+        ))
+    }
+
+    /// Length of the hosted file up to the end of the footer, before any certificate table.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Adds the mirrors the patch names that are not known yet, after the others.
+    pub fn extend(&mut self, urls: &[String]) {
+        for url in urls {
+            if !self.urls.contains(url) {
+                self.urls.push(url.clone());
+                self.same_file.get_mut().unwrap().push(None);
+            }
+        }
+    }
+
+    /// Puts the mirrors that answer fastest first; those that do not answer, or serve a
+    /// different file, go last.
+    pub fn rank_by_latency(&mut self) {
+        let this = &*self;
+        let timings: Vec<Option<Duration>> = std::thread::scope(|scope| {
+            let probes: Vec<_> = (0..this.urls.len())
+                .map(|i| {
+                    scope.spawn(move || {
+                        let started = Instant::now();
+                        this.serves_same_file(i).then(|| started.elapsed())
+                    })
+                })
+                .collect();
+            probes
+                .into_iter()
+                .map(|probe| probe.join().ok().flatten())
+                .collect()
+        });
+        let mut order: Vec<usize> = (0..self.urls.len()).collect();
+        order.sort_by_key(|&i| timings[i].unwrap_or(Duration::MAX));
+        let same_file = self.same_file.get_mut().unwrap();
+        let checked: Vec<Option<bool>> = order.iter().map(|&i| same_file[i]).collect();
+        *same_file = checked;
+        self.urls = order.iter().map(|&i| self.urls[i].clone()).collect();
+        self.current.store(0, Ordering::Relaxed);
+    }
+
+    /// Whether mirror `i` answers with the same length and footer as the first.
+    fn serves_same_file(&self, i: usize) -> bool {
+        if let Some(same) = self.same_file.lock().unwrap()[i] {
+            return same;
+        }
+        let same = remote_footer(&self.agent, &self.urls[i])
+            .is_ok_and(|(footer, len)| len == self.len && footer == self.footer);
+        self.same_file.lock().unwrap()[i] = Some(same);
+        same
+    }
+
+    /// Fetches `len` bytes at `start` of the file, from the mirror in use or, when it fails or
+    /// `check` rejects what it sent, from the next one that serves them.
+    pub fn fetch(&self, start: u64, len: u64, check: impl Fn(&[u8]) -> bool) -> Result<Vec<u8>> {
+        // An empty range has no last byte to request
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let first = self.current.load(Ordering::Relaxed);
+        let mut failures = Vec::new();
+        for i in (first..self.urls.len()).chain(0..first) {
+            if i != first && !self.serves_same_file(i) {
+                continue;
+            }
+            let url = &self.urls[i];
+            match fetch_range(&self.agent, url, start, len) {
+                Ok(bytes) if check(&bytes) => {
+                    self.current.store(i, Ordering::Relaxed);
+                    return Ok(bytes);
+                }
+                Ok(_) => failures.push(anyhow::anyhow!(
+                    "Bytes {start}-{} from {url} do not match their hash",
+                    start + len - 1
+                )),
+                Err(e) => failures.push(e),
+            }
+        }
+        Err(all_failed(
+            failures,
+            &format!("No mirror could serve bytes {start}-{}", start + len - 1),
+        ))
+    }
+
+    /// Appends bytes `done..total` past `start` of the file to `out`, carrying on from the next
+    /// mirror where one fails partway. `progress` receives the bytes written so far.
+    pub fn download(
+        &self,
+        out: &mut File,
+        start: u64,
+        mut done: u64,
+        total: u64,
+        mut progress: impl FnMut(u64),
+    ) -> Result<u64> {
+        let first = self.current.load(Ordering::Relaxed);
+        let mut failures = Vec::new();
+        for i in (first..self.urls.len()).chain(0..first) {
+            if done == total {
+                break;
+            }
+            if i != first && !self.serves_same_file(i) {
+                continue;
+            }
+            let url = &self.urls[i];
+            let before = done;
+            let result = stream_range(&self.agent, url, start + done, total - done, out, |n| {
+                done += n;
+                progress(done);
+            });
+            match result {
+                Ok(()) if done == total => return Ok(done),
+                Ok(()) => failures.push(anyhow::anyhow!(
+                    "Download from {url} stopped after {} bytes; run again to resume",
+                    done - before
+                )),
+                Err(e) => failures.push(e),
+            }
+        }
+        if done == total {
+            return Ok(done);
+        }
+        Err(all_failed(
+            failures,
+            &format!("Download stopped after {done} of {total} bytes; run again to resume"),
+        ))
+    }
+}
+
+/// The error of a single failed mirror as it is, or the failures of several listed under
+/// `summary`.
+fn all_failed(mut failures: Vec<anyhow::Error>, summary: &str) -> anyhow::Error {
+    if failures.len() == 1 {
+        return failures.remove(0);
+    }
+    let listing: Vec<String> = failures.iter().map(|e| format!("  {e:#}")).collect();
+    anyhow::anyhow!("{summary}:\n{}", listing.join("\n"))
+}
+
+fn range_request(
+    agent: &Agent,
+    url: &str,
+    range: &str,
+) -> Result<ureq::http::Response<ureq::Body>> {
+    let resp = agent
+        .get(url)
+        .header("Range", range)
+        .call()
+        .map_err(|e| explain(e, url))?;
+    if resp.status() != 206 {
+        anyhow::bail!("Server does not support range requests for {url}");
+    }
+    Ok(resp)
+}
+
+fn fetch_range(agent: &Agent, url: &str, start: u64, len: u64) -> Result<Vec<u8>> {
+    let end = start + len - 1;
+    let mut resp = range_request(agent, url, &format!("bytes={start}-{end}"))
+        .with_context(|| format!("Fetching bytes {start}-{end}"))?;
+    let mut buffer = Vec::with_capacity(buffers::memory_len(len, || {
+        format!("Bytes {start}-{end} of {url}")
+    })?);
+    DownloadReader(resp.body_mut().as_reader())
+        .take(len + 1)
+        .read_to_end(&mut buffer)
+        .with_context(|| format!("Downloading bytes {start}-{end} of {url}"))?;
+    if buffer.len() as u64 != len {
+        anyhow::bail!(
+            "Short read from {url}: expected {len} bytes, got {}",
+            buffer.len()
+        );
+    }
+    Ok(buffer)
+}
+
+/// Writes `len` bytes from `start` of `url` to `out`, passing each piece's length to `written`.
+fn stream_range(
+    agent: &Agent,
+    url: &str,
+    start: u64,
+    len: u64,
+    out: &mut File,
+    mut written: impl FnMut(u64),
+) -> Result<()> {
+    let mut resp = range_request(agent, url, &format!("bytes={start}-"))?;
+    let mut reader = DownloadReader(resp.body_mut().as_reader()).take(len);
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader
+            .read(&mut buffer)
+            .with_context(|| format!("Downloading {url}"))?;
+        if n == 0 {
+            return Ok(());
+        }
+        out.write_all(&buffer[..n])
+            .context("Writing the download")?;
+        written(n as u64);
+    }
+}
+
+/// Fetches the footer of the file at `url`, with the length of the file up to its end: all of
+/// it, or what comes before the certificate table of a signed patcher.
+fn remote_footer(agent: &Agent, url: &str) -> Result<([u8; FOOTER_LEN as usize], u64)> {
+    let mut resp = agent
+        .get(url)
+        .header("Range", format!("bytes=0-{}", authenticode::HEADER_LEN - 1))
+        .call()
+        .map_err(|e| explain(e, url))?;
+    let len = total_len(&resp).with_context(|| format!("Range request to {url}"))?;
+    let head = resp.body_mut().read_to_vec()?;
+    let len = authenticode::unsigned_len(&mut io::Cursor::new(head), len)?;
+    if len < FOOTER_LEN {
+        anyhow::bail!("{url} is too small to be a patch");
+    }
+    let mut resp = agent
+        .get(url)
+        .header("Range", format!("bytes={}-{}", len - FOOTER_LEN, len - 1))
+        .call()
+        .map_err(|e| explain(e, url))?;
+    let bytes = resp.body_mut().read_to_vec()?;
+    let footer = bytes
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid footer length from {url}"))?;
+    Ok((footer, len))
+}
+
+/// Total resource length from the `Content-Range` header of a 206 response.
+fn total_len(resp: &ureq::http::Response<ureq::Body>) -> Result<u64> {
+    if resp.status() != 206 {
+        anyhow::bail!("Server does not support range requests");
+    }
+    let header = resp
+        .headers()
+        .get("Content-Range")
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| anyhow::anyhow!("Missing Content-Range header"))?;
+    let total = header
+        .rsplit('/')
+        .next()
+        .and_then(|t| t.trim().parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("Unparsable Content-Range header: {header}"))?;
+    Ok(total)
+}
@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use ureq::Agent;
+use ureq::tls::{PemItem, RootCerts, TlsConfig};
+
+/// Network settings for download mode.
+#[derive(Default)]
+pub struct HttpOptions {
+    /// Explicit proxy URL; when unset, `HTTPS_PROXY`/`HTTP_PROXY`/`ALL_PROXY` and `NO_PROXY` are honoured
+    pub proxy: Option<String>,
+    /// PEM file with the root certificates to trust instead of the built-in ones
+    pub ca_bundle: Option<PathBuf>,
+}
+
+pub fn build_agent(options: &HttpOptions) -> Result<Agent> {
+    let mut config = Agent::config_builder();
+
+    if let Some(proxy) = &options.proxy {
+        let proxy = ureq::Proxy::new(proxy).with_context(|| format!("Invalid proxy {proxy}"))?;
+        config = config.proxy(Some(proxy));
+    }
+
+    if let Some(path) = &options.ca_bundle {
+        let pem =
+            std::fs::read(path).with_context(|| format!("Reading CA bundle {}", path.display()))?;
+        let mut certs = Vec::new();
+        for item in ureq::tls::parse_pem(&pem) {
+            if let PemItem::Certificate(cert) =
+                item.with_context(|| format!("Parsing CA bundle {}", path.display()))?
+            {
+                certs.push(cert);
+            }
+        }
+        if certs.is_empty() {
+            anyhow::bail!("CA bundle {} contains no certificates", path.display());
+        }
+        config = config.tls_config(
+            TlsConfig::builder()
+                .root_certs(RootCerts::new_with_certs(&certs))
+                .build(),
+        );
+    }
+
+    Ok(config.build().into())
+}
+
+/// Turns a ureq error into a message that tells the user what to change.
+pub fn explain(err: ureq::Error, url: &str) -> anyhow::Error {
+    let hint = match &err {
+        ureq::Error::Tls(_) | ureq::Error::Rustls(_) => Some(
+            "the TLS connection could not be verified. If you are behind a TLS-intercepting \
+             proxy, pass its root certificate with --ca-bundle",
+        ),
+        ureq::Error::HostNotFound => Some(
+            "the host could not be resolved. Check the URL, or set --proxy if direct DNS is blocked",
+        ),
+        ureq::Error::ConnectionFailed => Some(
+            "the connection failed. If your network requires a proxy, set --proxy or HTTPS_PROXY",
+        ),
+        ureq::Error::StatusCode(407) => {
+            Some("the proxy requires authentication; include credentials in the --proxy URL")
+        }
+        _ => None,
+    };
+    match hint {
+        Some(hint) => anyhow::anyhow!("Requesting {url} failed: {err}; {hint}"),
+        None => anyhow::anyhow!("Requesting {url} failed: {err}"),
+    }
+}
@@ -0,0 +1,202 @@
+//! Applying from async code: the apply runs on tokio's blocking pool, is cancelled through a
+//! [`CancellationToken`] and reports progress over a channel, so an updater's event loop never
+//! waits on it.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
+
+use crate::{ApplyOptions, Bundle, PatchObserver, Stage, Summary};
+
+/// A [`PatchObserver`] event, as sent over the progress channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Progress {
+    Stage(Stage),
+    /// Number of files and the total that the `FileFinished` weights add up to
+    Totals {
+        files: u64,
+        bytes: u64,
+    },
+    FileStarted {
+        worker: usize,
+        path: String,
+        bytes: u64,
+    },
+    FileProgress {
+        worker: usize,
+        done: u64,
+    },
+    FileFinished {
+        path: String,
+        weight: u64,
+    },
+    Notice(String),
+}
+
+/// Applies `bundle` to `target` with default options; see [`apply_with_options`].
+pub async fn apply(
+    bundle: Arc<Bundle>,
+    target: PathBuf,
+    cancel: CancellationToken,
+    progress: UnboundedSender<Progress>,
+) -> Result<Summary> {
+    apply_with_options(bundle, target, ApplyOptions::default(), cancel, progress).await
+}
+
+/// [`apply_bundle_with_options`](crate::apply_bundle_with_options) on a blocking thread.
+///
+/// Cancelling `cancel`, or dropping the returned future, stops the apply at the next file or
+/// before the commit, leaving the installation as it was; once the commit has started the
+/// apply runs to the end. Conflicting files abort the apply, since there is no one to ask.
+/// Events are dropped once the receiver of `progress` is closed.
+pub async fn apply_with_options(
+    bundle: Arc<Bundle>,
+    target: PathBuf,
+    options: ApplyOptions,
+    cancel: CancellationToken,
+    progress: UnboundedSender<Progress>,
+) -> Result<Summary> {
+    // A child, so dropping the future stops the apply without cancelling the caller's token
+    let cancel = cancel.child_token();
+    let _guard = cancel.clone().drop_guard();
+    let mut observer = ChannelObserver { cancel, progress };
+    let apply = tokio::task::spawn_blocking(move || {
+        crate::apply_bundle_with_options(&bundle, &target, &options, &mut observer)
+    });
+    match apply.await {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(e) => Err(anyhow::anyhow!("The apply task was stopped: {e}")),
+    }
+}
+
+/// Passes events on to the channel and polls the token for cancellation.
+struct ChannelObserver {
+    cancel: CancellationToken,
+    progress: UnboundedSender<Progress>,
+}
+
+impl ChannelObserver {
+    fn send(&self, event: Progress) {
+        let _ = self.progress.send(event);
+    }
+}
+
+impl PatchObserver for ChannelObserver {
+    fn stage(&mut self, stage: Stage) {
+        self.send(Progress::Stage(stage));
+    }
+
+    fn totals(&mut self, files: u64, bytes: u64) {
+        self.send(Progress::Totals { files, bytes });
+    }
+
+    fn file_started(&mut self, worker: usize, path: &str, bytes: u64) {
+        self.send(Progress::FileStarted {
+            worker,
+            path: path.to_string(),
+            bytes,
+        });
+    }
+
+    fn file_progress(&mut self, worker: usize, done: u64) {
+        self.send(Progress::FileProgress { worker, done });
+    }
+
+    fn file_finished(&mut self, path: &str, weight: u64) {
+        self.send(Progress::FileFinished {
+            path: path.to_string(),
+            weight,
+        });
+    }
+
+    fn notice(&mut self, message: &str) {
+        self.send(Progress::Notice(message.to_string()));
+    }
+
+    fn cancelled(&mut self) -> bool {
+        self.cancel.is_cancelled()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::time::Instant;
+
+    use patch_progress::throttle::REDRAW_INTERVAL;
+    use patch_types::normalize::Normalization;
+    use patch_types::{FileEntry, PatchKind, ZIP_MANIFEST_NAME, ZipManifest};
+    use zip::write::SimpleFileOptions;
+
+    use super::*;
+
+    #[test]
+    fn file_progress_is_throttled_for_a_large_file() {
+        let dir = std::env::temp_dir().join(format!("patch_nonblocking_{}", std::process::id()));
+        let target = dir.join("target");
+        fs::create_dir_all(&target).unwrap();
+        // Written a buffer at a time, a few dozen of them
+        let content: Vec<u8> = (0..256u32 << 20).map(|i| (i >> 12) as u8).collect();
+        let file = FileEntry {
+            path: "big.bin".to_string(),
+            kind: PatchKind::Added { idx: 0 },
+            original_hash: [0; 32],
+            new_hash: *blake3::hash(&content).as_bytes(),
+            old_size: 0,
+            new_size: content.len() as u64,
+            mtime: None,
+            normalization: Normalization::None,
+            mode: None,
+            sparse: false,
+            full_copy: None,
+        };
+        let index = ZipManifest {
+            manifest: crate::testing::manifest(vec![file]),
+            entry_files: vec!["added/big.bin".to_string()],
+        };
+        let patch = dir.join("patch.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&patch).unwrap());
+        let stored = SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored)
+            .large_file(true);
+        zip.start_file(ZIP_MANIFEST_NAME, stored).unwrap();
+        zip.write_all(&serde_json::to_vec(&index).unwrap()).unwrap();
+        zip.start_file("added/big.bin", stored).unwrap();
+        zip.write_all(&content).unwrap();
+        zip.finish().unwrap();
+
+        let bundle = Arc::new(Bundle::open(&patch).unwrap());
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let started = Instant::now();
+        let result = runtime.block_on(apply(
+            bundle,
+            target.clone(),
+            CancellationToken::new(),
+            sender,
+        ));
+        let elapsed = started.elapsed();
+        let written = fs::read(target.join("big.bin"));
+        fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+        assert!(written.unwrap() == content);
+
+        let mut updates = 0;
+        while let Ok(event) = receiver.try_recv() {
+            updates += u32::from(matches!(event, Progress::FileProgress { .. }));
+        }
+        let intervals = elapsed.as_nanos() / REDRAW_INTERVAL.as_nanos();
+        // One per interval at most, where every buffer would pass a few times as many
+        assert!(
+            u128::from(updates) <= intervals,
+            "{updates} updates in {elapsed:?}"
+        );
+    }
+}
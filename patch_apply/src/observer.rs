@@ -0,0 +1,97 @@
+use std::fmt;
+
+use patch_types::error::PatchError;
+
+/// Receives progress from [`apply_bundle_with`](crate::apply_bundle_with) and steers it.
+///
+/// Files are staged on several worker threads, so calls arrive from any of them, one at a
+/// time. Every method has a default that ignores the event, so an observer only implements
+/// what its UI shows.
+pub trait PatchObserver: Send {
+    /// The apply moved on to another stage.
+    fn stage(&mut self, _stage: Stage) {}
+
+    /// Number of files in the patch and the total weight that [`file_finished`] calls add up
+    /// to. Sent once, before the first file is staged.
+    ///
+    /// [`file_finished`]: PatchObserver::file_finished
+    fn totals(&mut self, _files: u64, _bytes: u64) {}
+
+    /// `worker` started writing `path`, which reads and writes `bytes` in total.
+    fn file_started(&mut self, _worker: usize, _path: &str, _bytes: u64) {}
+
+    /// `worker` has read and written `done` bytes of its current file. Sent at most every
+    /// [`REDRAW_INTERVAL`](patch_progress::throttle::REDRAW_INTERVAL) per file, and not at all
+    /// for a file done sooner.
+    fn file_progress(&mut self, _worker: usize, _done: u64) {}
+
+    /// `path` is done with staging; `weight` is its share of the total from `totals`.
+    fn file_finished(&mut self, _path: &str, _weight: u64) {}
+
+    /// [`Bundle::download`](crate::Bundle::download) has fetched `done` of `total` bytes.
+    fn downloaded(&mut self, _done: u64, _total: u64) {}
+
+    /// A message for the user that is not an error, such as a file being kept in place.
+    fn notice(&mut self, _message: &str) {}
+
+    /// A file matches neither its old nor its new state. Skipping leaves it as it is and
+    /// patches everything else; the version markers are then not written.
+    fn conflict(&mut self, _conflict: &Conflict) -> ConflictAction {
+        ConflictAction::Abort
+    }
+
+    /// Polled between files while staging and once before committing. Cancelling before the
+    /// commit leaves the installation untouched.
+    fn cancelled(&mut self) -> bool {
+        false
+    }
+}
+
+/// An observer that ignores every event and aborts on the first conflict.
+impl PatchObserver for () {}
+
+/// The stages of an apply, in order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Hashing the installation against the patch
+    Verifying,
+    /// Decoding new and patched files into the staging folder
+    Applying,
+    /// Hashing the staged files against the new version
+    VerifyingOutput,
+    /// Moving staged files into place
+    Committing,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Verifying => "Verifying",
+            Stage::Applying => "Applying",
+            Stage::VerifyingOutput => "Verifying patched files",
+            Stage::Committing => "Committing",
+        })
+    }
+}
+
+/// A file in the installation that blocks patching.
+#[derive(Clone, Debug)]
+pub struct Conflict {
+    pub(crate) index: usize,
+    /// Path of the file, relative to the target folder
+    pub path: String,
+    pub error: PatchError,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+/// What to do about a [`Conflict`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictAction {
+    Abort,
+    Skip,
+}
@@ -0,0 +1,262 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use indicatif::{HumanBytes, HumanDuration};
+use serde::Serialize;
+
+use patch_types::{Manifest, PatchKind};
+
+use crate::disk::free_space;
+use crate::filesystem::Filesystem;
+use crate::verify::Verification;
+
+/// What applying the patch would do, as reported by `--plan`.
+#[derive(Serialize)]
+pub struct Plan {
+    pub product: String,
+    pub from_version: String,
+    pub to_version: String,
+    pub target: PathBuf,
+    pub patched: Vec<String>,
+    pub added: Vec<String>,
+    pub deleted: Vec<String>,
+    pub moved: Vec<Move>,
+    /// New files copied from an identical old file
+    pub copied: Vec<Move>,
+    /// Files whose base is missing or does not verify, written from their full copy instead
+    pub restored: Vec<String>,
+    pub up_to_date: usize,
+    /// Files of components left out, which are not installed
+    pub left_out: Vec<String>,
+    /// Files the patch changes that `--only` and `--skip` leave as they are
+    pub unselected: Vec<String>,
+    /// Files the patch deletes that `--no-delete` leaves in place
+    pub kept: Vec<String>,
+    /// Files the patch does not know, which `--purge-unknown` removes
+    pub unknown: Vec<String>,
+    pub created_dirs: Vec<String>,
+    pub deleted_dirs: Vec<String>,
+    /// Bytes written to the installation by patched and added files
+    pub bytes_written: u64,
+    pub space_needed: u64,
+    pub free_space: Option<u64>,
+    /// What the filesystem holding the target can do
+    pub filesystem: Filesystem,
+    /// Rough time applying the whole patch takes, as estimated from the builder's measurements
+    pub estimated_seconds: u64,
+    pub conflicts: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct Move {
+    pub from: String,
+    pub to: String,
+}
+
+impl Plan {
+    pub(crate) fn new(manifest: &Manifest, verification: &Verification, cwd: &Path) -> Self {
+        let mut plan = Plan {
+            product: manifest.product.clone(),
+            from_version: manifest.from_version.clone(),
+            to_version: manifest.to_version.clone(),
+            target: cwd.to_path_buf(),
+            patched: Vec::new(),
+            added: Vec::new(),
+            deleted: Vec::new(),
+            moved: Vec::new(),
+            copied: Vec::new(),
+            restored: Vec::new(),
+            up_to_date: 0,
+            left_out: Vec::new(),
+            unselected: Vec::new(),
+            kept: Vec::new(),
+            unknown: Vec::new(),
+            created_dirs: manifest
+                .created_dirs
+                .iter()
+                .filter(|dir| !cwd.join(dir).is_dir())
+                .cloned()
+                .collect(),
+            deleted_dirs: manifest
+                .deleted_dirs
+                .iter()
+                .filter(|dir| cwd.join(dir).is_dir())
+                .cloned()
+                .collect(),
+            bytes_written: 0,
+            space_needed: verification.space_needed(manifest),
+            free_space: free_space(cwd),
+            filesystem: verification.filesystem.clone(),
+            estimated_seconds: manifest.cost.estimate().as_secs(),
+            conflicts: verification
+                .conflicts
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+        };
+
+        for (i, file) in manifest.files.iter().enumerate() {
+            if verification.up_to_date.contains(&i) {
+                plan.up_to_date += 1;
+                continue;
+            }
+            if verification.left_out.contains(&i) {
+                plan.left_out.push(file.path.clone());
+                continue;
+            }
+            if verification.unselected.contains(&i) {
+                if !matches!(file.kind, PatchKind::Unchanged) {
+                    plan.unselected.push(file.path.clone());
+                }
+                continue;
+            }
+            if verification.kept.contains(&i) {
+                plan.kept.push(file.path.clone());
+                continue;
+            }
+            match &file.kind {
+                _ if verification.use_fallback.contains(&i) => {
+                    plan.restored.push(file.path.clone());
+                    plan.bytes_written += file.new_size;
+                }
+                PatchKind::Unchanged => {}
+                PatchKind::Patched { .. } | PatchKind::Segmented { .. } => {
+                    plan.patched.push(file.path.clone());
+                    plan.bytes_written += file.new_size;
+                }
+                PatchKind::Added { .. } => {
+                    plan.added.push(file.path.clone());
+                    plan.bytes_written += file.new_size;
+                }
+                PatchKind::Streamed { .. } => {
+                    // The old version has no chunks of a new file
+                    match manifest.chunked_file(&file.path) {
+                        Some(chunked) if chunked.old_chunks.is_empty() => {
+                            plan.added.push(file.path.clone())
+                        }
+                        _ => plan.patched.push(file.path.clone()),
+                    }
+                    plan.bytes_written += file.new_size;
+                }
+                PatchKind::Deleted => plan.deleted.push(file.path.clone()),
+                PatchKind::Moved { from } => plan.moved.push(Move {
+                    from: from.clone(),
+                    to: file.path.clone(),
+                }),
+                PatchKind::Copied { from } => {
+                    plan.copied.push(Move {
+                        from: from.clone(),
+                        to: file.path.clone(),
+                    });
+                    plan.bytes_written += file.new_size;
+                }
+            }
+        }
+        if plan.free_space.is_some_and(|free| plan.space_needed > free) {
+            plan.conflicts.push(format!(
+                "Not enough disk space: patching needs {} but only {} is free",
+                HumanBytes(plan.space_needed),
+                HumanBytes(plan.free_space.unwrap_or(0))
+            ));
+        }
+        plan
+    }
+
+    pub fn print(&self) {
+        println!(
+            "Plan for {} {} -> {} in {}:",
+            self.product,
+            self.from_version,
+            self.to_version,
+            self.target.display()
+        );
+        println!(
+            "  {} files patched",
+            self.patched.len() + self.restored.len()
+        );
+        if !self.restored.is_empty() {
+            println!(
+                "    of which {} restored from full copies",
+                self.restored.len()
+            );
+        }
+        println!("  {} files added", self.added.len());
+        println!("  {} files deleted", self.deleted.len());
+        if !self.kept.is_empty() {
+            println!(
+                "  {} files the patch deletes kept in place",
+                self.kept.len()
+            );
+        }
+        if !self.unknown.is_empty() {
+            println!(
+                "  {} files the patch does not know removed:",
+                self.unknown.len()
+            );
+            for path in &self.unknown {
+                println!("    {path}");
+            }
+        }
+        println!("  {} files moved", self.moved.len());
+        println!("  {} files copied from old files", self.copied.len());
+        println!("  {} files already up to date", self.up_to_date);
+        if !self.left_out.is_empty() {
+            println!("  {} files of components left out", self.left_out.len());
+        }
+        if !self.unselected.is_empty() {
+            println!(
+                "  {} files not selected, left as they are",
+                self.unselected.len()
+            );
+        }
+        if !self.created_dirs.is_empty() || !self.deleted_dirs.is_empty() {
+            println!(
+                "  {} folders created, {} removed",
+                self.created_dirs.len(),
+                self.deleted_dirs.len()
+            );
+        }
+        println!(
+            "  {} written, {} of free space needed",
+            HumanBytes(self.bytes_written),
+            HumanBytes(self.space_needed)
+        );
+        // Case folding and symbolic links are usual on Windows, so only the JSON has them
+        let fs = &self.filesystem;
+        let limits: Vec<String> = [
+            fs.max_file_size
+                .map(|max| format!("files up to {}", HumanBytes(max + 1))),
+            (!fs.permissions).then(|| "no file permissions".to_string()),
+            (!fs.atomic_rename).then(|| "no atomic replace".to_string()),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if !limits.is_empty() {
+            println!(
+                "  on {}: {}",
+                fs.kind.as_deref().unwrap_or("a filesystem with limits"),
+                limits.join(", ")
+            );
+        }
+        println!(
+            "  about {} to apply from {}",
+            HumanDuration(Duration::from_secs(self.estimated_seconds)),
+            self.from_version
+        );
+        if self.conflicts.is_empty() {
+            println!("No conflicts found");
+        } else {
+            println!("{} conflicts found:", self.conflicts.len());
+            for conflict in &self.conflicts {
+                println!("  {conflict}");
+            }
+        }
+    }
+
+    pub fn write_json(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Writing {}", path.display()))
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+
+use anyhow::Result;
+
+use patch_types::PatchData;
+
+use crate::source::BundleSource;
+
+/// Entries downloaded at once ahead of the workers.
+const CONNECTIONS: usize = 4;
+/// Downloaded bytes waiting for a worker, at most, unless `--max-memory` asks for less.
+pub(crate) const AHEAD_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Downloads the entries of a hosted patch ahead of the workers, in the order they will
+/// need them, so the network stays busy while earlier files are decoded and written. Entries
+/// wait in memory up to a limit; a worker that gets to an entry before its download started
+/// fetches it itself. Entries of a local patch are read when needed, as before.
+pub(crate) struct Prefetch<'a> {
+    source: &'a BundleSource,
+    /// Entry indices in the order the workers take their files
+    queue: Vec<usize>,
+    limit: u64,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    /// Position in `queue` of the next entry to download
+    next: usize,
+    /// Stored bytes downloaded and not yet taken by a worker
+    held: u64,
+    entries: HashMap<usize, Slot>,
+    stopped: bool,
+}
+
+enum Slot {
+    Fetching,
+    Ready(Result<Vec<u8>>),
+    /// Handed to a worker, or fetched by one directly
+    Taken,
+}
+
+impl<'a> Prefetch<'a> {
+    pub fn new(source: &'a BundleSource, mut queue: Vec<usize>, limit: u64) -> Self {
+        if !source.is_remote() {
+            queue.clear();
+        }
+        Prefetch {
+            source,
+            queue,
+            limit,
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Runs `work` while the entries download alongside it.
+    pub fn alongside<R>(&self, work: impl FnOnce() -> R) -> R {
+        if self.queue.is_empty() {
+            return work();
+        }
+        std::thread::scope(|scope| {
+            for _ in 0..CONNECTIONS.min(self.queue.len()) {
+                scope.spawn(|| self.download());
+            }
+            let result = work();
+            self.state.lock().unwrap().stopped = true;
+            self.changed.notify_all();
+            result
+        })
+    }
+
+    /// Downloads queued entries one after another until the queue runs out or work stops.
+    fn download(&self) {
+        loop {
+            let mut state = self.state.lock().unwrap();
+            let (idx, len) = loop {
+                if state.stopped {
+                    return;
+                }
+                let Some(&idx) = self.queue.get(state.next) else {
+                    return;
+                };
+                if state.entries.contains_key(&idx) {
+                    state.next += 1;
+                    continue;
+                }
+                let len = self.source.entry_len(idx);
+                if state.held > 0 && state.held + len > self.limit {
+                    state = self.changed.wait(state).unwrap();
+                    continue;
+                }
+                state.next += 1;
+                state.held += len;
+                state.entries.insert(idx, Slot::Fetching);
+                break (idx, len);
+            };
+            drop(state);
+
+            let bytes = self.source.fetch_entry(idx);
+            let mut state = self.state.lock().unwrap();
+            if bytes.is_err() {
+                // Not held while it waits, so a failure cannot stall the others
+                state.held -= len;
+            }
+            state.entries.insert(idx, Slot::Ready(bytes));
+            self.changed.notify_all();
+        }
+    }
+
+    /// The entry at `idx`, once its download finishes, or fetched now when it has not started.
+    pub fn read(&self, idx: usize) -> Result<PatchData> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            match state.entries.get(&idx) {
+                Some(Slot::Fetching) => state = self.changed.wait(state).unwrap(),
+                Some(Slot::Ready(_)) => {
+                    let Some(Slot::Ready(bytes)) = state.entries.insert(idx, Slot::Taken) else {
+                        unreachable!("checked above");
+                    };
+                    if bytes.is_ok() {
+                        state.held -= self.source.entry_len(idx);
+                    }
+                    drop(state);
+                    self.changed.notify_all();
+                    return self.source.decode_entry(idx, bytes?);
+                }
+                _ => {
+                    state.entries.insert(idx, Slot::Taken);
+                    drop(state);
+                    return self.source.read_entry(idx);
+                }
+            }
+        }
+    }
+}
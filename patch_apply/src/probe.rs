@@ -0,0 +1,170 @@
+//! Quick checks of whether a patch can be applied to a folder, for launchers deciding what to
+//! ask the user for first. Nothing is hashed beyond the few files that identify a release, and
+//! nothing in the folder changes.
+
+use std::collections::BTreeSet;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use patch_types::{Manifest, PatchKind};
+
+use crate::verify::Verification;
+use crate::{access, disk, identify, lock, receipt, selfexe};
+
+/// Whether a patch can be applied to a folder and what stands in the way, as reported by
+/// `--probe`.
+#[derive(Serialize)]
+pub struct Probe {
+    pub product: String,
+    pub from_version: String,
+    pub to_version: String,
+    pub target: PathBuf,
+    /// Product the receipt of the last patch applied to the folder names, if there is one
+    pub installed_product: Option<String>,
+    /// Release found in the folder: the one its files match, if exactly one the patch knows
+    /// does, or else the one the receipt records
+    pub installed_version: Option<String>,
+    /// True when the patch applies to the release installed, false when another product or
+    /// release is installed or the folder is already patched, unknown when neither could be told
+    pub version_ok: Option<bool>,
+    /// Files the patch changes that are missing or neither their old nor their new size, which
+    /// patching reports as conflicts unless the patch carries a full copy
+    pub unexpected_files: Vec<String>,
+    /// Most free space patching needs, when no file is up to date yet
+    pub space_needed: u64,
+    pub free_space: Option<u64>,
+    pub enough_space: bool,
+    /// Files the patch replaces or removes that another program has open, such as the
+    /// running product: it has to be closed first
+    pub locked_files: Vec<String>,
+    /// Files and folders the patch writes that the current user may not change: the patcher
+    /// needs to run elevated, as an administrator or root
+    pub denied: Vec<String>,
+    /// Process id of another patcher applying to the folder right now
+    pub patching_pid: Option<u32>,
+    /// Whether patching can start without the user doing anything first
+    pub ready: bool,
+}
+
+/// Checks the folder `target` for `manifest`'s patch: which release is installed, free space,
+/// and whether the files and folders the patch touches can be written now.
+pub fn probe(manifest: &Manifest, target: &Path) -> Probe {
+    let receipt = receipt::installed(target);
+    let installed_product = receipt.as_ref().map(|(product, _)| product.clone());
+    let installed_version = identify::installed_version(manifest, target)
+        .map(str::to_string)
+        .or_else(|| {
+            receipt
+                .filter(|(product, _)| *product == manifest.product)
+                .map(|(_, version)| version)
+        });
+    let version_ok = match (&installed_product, &installed_version) {
+        (Some(product), _) if *product != manifest.product => Some(false),
+        (_, Some(version)) => Some(*version == manifest.from_version),
+        _ => None,
+    };
+    let space_needed = Verification::default().space_needed(manifest);
+    let free_space = disk::free_space(target);
+    let (locked_files, denied) = check_access(manifest, target);
+    let patching_pid = lock::holder(target).map(|(pid, _)| pid);
+    let enough_space = free_space.is_none_or(|free| space_needed <= free);
+    Probe {
+        product: manifest.product.clone(),
+        from_version: manifest.from_version.clone(),
+        to_version: manifest.to_version.clone(),
+        target: target.to_path_buf(),
+        unexpected_files: unexpected_files(manifest, target),
+        ready: version_ok != Some(false)
+            && enough_space
+            && locked_files.is_empty()
+            && denied.is_empty()
+            && patching_pid.is_none(),
+        installed_product,
+        installed_version,
+        version_ok,
+        space_needed,
+        free_space,
+        enough_space,
+        locked_files,
+        denied,
+        patching_pid,
+    }
+}
+
+/// Files the patch changes that are at neither version by their size alone.
+fn unexpected_files(manifest: &Manifest, target: &Path) -> Vec<String> {
+    manifest
+        .files
+        .iter()
+        .filter(|file| match file.kind {
+            PatchKind::Patched { .. }
+            | PatchKind::Streamed { .. }
+            | PatchKind::Segmented { .. } => !fs::metadata(target.join(&file.path))
+                .is_ok_and(|meta| meta.len() == file.old_size || meta.len() == file.new_size),
+            _ => false,
+        })
+        .map(|file| file.path.clone())
+        .collect()
+}
+
+/// Files the patch replaces, removes or moves that are open elsewhere, and the files and
+/// folders among them and above them the current user may not write, both slash-separated.
+fn check_access(manifest: &Manifest, target: &Path) -> (Vec<String>, Vec<String>) {
+    let touched: BTreeSet<&str> = manifest
+        .files
+        .iter()
+        .filter(|file| !matches!(file.kind, PatchKind::Unchanged))
+        .flat_map(|file| match &file.kind {
+            PatchKind::Moved { from } => vec![file.path.as_str(), from.as_str()],
+            _ => vec![file.path.as_str()],
+        })
+        .collect();
+    let running_exe = selfexe::running_exe();
+    let mut locked = Vec::new();
+    let mut denied = Vec::new();
+    for &rel in &touched {
+        let path = target.join(rel);
+        // Read-only files are cleared by the patcher before it replaces them, and the running
+        // patcher replaces itself
+        if !fs::metadata(&path).is_ok_and(|meta| meta.is_file() && !meta.permissions().readonly())
+            || selfexe::is_running_exe(&path, running_exe.as_deref())
+        {
+            continue;
+        }
+        match OpenOptions::new().write(true).open(&path) {
+            Err(e) if access::in_use(&e) => locked.push(rel.to_string()),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => denied.push(rel.to_string()),
+            _ => {}
+        }
+    }
+    // The staging folder goes in the target, and each file is renamed into its folder
+    let dirs: BTreeSet<&str> = touched
+        .iter()
+        .filter_map(|rel| rel.rsplit_once('/').map(|(dir, _)| dir))
+        .chain([""])
+        .collect();
+    for dir in dirs {
+        let path = target.join(dir);
+        if !path.is_dir() {
+            continue;
+        }
+        let probe = path.join(access::PROBE_NAME);
+        match OpenOptions::new().write(true).create_new(true).open(&probe) {
+            Ok(_) => {
+                let _ = fs::remove_file(&probe);
+            }
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                denied.push(if dir.is_empty() {
+                    ".".to_string()
+                } else {
+                    format!("{dir}/")
+                });
+            }
+            Err(_) => {}
+        }
+    }
+    (locked, denied)
+}
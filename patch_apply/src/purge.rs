@@ -0,0 +1,112 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use patch_types::{Manifest, PatchKind};
+
+use crate::apply::STAGING_DIR;
+use crate::history::HISTORY_FILE;
+use crate::receipt::RECEIPT_FILE;
+use crate::segments::SEGMENTS_DIR;
+use crate::{access, hash_cache, inplace, lock, selfexe, verify};
+
+/// Files the patcher keeps in the folder it patches, which are never unknown.
+const PATCHER_FILES: [&str; 6] = [
+    RECEIPT_FILE,
+    HISTORY_FILE,
+    hash_cache::CACHE_FILE,
+    lock::LOCK_FILE,
+    inplace::JOURNAL_FILE,
+    access::PROBE_NAME,
+];
+
+/// Files in `cwd` that the manifest does not know under any path, slash-separated and sorted:
+/// what `--purge-unknown` removes. The patcher's own files are left out, as are the running
+/// patcher and the files beside it that share its name, such as its download cache.
+pub(crate) fn unknown_files(manifest: &Manifest, cwd: &Path) -> Result<Vec<String>> {
+    let fold = if verify::is_case_insensitive(cwd)? {
+        str::to_lowercase
+    } else {
+        str::to_string
+    };
+    let mut known: HashSet<String> = manifest
+        .files
+        .iter()
+        .flat_map(|file| match &file.kind {
+            PatchKind::Moved { from } | PatchKind::Copied { from } => vec![&file.path, from],
+            _ => vec![&file.path],
+        })
+        .chain(&manifest.markers.version_file)
+        .map(|path| fold(path))
+        .collect();
+    known.extend(PATCHER_FILES.iter().map(|name| fold(name)));
+    let exe = selfexe::running_exe();
+    let beside_exe = |path: &Path| {
+        exe.as_deref().is_some_and(|exe| {
+            path.parent()
+                .and_then(|dir| dir.canonicalize().ok())
+                .as_deref()
+                == exe.parent()
+                && path.file_stem() == exe.file_stem()
+        })
+    };
+
+    let mut unknown = Vec::new();
+    let mut dirs = vec![String::new()];
+    while let Some(rel_dir) = dirs.pop() {
+        let dir = cwd.join(&rel_dir);
+        for entry in fs::read_dir(&dir).with_context(|| format!("Listing {}", dir.display()))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let rel = if rel_dir.is_empty() {
+                name
+            } else {
+                format!("{rel_dir}/{name}")
+            };
+            // Symbolic links are removed as files, never followed
+            if entry.file_type()?.is_dir() {
+                if rel != STAGING_DIR && rel != SEGMENTS_DIR {
+                    dirs.push(rel);
+                }
+            } else if !known.contains(&fold(&rel)) && !beside_exe(&entry.path()) {
+                unknown.push(rel);
+            }
+        }
+    }
+    unknown.sort();
+    Ok(unknown)
+}
+
+/// Removes the files in `cwd` that the manifest does not know, and the folders that leaves
+/// empty unless the patch creates them. Runs once the patch is in place, so a file that cannot
+/// be removed is reported to `failed` instead of failing the apply. Returns the files removed.
+pub(crate) fn purge_unknown(
+    manifest: &Manifest,
+    cwd: &Path,
+    mut failed: impl FnMut(&str, &anyhow::Error),
+) -> Result<Vec<String>> {
+    let created: HashSet<&str> = manifest.created_dirs.iter().map(String::as_str).collect();
+    let mut removed = Vec::new();
+    for rel in unknown_files(manifest, cwd)? {
+        let path = cwd.join(&rel);
+        let removal = access::clear_readonly(&path).and_then(|()| {
+            fs::remove_file(&path).map_err(|e| access::explain(e, &path, "removing"))
+        });
+        match removal {
+            Ok(()) => removed.push(rel),
+            Err(e) => failed(&rel, &e),
+        }
+    }
+    for rel in &removed {
+        // Stops at the first folder that still holds something, which then stays with its parents
+        for (i, _) in rel.rmatch_indices('/') {
+            let dir = &rel[..i];
+            if created.contains(dir) || fs::remove_dir(cwd.join(dir)).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(removed)
+}
@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use ring::signature::{ED25519, UnparsedPublicKey};
+use serde::{Deserialize, Serialize};
+
+use patch_types::hex_hash;
+use patch_types::{Manifest, Release};
+
+use crate::ApplyOptions;
+use crate::select::Selection;
+use crate::snapshot::Snapshot;
+
+/// Record of the last patch applied to the folder.
+pub const RECEIPT_FILE: &str = ".patch_receipt.json";
+
+/// How the user accepted the license of a patch that has one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EulaAcceptance {
+    /// Shown the text and agreed to it
+    Interactive,
+    /// Accepted up front for an unattended install, e.g. with `--accept-eula`
+    Unattended,
+}
+
+#[derive(Serialize)]
+struct Receipt<'a> {
+    product: &'a str,
+    from_version: &'a str,
+    to_version: &'a str,
+    /// Seconds since the Unix epoch
+    applied_at: u64,
+    status: Status,
+    /// Globs that picked the files applied, for a partial apply
+    #[serde(skip_serializing_if = "Option::is_none")]
+    selection: Option<&'a Selection>,
+    eula: Option<EulaRecord>,
+    /// Ids of the components installed, for a patch with a components page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    components: Option<Vec<String>>,
+    /// Key-values the publisher attached to the patch
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: &'a BTreeMap<String, String>,
+    /// Release number and publisher signature of `to_version`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    release: Option<&'a Release>,
+    /// Snapshot taken before patching, to roll back to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snapshot: Option<&'a Snapshot>,
+}
+
+/// Whether every file of the patch was applied.
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Status {
+    Complete,
+    /// Only the files `--only` and `--skip` picked; the rest is still at the old version
+    Partial,
+}
+
+/// The parts of an existing receipt a later patch checks.
+#[derive(Deserialize)]
+struct Installed {
+    product: String,
+    to_version: String,
+    #[serde(default)]
+    release: Option<Release>,
+}
+
+#[derive(Serialize)]
+struct EulaRecord {
+    accepted: EulaAcceptance,
+    /// BLAKE3 hash of the license text, identifying the version that was accepted
+    text_hash: String,
+}
+
+/// Writes the receipt of `manifest` to `cwd`, with how its license was accepted, the
+/// components chosen, the files selected for a partial apply and the snapshot taken before
+/// patching.
+pub(crate) fn write_receipt(
+    manifest: &Manifest,
+    cwd: &Path,
+    options: &ApplyOptions,
+    status: Status,
+    snapshot: Option<&Snapshot>,
+) -> Result<()> {
+    let components = manifest
+        .wizard
+        .as_ref()
+        .map(|wizard| wizard.components())
+        .filter(|all| !all.is_empty());
+    let receipt = Receipt {
+        product: &manifest.product,
+        from_version: &manifest.from_version,
+        to_version: &manifest.to_version,
+        applied_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        status,
+        selection: match status {
+            Status::Complete => None,
+            Status::Partial => options.selection.as_ref(),
+        },
+        eula: manifest
+            .eula
+            .as_ref()
+            .zip(options.eula)
+            .map(|(text, accepted)| EulaRecord {
+                accepted,
+                text_hash: hex_hash::encode(blake3::hash(text.as_bytes()).as_bytes()),
+            }),
+        components: components.map(|all| {
+            all.iter()
+                .filter(|component| {
+                    options
+                        .components
+                        .as_ref()
+                        .is_none_or(|chosen| chosen.contains(&component.id))
+                })
+                .map(|component| component.id.clone())
+                .collect()
+        }),
+        metadata: &manifest.metadata,
+        release: manifest.release.as_ref(),
+        snapshot,
+    };
+    let path = cwd.join(RECEIPT_FILE);
+    fs::write(&path, serde_json::to_vec_pretty(&receipt)?)
+        .with_context(|| format!("Writing receipt {}", path.display()))
+}
+
+/// Product and release the receipt in `cwd` records as installed, if there is one.
+pub(crate) fn installed(cwd: &Path) -> Option<(String, String)> {
+    let installed: Installed =
+        serde_json::from_slice(&fs::read(cwd.join(RECEIPT_FILE)).ok()?).ok()?;
+    Some((installed.product, installed.to_version))
+}
+
+/// Refuses a patch to a release older than the one the receipt in `cwd` records, by their
+/// release counters. When the patch is signed, the receipt's counter only counts if the same
+/// publisher signed it; `untrusted` hears about one that was not.
+pub(crate) fn check_downgrade(
+    manifest: &Manifest,
+    cwd: &Path,
+    untrusted: impl FnOnce(&str),
+) -> Result<()> {
+    let Some(installed) = fs::read(cwd.join(RECEIPT_FILE))
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<Installed>(&bytes).ok())
+        .filter(|installed| installed.product == manifest.product)
+    else {
+        return Ok(());
+    };
+    let (Some(release), Some(new)) = (&installed.release, manifest.release.as_ref()) else {
+        return Ok(());
+    };
+    let (Some(installed_counter), Some(new_counter)) = (release.counter, new.counter) else {
+        return Ok(());
+    };
+    if let Some(key) = &new.public_key
+        && !signed_by(key, &installed.product, &installed.to_version, release)
+    {
+        untrusted(&format!(
+            "The receipt of {} {} is not signed by this patch's publisher; not checking for a downgrade",
+            installed.product, installed.to_version
+        ));
+        return Ok(());
+    }
+    if new_counter < installed_counter {
+        anyhow::bail!(
+            "{} {} (release {installed_counter}) is installed, newer than {} (release {new_counter}) that this \
+             patch installs; pass --allow-downgrade to install it anyway",
+            manifest.product,
+            installed.to_version,
+            manifest.to_version
+        );
+    }
+    Ok(())
+}
+
+/// Whether `release` of `version` carries a valid signature by the hex public key `key`.
+fn signed_by(key: &str, product: &str, version: &str, release: &Release) -> bool {
+    let (Some(key), Some(signature)) = (
+        hex_hash::decode(key),
+        release.signature.as_deref().and_then(hex_hash::decode),
+    ) else {
+        return false;
+    };
+    let text = Release::signed_text(product, version, release.counter);
+    UnparsedPublicKey::new(&ED25519, key)
+        .verify(text.as_bytes(), &signature)
+        .is_ok()
+}
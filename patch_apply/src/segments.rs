@@ -0,0 +1,287 @@
+//! Large files diffed segment by segment ([`PatchKind::Segmented`](patch_types::PatchKind)).
+//! Their segments are decoded in parallel into a file kept outside the staging folder, so an
+//! apply cut short by a crash, a dropped download or a corrupt entry loses only the segments
+//! it had not finished: the next apply keeps every segment that file already holds.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+
+use patch_types::error::PatchError;
+use patch_types::hex_hash::to_hex;
+use patch_types::{CHUNK_SIZE, ChunkedFile, FileEntry, PatchData, segment_base};
+
+use crate::chaos::Chaos;
+use crate::chunks::decode_error;
+use crate::memory::MemoryBudget;
+use crate::source::BundleSource;
+use crate::telemetry::Telemetry;
+use crate::{access, disk, throttle};
+
+/// Folder in the target where segmented files are written. Unlike the staging folder it
+/// survives a failed apply, and it is removed once the patch is committed.
+pub(crate) const SEGMENTS_DIR: &str = ".patch_segments";
+
+/// Most memory one segment takes while decoded: its base of three chunks, the delta and the
+/// decoded chunk.
+const SEGMENT_MEMORY: u64 = 5 * CHUNK_SIZE;
+
+/// Where the new version of a segmented file is written, named by its new hash so the next
+/// apply to that version finds what this one wrote.
+pub(crate) fn staged_path(cwd: &Path, file: &FileEntry) -> PathBuf {
+    cwd.join(SEGMENTS_DIR).join(to_hex(&file.new_hash))
+}
+
+/// How the segments of a file were written.
+pub(crate) struct Staged {
+    /// Decoded from their delta
+    pub decoded: usize,
+    /// Already written by an earlier apply
+    pub resumed: usize,
+}
+
+/// Writes the new version of the segmented `file` to `staged`, its segments in parallel: each
+/// is kept when `staged` already holds it, taken from the same place in `target` when that
+/// holds it, or decoded from its delta in `segments` otherwise, and checked against its hash
+/// in `chunked` before it is written. `progress` gets the bytes done so far.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn stage(
+    source: &BundleSource,
+    chunked: &ChunkedFile,
+    segments: &[Option<usize>],
+    file: &FileEntry,
+    index: usize,
+    target: &Path,
+    staged: &Path,
+    budget: Option<&MemoryBudget>,
+    chaos: Option<Chaos>,
+    telemetry: &Telemetry,
+    progress: &(dyn Fn(u64) + Sync),
+) -> Result<Staged> {
+    if segments.len() != chunked.new_chunks.len() {
+        let reason = format!(
+            "{} segment entries for {} chunks",
+            segments.len(),
+            chunked.new_chunks.len()
+        );
+        return Err(PatchError::Decode {
+            path: file.path.clone(),
+            reason,
+        }
+        .into());
+    }
+    if let Some(dir) = staged.parent() {
+        fs::create_dir_all(dir).map_err(|e| access::explain(e, dir, "creating"))?;
+    }
+    let fresh = !staged.exists();
+    let out = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(staged)
+        .map_err(|e| access::explain(e, staged, "creating"))?;
+    if fresh && file.sparse {
+        disk::mark_sparse(&out);
+    }
+    out.set_len(file.new_size).with_context(|| {
+        format!(
+            "Allocating {} bytes for {}",
+            file.new_size,
+            staged.display()
+        )
+    })?;
+    drop(out);
+
+    let done = AtomicU64::new(0);
+    let decoded = AtomicUsize::new(0);
+    let resumed = AtomicUsize::new(0);
+    let old_size = fs::metadata(target).map_or(0, |meta| meta.len());
+    (0..chunked.new_chunks.len() as u64)
+        .into_par_iter()
+        .try_for_each(|segment| -> Result<()> {
+            let _memory = budget.map(|budget| budget.reserve(SEGMENT_MEMORY));
+            let hash = &chunked.new_chunks[segment as usize];
+            let range = segment * CHUNK_SIZE..((segment + 1) * CHUNK_SIZE).min(file.new_size);
+            if !fresh
+                && read_range(staged, range.clone(), telemetry)
+                    .is_ok_and(|chunk| blake3::hash(&chunk).as_bytes() == hash)
+            {
+                resumed.fetch_add(1, Ordering::Relaxed);
+                progress(
+                    done.fetch_add(range.end - range.start, Ordering::Relaxed) + range.end
+                        - range.start,
+                );
+                return Ok(());
+            }
+            let held = read_range(target, range.clone(), telemetry).ok();
+            let chunk = match (held, segments[segment as usize]) {
+                (Some(chunk), _) if blake3::hash(&chunk).as_bytes() == hash => chunk,
+                (_, None) => {
+                    let reason = "the file on disk does not hold it and the patch stores no delta";
+                    return Err(decode_error(&file.path, segment, reason).into());
+                }
+                (_, Some(entry)) => {
+                    let chunk = decode(
+                        source, chunked, file, segment, entry, target, old_size, telemetry,
+                    )?;
+                    decoded.fetch_add(1, Ordering::Relaxed);
+                    chunk
+                }
+            };
+            if let Some(chaos) = chaos {
+                chaos
+                    .write_error(index)
+                    .with_context(|| format!("Writing {}", file.path))?;
+            }
+            // Left a hole in a new sparse file, which reads back as zeros
+            if !(fresh && file.sparse && chunk.iter().all(|&byte| byte == 0)) {
+                let write_started = Instant::now();
+                let mut out = OpenOptions::new()
+                    .write(true)
+                    .open(staged)
+                    .map_err(|e| access::explain(e, staged, "opening"))?;
+                out.seek(SeekFrom::Start(range.start))
+                    .and_then(|_| out.write_all(&chunk))
+                    .map_err(|e| access::explain(e, staged, "writing"))?;
+                throttle::io(chunk.len() as u64);
+                telemetry.add_write(write_started.elapsed());
+                telemetry.add_written(chunk.len() as u64);
+            }
+            progress(
+                done.fetch_add(range.end - range.start, Ordering::Relaxed) + range.end
+                    - range.start,
+            );
+            Ok(())
+        })?;
+    Ok(Staged {
+        decoded: decoded.into_inner(),
+        resumed: resumed.into_inner(),
+    })
+}
+
+/// Decodes segment `segment` of `file` from its delta against the base in `target`.
+#[allow(clippy::too_many_arguments)]
+fn decode(
+    source: &BundleSource,
+    chunked: &ChunkedFile,
+    file: &FileEntry,
+    segment: u64,
+    entry: usize,
+    target: &Path,
+    old_size: u64,
+    telemetry: &Telemetry,
+) -> Result<Vec<u8>> {
+    let PatchData::Xdelta(delta) = source.read_entry(entry)? else {
+        return Err(decode_error(&file.path, segment, "its entry is not a delta").into());
+    };
+    let base = read_range(target, segment_base(segment, old_size), telemetry)?;
+    let decode_started = Instant::now();
+    let chunk = xdelta3::decode(&delta, &base);
+    telemetry.add_decode(decode_started.elapsed());
+    match chunk {
+        Some(chunk)
+            if Some(blake3::hash(&chunk).as_bytes())
+                == chunked.new_chunks.get(segment as usize) =>
+        {
+            Ok(chunk)
+        }
+        Some(_) => {
+            Err(decode_error(&file.path, segment, "decoding its delta gave other content").into())
+        }
+        None => Err(decode_error(&file.path, segment, "xdelta could not apply its delta").into()),
+    }
+}
+
+fn read_range(path: &Path, range: Range<u64>, telemetry: &Telemetry) -> Result<Vec<u8>> {
+    let reading = |e| access::explain(e, path, "reading");
+    let mut file = File::open(path).map_err(reading)?;
+    file.seek(SeekFrom::Start(range.start)).map_err(reading)?;
+    let mut bytes = Vec::with_capacity((range.end - range.start) as usize);
+    (&mut file)
+        .take(range.end - range.start)
+        .read_to_end(&mut bytes)
+        .map_err(reading)?;
+    throttle::io(bytes.len() as u64);
+    telemetry.add_read(bytes.len() as u64);
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use patch_types::PatchKind;
+    use patch_types::normalize::Normalization;
+
+    use super::*;
+
+    #[test]
+    fn resumes_from_the_segments_already_staged() {
+        let dir = std::env::temp_dir().join(format!("patch_segments_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let new: Vec<u8> = (0..2 * CHUNK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let new_chunks: Vec<[u8; 32]> = new
+            .chunks(CHUNK_SIZE as usize)
+            .map(|chunk| *blake3::hash(chunk).as_bytes())
+            .collect();
+        // The old file differs in the first and last segments, and holds the middle one
+        let mut old = new.clone();
+        old[0] ^= 1;
+        *old.last_mut().unwrap() ^= 1;
+        let target = dir.join("huge.pak");
+        fs::write(&target, &old).unwrap();
+
+        let segments = [Some(0), None, Some(1)];
+        let file = FileEntry {
+            path: "huge.pak".to_string(),
+            kind: PatchKind::Segmented {
+                segments: segments.to_vec(),
+            },
+            original_hash: *blake3::hash(&old).as_bytes(),
+            new_hash: *blake3::hash(&new).as_bytes(),
+            old_size: old.len() as u64,
+            new_size: new.len() as u64,
+            mtime: None,
+            normalization: Normalization::None,
+            mode: None,
+            sparse: false,
+            full_copy: None,
+        };
+        let chunked = ChunkedFile {
+            path: file.path.clone(),
+            old_chunks: Vec::new(),
+            new_chunks,
+            chunk_entries: Vec::new(),
+        };
+        // An earlier apply wrote the changed segments, then stopped before the middle one
+        let staged = staged_path(&dir, &file);
+        fs::create_dir_all(staged.parent().unwrap()).unwrap();
+        let mut partial = new.clone();
+        partial[CHUNK_SIZE as usize..2 * CHUNK_SIZE as usize].fill(0);
+        fs::write(&staged, &partial).unwrap();
+
+        // The source holds no entries, so decoding either delta would fail
+        let result = stage(
+            &BundleSource::empty(),
+            &chunked,
+            &segments,
+            &file,
+            0,
+            &target,
+            &staged,
+            None,
+            None,
+            &Telemetry::default(),
+            &|_| {},
+        );
+        let written = fs::read(&staged).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let result = result.unwrap();
+        assert_eq!((result.resumed, result.decoded), (2, 0));
+        assert!(written == new);
+    }
+}
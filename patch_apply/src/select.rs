@@ -0,0 +1,96 @@
+use std::collections::HashSet;
+
+use anyhow::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Serialize;
+
+use patch_types::{FileEntry, Manifest, PatchKind};
+
+/// Part of a patch to apply, picked with `--only` and `--skip` globs over the paths of its
+/// files. The rest of the installation is left as it is, and the receipt records the apply as
+/// partial.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Selection {
+    /// Files are applied only if they match one of these, or any file when there are none
+    only: Vec<String>,
+    /// Files matching one of these are left as they are
+    skip: Vec<String>,
+    #[serde(skip)]
+    only_set: GlobSet,
+    #[serde(skip)]
+    skip_set: GlobSet,
+}
+
+impl Selection {
+    pub fn new(only: Vec<String>, skip: Vec<String>) -> Result<Self> {
+        let build = |patterns: &[String]| -> Result<GlobSet> {
+            let mut set = GlobSetBuilder::new();
+            for pattern in patterns {
+                set.add(Glob::new(pattern).with_context(|| format!("Invalid glob {pattern}"))?);
+            }
+            Ok(set.build()?)
+        };
+        Ok(Selection {
+            only_set: build(&only)?,
+            skip_set: build(&skip)?,
+            only,
+            skip,
+        })
+    }
+
+    /// Whether the globs pick `file`. A move is picked by either of its paths.
+    fn picks(&self, file: &FileEntry) -> bool {
+        let matches = |set: &GlobSet| match &file.kind {
+            PatchKind::Moved { from } => set.is_match(&file.path) || set.is_match(from),
+            _ => set.is_match(&file.path),
+        };
+        (self.only.is_empty() || matches(&self.only_set)) && !matches(&self.skip_set)
+    }
+
+    /// Indices of the files of `manifest` left out of the apply. A move or copy whose source
+    /// a picked file overwrites or removes is applied with it, since it could not be applied
+    /// later; `pulled_in` hears about each such file and the path it depends on. Fails when an
+    /// `--only` glob matches no file of the patch, which is most likely a typo.
+    pub(crate) fn left_out(
+        &self,
+        manifest: &Manifest,
+        mut pulled_in: impl FnMut(&str, &str),
+    ) -> Result<HashSet<usize>> {
+        for pattern in &self.only {
+            let glob = Glob::new(pattern)?.compile_matcher();
+            if !manifest.files.iter().any(|file| glob.is_match(&file.path)) {
+                anyhow::bail!("--only {pattern} matches no file of the patch");
+            }
+        }
+
+        let files = &manifest.files;
+        let mut picked: Vec<bool> = files.iter().map(|file| self.picks(file)).collect();
+        loop {
+            // Paths that picked files write or remove
+            let mut changed: HashSet<&str> = HashSet::new();
+            for (file, _) in files.iter().zip(&picked).filter(|&(_, &picked)| picked) {
+                if let PatchKind::Moved { from } = &file.kind {
+                    changed.insert(from);
+                }
+                if !matches!(file.kind, PatchKind::Unchanged) {
+                    changed.insert(&file.path);
+                }
+            }
+            let mut grew = false;
+            for (file, picked) in files.iter().zip(picked.iter_mut()) {
+                if let PatchKind::Moved { from } | PatchKind::Copied { from } = &file.kind
+                    && !*picked
+                    && changed.contains(from.as_str())
+                {
+                    pulled_in(&file.path, from);
+                    *picked = true;
+                    grew = true;
+                }
+            }
+            if !grew {
+                break;
+            }
+        }
+        Ok((0..files.len()).filter(|&i| !picked[i]).collect())
+    }
+}
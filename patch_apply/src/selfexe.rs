@@ -0,0 +1,43 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Extension the running patcher is renamed to when it has to replace itself.
+const ASIDE_EXTENSION: &str = "old";
+
+/// Canonical path of the running patcher, when it can be determined.
+pub fn running_exe() -> Option<PathBuf> {
+    std::env::current_exe().ok()?.canonicalize().ok()
+}
+
+/// Whether `path` is the running patcher. Only paths with the same file name are resolved.
+pub fn is_running_exe(path: &Path, exe: Option<&Path>) -> bool {
+    let Some(exe) = exe else {
+        return false;
+    };
+    let same_name = match (path.file_name(), exe.file_name()) {
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+        _ => false,
+    };
+    same_name && path.canonicalize().is_ok_and(|p| p == exe)
+}
+
+/// Puts `tmp` in place of the running patcher. Windows refuses to overwrite a running image
+/// but allows renaming it, so the old binary is moved aside first and removed on the next run.
+pub fn replace_running(tmp: &Path, exe: &Path) -> io::Result<()> {
+    if fs::rename(tmp, exe).is_ok() {
+        return Ok(());
+    }
+    let aside = exe.with_extension(ASIDE_EXTENSION);
+    let _ = fs::remove_file(&aside);
+    fs::rename(exe, &aside)?;
+    fs::rename(tmp, exe)
+}
+
+/// Deletes the binary a previous self-replacement moved aside. Best effort: it may still be
+/// running if several patchers were started at once.
+pub fn remove_leftover() {
+    if let Some(exe) = running_exe() {
+        let _ = fs::remove_file(exe.with_extension(ASIDE_EXTENSION));
+    }
+}
@@ -0,0 +1,169 @@
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+/// A read-only copy of the installation's volume (or Btrfs subvolume, or ZFS dataset) taken
+/// before anything in it changes, so a bad patch can be rolled back at once.
+#[derive(Clone, Debug, Serialize)]
+pub struct Snapshot {
+    /// `btrfs`, `zfs` or `vss`
+    pub kind: &'static str,
+    /// Path of the Btrfs snapshot, name of the ZFS snapshot or id of the VSS shadow copy
+    pub id: String,
+    /// How to bring the installation back from it
+    pub restore: String,
+}
+
+/// Snapshots the filesystem holding `target`: Btrfs and ZFS on Linux, a VSS shadow copy of
+/// the volume on Windows. Fails on other filesystems, and without the rights to snapshot,
+/// which usually means running as root or an administrator.
+pub fn create(target: &Path) -> Result<Snapshot> {
+    let target = target
+        .canonicalize()
+        .with_context(|| format!("Resolving {}", target.display()))?;
+    let name = format!(
+        "pre-patch-{}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    );
+    platform::create(&target, &name)
+        .with_context(|| format!("Snapshotting {} before patching", target.display()))
+}
+
+/// Runs `program` and returns what it printed, or fails with what it printed on error.
+#[cfg(any(target_os = "linux", target_os = "android", windows))]
+fn run(program: &str, args: &[&str]) -> Result<String> {
+    let output = std::process::Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("Running {program}"))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{program} {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod platform {
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    use anyhow::Result;
+
+    use super::{Snapshot, run};
+    use crate::disk::fs_magic;
+
+    /// Filesystem magic numbers, from linux/magic.h and the ZFS sources.
+    const BTRFS_MAGIC: i64 = 0x9123_683E;
+    const ZFS_MAGIC: i64 = 0x2FC1_2FC1;
+    /// Inode number of the root of every Btrfs subvolume.
+    const SUBVOLUME_ROOT_INODE: u64 = 256;
+
+    pub fn create(target: &Path, name: &str) -> Result<Snapshot> {
+        match fs_magic(target) {
+            Some(BTRFS_MAGIC) => btrfs(target, name),
+            Some(ZFS_MAGIC) => zfs(target, name),
+            _ => anyhow::bail!("snapshots need Btrfs or ZFS, and this folder is on neither"),
+        }
+    }
+
+    /// Snapshots the subvolume holding `target` next to it, read-only.
+    fn btrfs(target: &Path, name: &str) -> Result<Snapshot> {
+        let subvolume = target
+            .ancestors()
+            .find(|dir| {
+                dir.metadata()
+                    .is_ok_and(|meta| meta.ino() == SUBVOLUME_ROOT_INODE)
+            })
+            .ok_or_else(|| anyhow::anyhow!("no Btrfs subvolume holds {}", target.display()))?;
+        let mut dest = subvolume.as_os_str().to_owned();
+        dest.push(format!(".{name}"));
+        let (subvolume, dest) = (subvolume.to_string_lossy(), dest.to_string_lossy());
+        run("btrfs", &["subvolume", "snapshot", "-r", &subvolume, &dest])?;
+        Ok(Snapshot {
+            kind: "btrfs",
+            restore: format!(
+                "mv {subvolume} {subvolume}.broken && btrfs subvolume snapshot {dest} {subvolume}"
+            ),
+            id: dest.into_owned(),
+        })
+    }
+
+    /// Snapshots the dataset holding `target`.
+    fn zfs(target: &Path, name: &str) -> Result<Snapshot> {
+        let dataset = run(
+            "zfs",
+            &["list", "-H", "-o", "name", &target.to_string_lossy()],
+        )?;
+        let id = format!("{dataset}@{name}");
+        run("zfs", &["snapshot", &id])?;
+        Ok(Snapshot {
+            kind: "zfs",
+            restore: format!("zfs rollback {id}"),
+            id,
+        })
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::path::{Component, Path, Prefix};
+
+    use anyhow::Result;
+
+    use super::{Snapshot, run};
+
+    /// Creates a VSS shadow copy of the volume holding `target`. The name is not used: shadow
+    /// copies are known by the id Windows gives them.
+    pub fn create(target: &Path, _name: &str) -> Result<Snapshot> {
+        let volume = match target.components().next() {
+            Some(Component::Prefix(prefix)) => match prefix.kind() {
+                Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => {
+                    format!("{}:\\", letter as char)
+                }
+                _ => anyhow::bail!("shadow copies need a folder on a local drive"),
+            },
+            _ => anyhow::bail!("shadow copies need a folder on a local drive"),
+        };
+        let script = format!(
+            "$r = Invoke-CimMethod -ClassName Win32_ShadowCopy -MethodName Create \
+             -Arguments @{{Volume='{volume}'; Context='ClientAccessible'}}; \
+             if ($r.ReturnValue -ne 0) {{ [Console]::Error.WriteLine(\"error $($r.ReturnValue)\"); exit 1 }}; \
+             $r.ShadowID"
+        );
+        let id = run(
+            "powershell",
+            &["-NoProfile", "-NonInteractive", "-Command", &script],
+        )?;
+        Ok(Snapshot {
+            kind: "vss",
+            restore: format!(
+                "restore {} from the Previous Versions tab of its properties, or copy it out of shadow copy {id} \
+                 (vssadmin list shadows)",
+                target.display()
+            ),
+            id,
+        })
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+mod platform {
+    use std::path::Path;
+
+    use anyhow::Result;
+
+    use super::Snapshot;
+
+    pub fn create(_target: &Path, _name: &str) -> Result<Snapshot> {
+        anyhow::bail!("snapshots are only supported on Btrfs and ZFS on Linux, and NTFS on Windows")
+    }
+}
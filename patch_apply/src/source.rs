@@ -0,0 +1,491 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use patch_types::error::PatchError;
+use patch_types::{
+    Compression, EntryRange, FOOTER_LEN, FORMAT_VERSION, Footer, Manifest, PatchData,
+    ZIP_MANIFEST_NAME, ZipManifest, authenticode, buffers, versions,
+};
+use ureq::Agent;
+use zip::ZipArchive;
+use zstd::dict::DecoderDictionary;
+
+use crate::frames::FrameCache;
+use crate::mirrors::{MirrorOrder, Mirrors};
+use crate::throttle;
+
+/// Where the payload of a patch lives: appended to an executable on disk, hosted over HTTP,
+/// or stored as members of a zip archive.
+pub struct BundleSource {
+    location: Location,
+    payload_start: u64,
+    compression: Compression,
+    entries: Vec<EntryRange>,
+    /// The dictionary small entries are compressed against, when the patch has one
+    dictionary: Option<DecoderDictionary<'static>>,
+    /// Ranges of the frames small entries are compressed together in
+    frames: Vec<EntryRange>,
+    /// Frame holding each entry stored in one
+    framed: HashMap<usize, usize>,
+    frame_cache: FrameCache,
+}
+
+enum Location {
+    Local(PathBuf),
+    Remote(Mirrors),
+    Zip {
+        path: PathBuf,
+        entry_files: Vec<String>,
+    },
+}
+
+impl BundleSource {
+    /// Open a payload appended to a local file and decode its manifest.
+    pub fn open_local(path: &Path) -> Result<(Self, Manifest)> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        // A signed patcher ends in its certificate table, after the footer
+        let len = authenticode::unsigned_len(&mut file, len)?;
+        if len < FOOTER_LEN {
+            anyhow::bail!("Invalid patch exe (too small)");
+        }
+
+        // Read footer
+        file.seek(SeekFrom::Start(len - FOOTER_LEN))?;
+        let mut footer = [0u8; FOOTER_LEN as usize];
+        file.read_exact(&mut footer)?;
+        let footer = Footer::from_bytes(&footer);
+        let payload_start = payload_start(&footer, len)?;
+
+        // Read manifest
+        let source = BundleSource {
+            location: Location::Local(path.to_path_buf()),
+            payload_start,
+            compression: Compression::None,
+            entries: Vec::new(),
+            dictionary: None,
+            frames: Vec::new(),
+            framed: HashMap::new(),
+            frame_cache: FrameCache::default(),
+        };
+        let (source, manifest) = source.with_manifest(&footer)?;
+        // A bare payload has no executable in front of it to check
+        if let Some(expected) = &manifest.stub_hash
+            && payload_start > 0
+        {
+            check_stub(&mut file, payload_start, expected, path)?;
+        }
+        Ok((source, manifest))
+    }
+
+    /// Open a payload hosted at `urls`, fetching only the footer and manifest. The mirrors the
+    /// manifest names are tried after `urls`.
+    pub fn open_remote(
+        agent: Agent,
+        urls: &[String],
+        order: MirrorOrder,
+    ) -> Result<(Self, Manifest)> {
+        let (mirrors, footer) = Mirrors::open(agent, urls)?;
+        let payload_start = payload_start(&footer, mirrors.len())?;
+
+        let source = BundleSource {
+            location: Location::Remote(mirrors),
+            payload_start,
+            compression: Compression::None,
+            entries: Vec::new(),
+            dictionary: None,
+            frames: Vec::new(),
+            framed: HashMap::new(),
+            frame_cache: FrameCache::default(),
+        };
+        let (mut source, manifest) = source.with_manifest(&footer)?;
+        if let Location::Remote(mirrors) = &mut source.location {
+            mirrors.extend(&manifest.mirrors);
+            if order == MirrorOrder::Latency {
+                mirrors.rank_by_latency();
+            }
+        }
+        Ok((source, manifest))
+    }
+
+    /// Open a zip-format patch and decode its `manifest.json`.
+    pub fn open_zip(path: &Path) -> Result<(Self, Manifest)> {
+        let mut archive = ZipArchive::new(File::open(path)?)
+            .with_context(|| format!("Opening {}", path.display()))?;
+        let json: serde_json::Value = serde_json::from_reader(
+            archive
+                .by_name(ZIP_MANIFEST_NAME)
+                .with_context(|| format!("{} has no {ZIP_MANIFEST_NAME}", path.display()))?,
+        )?;
+        let min_stub_version = json["manifest"]["min_stub_version"]
+            .as_u64()
+            .ok_or_else(|| anyhow::anyhow!("{ZIP_MANIFEST_NAME} has no min_stub_version"))?;
+        check_stub_version(u32::try_from(min_stub_version).unwrap_or(u32::MAX))?;
+        let index: ZipManifest = serde_json::from_value(json)?;
+        check_required_metadata(&index.manifest)?;
+
+        let source = BundleSource {
+            location: Location::Zip {
+                path: path.to_path_buf(),
+                entry_files: index.entry_files,
+            },
+            payload_start: 0,
+            compression: Compression::None,
+            entries: Vec::new(),
+            dictionary: None,
+            frames: Vec::new(),
+            framed: HashMap::new(),
+            frame_cache: FrameCache::default(),
+        };
+        Ok((source, index.manifest))
+    }
+
+    /// A local payload with no entries, for tests that must not read one.
+    #[cfg(test)]
+    pub(crate) fn empty() -> Self {
+        BundleSource {
+            location: Location::Local(PathBuf::new()),
+            payload_start: 0,
+            compression: Compression::None,
+            entries: Vec::new(),
+            dictionary: None,
+            frames: Vec::new(),
+            framed: HashMap::new(),
+            frame_cache: FrameCache::default(),
+        }
+    }
+
+    /// Reads the manifest and adopts its entry compression.
+    fn with_manifest(mut self, footer: &Footer) -> Result<(Self, Manifest)> {
+        let manifest_bytes = self.read_range(
+            footer.payload_len - footer.manifest_len,
+            footer.manifest_len,
+            None,
+        )?;
+        let manifest = decode_manifest(&manifest_bytes)?;
+        self.compression = manifest.compression;
+        self.entries = manifest.entries.clone();
+        if let Some(range) = &manifest.dictionary {
+            let bytes = self
+                .read_range(range.offset, range.len, Some(&range.hash))
+                .context("Reading the compression dictionary")?;
+            self.dictionary = Some(DecoderDictionary::copy(&bytes));
+        }
+        self.frames = manifest.frames.iter().map(|frame| frame.range).collect();
+        self.framed = manifest
+            .frames
+            .iter()
+            .enumerate()
+            .flat_map(|(frame, solid)| solid.entries.iter().map(move |&idx| (idx, frame)))
+            .collect();
+        Ok((self, manifest))
+    }
+
+    /// Stored size of the entry at `idx`, used to weight progress.
+    pub fn entry_len(&self, idx: usize) -> u64 {
+        match &self.location {
+            Location::Zip { path, entry_files } => entry_files
+                .get(idx)
+                .and_then(|name| {
+                    let mut archive = ZipArchive::new(File::open(path).ok()?).ok()?;
+                    let size = archive.by_name(name).ok()?.size();
+                    Some(size)
+                })
+                .unwrap_or(0),
+            _ => self.entries.get(idx).map_or(0, |range| range.len),
+        }
+    }
+
+    /// Whether entries are fetched over HTTP.
+    pub(crate) fn is_remote(&self) -> bool {
+        matches!(self.location, Location::Remote(_))
+    }
+
+    /// Fetch and decode the entry at `idx`.
+    pub fn read_entry(&self, idx: usize) -> Result<PatchData> {
+        if let Location::Zip { path, entry_files } = &self.location {
+            return read_zip_entry(path, entry_files, idx);
+        }
+        self.decode_entry(idx, self.fetch_entry(idx)?)
+    }
+
+    /// The stored bytes of the entry at `idx` in an executable or hosted payload, checked
+    /// against their hash. An entry in a frame is cut from the decompressed frame, fetched
+    /// once for all of its entries.
+    pub(crate) fn fetch_entry(&self, idx: usize) -> Result<Vec<u8>> {
+        let range = self.entries.get(idx).ok_or(PatchError::InvalidIndex {
+            what: "entry",
+            index: idx,
+        })?;
+        let Some(&frame_idx) = self.framed.get(&idx) else {
+            return self
+                .read_range(range.offset, range.len, Some(&range.hash))
+                .with_context(|| format!("Reading entry {idx}"));
+        };
+        let frame = self.frame_cache.get(frame_idx, || {
+            let frame = self.frames.get(frame_idx).ok_or(PatchError::InvalidIndex {
+                what: "frame",
+                index: frame_idx,
+            })?;
+            let compressed = self
+                .read_range(frame.offset, frame.len, Some(&frame.hash))
+                .with_context(|| format!("Reading frame {frame_idx}"))?;
+            Ok(zstd::decode_all(compressed.as_slice())?)
+        })?;
+        let bytes = usize::try_from(range.offset)
+            .ok()
+            .zip(usize::try_from(range.len).ok())
+            .and_then(|(offset, len)| frame.get(offset..offset.checked_add(len)?))
+            .ok_or_else(|| anyhow::anyhow!("Entry {idx} lies outside frame {frame_idx}"))?;
+        if blake3::hash(bytes).as_bytes() != &range.hash {
+            anyhow::bail!("Entry {idx} in frame {frame_idx} does not match its hash");
+        }
+        Ok(bytes.to_vec())
+    }
+
+    /// Decodes the stored bytes of entry `idx` from [`BundleSource::fetch_entry`].
+    pub(crate) fn decode_entry(&self, idx: usize, mut bytes: Vec<u8>) -> Result<PatchData> {
+        let dictionary = self.entries.get(idx).is_some_and(|range| range.dictionary);
+        match (&self.compression, &self.dictionary) {
+            // Decompressed with its frame
+            (Compression::Zstd, _) if self.framed.contains_key(&idx) => {}
+            (Compression::Zstd, Some(prepared)) if dictionary => {
+                let mut decoded = Vec::new();
+                zstd::Decoder::with_prepared_dictionary(bytes.as_slice(), prepared)?
+                    .read_to_end(&mut decoded)?;
+                bytes = decoded;
+            }
+            (Compression::Zstd, _) if dictionary => {
+                anyhow::bail!(
+                    "Entry {idx} needs the patch's compression dictionary, which it lacks"
+                )
+            }
+            (Compression::Zstd, _) => bytes = zstd::decode_all(bytes.as_slice())?,
+            (Compression::None, _) => {}
+        }
+        let data = bincode::decode_from_slice(&bytes, bincode::config::standard())?.0;
+        Ok(data)
+    }
+
+    /// Read `len` bytes starting at `offset` within the payload, checked against `hash` when
+    /// given. A hosted payload is read from another mirror when one sends corrupt bytes.
+    fn read_range(&self, offset: u64, len: u64, hash: Option<&[u8; 32]>) -> Result<Vec<u8>> {
+        let payload_start = self.payload_start;
+        let Some(start) = payload_start
+            .checked_add(offset)
+            .filter(|start| start.checked_add(len).is_some())
+        else {
+            anyhow::bail!("Invalid entry range: {len} bytes at {offset}");
+        };
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let intact = |bytes: &[u8]| hash.is_none_or(|hash| blake3::hash(bytes).as_bytes() == hash);
+        match &self.location {
+            Location::Local(path) => {
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(start))?;
+                let mut buffer = vec![
+                    0u8;
+                    buffers::memory_len(len, || format!(
+                        "The data at {offset} in {}",
+                        path.display()
+                    ))?
+                ];
+                file.read_exact(&mut buffer)?;
+                throttle::io(len);
+                if !intact(&buffer) {
+                    anyhow::bail!(
+                        "{} is corrupt: bytes {offset}-{} do not match their hash",
+                        path.display(),
+                        offset + len - 1
+                    );
+                }
+                Ok(buffer)
+            }
+            Location::Zip { .. } => unreachable!("zip members are read by name"),
+            Location::Remote(mirrors) => mirrors.fetch(start, len, intact),
+        }
+    }
+}
+
+/// Downloads the payload hosted at `urls` (or the mirrors its manifest names), with its
+/// manifest and footer, to `path`. The data goes to `<path>.part` first, and a download
+/// interrupted earlier is resumed from there, from whichever mirror serves it.
+/// `progress` receives the bytes downloaded so far and the total.
+pub fn download_payload(
+    agent: Agent,
+    urls: &[String],
+    order: MirrorOrder,
+    path: &Path,
+    mut progress: impl FnMut(u64, u64),
+) -> Result<()> {
+    let (source, manifest) = BundleSource::open_remote(agent, urls, order)?;
+    let Location::Remote(mirrors) = &source.location else {
+        unreachable!("opened from URLs");
+    };
+    let start = source.payload_start;
+    let total = mirrors.len() - start;
+
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    let part = PathBuf::from(part);
+    let mut done = fs::metadata(&part).map_or(0, |meta| meta.len());
+    if done > total {
+        // Left over from a different patch
+        done = 0;
+    }
+    let mut out = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(done == 0)
+        .append(done > 0)
+        .open(&part)
+        .with_context(|| format!("Creating {}", part.display()))?;
+
+    progress(done, total);
+    mirrors
+        .download(&mut out, start, done, total, |done| progress(done, total))
+        .with_context(|| format!("Downloading to {}", part.display()))?;
+    drop(out);
+    // Entries in frames are checked through the frame that holds them
+    let ranges: Vec<EntryRange> = source
+        .entries
+        .iter()
+        .enumerate()
+        .filter(|(idx, _)| !source.framed.contains_key(idx))
+        .map(|(_, range)| range)
+        .chain(&source.frames)
+        .chain(&manifest.dictionary)
+        .copied()
+        .collect();
+    repair_entries(&ranges, mirrors, start, &part)?;
+    fs::rename(&part, path).with_context(|| format!("Moving download to {}", path.display()))?;
+    Ok(())
+}
+
+/// Checks each entry (and frame and dictionary) of the payload downloaded to `part` against its hash, fetching those a
+/// mirror sent corrupt again from the mirrors that serve them intact.
+fn repair_entries(
+    entries: &[EntryRange],
+    mirrors: &Mirrors,
+    start: u64,
+    part: &Path,
+) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(part)
+        .with_context(|| format!("Opening {}", part.display()))?;
+    for range in entries {
+        let mut hasher = blake3::Hasher::new();
+        file.seek(SeekFrom::Start(range.offset))?;
+        io::copy(&mut (&file).take(range.len), &mut hasher)
+            .with_context(|| format!("Reading {}", part.display()))?;
+        if hasher.finalize().as_bytes() == &range.hash {
+            continue;
+        }
+        let intact = mirrors.fetch(start + range.offset, range.len, |bytes| {
+            blake3::hash(bytes).as_bytes() == &range.hash
+        })?;
+        file.seek(SeekFrom::Start(range.offset))?;
+        file.write_all(&intact)
+            .with_context(|| format!("Writing {}", part.display()))?;
+    }
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Reads a zip member; its folder tells which kind of data it holds.
+fn read_zip_entry(path: &Path, entry_files: &[String], idx: usize) -> Result<PatchData> {
+    let name = entry_files.get(idx).ok_or(PatchError::InvalidIndex {
+        what: "entry",
+        index: idx,
+    })?;
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let mut member = archive
+        .by_name(name)
+        .with_context(|| format!("Missing archive member {name}"))?;
+    let mut bytes = Vec::with_capacity(buffers::memory_len(member.size(), || {
+        format!("Archive member {name}")
+    })?);
+    member.read_to_end(&mut bytes)?;
+
+    if name.starts_with("patched/") {
+        Ok(PatchData::Xdelta(bytes))
+    } else if name.starts_with("fallback/") || name.starts_with("chunks/") {
+        Ok(PatchData::CompressedFull(bytes))
+    } else {
+        Ok(PatchData::Full(bytes))
+    }
+}
+
+/// Refuses a patcher whose executable, the first `len` bytes of `file`, no longer hashes to
+/// what the builder recorded: it was modified after the build, by accident or on purpose.
+fn check_stub(file: &mut File, len: u64, expected: &[u8; 32], path: &Path) -> Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    let hash = authenticode::stub_hash(file, len)
+        .with_context(|| format!("Reading {}", path.display()))?;
+    if hash != *expected {
+        return Err(PatchError::Decode {
+            path: path.display().to_string(),
+            reason: "the patcher's code was modified after it was built; download it again".into(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+fn payload_start(footer: &Footer, len: u64) -> Result<u64> {
+    if footer.manifest_len > footer.payload_len
+        || footer
+            .payload_len
+            .checked_add(FOOTER_LEN)
+            .is_none_or(|end| end > len)
+    {
+        anyhow::bail!("Invalid bundle length");
+    }
+    Ok(len - FOOTER_LEN - footer.payload_len)
+}
+
+fn decode_manifest(bytes: &[u8]) -> Result<Manifest> {
+    let min_stub_version: u32 = bincode::decode_from_slice(bytes, bincode::config::standard())?.0;
+    check_stub_version(min_stub_version)?;
+    let manifest = versions::decode_manifest(bytes)?;
+    check_required_metadata(&manifest)?;
+    Ok(manifest)
+}
+
+/// Refuses bundles whose metadata asks for behavior this stub does not know.
+fn check_required_metadata(manifest: &Manifest) -> Result<()> {
+    let unknown = manifest.unknown_required_metadata();
+    if !unknown.is_empty() {
+        anyhow::bail!(
+            "This patch requires a newer patcher (it needs {})",
+            unknown.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Refuses bundles written for a newer stub, or too old a one, rather than misinterpreting
+/// their data.
+fn check_stub_version(min_stub_version: u32) -> Result<()> {
+    if min_stub_version > FORMAT_VERSION {
+        anyhow::bail!(
+            "This patch requires a newer patcher (format {min_stub_version}, this patcher supports up to {FORMAT_VERSION})"
+        );
+    }
+    if min_stub_version < versions::OLDEST_FORMAT {
+        anyhow::bail!(
+            "This patch is too old for this patcher (format {min_stub_version}, this patcher reads formats {} to \
+             {FORMAT_VERSION})",
+            versions::OLDEST_FORMAT
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use indicatif::HumanBytes;
+use serde::Serialize;
+
+const SLOWEST_FILES: usize = 5;
+
+/// Counters shared by all workers while a patch is applied.
+#[derive(Default)]
+pub struct Telemetry {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    decode_nanos: AtomicU64,
+    write_nanos: AtomicU64,
+    file_times: Mutex<Vec<(String, Duration)>>,
+}
+
+impl Telemetry {
+    pub fn add_read(&self, bytes: u64) {
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_written(&self, bytes: u64) {
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_decode(&self, elapsed: Duration) {
+        self.decode_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_write(&self, elapsed: Duration) {
+        self.write_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_file(&self, path: &str, elapsed: Duration) {
+        self.file_times
+            .lock()
+            .unwrap()
+            .push((path.to_string(), elapsed));
+    }
+
+    pub fn summary(&self, wall: Duration, verify: Duration) -> Summary {
+        let mut files = self.file_times.lock().unwrap().clone();
+        files.sort_by_key(|(_, elapsed)| std::cmp::Reverse(*elapsed));
+        files.truncate(SLOWEST_FILES);
+
+        Summary {
+            wall_secs: wall.as_secs_f64(),
+            verify_secs: verify.as_secs_f64(),
+            decode_secs: Duration::from_nanos(self.decode_nanos.load(Ordering::Relaxed))
+                .as_secs_f64(),
+            write_secs: Duration::from_nanos(self.write_nanos.load(Ordering::Relaxed))
+                .as_secs_f64(),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            slowest_files: files
+                .into_iter()
+                .map(|(path, elapsed)| FileTiming {
+                    path,
+                    secs: elapsed.as_secs_f64(),
+                })
+                .collect(),
+            kept: Vec::new(),
+            purged: Vec::new(),
+        }
+    }
+}
+
+/// Performance summary printed after apply and included in the result JSON.
+///
+/// Decode and write timings are summed across workers, so they can exceed the wall time.
+#[derive(Serialize, Clone)]
+pub struct Summary {
+    pub wall_secs: f64,
+    pub verify_secs: f64,
+    pub decode_secs: f64,
+    pub write_secs: f64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub slowest_files: Vec<FileTiming>,
+    /// Files the patch deletes that `--no-delete` left in place
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub kept: Vec<String>,
+    /// Files the patch does not know that `--purge-unknown` removed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub purged: Vec<String>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct FileTiming {
+    pub path: String,
+    pub secs: f64,
+}
+
+impl Summary {
+    pub fn print(&self) {
+        println!("Finished in {:.2}s", self.wall_secs);
+        println!(
+            "  Read {}, wrote {}",
+            HumanBytes(self.bytes_read),
+            HumanBytes(self.bytes_written)
+        );
+        println!(
+            "  Verify {:.2}s, decode {:.2}s, write {:.2}s (decode/write summed across workers)",
+            self.verify_secs, self.decode_secs, self.write_secs
+        );
+        if !self.slowest_files.is_empty() {
+            println!("  Slowest files:");
+            for file in &self.slowest_files {
+                println!("    {:>8.2}s  {}", file.secs, file.path);
+            }
+        }
+        for (files, what) in [
+            (&self.kept, "Left in place instead of deleted"),
+            (&self.purged, "Removed as unknown"),
+        ] {
+            if !files.is_empty() {
+                println!("  {what}:");
+                for path in files {
+                    println!("    {path}");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,36 @@
+//! Helpers shared by the unit tests.
+
+use std::collections::BTreeMap;
+
+use patch_types::{ApplyCost, Compression, FileEntry, Manifest, VersionMarkers};
+
+/// A manifest of the current format updating `App` from 1.0 to 1.1 with `files`, and nothing
+/// else.
+pub(crate) fn manifest(files: Vec<FileEntry>) -> Manifest {
+    Manifest {
+        min_stub_version: patch_types::FORMAT_VERSION,
+        product: "App".to_string(),
+        from_version: "1.0".to_string(),
+        to_version: "1.1".to_string(),
+        files,
+        entries: Vec::new(),
+        markers: VersionMarkers::default(),
+        compression: Compression::default(),
+        transforms: Vec::new(),
+        created_dirs: Vec::new(),
+        deleted_dirs: Vec::new(),
+        fingerprints: Vec::new(),
+        eula: None,
+        cost: ApplyCost::default(),
+        main_exe: None,
+        chunked: Vec::new(),
+        wizard: None,
+        mirrors: Vec::new(),
+        metadata: BTreeMap::new(),
+        release: None,
+        dictionary: None,
+        stub_hash: None,
+        frames: Vec::new(),
+        diffs: Vec::new(),
+    }
+}
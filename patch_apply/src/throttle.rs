@@ -0,0 +1,126 @@
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+/// Default disk limit in `--background` mode, in bytes per second.
+pub const BACKGROUND_IO_LIMIT: u64 = 32 * 1024 * 1024;
+/// Default download limit in `--background` mode, in bytes per second.
+pub const BACKGROUND_DOWNLOAD_LIMIT: u64 = 2 * 1024 * 1024;
+
+static IO: OnceLock<Throttle> = OnceLock::new();
+static DOWNLOAD: OnceLock<Throttle> = OnceLock::new();
+static MOVED: AtomicU64 = AtomicU64::new(0);
+
+/// Token bucket shared by every worker. Up to one second of unused allowance is kept, so
+/// short bursts pass unthrottled but the sustained rate stays at the limit.
+struct Throttle {
+    bytes_per_sec: f64,
+    state: Mutex<Bucket>,
+}
+
+struct Bucket {
+    allowance: f64,
+    last: Instant,
+}
+
+impl Throttle {
+    fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        Throttle {
+            bytes_per_sec,
+            state: Mutex::new(Bucket {
+                allowance: bytes_per_sec,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.state.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.last).as_secs_f64() * self.bytes_per_sec;
+            bucket.allowance = (bucket.allowance + refill).min(self.bytes_per_sec) - bytes as f64;
+            bucket.last = now;
+            (-bucket.allowance).max(0.0) / self.bytes_per_sec
+        };
+        if wait > 0.0 {
+            std::thread::sleep(Duration::from_secs_f64(wait));
+        }
+    }
+}
+
+/// Caps disk reads and writes for the rest of the run.
+pub fn limit_io(bytes_per_sec: u64) {
+    let _ = IO.set(Throttle::new(bytes_per_sec));
+}
+
+/// Caps downloads in remote mode for the rest of the run.
+pub fn limit_download(bytes_per_sec: u64) {
+    let _ = DOWNLOAD.set(Throttle::new(bytes_per_sec));
+}
+
+/// Accounts for `bytes` of disk I/O, sleeping while over the limit.
+pub fn io(bytes: u64) {
+    MOVED.fetch_add(bytes, Ordering::Relaxed);
+    if let Some(throttle) = IO.get() {
+        throttle.consume(bytes);
+    }
+}
+
+/// Accounts for `bytes` downloaded, sleeping while over the limit.
+fn download(bytes: u64) {
+    MOVED.fetch_add(bytes, Ordering::Relaxed);
+    if let Some(throttle) = DOWNLOAD.get() {
+        throttle.consume(bytes);
+    }
+}
+
+/// Bytes read, written or downloaded so far, limited or not. It moves on whenever patching
+/// does, hashing included, so a watchdog can tell a slow run from a stuck one.
+pub fn bytes_moved() -> u64 {
+    MOVED.load(Ordering::Relaxed)
+}
+
+/// Reader that paces a download against the download limit as it is consumed.
+pub struct DownloadReader<R>(pub R);
+
+impl<R: Read> Read for DownloadReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.0.read(buf)?;
+        download(n as u64);
+        Ok(n)
+    }
+}
+
+/// Lowers CPU (and on Windows, I/O) priority so foreground work stays responsive.
+#[cfg(windows)]
+pub fn lower_priority() -> Result<()> {
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentProcess, PROCESS_MODE_BACKGROUND_BEGIN, SetPriorityClass,
+    };
+
+    // SAFETY: GetCurrentProcess returns a pseudo-handle that is always valid for this process.
+    let ok = unsafe { SetPriorityClass(GetCurrentProcess(), PROCESS_MODE_BACKGROUND_BEGIN) };
+    if ok == 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn lower_priority() -> Result<()> {
+    // SAFETY: setpriority only adjusts the scheduling priority of the calling process.
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 10) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(windows, unix)))]
+pub fn lower_priority() -> Result<()> {
+    Ok(())
+}
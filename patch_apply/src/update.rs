@@ -0,0 +1,70 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use ring::signature::{ED25519, UnparsedPublicKey};
+use serde::Serialize;
+
+use patch_types::hex_hash;
+use patch_types::{SignedUpdateInfo, UpdateInfo};
+
+use crate::identify;
+
+/// Parses an update descriptor written by `patch_builder --update-info`. With a `public_key`
+/// (raw Ed25519, as printed by `patch_builder keygen`), an unsigned descriptor or one whose
+/// signature does not match is rejected.
+pub fn read_update_info(bytes: &[u8], public_key: Option<&[u8]>) -> Result<UpdateInfo> {
+    let signed: SignedUpdateInfo =
+        serde_json::from_slice(bytes).context("Parsing update descriptor")?;
+    if let Some(key) = public_key {
+        let signature = signed
+            .signature
+            .as_deref()
+            .and_then(hex_hash::decode)
+            .context("Update descriptor is not signed")?;
+        UnparsedPublicKey::new(&ED25519, key)
+            .verify(signed.info.as_bytes(), &signature)
+            .map_err(|_| {
+                anyhow::anyhow!("Update descriptor signature does not match the public key")
+            })?;
+    }
+    serde_json::from_str(&signed.info).context("Parsing update descriptor")
+}
+
+/// Whether a folder needs the update a descriptor describes, as reported by `--check-update`.
+#[derive(Serialize)]
+pub struct UpdateCheck {
+    pub product: String,
+    /// Release found in the folder, if its files match exactly one the patch knows
+    pub installed_version: Option<String>,
+    pub from_version: String,
+    pub to_version: String,
+    /// False when the folder is already at the patched version, true when the patch applies to
+    /// it, unknown when neither could be told
+    pub update_needed: Option<bool>,
+    /// Size of the patch to download
+    pub download_bytes: u64,
+    /// Space the added and patched files take once written
+    pub disk_bytes: u64,
+    pub url: Option<String>,
+}
+
+/// Identifies the release installed in `target` from the descriptor's fingerprints, hashing
+/// only a few small files.
+pub fn check_update(info: &UpdateInfo, target: &Path) -> UpdateCheck {
+    let manifest = &info.manifest;
+    let installed = identify::installed_version(manifest, target);
+    UpdateCheck {
+        product: manifest.product.clone(),
+        installed_version: installed.map(str::to_string),
+        from_version: manifest.from_version.clone(),
+        to_version: manifest.to_version.clone(),
+        update_needed: match installed {
+            Some(v) if v == manifest.to_version => Some(false),
+            Some(v) if v == manifest.from_version => Some(true),
+            _ => None,
+        },
+        download_bytes: info.payload.size,
+        disk_bytes: info.disk_bytes,
+        url: info.payload.url.clone(),
+    }
+}
@@ -0,0 +1,705 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::hash::{BuildHasher, RandomState};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{Context, Result};
+
+use patch_types::error::PatchError;
+use patch_types::{CHUNK_SIZE, FileEntry, Manifest, PatchKind, case_collisions};
+
+use crate::chaos::Chaos;
+use crate::filesystem::Filesystem;
+use crate::hash_cache::HashCache;
+use crate::observer::{Conflict, ConflictAction, PatchObserver};
+use crate::telemetry::Telemetry;
+use crate::{apply, chunks, selfexe};
+
+/// Share of unchanged files hashed in [`VerifyMode::Sampled`], as one in this many.
+const SAMPLE_EVERY: u64 = 20;
+
+/// How much of the installation is hashed before patching.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VerifyMode {
+    /// Every file in the patch
+    #[default]
+    Full,
+    /// Only files the patch changes; unchanged files are checked for existence
+    ChangedOnly,
+    /// Changed files plus a random sample of the unchanged ones
+    Sampled,
+}
+
+impl VerifyMode {
+    /// Whether an unchanged file is hashed rather than only checked for existence. Sampling is
+    /// seeded per run, so repeated runs spot-check different files.
+    fn hashes_unchanged(self, rel: &str, seed: &RandomState) -> bool {
+        match self {
+            VerifyMode::Full => true,
+            VerifyMode::ChangedOnly => false,
+            VerifyMode::Sampled => seed.hash_one(rel).is_multiple_of(SAMPLE_EVERY),
+        }
+    }
+}
+
+/// Outcome of checking the base folder against the manifest.
+#[derive(Default)]
+pub(crate) struct Verification {
+    /// Files already at their new state
+    pub up_to_date: HashSet<usize>,
+    /// Files whose base is missing or corrupt, restored from their full fallback copy: patched
+    /// files with a fallback, and the unchanged, moved and copied files of a full install
+    pub use_fallback: HashSet<usize>,
+    /// Files that match neither their old nor their new state, which block patching
+    pub conflicts: Vec<Conflict>,
+    /// Conflicting files the observer chose to leave as they are
+    pub skipped: HashSet<usize>,
+    /// Files of components the user left out that are not installed, and stay that way
+    pub left_out: HashSet<usize>,
+    /// Files outside the `--only` and `--skip` selection, left as they are
+    pub unselected: HashSet<usize>,
+    /// Files the patch deletes that `--no-delete` leaves in place
+    pub kept: HashSet<usize>,
+    /// Streamed files `--in-place` patches where they are rather than staging
+    pub in_place: HashSet<usize>,
+    /// What the filesystem holding the target can do
+    pub filesystem: Filesystem,
+    /// Hashes read while verifying, saved to the target once the apply succeeds
+    pub hashes: HashCache,
+}
+
+impl Verification {
+    fn conflict(&mut self, index: usize, path: &str, error: PatchError) {
+        self.conflicts.push(Conflict {
+            index,
+            path: path.to_string(),
+            error,
+        });
+    }
+
+    /// Adds conflicts found by a separate pass, keeping them in manifest order.
+    pub fn add_conflicts(&mut self, manifest: &Manifest, conflicts: Vec<Conflict>) {
+        self.conflicts.extend(
+            conflicts
+                .into_iter()
+                .filter(|conflict| !self.unselected.contains(&conflict.index)),
+        );
+        self.conflicts.sort_by_key(|conflict| conflict.index);
+        self.restore_full_copies(manifest);
+    }
+
+    /// Restores the files the patch carries a full copy of from it, rather than failing over
+    /// their missing or corrupt base.
+    fn restore_full_copies(&mut self, manifest: &Manifest) {
+        let (restored, conflicts): (Vec<Conflict>, Vec<Conflict>) =
+            std::mem::take(&mut self.conflicts)
+                .into_iter()
+                .partition(|conflict| manifest.files[conflict.index].full_copy.is_some());
+        self.use_fallback
+            .extend(restored.iter().map(|conflict| conflict.index));
+        self.conflicts = conflicts;
+    }
+
+    /// Asks the observer about every conflict, then fails with those it did not skip.
+    pub fn resolve_conflicts(&mut self, observer: &mut impl PatchObserver) -> Result<()> {
+        for conflict in std::mem::take(&mut self.conflicts) {
+            match observer.conflict(&conflict) {
+                ConflictAction::Skip => {
+                    self.skipped.insert(conflict.index);
+                }
+                ConflictAction::Abort => self.conflicts.push(conflict),
+            }
+        }
+        match self.conflicts.as_slice() {
+            [] => Ok(()),
+            [only] => Err(only.error.clone().into()),
+            conflicts => Err(PatchError::Conflicts(
+                conflicts.iter().map(|c| c.error.clone()).collect(),
+            )
+            .into()),
+        }
+    }
+
+    /// Whether the file is left as it is: already at its new state, skipped, left out, not
+    /// selected or kept from deletion.
+    pub fn untouched(&self, index: usize) -> bool {
+        self.up_to_date.contains(&index)
+            || self.skipped.contains(&index)
+            || self.left_out.contains(&index)
+            || self.unselected.contains(&index)
+            || self.kept.contains(&index)
+    }
+
+    /// Leaves the files the patch deletes in place, dropping the conflicts they raised, except
+    /// those in the way of a new file or folder. Returns the indices of those, which are
+    /// deleted all the same.
+    pub fn keep_deleted(&mut self, manifest: &Manifest) -> Vec<usize> {
+        let blocking = apply::blocking_deletions(&manifest.files);
+        let (removed, kept): (Vec<usize>, Vec<usize>) = (0..manifest.files.len())
+            .filter(|&i| matches!(manifest.files[i].kind, PatchKind::Deleted) && !self.untouched(i))
+            .partition(|i| blocking.contains(i));
+        self.conflicts
+            .retain(|conflict| !kept.contains(&conflict.index));
+        self.kept.extend(kept);
+        removed
+    }
+
+    /// Leaves the files in `unselected` as they are, dropping the conflicts they raised.
+    pub fn deselect(&mut self, unselected: HashSet<usize>) {
+        self.use_fallback.retain(|i| !unselected.contains(i));
+        self.conflicts
+            .retain(|conflict| !unselected.contains(&conflict.index));
+        self.unselected = unselected;
+    }
+
+    /// Whether a file the patch changes was left out of the selection while not yet at its
+    /// new state, so the installation is only partly at the new version.
+    pub fn partial(&self, manifest: &Manifest) -> bool {
+        self.unselected.iter().any(|&i| {
+            !matches!(manifest.files[i].kind, PatchKind::Unchanged) && !self.up_to_date.contains(&i)
+        })
+    }
+
+    /// Leaves out the files that belong only to components not in `chosen` and are not
+    /// installed, dropping the conflicts their absence raised. Files of such components that
+    /// are installed are patched as usual.
+    pub fn leave_out(&mut self, manifest: &Manifest, cwd: &Path, chosen: &[String]) {
+        let Some(wizard) = &manifest.wizard else {
+            return;
+        };
+        let (kept, dropped): (Vec<_>, Vec<_>) = wizard
+            .components()
+            .iter()
+            .partition(|component| chosen.contains(&component.id));
+        let kept: HashSet<&str> = kept
+            .iter()
+            .flat_map(|component| &component.files)
+            .map(String::as_str)
+            .collect();
+        let dropped: HashSet<&str> = dropped
+            .iter()
+            .flat_map(|component| &component.files)
+            .map(String::as_str)
+            .filter(|path| !kept.contains(path))
+            .collect();
+        for (i, file) in manifest.files.iter().enumerate() {
+            if !dropped.contains(file.path.as_str()) || cwd.join(&file.path).exists() {
+                continue;
+            }
+            if let PatchKind::Moved { from } = &file.kind
+                && cwd.join(from).exists()
+            {
+                continue;
+            }
+            self.left_out.insert(i);
+            self.use_fallback.remove(&i);
+            self.conflicts.retain(|conflict| conflict.index != i);
+        }
+    }
+
+    /// Whether the file is decoded or copied into the staging folder before being committed.
+    pub fn is_staged(&self, index: usize, file: &FileEntry) -> bool {
+        (matches!(
+            file.kind,
+            PatchKind::Added { .. }
+                | PatchKind::Patched { .. }
+                | PatchKind::Copied { .. }
+                | PatchKind::Streamed { .. }
+                | PatchKind::Segmented { .. }
+        ) || self.use_fallback.contains(&index))
+            && !self.untouched(index)
+            && !self.in_place.contains(&index)
+    }
+
+    /// Streamed files left to patch, which `--in-place` patches where they are.
+    pub fn patch_in_place(&mut self, manifest: &Manifest) {
+        self.in_place = (0..manifest.files.len())
+            .filter(|&i| {
+                matches!(manifest.files[i].kind, PatchKind::Streamed { .. }) && !self.untouched(i)
+            })
+            .collect();
+    }
+
+    /// Free space patching needs: every new and patched file is staged in full while the
+    /// originals are still in place, and files patched in place need what they grow by.
+    pub fn space_needed(&self, manifest: &Manifest) -> u64 {
+        let staged: u64 = manifest
+            .files
+            .iter()
+            .enumerate()
+            .filter(|&(i, file)| self.is_staged(i, file))
+            .map(|(_, file)| file.new_size)
+            .sum();
+        let grown: u64 = self
+            .in_place
+            .iter()
+            .filter(|&&i| !self.untouched(i))
+            .map(|&i| {
+                manifest.files[i]
+                    .new_size
+                    .saturating_sub(manifest.files[i].old_size)
+            })
+            .sum();
+        // One chunk at a time is journaled on its way in
+        staged
+            + grown
+            + if self.in_place.is_empty() {
+                0
+            } else {
+                CHUNK_SIZE
+            }
+    }
+
+    /// Whether every file and directory that the patch changes is already at its new state
+    /// or skipped, and no unchanged file has to be restored.
+    pub fn nothing_to_do(&self, manifest: &Manifest, cwd: &Path) -> bool {
+        manifest.files.iter().enumerate().all(|(i, file)| {
+            (matches!(file.kind, PatchKind::Unchanged) && !self.use_fallback.contains(&i))
+                || self.untouched(i)
+        }) && manifest
+            .created_dirs
+            .iter()
+            .all(|dir| cwd.join(dir).is_dir())
+            && !manifest
+                .deleted_dirs
+                .iter()
+                .any(|dir| cwd.join(dir).is_dir())
+    }
+}
+
+/// What a file on disk matches.
+#[derive(Clone, Copy)]
+enum FileState {
+    Missing,
+    Old,
+    New,
+    /// Neither; holds the file's hash unless its size already ruled both out
+    Unknown(Option<[u8; 32]>),
+}
+
+impl FileState {
+    /// Hash of the file on disk, where it is known.
+    fn hash(self, file: &FileEntry) -> Option<[u8; 32]> {
+        match self {
+            FileState::Missing => None,
+            FileState::Old => Some(file.original_hash),
+            FileState::New => Some(file.new_hash),
+            FileState::Unknown(hash) => hash,
+        }
+    }
+}
+
+/// Hashes `rel` under `cwd` and compares it with the file's old and new hashes.
+fn file_state(
+    cwd: &Path,
+    rel: &str,
+    file: &FileEntry,
+    index: usize,
+    hashes: &HashCache,
+    telemetry: &Telemetry,
+    chaos: Option<Chaos>,
+) -> Result<FileState> {
+    let path = cwd.join(rel);
+    let Ok(meta) = fs::metadata(&path) else {
+        return Ok(FileState::Missing);
+    };
+    // A file of neither size cannot match either hash, so skip reading it
+    if meta.len() != file.old_size && meta.len() != file.new_size {
+        return Ok(FileState::Unknown(None));
+    }
+    let mut hash = hashes
+        .hash(cwd, rel, file.normalization, telemetry)
+        .with_context(|| format!("Hashing {rel}"))?;
+    if chaos.is_some_and(|c| c.hash_mismatch(index)) {
+        hash = [0xff; 32];
+    }
+    Ok(if hash == file.original_hash {
+        FileState::Old
+    } else if hash == file.new_hash {
+        FileState::New
+    } else {
+        FileState::Unknown(Some(hash))
+    })
+}
+
+/// Checks the base files the patch changes, deletes, moves or copies from before anything is
+/// modified, skipping files already at their new state; unchanged files are left to
+/// [`verify_unchanged`]. Every file that matches neither state is recorded as a conflict rather
+/// than stopping the check. Files unchanged since `hashes` recorded them are not read again.
+pub(crate) fn verify_changed(
+    manifest: &Manifest,
+    cwd: &Path,
+    hashes: HashCache,
+    telemetry: &Telemetry,
+    chaos: Option<Chaos>,
+) -> Result<Verification> {
+    let mut verification = Verification::default();
+    let running_exe = selfexe::running_exe();
+    for (i, file) in manifest.files.iter().enumerate() {
+        let missing = |path: &str, needed_for: Option<&str>| PatchError::MissingFile {
+            path: path.to_string(),
+            needed_for: needed_for.map(str::to_string),
+        };
+        let mismatch = |path: &str, state: FileState| PatchError::HashMismatch {
+            path: path.to_string(),
+            expected: file.original_hash,
+            actual: state.hash(file),
+        };
+        match file.kind {
+            // Kept in place during apply, so its content does not matter
+            PatchKind::Deleted
+                if selfexe::is_running_exe(&cwd.join(&file.path), running_exe.as_deref()) => {}
+            PatchKind::Unchanged => {}
+            PatchKind::Patched { fallback, .. } => {
+                let chunked = manifest.chunked_file(&file.path);
+                match file_state(cwd, &file.path, file, i, &hashes, telemetry, chaos)? {
+                    FileState::Old => {}
+                    FileState::New => {
+                        verification.up_to_date.insert(i);
+                    }
+                    _ if fallback.is_some()
+                        || chunked.is_some_and(|chunked| !chunked.chunk_entries.is_empty()) =>
+                    {
+                        verification.use_fallback.insert(i);
+                    }
+                    FileState::Missing => {
+                        verification.conflict(i, &file.path, missing(&file.path, None))
+                    }
+                    state @ FileState::Unknown(_) => {
+                        let error = match chunked {
+                            // Narrowed down to the chunks that are corrupt
+                            Some(chunked) => PatchError::CorruptChunks {
+                                path: file.path.clone(),
+                                chunks: chunks::differing(
+                                    &cwd.join(&file.path),
+                                    &chunked.old_chunks,
+                                    telemetry,
+                                )?,
+                            },
+                            None => mismatch(&file.path, state),
+                        };
+                        verification.conflict(i, &file.path, error)
+                    }
+                }
+            }
+            PatchKind::Streamed {
+                chunks: ref entries,
+            } => match file_state(cwd, &file.path, file, i, &hashes, telemetry, chaos)? {
+                FileState::New => {
+                    verification.up_to_date.insert(i);
+                }
+                // Written from the patch's chunks whatever is on disk
+                _ if entries.iter().all(Option::is_some) => {}
+                FileState::Old => {}
+                FileState::Missing => {
+                    verification.conflict(i, &file.path, missing(&file.path, None))
+                }
+                state @ FileState::Unknown(_) => {
+                    let Some(chunked) = manifest.chunked_file(&file.path) else {
+                        verification.conflict(i, &file.path, mismatch(&file.path, state));
+                        continue;
+                    };
+                    // Only corrupt chunks that the new version keeps and the patch stores no
+                    // copy of stand in the way
+                    let corrupt: Vec<u64> =
+                        chunks::differing(&cwd.join(&file.path), &chunked.old_chunks, telemetry)?
+                            .into_iter()
+                            .filter(|&chunk| entries.get(chunk as usize) == Some(&None))
+                            .collect();
+                    if !corrupt.is_empty() {
+                        let error = PatchError::CorruptChunks {
+                            path: file.path.clone(),
+                            chunks: corrupt,
+                        };
+                        verification.conflict(i, &file.path, error);
+                    }
+                }
+            },
+            PatchKind::Segmented { ref segments } => {
+                match file_state(cwd, &file.path, file, i, &hashes, telemetry, chaos)? {
+                    FileState::New => {
+                        verification.up_to_date.insert(i);
+                    }
+                    FileState::Old => {}
+                    FileState::Missing => {
+                        verification.conflict(i, &file.path, missing(&file.path, None))
+                    }
+                    state @ FileState::Unknown(_) => {
+                        let Some(chunked) = manifest.chunked_file(&file.path) else {
+                            verification.conflict(i, &file.path, mismatch(&file.path, state));
+                            continue;
+                        };
+                        // A segment kept as it is needs its own chunk intact, and one decoded from
+                        // its delta the chunks its base spans
+                        let corrupt: HashSet<u64> = chunks::differing(
+                            &cwd.join(&file.path),
+                            &chunked.old_chunks,
+                            telemetry,
+                        )?
+                        .into_iter()
+                        .collect();
+                        let blocked: Vec<u64> = (0..segments.len() as u64)
+                            .filter(|&segment| match segments[segment as usize] {
+                                None => corrupt.contains(&segment),
+                                Some(_) => (segment.saturating_sub(1)..=segment + 1)
+                                    .any(|chunk| corrupt.contains(&chunk)),
+                            })
+                            .collect();
+                        if !blocked.is_empty() {
+                            let error = PatchError::CorruptChunks {
+                                path: file.path.clone(),
+                                chunks: blocked,
+                            };
+                            verification.conflict(i, &file.path, error);
+                        }
+                    }
+                }
+            }
+            PatchKind::Deleted => {
+                match file_state(cwd, &file.path, file, i, &hashes, telemetry, chaos)? {
+                    FileState::Old => {}
+                    FileState::Missing => {
+                        verification.up_to_date.insert(i);
+                    }
+                    state @ (FileState::New | FileState::Unknown(_)) => {
+                        verification.conflict(i, &file.path, mismatch(&file.path, state))
+                    }
+                }
+            }
+            PatchKind::Added { .. } => {
+                if let FileState::New =
+                    file_state(cwd, &file.path, file, i, &hashes, telemetry, None)?
+                {
+                    verification.up_to_date.insert(i);
+                }
+            }
+            PatchKind::Moved { ref from } => {
+                match file_state(cwd, from, file, i, &hashes, telemetry, chaos)? {
+                    FileState::Old => {}
+                    FileState::Missing => {
+                        // A moved file keeps its content, so its old and new hashes are the same
+                        if let FileState::Old =
+                            file_state(cwd, &file.path, file, i, &hashes, telemetry, None)?
+                        {
+                            verification.up_to_date.insert(i);
+                        } else {
+                            verification.conflict(i, from, missing(from, Some(&file.path)));
+                        }
+                    }
+                    state @ (FileState::New | FileState::Unknown(_)) => {
+                        verification.conflict(i, from, mismatch(from, state))
+                    }
+                }
+            }
+            // A copy has the content of its source, so its old and new hashes are the same
+            PatchKind::Copied { ref from } => {
+                match file_state(cwd, &file.path, file, i, &hashes, telemetry, None)? {
+                    FileState::Old | FileState::New => {
+                        verification.up_to_date.insert(i);
+                    }
+                    FileState::Missing | FileState::Unknown(_) => {
+                        match file_state(cwd, from, file, i, &hashes, telemetry, chaos)? {
+                            FileState::Old | FileState::New => {}
+                            FileState::Missing => {
+                                verification.conflict(i, from, missing(from, Some(&file.path)))
+                            }
+                            state @ FileState::Unknown(_) => {
+                                verification.conflict(i, from, mismatch(from, state))
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    verification.restore_full_copies(manifest);
+    verification.hashes = hashes;
+    Ok(verification)
+}
+
+/// Checks the files the patch leaves as they are, as [`VerifyMode`] asks, and returns their
+/// conflicts. Nothing is patched from them, so this can run while changed files are staged;
+/// it stops early, with what it found so far, once `stop` is set.
+pub(crate) fn verify_unchanged(
+    manifest: &Manifest,
+    cwd: &Path,
+    hashes: &HashCache,
+    telemetry: &Telemetry,
+    chaos: Option<Chaos>,
+    mode: VerifyMode,
+    stop: &AtomicBool,
+) -> Result<Vec<Conflict>> {
+    let mut verification = Verification::default();
+    let seed = RandomState::new();
+    for (i, file) in manifest.files.iter().enumerate() {
+        if !matches!(file.kind, PatchKind::Unchanged) {
+            continue;
+        }
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+        let missing = PatchError::MissingFile {
+            path: file.path.clone(),
+            needed_for: None,
+        };
+        if !mode.hashes_unchanged(&file.path, &seed) {
+            if !cwd.join(&file.path).exists() {
+                verification.conflict(i, &file.path, missing);
+            }
+            continue;
+        }
+        match file_state(cwd, &file.path, file, i, hashes, telemetry, chaos)? {
+            FileState::Old | FileState::New => {}
+            FileState::Missing => verification.conflict(i, &file.path, missing),
+            state @ FileState::Unknown(_) => verification.conflict(
+                i,
+                &file.path,
+                PatchError::HashMismatch {
+                    path: file.path.clone(),
+                    expected: file.original_hash,
+                    actual: state.hash(file),
+                },
+            ),
+        }
+    }
+    Ok(verification.conflicts)
+}
+
+/// Refuses to apply when paths differing only by case would land on the same file.
+pub(crate) fn check_case_collisions(manifest: &Manifest, cwd: &Path) -> Result<()> {
+    let collisions = case_collisions(manifest.files.iter().map(|f| f.path.as_str()));
+    if collisions.is_empty() || !is_case_insensitive(cwd)? {
+        return Ok(());
+    }
+    let listing = collisions
+        .iter()
+        .map(|group| format!("  {}", group.join(" <-> ")))
+        .collect::<Vec<_>>()
+        .join("\n");
+    anyhow::bail!(
+        "This patch contains paths that differ only by case, which collide on this filesystem:\n{listing}"
+    );
+}
+
+/// Refuses to apply on a case-sensitive filesystem when a file the patch changes is on disk
+/// only under another letter case, as in patches built with `--ignore-path-case`: its base
+/// would not be found under the new casing, so patching would fail partway or leave both.
+pub(crate) fn check_case_renames(manifest: &Manifest, cwd: &Path) -> Result<()> {
+    let renamed = case_renamed(&manifest.files, cwd);
+    if renamed.is_empty() || is_case_insensitive(cwd)? {
+        return Ok(());
+    }
+    let listing = renamed
+        .iter()
+        .map(|(found, path)| format!("  {found} -> {path}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    anyhow::bail!(
+        "This patch changes files whose names differ from the ones here only by case, which \
+         needs a filesystem that ignores case, as Windows and macOS do by default:\n{listing}"
+    );
+}
+
+/// Files patched from their base that are missing from `cwd` under their own path but held
+/// under another casing of it, as that path and their own.
+fn case_renamed(files: &[FileEntry], cwd: &Path) -> Vec<(String, String)> {
+    files
+        .iter()
+        .filter(|file| {
+            matches!(
+                file.kind,
+                PatchKind::Patched { .. }
+                    | PatchKind::Streamed { .. }
+                    | PatchKind::Segmented { .. }
+            )
+        })
+        .filter(|file| !cwd.join(&file.path).exists())
+        .filter_map(|file| Some((held_as(cwd, &file.path)?, file.path.clone())))
+        .collect()
+}
+
+/// Path of the file under `cwd` that `rel` names when letter case is ignored, one component
+/// at a time.
+fn held_as(cwd: &Path, rel: &str) -> Option<String> {
+    let mut dir = cwd.to_path_buf();
+    let mut found = Vec::new();
+    for part in rel.split('/') {
+        let name = if dir.join(part).exists() {
+            part.to_string()
+        } else {
+            let wanted = part.to_lowercase();
+            fs::read_dir(&dir)
+                .ok()?
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .find(|name| name.to_lowercase() == wanted)?
+        };
+        dir.push(&name);
+        found.push(name);
+    }
+    dir.is_file().then(|| found.join("/"))
+}
+
+/// Probes whether `dir` is on a case-insensitive filesystem.
+pub(crate) fn is_case_insensitive(dir: &Path) -> Result<bool> {
+    let name = format!(".patch_case_probe_{}", std::process::id());
+    let probe = dir.join(&name);
+    File::create(&probe).with_context(|| format!("Creating {}", probe.display()))?;
+    let insensitive = dir.join(name.to_uppercase()).exists();
+    fs::remove_file(&probe)?;
+    Ok(insensitive)
+}
+
+#[cfg(test)]
+mod tests {
+    use patch_types::normalize::Normalization;
+
+    use super::*;
+
+    fn entry(path: &str, kind: PatchKind) -> FileEntry {
+        FileEntry {
+            path: path.to_string(),
+            kind,
+            original_hash: [0; 32],
+            new_hash: [0; 32],
+            old_size: 0,
+            new_size: 0,
+            mtime: None,
+            normalization: Normalization::None,
+            mode: None,
+            sparse: false,
+            full_copy: None,
+        }
+    }
+
+    #[test]
+    fn changed_files_held_under_another_case_are_found() {
+        let cwd = std::env::temp_dir().join(format!("patch_case_renames_{}", std::process::id()));
+        fs::create_dir_all(cwd.join("assets")).unwrap();
+        fs::write(cwd.join("assets/foo.dat"), b"old").unwrap();
+        fs::write(cwd.join("same.txt"), b"old").unwrap();
+        let patched = PatchKind::Patched {
+            idx: 0,
+            fallback: None,
+            transform: None,
+        };
+        let files = [
+            entry("Assets/Foo.dat", patched.clone()),
+            entry("same.txt", patched.clone()),
+            entry("missing.bin", patched),
+            // Written whole, so its old casing does not matter
+            entry("ASSETS/FOO.DAT", PatchKind::Added { idx: 1 }),
+        ];
+        let renamed = case_renamed(&files, &cwd);
+        let insensitive = is_case_insensitive(&cwd).unwrap();
+        fs::remove_dir_all(&cwd).unwrap();
+        if insensitive {
+            assert!(renamed.is_empty());
+        } else {
+            assert_eq!(
+                renamed,
+                [("assets/foo.dat".to_string(), "Assets/Foo.dat".to_string())]
+            );
+        }
+    }
+}
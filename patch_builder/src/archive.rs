@@ -0,0 +1,92 @@
+use std::io::{Seek, Write};
+
+use anyhow::{Context, Result};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use patch_types::{
+    Compression, Manifest, PatchBundle, PatchData, PatchKind, ZIP_MANIFEST_NAME, ZipManifest,
+};
+
+/// Writes the bundle as a standard zip: `manifest.json` plus one member per entry, named
+/// after the file it belongs to (`patched/<path>.xdelta`, `added/<path>`, `replaced/<path>` for
+/// the full new content of a file changed from or to an empty one, `fallback/<path>.zst` for
+/// fallbacks and full copies, `chunks/<path>.<n>.zst`).
+/// Returns the manifest as written to the archive.
+pub fn build_zip_archive<W: Write + Seek>(bundle: PatchBundle, out: W) -> Result<(W, Manifest)> {
+    let PatchBundle {
+        mut manifest,
+        entries,
+    } = bundle;
+
+    let mut entry_files = vec![String::new(); entries.len()];
+    for file in &manifest.files {
+        match file.kind {
+            PatchKind::Patched { idx, fallback, .. } => {
+                entry_files[idx] = match entries.get(idx) {
+                    Some(PatchData::Full(_)) => format!("replaced/{}", file.path),
+                    _ => format!("patched/{}.xdelta", file.path),
+                };
+                if let Some(fallback) = fallback {
+                    entry_files[fallback] = format!("fallback/{}.zst", file.path);
+                }
+            }
+            PatchKind::Added { idx } => entry_files[idx] = format!("added/{}", file.path),
+            PatchKind::Streamed { ref chunks } => {
+                for (index, &idx) in chunks.iter().enumerate() {
+                    if let Some(idx) = idx {
+                        entry_files[idx] = format!("chunks/{}.{index}.zst", file.path);
+                    }
+                }
+            }
+            PatchKind::Segmented { ref segments } => {
+                for (index, &idx) in segments.iter().enumerate() {
+                    if let Some(idx) = idx {
+                        entry_files[idx] = format!("segments/{}.{index}.xdelta", file.path);
+                    }
+                }
+            }
+            PatchKind::Unchanged
+            | PatchKind::Deleted
+            | PatchKind::Moved { .. }
+            | PatchKind::Copied { .. } => {}
+        }
+        if let Some(full_copy) = file.full_copy {
+            entry_files[full_copy] = format!("fallback/{}.zst", file.path);
+        }
+    }
+
+    for chunked in &manifest.chunked {
+        for (index, &idx) in chunked.chunk_entries.iter().enumerate() {
+            entry_files[idx] = format!("chunks/{}.{index}.zst", chunked.path);
+        }
+    }
+
+    let mut zip = ZipWriter::new(out);
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .large_file(true);
+
+    for (data, name) in entries.iter().zip(&entry_files) {
+        let bytes = match data {
+            PatchData::Xdelta(b) | PatchData::Full(b) | PatchData::CompressedFull(b) => b,
+        };
+        zip.start_file(name.as_str(), options)
+            .with_context(|| format!("Adding {name}"))?;
+        zip.write_all(bytes)?;
+    }
+
+    // Zip members are deflated individually, so the entries carry no extra compression
+    manifest.compression = Compression::None;
+    manifest.entries.clear();
+    let index = ZipManifest {
+        manifest,
+        entry_files,
+    };
+    zip.start_file(ZIP_MANIFEST_NAME, options)?;
+    serde_json::to_writer_pretty(&mut zip, &index)?;
+
+    let mut out = zip.finish()?;
+    out.flush()?;
+    Ok((out, index.manifest))
+}
@@ -0,0 +1,184 @@
+use std::fmt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use patch_apply::{Bundle, hash_file};
+use patch_types::schedule::largest_first;
+use patch_types::{FileEntry, Manifest, PatchKind, ZipManifest};
+
+/// What a file in the audited folder matches.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Found {
+    /// The state after patching: the new content, or absent for deleted files
+    New,
+    /// The state before patching
+    Old,
+    Missing,
+    /// Content matching neither version
+    Modified,
+}
+
+/// Overall state of the audited folder.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum InstallState {
+    Patched,
+    Unpatched,
+    /// Some changed files are at the old version and some at the new one
+    Partial,
+    /// Files are missing or match neither version
+    Tampered,
+}
+
+impl fmt::Display for InstallState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InstallState::Patched => "patched",
+            InstallState::Unpatched => "unpatched",
+            InstallState::Partial => "partially patched",
+            InstallState::Tampered => "tampered",
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct Discrepancy {
+    path: String,
+    found: Found,
+}
+
+/// Report written by `verify-install`.
+#[derive(Serialize)]
+struct InstallReport {
+    product: String,
+    from_version: String,
+    to_version: String,
+    dir: PathBuf,
+    state: InstallState,
+    files_checked: usize,
+    /// Every file not at its patched state
+    discrepancies: Vec<Discrepancy>,
+}
+
+/// Checks `dir` against the manifest in `patch` (an installer, payload, zip-format patch or the
+/// zip's `manifest.json`) and writes a JSON report to `report`, or stdout. Fails unless the
+/// folder is fully patched, so scripts can act on the exit code.
+pub fn verify_install(patch: &Path, dir: &Path, report: Option<&Path>) -> Result<()> {
+    let manifest = load_manifest(patch)?;
+    let found = largest_first(
+        &manifest.files,
+        |file| file.new_size,
+        |_, file| check_file(dir, file),
+    )?;
+
+    let changed = || {
+        manifest
+            .files
+            .iter()
+            .zip(&found)
+            .filter(|(file, _)| !matches!(file.kind, PatchKind::Unchanged))
+            .map(|(_, found)| *found)
+    };
+    let state = if found
+        .iter()
+        .any(|f| matches!(f, Found::Missing | Found::Modified))
+    {
+        InstallState::Tampered
+    } else if changed().all(|f| f == Found::New) {
+        InstallState::Patched
+    } else if changed().all(|f| f == Found::Old) {
+        InstallState::Unpatched
+    } else {
+        InstallState::Partial
+    };
+
+    let result = InstallReport {
+        product: manifest.product.clone(),
+        from_version: manifest.from_version.clone(),
+        to_version: manifest.to_version.clone(),
+        dir: dir.to_path_buf(),
+        state,
+        files_checked: manifest.files.len(),
+        discrepancies: manifest
+            .files
+            .iter()
+            .zip(&found)
+            .filter(|(_, found)| **found != Found::New)
+            .map(|(file, found)| Discrepancy {
+                path: file.path.clone(),
+                found: *found,
+            })
+            .collect(),
+    };
+    let json = serde_json::to_vec_pretty(&result)?;
+    match report {
+        Some(path) => {
+            std::fs::write(path, json).with_context(|| format!("Writing {}", path.display()))?
+        }
+        None => println!("{}", String::from_utf8_lossy(&json)),
+    }
+
+    if state != InstallState::Patched {
+        anyhow::bail!(
+            "{} is {state}: {} files differ from {} {}",
+            dir.display(),
+            result.discrepancies.len(),
+            manifest.product,
+            manifest.to_version
+        );
+    }
+    Ok(())
+}
+
+/// Reads the manifest from a patch, or from a `manifest.json` extracted from a zip-format patch.
+pub(crate) fn load_manifest(path: &Path) -> Result<Manifest> {
+    if path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+    {
+        let file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+        let index: ZipManifest =
+            serde_json::from_reader(file).with_context(|| format!("Reading {}", path.display()))?;
+        return Ok(index.manifest);
+    }
+    let bundle = Bundle::open(path)
+        .with_context(|| format!("Reading the manifest of {}", path.display()))?;
+    Ok(bundle.into_manifest())
+}
+
+fn check_file(dir: &Path, file: &FileEntry) -> Result<Found> {
+    let hash = |rel: &str| -> Result<Option<[u8; 32]>> {
+        let path = dir.join(rel);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        hash_file(&path, file.normalization)
+            .with_context(|| format!("Hashing {rel}"))
+            .map(Some)
+    };
+    Ok(match (&file.kind, hash(&file.path)?) {
+        (_, Some(h)) if h == file.new_hash && !matches!(file.kind, PatchKind::Deleted) => {
+            Found::New
+        }
+        (PatchKind::Deleted, None) => Found::New,
+        (PatchKind::Added { .. }, None) => Found::Old,
+        (PatchKind::Streamed { .. }, None) if file.original_hash == [0u8; 32] => Found::Old,
+        (PatchKind::Moved { from } | PatchKind::Copied { from }, None) => match hash(from)? {
+            Some(h) if h == file.original_hash => Found::Old,
+            _ => Found::Missing,
+        },
+        (_, None) => Found::Missing,
+        (
+            PatchKind::Patched { .. }
+            | PatchKind::Streamed { .. }
+            | PatchKind::Segmented { .. }
+            | PatchKind::Deleted,
+            Some(h),
+        ) if h == file.original_hash => Found::Old,
+        (_, Some(_)) => Found::Modified,
+    })
+}
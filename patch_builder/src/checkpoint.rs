@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use patch_types::hex_hash;
+use patch_types::normalize::Normalization;
+
+use crate::chunks::Chunks;
+use crate::scan::FileRec;
+use crate::store::{EntryKey, EntryStore};
+use crate::{BuildOptions, TempKind, TempResult, file_mode};
+
+const JOURNAL: &str = "journal.jsonl";
+/// Entries are kept here when no --store is given
+const ENTRIES: &str = "entries";
+
+/// A source file as it was when its result was recorded; any change invalidates the result.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+struct Source {
+    path: PathBuf,
+    len: u64,
+    modified: u64,
+}
+
+impl Source {
+    fn of(path: &Path) -> Result<Source> {
+        let meta = fs::metadata(path).with_context(|| format!("Reading {}", path.display()))?;
+        let modified = meta
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Source {
+            path: std::path::absolute(path)?,
+            len: meta.len(),
+            modified: modified.as_nanos().try_into().unwrap_or(u64::MAX),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum RowKind {
+    Unchanged,
+    Added(EntryKey),
+    Patched {
+        key: EntryKey,
+        transform: Option<usize>,
+        fallback: Option<EntryKey>,
+        #[serde(default)]
+        chunks: Option<RowChunks>,
+    },
+    /// Written chunk by chunk; `stored` tells which chunks have an entry
+    Streamed {
+        chunks: RowChunks,
+        stored: Vec<bool>,
+    },
+}
+
+/// Chunk hashes of a large patched file; its chunk entries are stored by the new hashes.
+#[derive(Serialize, Deserialize)]
+struct RowChunks {
+    #[serde(with = "hex_hash::list")]
+    old: Vec<[u8; 32]>,
+    #[serde(with = "hex_hash::list")]
+    new: Vec<[u8; 32]>,
+    /// zstd level of the chunk entries, when the file has them
+    level: Option<i32>,
+}
+
+/// One finished file of the new tree. Its entries are kept in the store.
+#[derive(Serialize, Deserialize)]
+struct Row {
+    new: Source,
+    old: Option<Source>,
+    #[serde(with = "hex_hash")]
+    original_hash: [u8; 32],
+    #[serde(with = "hex_hash")]
+    new_hash: [u8; 32],
+    normalization: Normalization,
+    kind: RowKind,
+}
+
+/// Journal of files finished by earlier, interrupted runs of the same build, so a rerun only
+/// hashes and diffs what is left. Each file is appended as soon as it is done; a torn last line
+/// from a crash is ignored.
+pub struct Checkpoint {
+    dir: PathBuf,
+    journal: Mutex<Option<File>>,
+    rows: HashMap<(Source, Option<Source>), Row>,
+}
+
+impl Checkpoint {
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Creating checkpoint {}", dir.display()))?;
+        let path = dir.join(JOURNAL);
+        let text = fs::read_to_string(&path).unwrap_or_default();
+        let mut rows = HashMap::new();
+        for line in text.lines() {
+            if let Ok(row) = serde_json::from_str::<Row>(line) {
+                rows.insert((row.new.clone(), row.old.clone()), row);
+            }
+        }
+        if !rows.is_empty() {
+            eprintln!(
+                "Resuming from {}: {} files already built",
+                dir.display(),
+                rows.len()
+            );
+        }
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Opening {}", path.display()))?;
+        if !text.is_empty() && !text.ends_with('\n') {
+            // Ends the line torn by the crash, so the next one is not appended to it
+            journal.write_all(b"\n")?;
+        }
+        Ok(Checkpoint {
+            dir: dir.to_path_buf(),
+            journal: Mutex::new(Some(journal)),
+            rows,
+        })
+    }
+
+    /// Store for the entries of checkpointed files when the build has none of its own.
+    pub fn entry_store(&self) -> Result<EntryStore> {
+        EntryStore::open(&self.dir.join(ENTRIES))
+    }
+
+    /// The result recorded for `rec` and `old`, if neither file nor the settings that shaped
+    /// it have changed since and its entries are still stored.
+    pub fn resume(
+        &self,
+        rec: &FileRec,
+        old: Option<&Path>,
+        options: &BuildOptions,
+    ) -> Result<Option<TempResult>> {
+        let new = Source::of(&rec.path)?;
+        let old = old.map(Source::of).transpose()?;
+        let (Some(row), Some(store)) = (self.rows.get(&(new, old)), options.store.as_ref()) else {
+            return Ok(None);
+        };
+        if row.normalization != options.normalization(&rec.rel) {
+            return Ok(None);
+        }
+        let (kind, fallback, chunks) = match &row.kind {
+            RowKind::Unchanged => (TempKind::Unchanged, None, None),
+            RowKind::Added(key) => match store.get(key) {
+                Some(data) => (TempKind::Added(key.clone(), data), None, None),
+                None => return Ok(None),
+            },
+            RowKind::Patched {
+                key,
+                transform,
+                fallback,
+                chunks,
+            } => {
+                let EntryKey::Delta {
+                    transform: id,
+                    level,
+                    ..
+                } = key
+                else {
+                    return Ok(None);
+                };
+                let current = options.transforms.find(&rec.rel);
+                let has_fallback = fallback.is_some()
+                    || chunks.as_ref().is_some_and(|chunks| chunks.level.is_some());
+                if *id != current.map(|t| options.transforms.id(t))
+                    || *level != options.xdelta_level
+                    || has_fallback != options.full_fallback.is_match(&rec.rel)
+                {
+                    return Ok(None);
+                }
+                let fallback = match fallback {
+                    Some(key) => match store.get(key) {
+                        Some(data) => Some((key.clone(), data)),
+                        None => return Ok(None),
+                    },
+                    None => None,
+                };
+                let chunks = match chunks {
+                    Some(chunks) => {
+                        let entries = match chunks.level {
+                            Some(level) => {
+                                let keys = chunks
+                                    .new
+                                    .iter()
+                                    .map(|&new| EntryKey::Fallback { new, level });
+                                let Some(entries) = keys
+                                    .map(|key| store.get(&key).map(|data| (key, data)))
+                                    .collect::<Option<Vec<_>>>()
+                                else {
+                                    return Ok(None);
+                                };
+                                entries
+                            }
+                            None => Vec::new(),
+                        };
+                        Some(Chunks {
+                            old: chunks.old.clone(),
+                            new: chunks.new.clone(),
+                            level: chunks.level,
+                            entries,
+                        })
+                    }
+                    None => None,
+                };
+                match store.get(key) {
+                    Some(data) => (
+                        TempKind::Patched(key.clone(), data, *transform),
+                        fallback,
+                        chunks,
+                    ),
+                    None => return Ok(None),
+                }
+            }
+            RowKind::Streamed { chunks, stored } => {
+                let Some(level) = chunks.level else {
+                    return Ok(None);
+                };
+                let len = row.new.len.max(row.old.as_ref().map_or(0, |s| s.len));
+                if row.old.is_some() && options.is_segmented(len, &rec.rel, row.normalization) {
+                    return Ok(None);
+                }
+                // A file whose glob now asks for a full copy needs every chunk
+                if options.full_fallback.is_match(&rec.rel) && stored.contains(&false) {
+                    return Ok(None);
+                }
+                let mut entries = Vec::with_capacity(stored.len());
+                for (&new, &stored) in chunks.new.iter().zip(stored) {
+                    if !stored {
+                        entries.push(None);
+                        continue;
+                    }
+                    let key = EntryKey::Fallback { new, level };
+                    let Some(data) = store.get(&key) else {
+                        return Ok(None);
+                    };
+                    entries.push(Some((key, data)));
+                }
+                let chunks = Chunks {
+                    old: chunks.old.clone(),
+                    new: chunks.new.clone(),
+                    level: chunks.level,
+                    entries: Vec::new(),
+                };
+                (TempKind::Streamed(entries), None, Some(chunks))
+            }
+        };
+        let written = !matches!(kind, TempKind::Unchanged);
+        Ok(Some(TempResult {
+            path: rec.rel.clone(),
+            original_hash: row.original_hash,
+            new_hash: row.new_hash,
+            old_size: row.old.as_ref().map_or(0, |s| s.len),
+            new_size: row.new.len,
+            mtime: if written {
+                options.mtime(&rec.path)?
+            } else {
+                None
+            },
+            normalization: row.normalization,
+            mode: file_mode(&rec.path)?,
+            kind,
+            fallback,
+            chunks,
+        }))
+    }
+
+    /// Appends a finished file to the journal. Moves and copies are not recorded: they depend
+    /// on which old files other new files claimed, and cost only a hash. Nor are segmented
+    /// files, whose deltas a rerun finds in the entry store.
+    pub fn record(&self, rec: &FileRec, old: Option<&Path>, result: &TempResult) -> Result<()> {
+        let kind = match &result.kind {
+            TempKind::Unchanged => RowKind::Unchanged,
+            TempKind::Added(key, _) => RowKind::Added(key.clone()),
+            TempKind::Patched(key, _, transform) => RowKind::Patched {
+                key: key.clone(),
+                transform: *transform,
+                fallback: result.fallback.as_ref().map(|(key, _)| key.clone()),
+                chunks: result.chunks.as_ref().map(|chunks| RowChunks {
+                    old: chunks.old.clone(),
+                    new: chunks.new.clone(),
+                    level: chunks.level,
+                }),
+            },
+            TempKind::Streamed(entries) => {
+                let Some(chunks) = &result.chunks else {
+                    return Ok(());
+                };
+                RowKind::Streamed {
+                    chunks: RowChunks {
+                        old: chunks.old.clone(),
+                        new: chunks.new.clone(),
+                        level: chunks.level,
+                    },
+                    stored: entries.iter().map(Option::is_some).collect(),
+                }
+            }
+            TempKind::Moved(_) | TempKind::Copied(_) | TempKind::Segmented(_) => return Ok(()),
+        };
+        let row = Row {
+            new: Source::of(&rec.path)?,
+            old: old.map(Source::of).transpose()?,
+            original_hash: result.original_hash,
+            new_hash: result.new_hash,
+            normalization: result.normalization,
+            kind,
+        };
+        let mut line = serde_json::to_string(&row)?;
+        line.push('\n');
+        if let Some(journal) = self.journal.lock().unwrap().as_mut() {
+            // One write per line, so a crash tears at most the last one
+            journal
+                .write_all(line.as_bytes())
+                .context("Writing checkpoint")?;
+        }
+        Ok(())
+    }
+
+    /// Removes the checkpoint once every patch it was for has been written.
+    pub fn finish(&self) -> Result<()> {
+        drop(self.journal.lock().unwrap().take());
+        fs::remove_file(self.dir.join(JOURNAL))
+            .with_context(|| format!("Removing checkpoint {}", self.dir.display()))?;
+        let entries = self.dir.join(ENTRIES);
+        if entries.is_dir() {
+            fs::remove_dir_all(&entries)
+                .with_context(|| format!("Removing {}", entries.display()))?;
+        }
+        // Left in place if it holds anything else, such as a --store pointed at the same folder
+        let _ = fs::remove_dir(&self.dir);
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+
+use patch_types::normalize::Normalization;
+use patch_types::{CHUNK_SIZE, PatchData, buffers, segment_base};
+
+use crate::store::{EntryKey, EntryStore, build_entry};
+use crate::xdelta;
+
+/// Chunk hashes of a large patched file, and its full fallback split into chunks.
+pub struct Chunks {
+    pub old: Vec<[u8; 32]>,
+    pub new: Vec<[u8; 32]>,
+    /// zstd level of `entries`
+    pub level: Option<i32>,
+    /// Compressed copy of each chunk of the new file, when it gets a fallback
+    pub entries: Vec<ChunkEntry>,
+}
+
+/// Compressed copy of a chunk, with the key it is stored under.
+pub type ChunkEntry = (EntryKey, PatchData);
+
+fn read_chunk(path: &Path, index: u64) -> Result<Vec<u8>> {
+    let mut file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    file.seek(SeekFrom::Start(index * CHUNK_SIZE))?;
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE as usize);
+    file.take(CHUNK_SIZE).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+/// Hashes of each chunk of the file at `path`, read in parallel.
+fn hash_chunks(path: &Path) -> Result<Vec<[u8; 32]>> {
+    let len = fs::metadata(path)?.len();
+    (0..len.div_ceil(CHUNK_SIZE))
+        .into_par_iter()
+        .map(|index| Ok(*blake3::hash(&read_chunk(path, index)?).as_bytes()))
+        .collect()
+}
+
+/// Compresses chunk `index` of the file at `path` at zstd `level`, as an entry of its own.
+fn chunk_entry(
+    path: &Path,
+    index: u64,
+    hash: [u8; 32],
+    level: i32,
+    store: Option<&EntryStore>,
+) -> Result<ChunkEntry> {
+    let key = EntryKey::Fallback { new: hash, level };
+    let data = build_entry(store, &key, || {
+        zstd::encode_all(read_chunk(path, index)?.as_slice(), level)
+            .with_context(|| format!("Compressing chunk {index} of {}", path.display()))
+    })?;
+    Ok((key, data))
+}
+
+/// Hashes both files by chunk and, with `fallback_level`, compresses each chunk of the new
+/// file at that zstd level as an entry of its own.
+pub fn build_chunks(
+    old: &Path,
+    new: &Path,
+    fallback_level: Option<i32>,
+    store: Option<&EntryStore>,
+) -> Result<Chunks> {
+    let _span = tracing::info_span!("chunks").entered();
+    let (old_chunks, new_chunks) = rayon::join(|| hash_chunks(old), || hash_chunks(new));
+    let (old_chunks, new_chunks) = (old_chunks?, new_chunks?);
+    let entries = match fallback_level {
+        Some(level) => (0..)
+            .zip(&new_chunks)
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|(index, &hash)| chunk_entry(new, index, hash, level, store))
+            .collect::<Result<_>>()?,
+        None => Vec::new(),
+    };
+    Ok(Chunks {
+        old: old_chunks,
+        new: new_chunks,
+        level: fallback_level,
+        entries,
+    })
+}
+
+/// Chunk hashes of a file written chunk by chunk (`PatchKind::Streamed`), with a copy of each
+/// chunk of the new file at zstd `level` that the old file does not hold at the same place,
+/// `None` for the others. With `all`, or without an old file, every chunk is stored. The chunks
+/// a `normalization` changes are stored too, since the user's copy of them may differ.
+pub fn build_streamed(
+    old: Option<&Path>,
+    new: &Path,
+    all: bool,
+    normalization: Normalization,
+    level: i32,
+    store: Option<&EntryStore>,
+) -> Result<(Chunks, Vec<Option<ChunkEntry>>)> {
+    let _span = tracing::info_span!("streamed").entered();
+    let (old_chunks, new_chunks) =
+        rayon::join(|| old.map(hash_chunks).transpose(), || hash_chunks(new));
+    let (old_chunks, new_chunks) = (old_chunks?.unwrap_or_default(), new_chunks?);
+    let normalized = if normalization == Normalization::None {
+        0
+    } else {
+        (buffers::head_len(fs::metadata(new)?.len()) as u64).div_ceil(CHUNK_SIZE)
+    };
+    let entries = (0..)
+        .zip(&new_chunks)
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .map(|(index, &hash)| {
+            let held = old_chunks.get(index as usize) == Some(&hash);
+            if held && !all && index >= normalized {
+                return Ok(None);
+            }
+            chunk_entry(new, index, hash, level, store).map(Some)
+        })
+        .collect::<Result<_>>()?;
+    let chunks = Chunks {
+        old: old_chunks,
+        new: new_chunks,
+        level: Some(level),
+        entries: Vec::new(),
+    };
+    Ok((chunks, entries))
+}
+
+/// Chunk hashes of a file diffed segment by segment (`PatchKind::Segmented`), with an xdelta
+/// delta of each chunk of the new file from the old file's `segment_base`, `None` for the
+/// chunks the old file holds at the same place. Deltas are made at xdelta compression `level`.
+pub fn build_segmented(
+    old: &Path,
+    new: &Path,
+    store: Option<&EntryStore>,
+    level: Option<u8>,
+) -> Result<(Chunks, Vec<Option<ChunkEntry>>)> {
+    let _span = tracing::info_span!("segmented").entered();
+    let (old_chunks, new_chunks) = rayon::join(|| hash_chunks(old), || hash_chunks(new));
+    let (old_chunks, new_chunks) = (old_chunks?, new_chunks?);
+    let old_size = fs::metadata(old)?.len();
+    let entries = (0..)
+        .zip(&new_chunks)
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .map(|(index, &hash)| {
+            if old_chunks.get(index as usize) == Some(&hash) {
+                return Ok(None);
+            }
+            let base = read_range(old, segment_base(index, old_size))?;
+            let key = EntryKey::Delta {
+                old: *blake3::hash(&base).as_bytes(),
+                new: hash,
+                transform: None,
+                level,
+            };
+            let data = build_entry(store, &key, || {
+                let _span = tracing::info_span!("diff").entered();
+                xdelta::encode(&read_chunk(new, index)?, &base, level)
+                    .with_context(|| format!("Diffing segment {index} of {}", new.display()))
+            })?;
+            Ok(Some((key, data)))
+        })
+        .collect::<Result<_>>()?;
+    let chunks = Chunks {
+        old: old_chunks,
+        new: new_chunks,
+        level: None,
+        entries: Vec::new(),
+    };
+    Ok((chunks, entries))
+}
+
+fn read_range(path: &Path, range: Range<u64>) -> Result<Vec<u8>> {
+    let mut file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    file.seek(SeekFrom::Start(range.start))?;
+    let mut bytes = Vec::with_capacity((range.end - range.start) as usize);
+    file.take(range.end - range.start).read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
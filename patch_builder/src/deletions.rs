@@ -0,0 +1,94 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use anyhow::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use indicatif::HumanBytes;
+
+/// Paths listed before asking to confirm; the rest are only counted.
+const PREVIEW: usize = 20;
+
+/// What a build does with files of the old version that the new one lacks.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ExtraFiles {
+    Keep,
+    /// Deleted, as `--delete-extra` asks, once they pass the [`DeletionGuard`]
+    Delete,
+    /// Deleted without asking, as the uninstaller does with the files the patch added
+    Revert,
+}
+
+/// Checks on `--delete-extra`, which would otherwise remove anything it finds in the old folder
+/// and not in the new one, user saves included.
+pub struct DeletionGuard {
+    protect: GlobSet,
+    /// Largest share of the old files, in percent, a patch may delete
+    max_percent: u8,
+    /// `--yes`: delete without asking
+    confirmed: bool,
+}
+
+impl DeletionGuard {
+    pub fn new(protect: &[String], max_percent: u8, confirmed: bool) -> Result<Self> {
+        let mut globs = GlobSetBuilder::new();
+        for pattern in protect {
+            globs.add(Glob::new(pattern).with_context(|| format!("Invalid glob {pattern}"))?);
+        }
+        Ok(DeletionGuard {
+            protect: globs.build()?,
+            max_percent,
+            confirmed,
+        })
+    }
+
+    /// Whether `rel` matches a `--protect` pattern, so no patch deletes it.
+    pub fn is_protected(&self, rel: &str) -> bool {
+        self.protect.is_match(rel)
+    }
+
+    /// Fails when `deleted`, paths and sizes, makes up more than `--max-delete-percent` of the
+    /// `installed` old files, and otherwise lists them and asks to go ahead, unless `--yes`
+    /// already did. Without a terminal to ask on, `--yes` is required.
+    pub fn check(&self, deleted: &[(&str, u64)], installed: usize) -> Result<()> {
+        if deleted.is_empty() {
+            return Ok(());
+        }
+        let count = deleted.len();
+        if count * 100 > installed * usize::from(self.max_percent) {
+            anyhow::bail!(
+                "--delete-extra would delete {count} of the {installed} files in the old version, more than \
+                 --max-delete-percent {}%; check the folders, --protect what must stay, or raise the limit",
+                self.max_percent
+            );
+        }
+        if self.confirmed {
+            return Ok(());
+        }
+        if !io::stdin().is_terminal() {
+            anyhow::bail!(
+                "--delete-extra would delete {count} files; pass --yes to confirm when not run interactively"
+            );
+        }
+
+        let bytes = deleted.iter().map(|(_, size)| size).sum::<u64>();
+        eprintln!(
+            "--delete-extra will delete {count} files ({}) from installations:",
+            HumanBytes(bytes)
+        );
+        let mut listed = deleted.to_vec();
+        listed.sort_unstable();
+        for (path, _) in listed.iter().take(PREVIEW) {
+            eprintln!("  {path}");
+        }
+        if count > PREVIEW {
+            eprintln!("  ... and {} more", count - PREVIEW);
+        }
+        eprint!("Delete them? [y/N] ");
+        io::stderr().flush()?;
+        let mut answer = String::new();
+        io::stdin().lock().read_line(&mut answer)?;
+        if !answer.trim().eq_ignore_ascii_case("y") {
+            anyhow::bail!("Deleting extra files declined; no patch was built");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+use std::fmt::Write;
+
+use anyhow::Result;
+
+use patch_apply::RECEIPT_FILE;
+use patch_types::{Manifest, RegistryHive};
+
+use crate::output::Destination;
+use crate::publish::write_sidecar;
+
+/// Exit status of the install script when the patcher fails for a reason without its own code,
+/// MSI's "fatal error during installation", which deployment tools report as a failure.
+const EXIT_FAILED: i32 = 1603;
+/// Exit status of the install script when it finds no folder to patch.
+const EXIT_NO_INSTALL_DIR: i32 = 2;
+/// Exit status of the install script by the `error_kind` of the patcher's report, as
+/// `PatchError::kind` names them, with what each means.
+const EXIT_CODES: &[(&str, i32, &str)] = &[
+    (
+        "missing_file",
+        10,
+        "A file the patch needs is missing from the installation",
+    ),
+    (
+        "hash_mismatch",
+        11,
+        "A file in the installation matches neither the old nor the new version",
+    ),
+    (
+        "corrupt_chunks",
+        12,
+        "A file in the installation is damaged",
+    ),
+    (
+        "conflicts",
+        13,
+        "Several files in the installation match neither version",
+    ),
+    (
+        "io",
+        14,
+        "A file could not be read or written: access denied, disk full or file in use",
+    ),
+    ("decode", 15, "The patch is damaged; download it again"),
+    (
+        "invalid_index",
+        15,
+        "The patch is damaged; download it again",
+    ),
+];
+
+/// Parent of every Add/Remove Programs entry.
+const UNINSTALL_KEY: &str = r"Software\Microsoft\Windows\CurrentVersion\Uninstall";
+
+/// Writes `<output>.install.ps1`, which runs the patcher unattended on a folder and turns its
+/// report into an exit code, `<output>.detect.ps1`, which tells Intune or Configuration Manager
+/// whether `to_version` is installed, and `<output>.exit-codes.csv`, listing the install
+/// script's exit codes for the deployment type. Everything in them comes from `manifest`.
+pub fn write_deploy_scripts(manifest: &Manifest, output: &Destination) -> Result<()> {
+    let file_name = output.file_name();
+    write_sidecar(
+        output,
+        ".install.ps1",
+        install_script(manifest, &file_name)?.as_bytes(),
+    )?;
+    write_sidecar(
+        output,
+        ".detect.ps1",
+        detection_script(manifest, &file_name)?.as_bytes(),
+    )?;
+
+    let mut csv = String::from("code,meaning\n0,\"Patched or already up to date\"\n");
+    writeln!(
+        csv,
+        "{EXIT_NO_INSTALL_DIR},\"No installation folder was given or found\""
+    )?;
+    let mut listed = Vec::new();
+    for &(_, code, meaning) in EXIT_CODES {
+        if !listed.contains(&code) {
+            listed.push(code);
+            writeln!(csv, "{code},\"{meaning}\"")?;
+        }
+    }
+    writeln!(
+        csv,
+        "{EXIT_FAILED},\"Patching failed for another reason; see the patcher's output\""
+    )?;
+    write_sidecar(output, ".exit-codes.csv", csv.as_bytes())?;
+
+    if manifest.markers.registry.is_none() && manifest.markers.uninstall.is_none() {
+        eprintln!(
+            "Warning: the patch records its version in no registry value (--registry-key or \
+             --uninstall-entry), so {file_name}.detect.ps1 only finds the receipt in the folder its \
+             $InstallDir names; set it before deploying"
+        );
+    }
+    Ok(())
+}
+
+/// `text` as a single-quoted PowerShell string.
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+/// PowerShell drive of a registry hive.
+fn drive(hive: RegistryHive) -> &'static str {
+    match hive {
+        RegistryHive::CurrentUser => "HKCU:",
+        RegistryHive::LocalMachine => "HKLM:",
+    }
+}
+
+/// PowerShell expression for the install folder: the location recorded in the product's
+/// Add/Remove Programs entry, or nothing when the patch has none.
+fn install_dir(manifest: &Manifest) -> String {
+    match &manifest.markers.uninstall {
+        Some(entry) => format!(
+            "(Get-ItemProperty -LiteralPath {} -ErrorAction SilentlyContinue).InstallLocation",
+            quote(&format!(
+                r"{}\{UNINSTALL_KEY}\{}",
+                drive(entry.hive),
+                entry.name
+            ))
+        ),
+        None => "''".to_string(),
+    }
+}
+
+fn install_script(manifest: &Manifest, file_name: &str) -> Result<String> {
+    let components = manifest
+        .wizard
+        .as_ref()
+        .map_or(&[][..], |wizard| wizard.components());
+    let mut ps = String::new();
+    writeln!(
+        ps,
+        "# Patches {} from {} to {} with {file_name}, unattended, for deployment tools.\n\
+         # Generated by patch_builder from the patch; the exit codes are listed in {file_name}.exit-codes.csv.\n\
+         [CmdletBinding()]\nparam(",
+        manifest.product, manifest.from_version, manifest.to_version
+    )?;
+    writeln!(
+        ps,
+        "    # Folder of the installation to patch\n    [string]$InstallDir = {},",
+        install_dir(manifest)
+    )?;
+    if !components.is_empty() {
+        let ids: Vec<&str> = components
+            .iter()
+            .map(|component| component.id.as_str())
+            .collect();
+        writeln!(
+            ps,
+            "    # Optional components to install, of: {}. By default those installed and those picked by default\n    \
+             [string[]]$Components = @(),",
+            ids.join(", ")
+        )?;
+    }
+    if manifest.eula.is_some() {
+        writeln!(
+            ps,
+            "    # Accept the patch's license agreement on behalf of the users\n    [switch]$AcceptEula,"
+        )?;
+    }
+    writeln!(
+        ps,
+        "    # More arguments for the patcher\n    [string[]]$PatcherArgs = @()\n)\n"
+    )?;
+
+    writeln!(ps, "$Product = {}", quote(&manifest.product))?;
+    writeln!(ps, "$ExitCodes = @{{")?;
+    for (kind, code, _) in EXIT_CODES {
+        writeln!(ps, "    {} = {code}", quote(kind))?;
+    }
+    writeln!(ps, "}}\n")?;
+    writeln!(
+        ps,
+        "if (-not $InstallDir -or -not (Test-Path -LiteralPath $InstallDir -PathType Container)) {{\n    \
+         Write-Output \"No installation of $Product found at '$InstallDir'\"\n    exit {EXIT_NO_INSTALL_DIR}\n}}\n"
+    )?;
+    writeln!(
+        ps,
+        "$Patcher = Join-Path $PSScriptRoot {}\n\
+         $Report = Join-Path ([IO.Path]::GetTempPath()) (\"patch-report-\" + [guid]::NewGuid() + \".json\")\n\
+         $Arguments = @('--target', $InstallDir, '--result-json', $Report, '--no-launch')",
+        quote(file_name)
+    )?;
+    if !components.is_empty() {
+        writeln!(
+            ps,
+            "if ($Components) {{ $Arguments += @('--components', ($Components -join ',')) }}"
+        )?;
+    }
+    if manifest.eula.is_some() {
+        writeln!(ps, "if ($AcceptEula) {{ $Arguments += '--accept-eula' }}")?;
+    }
+    writeln!(
+        ps,
+        "\n& $Patcher @Arguments @PatcherArgs\n\
+         if ($LASTEXITCODE -eq 0) {{\n    Remove-Item -LiteralPath $Report -ErrorAction SilentlyContinue\n    exit 0\n}}\n\
+         $Kind = $null\n\
+         if (Test-Path -LiteralPath $Report) {{\n    \
+         $Kind = (Get-Content -LiteralPath $Report -Raw | ConvertFrom-Json).error_kind\n    \
+         Remove-Item -LiteralPath $Report -ErrorAction SilentlyContinue\n}}\n\
+         if ($Kind -and $ExitCodes.ContainsKey($Kind)) {{ exit $ExitCodes[$Kind] }}\n\
+         exit {EXIT_FAILED}"
+    )?;
+    Ok(ps)
+}
+
+fn detection_script(manifest: &Manifest, file_name: &str) -> Result<String> {
+    let mut ps = String::new();
+    writeln!(
+        ps,
+        "# Detects {} {} for Intune or Configuration Manager: prints a line when it is installed,\n\
+         # nothing when it is not. Generated by patch_builder from {file_name}.\n\
+         $Product = {}\n$Version = {}",
+        manifest.product,
+        manifest.to_version,
+        quote(&manifest.product),
+        quote(&manifest.to_version)
+    )?;
+    if let Some(registry) = &manifest.markers.registry {
+        writeln!(
+            ps,
+            "\n$Value = (Get-ItemProperty -LiteralPath {} -Name {name} -ErrorAction SilentlyContinue).{name}\n\
+             if ($Value -eq $Version) {{ \"$Product $Version is installed\"; exit 0 }}",
+            quote(&format!(r"{}\{}", drive(registry.hive), registry.key)),
+            name = quote(&registry.value)
+        )?;
+    }
+    if let Some(entry) = &manifest.markers.uninstall {
+        writeln!(
+            ps,
+            "\n$Value = (Get-ItemProperty -LiteralPath {} -ErrorAction SilentlyContinue).DisplayVersion\n\
+             if ($Value -eq $Version) {{ \"$Product $Version is installed\"; exit 0 }}",
+            quote(&format!(
+                r"{}\{UNINSTALL_KEY}\{}",
+                drive(entry.hive),
+                entry.name
+            ))
+        )?;
+    }
+
+    // A later release also counts, so the deployment does not try to install this one over it
+    let later = match manifest
+        .release
+        .as_ref()
+        .and_then(|release| release.counter)
+    {
+        Some(counter) => format!(" -or $Receipt.release.counter -ge {counter}"),
+        None => String::new(),
+    };
+    writeln!(
+        ps,
+        "\n# Folder of the installation, whose receipt records the version patched to last\n\
+         $InstallDir = {}\n\
+         if ($InstallDir) {{\n    \
+         $Path = Join-Path $InstallDir {}\n    \
+         $Receipt = Get-Content -LiteralPath $Path -Raw -ErrorAction SilentlyContinue | ConvertFrom-Json\n    \
+         if ($Receipt -and $Receipt.product -eq $Product -and ($Receipt.to_version -eq $Version{later})) {{\n        \
+         \"$Product $Version is installed\"\n        exit 0\n    }}\n}}\nexit 0",
+        install_dir(manifest),
+        quote(RECEIPT_FILE)
+    )?;
+    Ok(ps)
+}
@@ -0,0 +1,121 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use similar::TextDiff;
+
+use patch_types::{FileDiff, FileEntry, Manifest, PatchKind};
+
+use crate::audit::load_manifest;
+
+/// Largest file, old or new version, whose diff `--store-diffs` stores; past this it is rarely
+/// a config anyone reviews line by line.
+const MAX_DIFF_FILE: u64 = 256 << 10;
+
+/// Unified diffs of the patched files in `files` small enough and made of text, read from
+/// both versions' folders. A file whose old version is not in `old_dir` (as with
+/// `--old-index`) is left out.
+pub fn text_diffs(files: &[FileEntry], old_dir: &Path, new_dir: &Path) -> Result<Vec<FileDiff>> {
+    let mut diffs = Vec::new();
+    for file in files {
+        if !matches!(file.kind, PatchKind::Patched { .. })
+            || file.old_size > MAX_DIFF_FILE
+            || file.new_size > MAX_DIFF_FILE
+        {
+            continue;
+        }
+        let Ok(old) = fs::read(old_dir.join(&file.path)) else {
+            continue;
+        };
+        let new_path = new_dir.join(&file.path);
+        let new = fs::read(&new_path).with_context(|| format!("Reading {}", new_path.display()))?;
+        let (Some(old), Some(new)) = (text(&old), text(&new)) else {
+            continue;
+        };
+        let unified = TextDiff::from_lines(old, new)
+            .unified_diff()
+            .context_radius(3)
+            .header(&format!("a/{}", file.path), &format!("b/{}", file.path))
+            .to_string();
+        diffs.push(FileDiff {
+            path: file.path.clone(),
+            unified,
+        });
+    }
+    Ok(diffs)
+}
+
+/// `bytes` as text, unless they are not UTF-8 or hold a NUL, as binary files do.
+fn text(bytes: &[u8]) -> Option<&str> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .filter(|text| !text.contains('\0'))
+}
+
+/// Prints what the patch at `patch` changes, and with `show_diff` the stored diff of that file.
+pub fn inspect(patch: &Path, show_diff: Option<&str>) -> Result<()> {
+    let manifest = load_manifest(patch)?;
+    if let Some(path) = show_diff {
+        let Some(diff) = manifest.diff(path) else {
+            let stored: Vec<&str> = manifest
+                .diffs
+                .iter()
+                .map(|diff| diff.path.as_str())
+                .collect();
+            if stored.is_empty() {
+                anyhow::bail!("The patch stores no diffs; build it with --store-diffs");
+            }
+            anyhow::bail!(
+                "The patch stores no diff of {path}; it has diffs of:\n  {}",
+                stored.join("\n  ")
+            );
+        };
+        print!("{}", diff.unified);
+        return Ok(());
+    }
+    print_summary(&manifest);
+    Ok(())
+}
+
+fn print_summary(manifest: &Manifest) {
+    println!(
+        "{} {} -> {} (format {})",
+        manifest.product, manifest.from_version, manifest.to_version, manifest.min_stub_version
+    );
+    let count = |kind: fn(&PatchKind) -> bool| {
+        manifest
+            .files
+            .iter()
+            .filter(|file| kind(&file.kind))
+            .count()
+    };
+    println!(
+        "  {} files patched",
+        count(|kind| matches!(
+            kind,
+            PatchKind::Patched { .. } | PatchKind::Streamed { .. } | PatchKind::Segmented { .. }
+        ))
+    );
+    println!(
+        "  {} files added",
+        count(|kind| matches!(kind, PatchKind::Added { .. }))
+    );
+    println!(
+        "  {} files deleted",
+        count(|kind| matches!(kind, PatchKind::Deleted))
+    );
+    println!(
+        "  {} files moved or copied",
+        count(|kind| matches!(kind, PatchKind::Moved { .. } | PatchKind::Copied { .. }))
+    );
+    println!(
+        "  {} files unchanged",
+        count(|kind| matches!(kind, PatchKind::Unchanged))
+    );
+    if !manifest.diffs.is_empty() {
+        println!("Diffs stored (show one with --show-diff <PATH>):");
+        for diff in &manifest.diffs {
+            println!("  {}", diff.path);
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use patch_types::{FOOTER_LEN, Footer, authenticode};
+
+/// Splits an installer back into the stub and the payload appended to it. The payload keeps
+/// its footer, so it can be applied with the stub's `--bundle`; appended to another stub, it
+/// fails the patcher's check of its own executable.
+pub fn extract_installer(
+    installer: &Path,
+    payload: Option<&Path>,
+    stub: Option<&Path>,
+) -> Result<()> {
+    let mut file =
+        File::open(installer).with_context(|| format!("Opening {}", installer.display()))?;
+    let len = file.metadata()?.len();
+    let len = authenticode::unsigned_len(&mut file, len)?;
+    if len < FOOTER_LEN {
+        anyhow::bail!("{} is too small to be a patcher", installer.display());
+    }
+
+    file.seek(SeekFrom::Start(len - FOOTER_LEN))?;
+    let mut footer = [0u8; FOOTER_LEN as usize];
+    file.read_exact(&mut footer)?;
+    let footer = Footer::from_bytes(&footer);
+    let stub_len = (len - FOOTER_LEN)
+        .checked_sub(footer.payload_len)
+        .filter(|_| footer.manifest_len <= footer.payload_len)
+        .ok_or_else(|| anyhow::anyhow!("{} has no valid patch payload", installer.display()))?;
+
+    // The manifest leads with the format it needs, readable whatever the rest looks like
+    file.seek(SeekFrom::Start(
+        stub_len + footer.payload_len - footer.manifest_len,
+    ))?;
+    let mut prefix = Vec::new();
+    (&mut file)
+        .take(footer.manifest_len.min(5))
+        .read_to_end(&mut prefix)?;
+    let format: u32 = bincode::decode_from_slice(&prefix, bincode::config::standard())
+        .context("Reading the payload format")?
+        .0;
+    println!(
+        "Stub: {stub_len} bytes, payload: {} bytes (format {format})",
+        footer.payload_len + FOOTER_LEN
+    );
+
+    if let Some(path) = stub {
+        file.seek(SeekFrom::Start(0))?;
+        copy_range(&mut file, stub_len, path)?;
+        println!("Wrote stub to {}", path.display());
+    }
+    if let Some(path) = payload {
+        file.seek(SeekFrom::Start(stub_len))?;
+        copy_range(&mut file, footer.payload_len + FOOTER_LEN, path)?;
+        println!("Wrote payload to {}", path.display());
+    }
+    Ok(())
+}
+
+/// Copies the next `len` bytes of `file` into a new file at `path`.
+fn copy_range(file: &mut File, len: u64, path: &Path) -> Result<()> {
+    let mut out =
+        BufWriter::new(File::create(path).with_context(|| format!("Creating {}", path.display()))?);
+    io::copy(&mut file.take(len), &mut out)
+        .with_context(|| format!("Writing {}", path.display()))?;
+    out.flush()?;
+    Ok(())
+}
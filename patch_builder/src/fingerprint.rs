@@ -0,0 +1,108 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use patch_apply::hash_file;
+use patch_types::normalize::Normalization;
+use patch_types::{Manifest, PatchKind, SentinelFile, VersionFingerprint};
+
+use crate::old_index::OldIndex;
+
+/// Most sentinel files per release; the stub hashes them only when verification fails.
+const MAX_SENTINELS: usize = 4;
+/// Smallest files of the old version considered as sentinels, so the stub hashes little.
+const CANDIDATES: usize = 32;
+
+/// Picks a few files whose hashes tell `versions` apart and records each release's hashes of
+/// them. Files the patch changes are preferred, smallest first; more are added only while they
+/// separate releases that still look alike. The release in `old_index`'s folder is read from
+/// the index.
+pub fn fingerprint_versions(
+    manifest: &Manifest,
+    versions: &[(String, PathBuf)],
+    old_index: Option<&OldIndex>,
+) -> Result<Vec<VersionFingerprint>> {
+    let mut candidates: Vec<(bool, u64, &str)> = manifest
+        .files
+        .iter()
+        .filter(|file| file.old_size > 0)
+        .filter_map(|file| match file.kind {
+            PatchKind::Patched { .. }
+            | PatchKind::Streamed { .. }
+            | PatchKind::Segmented { .. } => Some((false, file.old_size, file.path.as_str())),
+            PatchKind::Unchanged | PatchKind::Deleted => {
+                Some((true, file.old_size, file.path.as_str()))
+            }
+            PatchKind::Added { .. } | PatchKind::Moved { .. } | PatchKind::Copied { .. } => None,
+        })
+        .collect();
+    candidates.sort();
+    candidates.truncate(CANDIDATES);
+
+    // Hashes of each candidate in every release
+    let hashes = candidates
+        .iter()
+        .map(|&(_, _, path)| {
+            versions
+                .iter()
+                .map(
+                    |(_, dir)| match old_index.filter(|index| index.dir == *dir) {
+                        Some(index) => {
+                            Ok(index.hash(path, Normalization::None).unwrap_or([0u8; 32]))
+                        }
+                        None => sentinel_hash(&dir.join(path)),
+                    },
+                )
+                .collect::<Result<Vec<_>>>()
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut chosen: Vec<usize> = Vec::new();
+    let distinct = |chosen: &[usize]| {
+        (0..versions.len())
+            .map(|v| chosen.iter().map(|&c| hashes[c][v]).collect::<Vec<_>>())
+            .collect::<HashSet<_>>()
+            .len()
+    };
+    while chosen.len() < MAX_SENTINELS && distinct(&chosen) < versions.len() {
+        let best = (0..candidates.len())
+            .filter(|c| !chosen.contains(c))
+            .max_by_key(|&c| {
+                (
+                    distinct(&[chosen.as_slice(), &[c]].concat()),
+                    std::cmp::Reverse(c),
+                )
+            });
+        match best {
+            Some(c) if distinct(&[chosen.as_slice(), &[c]].concat()) > distinct(&chosen) => {
+                chosen.push(c)
+            }
+            _ => break,
+        }
+    }
+
+    Ok(versions
+        .iter()
+        .enumerate()
+        .map(|(v, (version, _))| VersionFingerprint {
+            version: version.clone(),
+            files: chosen
+                .iter()
+                .map(|&c| SentinelFile {
+                    path: candidates[c].2.to_string(),
+                    hash: hashes[c][v],
+                })
+                .collect(),
+        })
+        .collect())
+}
+
+/// Hash of a sentinel as the stub computes it, all zeros when the file is missing.
+fn sentinel_hash(path: &Path) -> Result<[u8; 32]> {
+    if !path.is_file() {
+        return Ok([0u8; 32]);
+    }
+    hash_file(path, Normalization::None)
+        .with_context(|| format!("Fingerprinting {}", path.display()))
+}
@@ -1,25 +1,353 @@
 use anyhow::Result;
-use std::fs::File;
+use patch_types::{
+    Compression, EntryRange, FOOTER_LEN, Footer, Manifest, PatchBundle, PatchData, PatchKind,
+    SolidFrame, authenticode, versions,
+};
+use rayon::prelude::*;
+use std::collections::HashSet;
 use std::io::Write;
-use std::path::Path;
-use bincode;
-use patch_types::PatchBundle;
 
-const PATCH_STUB_EXE: &[u8] = include_bytes!("../../target/release/patch_stub.exe");
+use crate::version_info::stamp_version;
 
-pub fn build_installer_exe(bundle: &PatchBundle, output: &Path) -> Result<()> {
-    let mut out = File::create(output)?;
+/// Entry bytes compressed in parallel before the results are written, bounding how many
+/// compressed copies are held at once.
+const WINDOW_BYTES: usize = 512 * 1024 * 1024;
+/// Entries up to this size are compressed against a dictionary trained on them, since zstd
+/// finds little to work with inside one small config or shader.
+const SMALL_ENTRY: usize = 64 * 1024;
+/// Fewest small entries worth training a dictionary on.
+const MIN_SAMPLES: usize = 64;
+/// Largest dictionary trained, zstd's usual size.
+const DICTIONARY_SIZE: usize = 110 * 1024;
 
-    // Write stub
-    out.write_all(PATCH_STUB_EXE)?;
+/// Writes the stub followed by the payload. With `compression_level` set, every entry is
+/// zstd-compressed at that level, several at a time; when there are many small entries, they
+/// are compressed against a dictionary trained on them, written ahead of the entries. With
+/// `solid_frame` as well, small entries are instead compressed together in frames of about
+/// that many bytes, in the order the patcher reads them. Entries are consumed as they are
+/// written, leaving `bundle.entries` empty.
+pub fn build_installer_exe(
+    stub: &[u8],
+    bundle: &mut PatchBundle,
+    out: &mut impl Write,
+    compression_level: Option<i32>,
+    solid_frame: Option<usize>,
+) -> Result<()> {
+    let config = bincode::config::standard();
 
-    // Serialize bundle
-    let bundle_bytes = bincode::encode_to_vec(bundle, bincode::config::standard())?;
-    out.write_all(&bundle_bytes)?;
+    // Write stub, its version resource stamped with this patch's versions
+    let stamped = tracing::info_span!("stamp").in_scope(|| stamp_version(stub, &bundle.manifest));
+    out.write_all(&stamped)?;
+    // Recorded in the manifest, so the patcher can tell its code was modified after this build
+    bundle.manifest.stub_hash = Some(authenticode::stub_hash(
+        &mut stamped.as_slice(),
+        stamped.len() as u64,
+    )?);
 
-    // Append length footer
-    let len = bundle_bytes.len() as u64;
-    out.write_all(&len.to_le_bytes())?;
+    // Write entries one by one, recording their ranges so they can be fetched individually
+    let mut offset = 0u64;
+    bundle.manifest.entries.clear();
+    bundle.manifest.frames.clear();
+    match compression_level {
+        None => {
+            bundle.manifest.compression = Compression::None;
+            for entry in std::mem::take(&mut bundle.entries) {
+                let mut hashing = HashingWriter {
+                    inner: &mut *out,
+                    hasher: blake3::Hasher::new(),
+                };
+                let len = bincode::encode_into_std_write(&entry, &mut hashing, config)? as u64;
+                let hash = *hashing.hasher.finalize().as_bytes();
+                bundle.manifest.entries.push(EntryRange {
+                    offset,
+                    len,
+                    hash,
+                    dictionary: false,
+                });
+                offset += len;
+            }
+        }
+        Some(level) => {
+            bundle.manifest.compression = Compression::Zstd;
+            // Small entries in shared frames leave a dictionary nothing to do
+            let dictionary = match solid_frame {
+                Some(_) => None,
+                None => tracing::info_span!("dictionary")
+                    .in_scope(|| train_dictionary(&bundle.entries, level))?,
+            };
+            let prepared = dictionary
+                .as_ref()
+                .map(|dictionary| zstd::dict::EncoderDictionary::copy(dictionary, level));
+            if let Some(dictionary) = &dictionary {
+                out.write_all(dictionary)?;
+                let len = dictionary.len() as u64;
+                let hash = *blake3::hash(dictionary).as_bytes();
+                bundle.manifest.dictionary = Some(EntryRange {
+                    offset,
+                    len,
+                    hash,
+                    dictionary: false,
+                });
+                offset += len;
+            }
+            let max_window = 2 * rayon::current_num_threads();
+            let unplaced = EntryRange {
+                offset: 0,
+                len: 0,
+                hash: [0; 32],
+                dictionary: false,
+            };
+            bundle.manifest.entries = vec![unplaced; bundle.entries.len()];
+            let entries = std::mem::take(&mut bundle.entries);
+            let mut groups = frame_groups(&bundle.manifest, entries, solid_frame)
+                .into_iter()
+                .peekable();
+            while groups.peek().is_some() {
+                // At least one group, so one bigger than the window is still written
+                let mut window = Vec::new();
+                let mut window_bytes = 0;
+                while let Some(group) = groups.next_if(|_| {
+                    window.is_empty() || (window.len() < max_window && window_bytes < WINDOW_BYTES)
+                }) {
+                    window_bytes += group
+                        .iter()
+                        .map(|(_, entry)| data_len(entry))
+                        .sum::<usize>();
+                    window.push(group);
+                }
+                let compress = tracing::info_span!("compress").entered();
+                let compressed = window
+                    .into_par_iter()
+                    .map(|mut group| {
+                        if group.len() > 1 {
+                            return compress_frame(group, level);
+                        }
+                        let (idx, entry) = group.pop().expect("groups are never empty");
+                        let small = prepared
+                            .as_ref()
+                            .filter(|_| data_len(&entry) <= SMALL_ENTRY);
+                        // Encoded straight into the compressor, without an uncompressed copy
+                        let mut encoder = match small {
+                            Some(prepared) => {
+                                zstd::Encoder::with_prepared_dictionary(Vec::new(), prepared)?
+                            }
+                            None => zstd::Encoder::new(Vec::new(), level)?,
+                        };
+                        bincode::encode_into_std_write(&entry, &mut encoder, config)?;
+                        Ok(Compressed::Entry {
+                            idx,
+                            bytes: encoder.finish()?,
+                            dictionary: small.is_some(),
+                        })
+                    })
+                    .collect::<Result<Vec<Compressed>>>()?;
+                drop(compress);
+                let _write = tracing::info_span!("write").entered();
+                for compressed in compressed {
+                    let bytes = match &compressed {
+                        Compressed::Entry { bytes, .. } | Compressed::Frame { bytes, .. } => bytes,
+                    };
+                    out.write_all(bytes)?;
+                    let len = bytes.len() as u64;
+                    let hash = *blake3::hash(bytes).as_bytes();
+                    match compressed {
+                        Compressed::Entry {
+                            idx, dictionary, ..
+                        } => {
+                            bundle.manifest.entries[idx] = EntryRange {
+                                offset,
+                                len,
+                                hash,
+                                dictionary,
+                            };
+                        }
+                        Compressed::Frame { members, .. } => {
+                            let entries = members.iter().map(|&(idx, _)| idx).collect();
+                            for (idx, range) in members {
+                                bundle.manifest.entries[idx] = range;
+                            }
+                            let range = EntryRange {
+                                offset,
+                                len,
+                                hash,
+                                dictionary: false,
+                            };
+                            bundle.manifest.frames.push(SolidFrame { range, entries });
+                        }
+                    }
+                    offset += len;
+                }
+            }
+        }
+    }
+
+    // Serialize manifest, in the layout of the format it was built for
+    let manifest = versions::encode_manifest(&bundle.manifest)?;
+    let manifest_len = manifest.len() as u64;
+    // Zeros ahead of the manifest end the installer at a multiple of eight bytes, where
+    // signing appends its certificate table without padding the footer away from the end
+    let end = stamped.len() as u64 + offset + manifest_len + FOOTER_LEN;
+    let padding = end.next_multiple_of(authenticode::ALIGNMENT) - end;
+    out.write_all(&vec![0; padding as usize])?;
+    out.write_all(&manifest)?;
+
+    // Append footer
+    let footer = Footer {
+        payload_len: offset + padding + manifest_len,
+        manifest_len,
+    };
+    out.write_all(&footer.to_bytes())?;
+    out.flush()?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// An entry compressed alone, or small entries compressed together in one frame.
+enum Compressed {
+    Entry {
+        idx: usize,
+        bytes: Vec<u8>,
+        dictionary: bool,
+    },
+    /// `members` locate each entry within the decompressed frame
+    Frame {
+        bytes: Vec<u8>,
+        members: Vec<(usize, EntryRange)>,
+    },
+}
+
+/// Splits `entries` into what is compressed as one frame: every large entry alone, and with
+/// `solid_frame`, runs of small ones of about that many bytes together. Entries go in the
+/// order the patcher reads them, files from the largest down, so the entries of one frame are
+/// needed around the same time and the patcher decompresses each frame about once.
+fn frame_groups(
+    manifest: &Manifest,
+    entries: Vec<PatchData>,
+    solid_frame: Option<usize>,
+) -> Vec<Vec<(usize, PatchData)>> {
+    let Some(limit) = solid_frame else {
+        return entries
+            .into_iter()
+            .enumerate()
+            .map(|entry| vec![entry])
+            .collect();
+    };
+    let mut files: Vec<_> = manifest.files.iter().collect();
+    files.sort_by_key(|file| std::cmp::Reverse(file.old_size + file.new_size));
+    let read_order = files.iter().flat_map(|file| {
+        let idx = match file.kind {
+            PatchKind::Added { idx } | PatchKind::Patched { idx, .. } => Some(idx),
+            _ => None,
+        };
+        idx.into_iter().chain(file.full_copy)
+    });
+    let mut seen = HashSet::new();
+    let order: Vec<usize> = read_order
+        .chain(0..entries.len())
+        .filter(|&idx| idx < entries.len() && seen.insert(idx))
+        .collect();
+
+    let mut entries: Vec<Option<PatchData>> = entries.into_iter().map(Some).collect();
+    let mut groups = Vec::new();
+    let mut frame = Vec::new();
+    let mut frame_bytes = 0;
+    for idx in order {
+        let entry = entries[idx].take().expect("each entry is taken once");
+        let len = data_len(&entry);
+        if len > SMALL_ENTRY {
+            groups.push(vec![(idx, entry)]);
+            continue;
+        }
+        frame.push((idx, entry));
+        frame_bytes += len;
+        if frame_bytes >= limit {
+            groups.push(std::mem::take(&mut frame));
+            frame_bytes = 0;
+        }
+    }
+    if !frame.is_empty() {
+        groups.push(frame);
+    }
+    groups
+}
+
+/// Encodes the entries of `group` one after another and compresses them as one frame.
+fn compress_frame(group: Vec<(usize, PatchData)>, level: i32) -> Result<Compressed> {
+    let config = bincode::config::standard();
+    let mut raw = Vec::new();
+    let mut members = Vec::with_capacity(group.len());
+    for (idx, entry) in group {
+        let offset = raw.len();
+        bincode::encode_into_std_write(&entry, &mut raw, config)?;
+        let encoded = &raw[offset..];
+        members.push((
+            idx,
+            EntryRange {
+                offset: offset as u64,
+                len: encoded.len() as u64,
+                hash: *blake3::hash(encoded).as_bytes(),
+                dictionary: false,
+            },
+        ));
+    }
+    let bytes = zstd::bulk::compress(&raw, level)?;
+    Ok(Compressed::Frame { bytes, members })
+}
+
+/// Passes writes on to `inner`, hashing what goes through.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: blake3::Hasher,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A zstd dictionary trained on the encoded small entries, when there are enough of them and
+/// it saves more than its own size.
+fn train_dictionary(entries: &[PatchData], level: i32) -> Result<Option<Vec<u8>>> {
+    let config = bincode::config::standard();
+    let samples = entries
+        .par_iter()
+        .filter(|entry| data_len(entry) <= SMALL_ENTRY)
+        .map(|entry| Ok(bincode::encode_to_vec(entry, config)?))
+        .collect::<Result<Vec<Vec<u8>>>>()?;
+    if samples.len() < MIN_SAMPLES {
+        return Ok(None);
+    }
+    // Samples too alike or too few bytes to learn from leave zstd nothing to train on
+    let Ok(dictionary) = zstd::dict::from_samples(&samples, DICTIONARY_SIZE) else {
+        return Ok(None);
+    };
+    let prepared = zstd::dict::EncoderDictionary::copy(&dictionary, level);
+    let (alone, against) = samples
+        .par_iter()
+        .map(|sample| {
+            let alone = zstd::bulk::compress(sample, level)?.len();
+            let against = zstd::bulk::Compressor::with_prepared_dictionary(&prepared)?
+                .compress(sample)?
+                .len();
+            Ok((alone, against))
+        })
+        .collect::<Result<Vec<(usize, usize)>>>()?
+        .into_iter()
+        .fold((0, 0), |(alone, against), (a, b)| (alone + a, against + b));
+    Ok((against + dictionary.len() < alone).then_some(dictionary))
+}
+
+fn data_len(data: &PatchData) -> usize {
+    match data {
+        PatchData::Xdelta(bytes) | PatchData::Full(bytes) | PatchData::CompressedFull(bytes) => {
+            bytes.len()
+        }
+    }
+}
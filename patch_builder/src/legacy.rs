@@ -0,0 +1,132 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use walkdir::WalkDir;
+
+/// The new version recreated in a temporary folder, removed again when this is dropped, and
+/// the patches it was decoded from.
+pub struct RebuiltVersion {
+    pub dir: PathBuf,
+    /// Each patch with an old file to apply to, by the hashes of that file and of the file it
+    /// decodes to, so the build stores it as it is rather than diffing the two again
+    pub deltas: HashMap<([u8; 32], [u8; 32]), PathBuf>,
+}
+
+impl Drop for RebuiltVersion {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Recreates the new version from `old_dir` and a folder of patches made with the xdelta3
+/// command line. `list` names every file of the new version, one path relative to the
+/// installation per line; each is decoded from `<path>.xdelta` in `xdelta_dir`, copied from
+/// `<path>` there when it is stored whole, or else kept as it is in `old_dir`. Old files the
+/// list leaves out are not part of the new version.
+pub fn rebuild_new_version(
+    old_dir: &Path,
+    xdelta_dir: &Path,
+    list: &Path,
+) -> Result<RebuiltVersion> {
+    let dir = std::env::temp_dir().join(format!("patch_builder-{}.legacy", std::process::id()));
+    if dir.exists() {
+        fs::remove_dir_all(&dir).with_context(|| format!("Removing {}", dir.display()))?;
+    }
+    let mut rebuilt = RebuiltVersion {
+        dir,
+        deltas: HashMap::new(),
+    };
+    let out = rebuilt.dir.clone();
+    let text = fs::read_to_string(list)
+        .with_context(|| format!("Reading file list {}", list.display()))?;
+    let mut listed = HashSet::new();
+    for (line, path) in text.lines().enumerate() {
+        let path = path.trim().replace('\\', "/");
+        if path.is_empty() || path.starts_with('#') {
+            continue;
+        }
+        let inside = Path::new(&path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+        if !inside {
+            anyhow::bail!(
+                "{}:{}: {path} is not a path inside the installation",
+                list.display(),
+                line + 1
+            );
+        }
+        if !listed.insert(path.clone()) {
+            anyhow::bail!("{}:{}: {path} is listed twice", list.display(), line + 1);
+        }
+
+        let target = out.join(&path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("Creating {}", parent.display()))?;
+        }
+        let old = old_dir.join(&path);
+        let delta = xdelta_dir.join(format!("{path}.xdelta"));
+        let whole = xdelta_dir.join(&path);
+        if delta.is_file() {
+            let patch = fs::read(&delta).with_context(|| format!("Reading {}", delta.display()))?;
+            // A patch made without a source file decodes against nothing
+            let source = if old.is_file() {
+                fs::read(&old).with_context(|| format!("Reading {}", old.display()))?
+            } else {
+                Vec::new()
+            };
+            let decoded = xdelta3::decode(&patch, &source).ok_or_else(|| {
+                anyhow::anyhow!("{} does not apply to {}", delta.display(), old.display())
+            })?;
+            if !source.is_empty() {
+                let hashes = (
+                    *blake3::hash(&source).as_bytes(),
+                    *blake3::hash(&decoded).as_bytes(),
+                );
+                rebuilt.deltas.insert(hashes, delta.clone());
+            }
+            fs::write(&target, decoded).with_context(|| format!("Writing {}", target.display()))?;
+        } else if whole.is_file() {
+            fs::copy(&whole, &target).with_context(|| format!("Copying {}", whole.display()))?;
+        } else if old.is_file() {
+            // Linked where the filesystem allows, since most files of a release are unchanged
+            if fs::hard_link(&old, &target).is_err() {
+                fs::copy(&old, &target).with_context(|| format!("Copying {}", old.display()))?;
+            }
+        } else {
+            anyhow::bail!(
+                "{}:{}: {path} has no {path}.xdelta or full copy in {}, and is not in {}",
+                list.display(),
+                line + 1,
+                xdelta_dir.display(),
+                old_dir.display()
+            );
+        }
+    }
+
+    // A delta the list does not name would silently be left out of the patch
+    let mut unlisted = Vec::new();
+    for entry in WalkDir::new(xdelta_dir) {
+        let entry = entry?;
+        let rel = entry
+            .path()
+            .strip_prefix(xdelta_dir)?
+            .to_string_lossy()
+            .replace('\\', "/");
+        if let Some(path) = rel.strip_suffix(".xdelta")
+            && entry.file_type().is_file()
+            && !listed.contains(path)
+        {
+            unlisted.push(rel);
+        }
+    }
+    if !unlisted.is_empty() {
+        anyhow::bail!(
+            "{} names no file for these patches:\n  {}",
+            list.display(),
+            unlisted.join("\n  ")
+        );
+    }
+    Ok(rebuilt)
+}
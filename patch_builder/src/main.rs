@@ -1,301 +1,2140 @@
+mod archive;
+mod audit;
+mod checkpoint;
+mod chunks;
+mod deletions;
+mod deploy;
+mod diffs;
+mod extract;
+mod fingerprint;
 mod installer;
+mod legacy;
+mod old_index;
+mod output;
+mod profile;
+mod publish;
+mod scan;
+mod ssh;
+mod store;
+mod stub;
+mod transform;
+mod update_info;
+mod version_info;
+mod wizard;
+mod xdelta;
 
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
 use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::{Context, Result};
-use clap::Parser;
-use indicatif::{ProgressBar, ProgressStyle, MultiProgress, ProgressState};
-use path_slash::PathExt as _;
+use clap::{Parser, Subcommand, ValueEnum};
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use rayon::prelude::*;
 use rayon::{current_num_threads, current_thread_index};
-use walkdir::WalkDir;
+use tracing::info_span;
 
+use crate::archive::build_zip_archive;
+use crate::audit::verify_install;
+use crate::checkpoint::Checkpoint;
+use crate::chunks::{ChunkEntry, Chunks, build_chunks, build_segmented, build_streamed};
+use crate::deletions::{DeletionGuard, ExtraFiles};
+use crate::deploy::write_deploy_scripts;
+use crate::diffs::{inspect, text_diffs};
+use crate::extract::extract_installer;
+use crate::fingerprint::fingerprint_versions;
 use crate::installer::build_installer_exe;
-use patch_types::{FileEntry, Manifest, PatchBundle, PatchData, PatchKind};
+use crate::legacy::rebuild_new_version;
+use crate::old_index::{OldIndex, write_index};
+use crate::output::Destination;
+use crate::profile::Profile;
+use crate::publish::write_metadata;
+use crate::scan::{FileRec, ScanFilter, SkipCounts, scan_dir};
+use crate::store::{EntryKey, EntryStore, build_entry};
+use crate::stub::load_stub;
+use crate::transform::{TransformRules, create_transformed_patch};
+use crate::update_info::{generate_key, sign_release, write_update_info};
+use crate::wizard::load_wizard;
+use patch_progress::{Frontend, ProgressDisplay};
+use patch_types::buffers;
+use patch_types::normalize::Normalization;
+use patch_types::schedule::{self, largest_first};
+use patch_types::versions;
+use patch_types::{
+    ApplyCost, CHUNK_SIZE, CHUNKED_MIN, ChunkedFile, Compression, FORMAT_VERSION, FileEntry,
+    MainExecutable, Manifest, PatchBundle, PatchData, PatchKind, PayloadRef,
+    REQUIRED_METADATA_PREFIX, RegistryHive, RegistryMarker, STREAMED_MIN, UninstallEntry,
+    VersionMarkers, case_collisions,
+};
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// Folder with the old version
-    old_dir: PathBuf,
+    #[arg(required = true)]
+    old_dir: Option<PathBuf>,
     /// Folder with the new version
-    new_dir: PathBuf,
-    /// Output patch executable
-    output: PathBuf,
-    /// Product name
-    #[arg(long)]
-    product: String,
+    #[arg(required = true)]
+    new_dir: Option<PathBuf>,
+    /// Output patch executable: a path, s3://bucket/key, an http(s) URL to PUT to, or - for
+    /// standard output
+    #[arg(required = true)]
+    output: Option<Destination>,
+    /// From Version String
+    #[arg(long, required = true)]
+    from_version: Option<String>,
+    /// To Version String
+    #[arg(long, required = true)]
+    to_version: Option<String>,
+    /// Sizes and hashes of the old version, from `index` or a previous patch to it, so old_dir
+    /// need only hold the files that changed
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["checkpoint", "uninstall_entry"])]
+    old_index: Option<PathBuf>,
+    #[command(flatten)]
+    build: BuildArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Build patchers from every prior version in a versions folder to the latest one
+    Matrix(Box<MatrixArgs>),
+    /// Split an installer into its stub and payload, e.g. to re-sign the stub or migrate the payload
+    Extract(ExtractArgs),
+    /// Check a folder against a patch and report every file not at its patched state as JSON
+    VerifyInstall(VerifyInstallArgs),
+    /// Create an Ed25519 key for signing update descriptors and print its public key
+    Keygen(KeygenArgs),
+    /// Record the size and hash of every file in a release, for building from it with --old-index
+    Index(IndexArgs),
+    /// Build a patcher from a folder of <file>.xdelta patches made with the xdelta3 command line
+    FromXdelta(Box<FromXdeltaArgs>),
+    /// Send a patcher to servers over SSH and apply it there, showing their progress here
+    Deploy(DeployArgs),
+    /// Print what a patch changes, or the diff it stores of a text file
+    Inspect(InspectArgs),
+}
+
+#[derive(clap::Args)]
+struct InspectArgs {
+    /// Installer, payload or zip-format patch, or the manifest.json of a zip-format patch
+    patch: PathBuf,
+    /// Print the stored diff of this file (a path relative to the installation), as built with --store-diffs
+    #[arg(long, value_name = "PATH")]
+    show_diff: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct DeployArgs {
+    /// Patcher to run, built with a stub for the servers' platform (see --stub-target)
+    patcher: PathBuf,
+    /// Server to patch, as user@host or a host from the SSH config; repeatable
+    #[arg(long = "host", value_name = "USER@HOST", required = true)]
+    hosts: Vec<String>,
+    /// Installation folder on the servers
+    #[arg(long, value_name = "DIR")]
+    target: String,
+    /// Servers patched at once
+    #[arg(long, value_name = "N", default_value_t = 4)]
+    parallel: usize,
+    /// Argument passed on to ssh, such as -i with a key file as the next one; repeatable
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    ssh_arg: Vec<String>,
+    /// Argument passed on to the patcher, such as --accept-eula or --snapshot; repeatable
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    patcher_arg: Vec<String>,
+}
+
+#[derive(clap::Args)]
+struct FromXdeltaArgs {
+    /// Folder with the old version the patches apply to
+    old_dir: PathBuf,
+    /// Folder of <file>.xdelta patches, and full copies of files stored whole
+    xdelta_dir: PathBuf,
+    /// Output patch executable: a path, s3://bucket/key, an http(s) URL to PUT to, or - for
+    /// standard output
+    output: Destination,
+    /// Every file of the new version, one path per line relative to the installation
+    #[arg(long, value_name = "FILE")]
+    files: PathBuf,
     /// From Version String
     #[arg(long)]
     from_version: String,
     /// To Version String
     #[arg(long)]
     to_version: String,
+    #[command(flatten)]
+    build: BuildArgs,
+}
+
+#[derive(clap::Args)]
+struct IndexArgs {
+    /// Folder with the release
+    dir: PathBuf,
+    /// Write the index here
+    output: PathBuf,
+}
+
+#[derive(clap::Args)]
+struct KeygenArgs {
+    /// Write the private key (PKCS#8) here; keep it out of the published files
+    key: PathBuf,
+}
+
+#[derive(clap::Args)]
+#[command(group = clap::ArgGroup::new("parts").required(true).multiple(true))]
+struct ExtractArgs {
+    /// Installer built by patch_builder
+    installer: PathBuf,
+    /// Write the payload (entries, manifest and footer) here; the stub applies it with --bundle
+    #[arg(long, value_name = "PATH", group = "parts")]
+    payload: Option<PathBuf>,
+    /// Write the stub executable here
+    #[arg(long, value_name = "PATH", group = "parts")]
+    stub: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct VerifyInstallArgs {
+    /// Installer, payload or zip-format patch, or the manifest.json of a zip-format patch
+    manifest: PathBuf,
+    /// Folder to check
+    dir: PathBuf,
+    /// Write the report here instead of stdout
+    #[arg(long, value_name = "PATH")]
+    report: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct MatrixArgs {
+    /// Folder with one subfolder per release, named after its version
+    #[arg(long, value_name = "DIR")]
+    versions_dir: PathBuf,
+    /// Version (subfolder name) every patcher updates to
+    #[arg(long, value_name = "VERSION")]
+    latest: String,
+    /// Only build from these versions instead of every other subfolder
+    #[arg(long, value_name = "VERSION")]
+    from: Vec<String>,
+    /// Folder (or s3:// or http(s) prefix) receiving <product>_<from>_to_<latest>.exe (or .zip) for each pair
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    output_dir: Destination,
+    #[command(flatten)]
+    build: BuildArgs,
+}
+
+/// Options shared by single and matrix builds.
+#[derive(clap::Args)]
+struct BuildArgs {
+    /// Product name
+    #[arg(long, required = true)]
+    product: Option<String>,
     /// If set, delete files that exist in old_dir but are not present in new_dir
     #[arg(short = 'd', long)]
     delete_extra: bool,
+    /// Never delete old files matching this glob (e.g. saves/**), even with --delete-extra
+    #[arg(long, value_name = "GLOB")]
+    protect: Vec<String>,
+    /// Fail when --delete-extra would delete more than this percentage of the old version's files
+    #[arg(long, value_name = "PCT", default_value_t = 25, value_parser = clap::value_parser!(u8).range(0..=100))]
+    max_delete_percent: u8,
+    /// Delete the files --delete-extra finds without listing them and asking first
+    #[arg(short = 'y', long)]
+    yes: bool,
+    /// Only warn about paths that differ only by case instead of failing
+    #[arg(long)]
+    allow_case_collisions: bool,
+    /// Match old and new files whose paths differ only by letter case, keeping the new casing
+    #[arg(long)]
+    ignore_path_case: bool,
+    /// Store new files identical to an old file elsewhere in the tree as a copy of that file,
+    /// made on the user's disk, instead of their full data
+    #[arg(long)]
+    copy_from_old: bool,
+    /// Also store a compressed full copy of patched files matching this glob, used when the base file is corrupt
+    #[arg(long, value_name = "GLOB")]
+    include_full_fallback: Vec<String>,
+    /// Also store a compressed full copy of every file, so the patcher installs the new version
+    /// into an empty folder, or over a broken installation, as well as updating the old one
+    #[arg(long)]
+    full_install: bool,
+    /// Ignore the link timestamp and checksum of PE files matching this glob when comparing
+    /// them, so a rebuild with no other changes is left as it is
+    #[arg(long, value_name = "GLOB")]
+    normalize_pe: Vec<String>,
+    /// Skip hidden files and folders (dotfiles, and the hidden attribute on Windows)
+    #[arg(long)]
+    skip_hidden: bool,
+    /// Skip system files (Thumbs.db, desktop.ini, .DS_Store, and the system attribute on Windows)
+    #[arg(long)]
+    skip_system: bool,
+    /// Skip 0-byte placeholder files
+    #[arg(long)]
+    skip_empty: bool,
+    /// Skip files smaller than this many bytes
+    #[arg(long, value_name = "BYTES")]
+    min_size: Option<u64>,
+    /// Skip files larger than this many bytes
+    #[arg(long, value_name = "BYTES")]
+    max_size: Option<u64>,
+    /// File in the install dir (e.g. version.txt) that is set to to_version after patching
+    #[arg(long, value_name = "REL_PATH")]
+    version_file: Option<String>,
+    /// Windows registry key updated after patching, e.g. HKCU\Software\Company\Product
+    #[arg(long, value_name = "KEY", requires = "registry_value")]
+    registry_key: Option<String>,
+    /// Name of the registry value under --registry-key that receives to_version
+    #[arg(long, value_name = "NAME", requires = "registry_key")]
+    registry_value: Option<String>,
+    /// Register the product in Add/Remove Programs under this key, e.g. HKLM\ExampleGame, with an uninstaller that reverts the patch
+    #[arg(long, value_name = "KEY")]
+    uninstall_entry: Option<String>,
+    /// Publisher shown in the Add/Remove Programs entry
+    #[arg(long, value_name = "NAME", requires = "uninstall_entry")]
+    publisher: Option<String>,
+    /// Main executable in the install dir, which the patcher offers to start once done
+    #[arg(long, value_name = "REL_PATH")]
+    main_exe: Option<String>,
+    /// Path the main executable had in the old version, when it was renamed with changes, so
+    /// the patcher can point Windows shortcuts at the new one. Detected for unchanged renames
+    #[arg(long, value_name = "REL_PATH", requires = "main_exe")]
+    previous_main_exe: Option<String>,
+    /// Text file with a license agreement the user must accept before the patch is applied
+    #[arg(long, value_name = "FILE")]
+    eula: Option<PathBuf>,
+    /// JSON file defining the install wizard the patcher shows: welcome, eula, directory,
+    /// components and confirm pages, in the order listed
+    #[arg(long, value_name = "FILE")]
+    wizard: Option<PathBuf>,
+    /// Path in the install dir where the reverting uninstaller is placed
+    #[arg(long, value_name = "REL_PATH", default_value = "patch_uninstall.exe")]
+    uninstaller: String,
+    /// Size/speed trade-off for the generated patch; without one, deltas are made at xdelta's
+    /// default level and entries are stored uncompressed, on every core
+    #[arg(long, value_enum)]
+    preset: Option<Preset>,
+    /// Override the preset's zstd level for entries (0 disables secondary compression)
+    #[arg(long, value_name = "LEVEL")]
+    compression_level: Option<i32>,
+    /// Override the preset's xdelta compression level: how hard the old file is searched for
+    /// matches, from 1 (fastest) to 9 (smallest deltas)
+    #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(u8).range(1..=9))]
+    xdelta_level: Option<u8>,
+    /// Compress entries of 64 KiB or less together in zstd frames of about this many bytes,
+    /// instead of one by one against a shared dictionary; large entries stay in frames of their own
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    solid_frame: Option<u64>,
+    /// Override the preset's number of worker threads
+    #[arg(long)]
+    threads: Option<usize>,
+    /// Also store a readable diff of every patched text file of 256 KiB or less, shown by
+    /// `inspect --show-diff` and the patcher's --preview
+    #[arg(long)]
+    store_diffs: bool,
+    /// Split changed files of at least this many bytes into 4 MiB segments diffed one by one,
+    /// which the patcher applies in parallel and resumes segment by segment
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(CHUNKED_MIN..))]
+    segment: Option<u64>,
+    /// Output format: a self-applying executable, or a zip with manifest.json for other tooling
+    #[arg(long, value_enum, default_value_t = OutputFormat::Exe)]
+    format: OutputFormat,
+    /// Write the patch in this bundle format, so patchers as old as it can apply it; fails
+    /// when the patch needs something added later. Defaults to the newest
+    #[arg(long, value_name = "N", default_value_t = FORMAT_VERSION)]
+    format_version: u32,
+    /// Stub executable to build patchers from, instead of the patch_stub built next to this one
+    #[arg(long, value_name = "PATH", conflicts_with = "stub_target")]
+    stub: Option<PathBuf>,
+    /// Build the stub for this target triple (e.g. x86_64-pc-windows-msvc) from the workspace
+    /// this builder was compiled from, and use the fresh binary
+    #[arg(long, value_name = "TRIPLE")]
+    stub_target: Option<String>,
+    /// Folder caching built entries by content hash, reused across builds for other versions
+    #[arg(long, value_name = "DIR")]
+    store: Option<PathBuf>,
+    /// Record finished files in this folder as the build runs, so a rerun after a crash resumes
+    /// instead of starting over. Removed once the build succeeds
+    #[arg(long, value_name = "DIR")]
+    checkpoint: Option<PathBuf>,
+    /// JSON file of {"glob", "normalize", "restore"} rules: commands that normalize matching files before diffing
+    #[arg(long, value_name = "FILE")]
+    transforms: Option<PathBuf>,
+    /// Modification time given to files the patcher writes
+    #[arg(long, value_enum, default_value_t = MtimeMode::Apply)]
+    mtimes: MtimeMode,
+    /// Which files the patcher writes with their runs of zeros left as holes
+    #[arg(long, value_enum, default_value_t = SparseMode::Auto)]
+    sparse: SparseMode,
+    /// Folder of another release, so the patcher can name it when it finds it installed
+    #[arg(long, value_name = "VERSION=DIR", value_parser = parse_version_dir)]
+    fingerprint: Vec<(String, PathBuf)>,
+    /// Also write <output>.update.json: the manifest, download size and hash of the patch, so
+    /// launchers can check for updates without fetching the patch itself
+    #[arg(long)]
+    update_info: bool,
+    /// Sign the update descriptor, and the release record patchers keep in their receipt, with
+    /// this Ed25519 key, as created by `keygen`
+    #[arg(long, value_name = "FILE")]
+    sign_key: Option<PathBuf>,
+    /// Number that grows with every release of the product; patchers refuse to install a
+    /// release with a lower number over the one their receipt records
+    #[arg(long, value_name = "N")]
+    release_counter: Option<u64>,
+    /// Also write <output>.sha256 and <output>.metadata.json (product, versions, size and
+    /// hashes) for the release page
+    #[arg(long)]
+    publish_metadata: bool,
+    /// Also write <output>.install.ps1, which runs the patcher unattended and maps its failures
+    /// to exit codes, <output>.detect.ps1 for Intune or Configuration Manager, and
+    /// <output>.exit-codes.csv
+    #[arg(long)]
+    emit_deploy_scripts: bool,
+    /// URL the patch is published under, without its file name; recorded in the update
+    /// descriptor and metadata. Defaults to the output URL for http(s) outputs
+    #[arg(long, value_name = "URL")]
+    publish_url: Option<String>,
+    /// URL of a hosted copy of the patcher, which the patcher's download mode falls back to
+    /// when the URL it was given fails; repeat for several, tried in order
+    #[arg(long, value_name = "URL")]
+    mirror: Vec<String>,
+    /// Key-value recorded in the patch for launchers and tooling, e.g. build=1234; repeatable.
+    /// Shown in the patcher's status and error reports and in the receipt
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_metadata)]
+    metadata: Vec<(String, String)>,
+    /// What to do when the new version is identical to the old one
+    #[arg(long, value_enum, default_value_t = IfUnchanged::Build)]
+    if_unchanged: IfUnchanged,
+    /// Time the stages of the build (scan, hash, diff, compress, serialize, write), print where
+    /// the time went and write it to this file as stacks for flame graph tools
+    #[arg(long, value_name = "FILE")]
+    profile: Option<PathBuf>,
+    /// How to show progress: bars, json (one event per line on stderr), none, or pipe:<PATH>
+    /// for JSON lines into a named pipe another program reads
+    #[arg(long, value_name = "FRONTEND", default_value_t = Frontend::Bars)]
+    progress: Frontend,
+}
+
+/// What a build whose versions are identical produces.
+#[derive(Clone, Copy, ValueEnum)]
+enum IfUnchanged {
+    /// Warn, and build a patcher that only reports the installation is up to date
+    Build,
+    /// Write nothing and exit with status 3, for CI to tell apart from a failure
+    Fail,
+}
+
+/// Exit status of a build stopped by `--if-unchanged fail`.
+const EXIT_UNCHANGED: i32 = 3;
+
+/// The versions of a build are identical, so the patch would change nothing.
+#[derive(Debug)]
+struct Unchanged {
+    from_version: String,
+    to_version: String,
 }
 
-#[derive(Clone)]
-struct FileRec {
-    rel: String,
-    path: PathBuf,
+impl std::fmt::Display for Unchanged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} and {} are identical; there is nothing to patch",
+            self.from_version, self.to_version
+        )
+    }
+}
+
+impl std::error::Error for Unchanged {}
+
+/// Which modification time patched and added files end up with.
+#[derive(Clone, Copy, ValueEnum)]
+enum MtimeMode {
+    /// The time the patch is applied
+    Apply,
+    /// The file's modification time in the new folder
+    Preserve,
+    /// The time the patch was built, the same for every file
+    Build,
+}
+
+/// Which files the patcher writes sparse, skipping their runs of zeros.
+#[derive(Clone, Copy, ValueEnum)]
+enum SparseMode {
+    /// Files that are sparse in the new folder
+    Auto,
+    /// Every file it writes, for trees copied without their holes
+    Always,
+    /// None: every byte is written
+    Never,
+}
+
+impl SparseMode {
+    /// Whether the patcher leaves holes in the new file at `path`.
+    fn keeps_holes(self, path: &Path) -> bool {
+        match self {
+            SparseMode::Auto => is_sparse(path),
+            SparseMode::Always => true,
+            SparseMode::Never => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Exe,
+    Zip,
+}
+
+/// Presets tuning the xdelta search, secondary compression and parallelism together.
+///
+/// Every level diffs each 8 MiB window of a new file against the whole old file, so presets
+/// differ in how hard xdelta searches that source rather than in how much of it they see.
+#[derive(Clone, Copy, ValueEnum)]
+enum Preset {
+    /// Quickest xdelta search, no secondary compression, every core: quickest to build and
+    /// to apply
+    Fast,
+    /// xdelta's default search and light zstd compression on every core
+    Balanced,
+    /// Most thorough xdelta search and maximum zstd compression on half the cores, since high
+    /// levels need far more memory per worker
+    Small,
+}
+
+impl Preset {
+    /// xdelta compression level, `None` for xdelta's default (6).
+    fn xdelta_level(self) -> Option<u8> {
+        match self {
+            Preset::Fast => Some(1),
+            Preset::Balanced => None,
+            Preset::Small => Some(9),
+        }
+    }
+
+    fn compression_level(self) -> i32 {
+        match self {
+            Preset::Fast => 0,
+            Preset::Balanced => 3,
+            Preset::Small => 19,
+        }
+    }
+
+    /// Worker threads for `preset`, every core without one.
+    fn threads(preset: Option<Self>) -> usize {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        match preset {
+            None | Some(Preset::Fast | Preset::Balanced) => cores,
+            Some(Preset::Small) => (cores / 2).max(1),
+        }
+    }
+}
+
+impl BuildArgs {
+    fn product(&self) -> &str {
+        self.product.as_deref().expect("clap requires --product")
+    }
+}
+
+/// Settings that shape how the bundle is built.
+struct BuildOptions {
+    extra_files: ExtraFiles,
+    deletion_guard: DeletionGuard,
+    allow_case_collisions: bool,
+    ignore_path_case: bool,
+    copy_from_old: bool,
+    full_fallback: GlobSet,
+    /// Store a full copy of every file, for installing into an empty folder
+    full_install: bool,
+    /// Bundle format the patch is written in
+    format_version: u32,
+    /// Size of the frames small entries are compressed together in, if they are
+    solid_frame: Option<usize>,
+    /// Store diffs of small patched text files
+    store_diffs: bool,
+    /// Smallest changed file diffed segment by segment, if any are
+    segment: Option<u64>,
+    /// xdelta compression level deltas are made at, `None` for xdelta's default
+    xdelta_level: Option<u8>,
+    /// Deltas made with the xdelta3 command line, by the hashes of the old and new file, stored
+    /// as they are instead of diffing those files again (`from-xdelta`)
+    imported: HashMap<([u8; 32], [u8; 32]), PathBuf>,
+    progress: Frontend,
+    normalize_pe: GlobSet,
+    scan: ScanFilter,
+    store: Option<EntryStore>,
+    checkpoint: Option<Checkpoint>,
+    new_hashes: HashCache,
+    transforms: TransformRules,
+    mtimes: MtimeMode,
+    sparse: SparseMode,
+    build_time: SystemTime,
+    /// Releases fingerprinted besides the two a patch is built between
+    known_versions: Vec<(String, PathBuf)>,
+    /// Stub executable, loaded when the build writes any patcher executable
+    stub: Option<Vec<u8>>,
+    /// Index standing in for the files of the old version missing from its folder
+    old_index: Option<OldIndex>,
+}
+
+impl BuildOptions {
+    fn stub(&self) -> Result<&[u8]> {
+        self.stub
+            .as_deref()
+            .context("No stub loaded for building a patcher executable")
+    }
+
+    /// Modification time to record for a new file, in nanoseconds since the Unix epoch.
+    fn mtime(&self, path: &Path) -> Result<Option<u64>> {
+        let time = match self.mtimes {
+            MtimeMode::Apply => return Ok(None),
+            MtimeMode::Preserve => fs::metadata(path)
+                .and_then(|m| m.modified())
+                .with_context(|| format!("Reading modification time of {}", path.display()))?,
+            MtimeMode::Build => self.build_time,
+        };
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Ok(Some(since_epoch.as_nanos().try_into().unwrap_or(u64::MAX)))
+    }
+
+    /// Key matching a new file with its old counterpart.
+    fn path_key(&self, rel: &str) -> String {
+        if self.ignore_path_case {
+            rel.to_lowercase()
+        } else {
+            rel.to_string()
+        }
+    }
+
+    /// Whether a changed file of `len` bytes (the larger of its versions) is diffed segment by
+    /// segment. Not a file with a full copy, which needs no base, nor a normalized one, whose
+    /// user's copy may differ from the old version in the first chunk.
+    fn is_segmented(&self, len: u64, rel: &str, normalization: Normalization) -> bool {
+        self.segment.is_some_and(|min| len >= min)
+            && !self.full_install
+            && !self.full_fallback.is_match(rel)
+            && normalization == Normalization::None
+    }
+
+    /// How the file at `rel` is normalized before hashing.
+    fn normalization(&self, rel: &str) -> Normalization {
+        if self.normalize_pe.is_match(rel) {
+            Normalization::PeHeader
+        } else {
+            Normalization::None
+        }
+    }
+}
+
+/// Hashes of new-tree files by path, so a matrix build hashes the latest tree only once.
+#[derive(Default)]
+struct HashCache(Mutex<HashMap<PathBuf, [u8; 32]>>);
+
+impl HashCache {
+    fn get_or_hash(
+        &self,
+        path: &Path,
+        display: &dyn ProgressDisplay,
+        normalization: Normalization,
+    ) -> Result<[u8; 32]> {
+        if let Some(hash) = self.0.lock().unwrap().get(path) {
+            return Ok(*hash);
+        }
+        let hash = hash_file(path, display, normalization)?;
+        self.0.lock().unwrap().insert(path.to_path_buf(), hash);
+        Ok(hash)
+    }
 }
 
 enum TempKind {
     Unchanged,
-    Added(PatchData),
-    Patched(PatchData),
+    Added(EntryKey, PatchData),
+    Patched(EntryKey, PatchData, Option<usize>),
+    Moved(String),
+    Copied(String),
+    /// Written chunk by chunk: a compressed copy of each chunk the patcher cannot take from the
+    /// old file, whose hashes are in `TempResult::chunks`
+    Streamed(Vec<Option<Fallback>>),
+    /// Diffed segment by segment: a delta of each chunk the old file does not hold at the same
+    /// place, whose hashes are in `TempResult::chunks`
+    Segmented(Vec<Option<ChunkEntry>>),
 }
 
+/// Compressed full copy of a patched file, with the key it is stored under.
+type Fallback = (EntryKey, PatchData);
+
 struct TempResult {
     path: String,
     original_hash: [u8; 32],
     new_hash: [u8; 32],
+    old_size: u64,
+    new_size: u64,
+    mtime: Option<u64>,
+    normalization: Normalization,
+    mode: Option<u32>,
     kind: TempKind,
+    fallback: Option<Fallback>,
+    /// Chunk hashes of a large patched file, whose chunk entries replace `fallback`
+    chunks: Option<Chunks>,
 }
 
+/// zstd level for full fallback copies; they are written once and rarely read.
+const FALLBACK_ZSTD_LEVEL: i32 = 19;
+/// zstd level for the chunks of streamed files, which are mostly media that barely compresses
+/// and would take hours at the fallback level over gigabytes.
+const STREAMED_ZSTD_LEVEL: i32 = 3;
+
 fn main() -> Result<()> {
+    let result = run();
+    if let Err(e) = &result
+        && e.chain().any(|e| e.is::<Unchanged>())
+    {
+        eprintln!("Error: {e:#}");
+        std::process::exit(EXIT_UNCHANGED);
+    }
+    result
+}
+
+fn run() -> Result<()> {
     let args = Args::parse();
-    let bundle = build_bundle(
-        &args.old_dir,
-        &args.new_dir,
-        &args.product,
-        &args.from_version,
-        &args.to_version,
-        args.delete_extra,
+    let build = match &args.command {
+        Some(Command::Extract(extract)) => {
+            return extract_installer(
+                &extract.installer,
+                extract.payload.as_deref(),
+                extract.stub.as_deref(),
+            );
+        }
+        Some(Command::VerifyInstall(verify)) => {
+            return verify_install(&verify.manifest, &verify.dir, verify.report.as_deref());
+        }
+        Some(Command::Keygen(keygen)) => return generate_key(&keygen.key),
+        Some(Command::Index(index)) => return write_index(&index.dir, &index.output),
+        Some(Command::Inspect(args)) => return inspect(&args.patch, args.show_diff.as_deref()),
+        Some(Command::Deploy(deploy)) => {
+            return ssh::deploy(
+                &deploy.patcher,
+                &deploy.hosts,
+                &deploy.target,
+                deploy.parallel,
+                &deploy.ssh_arg,
+                &deploy.patcher_arg,
+            );
+        }
+        Some(Command::Matrix(matrix)) => &matrix.build,
+        Some(Command::FromXdelta(legacy)) => &legacy.build,
+        None => &args.build,
+    };
+    if !(versions::OLDEST_FORMAT..=FORMAT_VERSION).contains(&build.format_version) {
+        anyhow::bail!(
+            "--format-version must be between {} and {FORMAT_VERSION}",
+            versions::OLDEST_FORMAT
+        );
+    }
+    if build.solid_frame.is_some() && build.format_version < versions::SOLID_FRAMES {
+        anyhow::bail!(
+            "--solid-frame needs format {} or later",
+            versions::SOLID_FRAMES
+        );
+    }
+    if build.store_diffs && build.format_version < versions::TEXT_DIFFS {
+        anyhow::bail!(
+            "--store-diffs needs format {} or later",
+            versions::TEXT_DIFFS
+        );
+    }
+    if build.segment.is_some() && build.format_version < versions::SEGMENTED_FILES {
+        anyhow::bail!(
+            "--segment needs format {} or later",
+            versions::SEGMENTED_FILES
+        );
+    }
+    if build.emit_deploy_scripts && matches!(build.format, OutputFormat::Zip) {
+        anyhow::bail!(
+            "--emit-deploy-scripts needs --format exe: the scripts run the patcher executable"
+        );
+    }
+
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(
+            build
+                .threads
+                .unwrap_or_else(|| Preset::threads(build.preset)),
+        )
+        .build_global()?;
+    let profile = build
+        .profile
+        .as_ref()
+        .map(|_| Profile::install())
+        .transpose()?;
+
+    let mut fallback = GlobSetBuilder::new();
+    for pattern in &build.include_full_fallback {
+        fallback.add(Glob::new(pattern).with_context(|| format!("Invalid glob {pattern}"))?);
+    }
+    if build.full_install {
+        fallback.add(Glob::new("**")?);
+    }
+    let mut normalize_pe = GlobSetBuilder::new();
+    for pattern in &build.normalize_pe {
+        normalize_pe.add(Glob::new(pattern).with_context(|| format!("Invalid glob {pattern}"))?);
+    }
+    let checkpoint = build
+        .checkpoint
+        .as_deref()
+        .map(Checkpoint::open)
+        .transpose()?;
+    let mut known_versions = build.fingerprint.clone();
+    if let Some(Command::Matrix(matrix)) = &args.command {
+        for version in matrix_from_versions(matrix)?
+            .into_iter()
+            .chain([matrix.latest.clone()])
+        {
+            let dir = matrix.versions_dir.join(&version);
+            known_versions.push((version, dir));
+        }
+    }
+    let mut options = BuildOptions {
+        extra_files: if build.delete_extra {
+            ExtraFiles::Delete
+        } else {
+            ExtraFiles::Keep
+        },
+        deletion_guard: DeletionGuard::new(&build.protect, build.max_delete_percent, build.yes)?,
+        allow_case_collisions: build.allow_case_collisions,
+        ignore_path_case: build.ignore_path_case,
+        copy_from_old: build.copy_from_old,
+        full_fallback: fallback.build()?,
+        full_install: build.full_install,
+        format_version: build.format_version,
+        solid_frame: build
+            .solid_frame
+            .map(|bytes| bytes.try_into().unwrap_or(usize::MAX)),
+        store_diffs: build.store_diffs,
+        segment: build.segment,
+        xdelta_level: build
+            .xdelta_level
+            .or_else(|| build.preset.and_then(Preset::xdelta_level)),
+        imported: HashMap::new(),
+        progress: build.progress.clone(),
+        normalize_pe: normalize_pe.build()?,
+        scan: ScanFilter {
+            skip_hidden: build.skip_hidden,
+            skip_system: build.skip_system,
+            skip_empty: build.skip_empty,
+            min_size: build.min_size,
+            max_size: build.max_size,
+        },
+        store: match (&build.store, &checkpoint) {
+            (Some(dir), _) => Some(EntryStore::open(dir)?),
+            (None, Some(checkpoint)) => Some(checkpoint.entry_store()?),
+            (None, None) => None,
+        },
+        checkpoint,
+        new_hashes: HashCache::default(),
+        transforms: match &build.transforms {
+            Some(path) => TransformRules::load(path)?,
+            None => TransformRules::default(),
+        },
+        mtimes: build.mtimes,
+        sparse: build.sparse,
+        build_time: SystemTime::now(),
+        known_versions,
+        stub: match (build.format, &build.uninstall_entry) {
+            (OutputFormat::Zip, None) => None,
+            _ => Some(load_stub(
+                build.stub.as_deref(),
+                build.stub_target.as_deref(),
+            )?),
+        },
+        old_index: match (&args.old_index, &args.old_dir) {
+            (Some(index), Some(old_dir)) => Some(OldIndex::load(index, old_dir)?),
+            _ => None,
+        },
+    };
+
+    match &args.command {
+        Some(Command::Matrix(matrix)) => build_matrix(matrix, &options)?,
+        Some(Command::FromXdelta(legacy)) => build_from_xdelta(legacy, &mut options)?,
+        Some(
+            Command::Extract(_)
+            | Command::VerifyInstall(_)
+            | Command::Keygen(_)
+            | Command::Index(_)
+            | Command::Deploy(_)
+            | Command::Inspect(_),
+        ) => {
+            unreachable!(
+                "extract, verify-install, keygen, index, deploy and inspect return before building"
+            )
+        }
+        None => {
+            let (Some(old_dir), Some(new_dir), Some(output), Some(from), Some(to)) = (
+                &args.old_dir,
+                &args.new_dir,
+                &args.output,
+                &args.from_version,
+                &args.to_version,
+            ) else {
+                unreachable!("clap requires the single-build arguments");
+            };
+            build_patch(old_dir, new_dir, output, from, to, build, &options)?
+        }
+    }
+    if let Some(checkpoint) = &options.checkpoint {
+        checkpoint.finish()?;
+    }
+    if let (Some(profile), Some(path)) = (&profile, &build.profile) {
+        profile.report(path)?;
+    }
+    Ok(())
+}
+
+/// Builds one patcher from `old_dir` to `new_dir` and writes it in the requested format.
+fn build_patch(
+    old_dir: &Path,
+    new_dir: &Path,
+    output: &Destination,
+    from_version: &str,
+    to_version: &str,
+    args: &BuildArgs,
+    options: &BuildOptions,
+) -> Result<()> {
+    let _build = info_span!("build", from = from_version, to = to_version).entered();
+    if let Destination::Stdout = output {
+        let beside = [
+            (args.update_info, "--update-info"),
+            (args.publish_metadata, "--publish-metadata"),
+            (args.emit_deploy_scripts, "--emit-deploy-scripts"),
+        ];
+        if let Some((_, flag)) = beside.iter().find(|(set, _)| *set) {
+            anyhow::bail!(
+                "{flag} writes files named after the output, so it needs an output other than -"
+            );
+        }
+    }
+    let compression_level = args
+        .compression_level
+        .unwrap_or_else(|| args.preset.map_or(0, Preset::compression_level));
+
+    let mut bundle = build_bundle(
+        old_dir,
+        new_dir,
+        args.product(),
+        from_version,
+        to_version,
+        options.extra_files,
+        options,
+    )?;
+    if changes_nothing(&bundle.manifest, old_dir) {
+        match args.if_unchanged {
+            IfUnchanged::Build => eprintln!(
+                "Warning: {from_version} and {to_version} are identical. The patcher changes nothing and \
+                 only reports that the installation is up to date"
+            ),
+            IfUnchanged::Fail => {
+                return Err(Unchanged {
+                    from_version: from_version.to_string(),
+                    to_version: to_version.to_string(),
+                }
+                .into());
+            }
+        }
+    }
+    bundle.manifest.markers = VersionMarkers {
+        version_file: args.version_file.clone(),
+        registry: match (&args.registry_key, &args.registry_value) {
+            (Some(key), Some(value)) => {
+                let (hive, key) = parse_registry_key(key)?;
+                Some(RegistryMarker {
+                    hive,
+                    key,
+                    value: value.clone(),
+                })
+            }
+            _ => None,
+        },
+        uninstall: None,
+    };
+    let mut versions = vec![
+        (from_version.to_string(), old_dir.to_path_buf()),
+        (to_version.to_string(), new_dir.to_path_buf()),
+    ];
+    for (version, dir) in &options.known_versions {
+        if !versions.iter().any(|(known, _)| known == version) {
+            versions.push((version.clone(), dir.clone()));
+        }
+    }
+    bundle.manifest.fingerprints =
+        fingerprint_versions(&bundle.manifest, &versions, options.old_index.as_ref())?;
+    if let Some(path) = &args.eula {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Reading license {}", path.display()))?;
+        bundle.manifest.eula = Some(text);
+    }
+    bundle.manifest.mirrors = args.mirror.clone();
+    bundle.manifest.metadata = args.metadata.iter().cloned().collect();
+    bundle.manifest.release = sign_release(
+        &bundle.manifest,
+        args.release_counter,
+        args.sign_key.as_deref(),
+    )?;
+    if let Some(path) = &args.wizard {
+        bundle.manifest.wizard = Some(load_wizard(path, &bundle.manifest)?);
+    }
+    if let Some(path) = &args.main_exe {
+        bundle.manifest.main_exe = Some(main_executable(
+            &bundle.manifest,
+            path,
+            args.previous_main_exe.as_deref(),
+        )?);
+    }
+    let level = (compression_level != 0).then_some(compression_level);
+    if let Some(key) = &args.uninstall_entry {
+        add_uninstaller(&mut bundle, old_dir, new_dir, key, args, options, level)?;
+    }
+
+    if let Some((feature, needs)) =
+        versions::unsupported_feature(&bundle.manifest, options.format_version)
+    {
+        anyhow::bail!(
+            "The patch uses {feature}, which format {} does not have; build it for format {needs} or later",
+            options.format_version
+        );
+    }
+    let mut out = output.open(matches!(args.format, OutputFormat::Zip))?;
+    let serialize = info_span!("serialize").entered();
+    let manifest = match args.format {
+        OutputFormat::Exe => {
+            build_installer_exe(
+                options.stub()?,
+                &mut bundle,
+                &mut out,
+                level,
+                options.solid_frame,
+            )?;
+            bundle.manifest
+        }
+        OutputFormat::Zip => {
+            let manifest;
+            (out, manifest) = build_zip_archive(bundle, out)?;
+            manifest
+        }
+    };
+    drop(serialize);
+    let _write = info_span!("write").entered();
+    let digest = if args.update_info || args.publish_metadata {
+        Some(out.digest()?)
+    } else {
+        None
+    };
+    out.finish().with_context(|| format!("Writing {output}"))?;
+    if args.emit_deploy_scripts {
+        write_deploy_scripts(&manifest, output)?;
+    }
+    let Some(digest) = digest else {
+        return Ok(());
+    };
+
+    let url = match (&args.publish_url, output) {
+        (Some(base), _) => Some(format!(
+            "{}/{}",
+            base.trim_end_matches('/'),
+            output.file_name()
+        )),
+        (None, Destination::Http(url)) => Some(url.clone()),
+        (None, _) => None,
+    };
+    if args.publish_metadata {
+        write_metadata(&manifest, &digest, output, url.as_deref())?;
+    }
+    if args.update_info {
+        let payload = PayloadRef {
+            url,
+            size: digest.size,
+            blake3: digest.blake3,
+        };
+        write_update_info(manifest, payload, output, args.sign_key.as_deref())?;
+    }
+    Ok(())
+}
+
+/// Whether applying `manifest` leaves the old version in `old_dir` as it is: every file
+/// unchanged, and no folder created or removed.
+fn changes_nothing(manifest: &Manifest, old_dir: &Path) -> bool {
+    manifest
+        .files
+        .iter()
+        .all(|file| matches!(file.kind, PatchKind::Unchanged))
+        && manifest.deleted_dirs.is_empty()
+        && manifest
+            .created_dirs
+            .iter()
+            .all(|dir| old_dir.join(dir).is_dir())
+}
+
+/// The main executable at `path` in the new version, and where it was in the old one: at
+/// `previous` if given, otherwise wherever the patch moves it from.
+fn main_executable(
+    manifest: &Manifest,
+    path: &str,
+    previous: Option<&str>,
+) -> Result<MainExecutable> {
+    let Some(file) = manifest
+        .files
+        .iter()
+        .find(|file| file.path == path && !matches!(file.kind, PatchKind::Deleted))
+    else {
+        anyhow::bail!("--main-exe {path} is not a file of the new version");
+    };
+    let previous = match (previous, &file.kind) {
+        (Some(previous), _) => Some(previous.to_string()),
+        (None, PatchKind::Moved { from }) => Some(from.clone()),
+        (None, _) => None,
+    };
+    Ok(MainExecutable {
+        path: path.to_string(),
+        previous: previous.filter(|previous| previous != path),
+    })
+}
+
+/// Adds an Add/Remove Programs entry whose uninstall command runs a patcher from `new_dir`
+/// back to `old_dir`. That patcher is shipped inside the bundle as an added file, and removes
+/// the entry again when it runs.
+fn add_uninstaller(
+    bundle: &mut PatchBundle,
+    old_dir: &Path,
+    new_dir: &Path,
+    key: &str,
+    args: &BuildArgs,
+    options: &BuildOptions,
+    level: Option<i32>,
+) -> Result<()> {
+    let manifest = &bundle.manifest;
+    if manifest
+        .files
+        .iter()
+        .any(|file| file.path == args.uninstaller)
+    {
+        anyhow::bail!(
+            "{} is already part of the new version; choose another --uninstaller",
+            args.uninstaller
+        );
+    }
+    let (hive, name) = parse_registry_key(key)?;
+    let entry = UninstallEntry {
+        hive,
+        name,
+        display_name: manifest.product.clone(),
+        publisher: args.publisher.clone(),
+        uninstaller: None,
+    };
+
+    eprintln!(
+        "Building uninstaller {} -> {}",
+        manifest.to_version, manifest.from_version
+    );
+    // Files the patch added are deleted again, whatever --delete-extra says
+    let mut reverse = build_bundle(
+        new_dir,
+        old_dir,
+        &manifest.product,
+        &manifest.to_version,
+        &manifest.from_version,
+        ExtraFiles::Revert,
+        options,
+    )?;
+    reverse.manifest.markers = VersionMarkers {
+        uninstall: Some(entry.clone()),
+        ..manifest.markers.clone()
+    };
+    // Reverting a rename of the main executable renames it back
+    reverse.manifest.main_exe = manifest.main_exe.as_ref().map(|main| MainExecutable {
+        path: main.previous.clone().unwrap_or_else(|| main.path.clone()),
+        previous: main.previous.as_ref().map(|_| main.path.clone()),
+    });
+    let mut uninstaller = Vec::new();
+    build_installer_exe(
+        options.stub()?,
+        &mut reverse,
+        &mut uninstaller,
+        level,
+        options.solid_frame,
     )?;
-    build_installer_exe(&bundle, &args.output)?;
+
+    let new_hash = *blake3::hash(&uninstaller).as_bytes();
+    bundle.manifest.files.push(FileEntry {
+        path: args.uninstaller.clone(),
+        kind: PatchKind::Added {
+            idx: bundle.entries.len(),
+        },
+        original_hash: [0u8; 32],
+        new_hash,
+        old_size: 0,
+        new_size: uninstaller.len() as u64,
+        mtime: None,
+        normalization: Normalization::None,
+        mode: None,
+        sparse: false,
+        full_copy: None,
+    });
+    bundle.entries.push(PatchData::Full(uninstaller));
+    bundle.manifest.cost = apply_cost(&bundle.manifest.files);
+    bundle.manifest.markers.uninstall = Some(UninstallEntry {
+        uninstaller: Some(args.uninstaller.clone()),
+        ..entry
+    });
     Ok(())
 }
 
-fn hash_file(path: &Path, worker_bars: &Arc<Vec<ProgressBar>>) -> Result<[u8; 32]> {
+/// Builds a patcher from each prior version folder to `--latest`, reusing the latest tree's hashes.
+fn build_matrix(matrix: &MatrixArgs, options: &BuildOptions) -> Result<()> {
+    let new_dir = matrix.versions_dir.join(&matrix.latest);
+    if !new_dir.is_dir() {
+        anyhow::bail!("Latest version folder {} does not exist", new_dir.display());
+    }
+
+    let from_versions = matrix_from_versions(matrix)?;
+    if from_versions.is_empty() {
+        anyhow::bail!(
+            "No prior versions found in {}",
+            matrix.versions_dir.display()
+        );
+    }
+
+    matrix.output_dir.create_dir()?;
+    let extension = match matrix.build.format {
+        OutputFormat::Exe => "exe",
+        OutputFormat::Zip => "zip",
+    };
+    for from in &from_versions {
+        let output = matrix.output_dir.join(&format!(
+            "{}_{from}_to_{}.{extension}",
+            matrix.build.product(),
+            matrix.latest
+        ));
+        println!("Building {from} -> {}: {output}", matrix.latest);
+        build_patch(
+            &matrix.versions_dir.join(from),
+            &new_dir,
+            &output,
+            from,
+            &matrix.latest,
+            &matrix.build,
+            options,
+        )
+        .with_context(|| format!("Building patch from {from}"))?;
+    }
+    Ok(())
+}
+
+/// Builds a patcher from a legacy folder of xdelta3 patches, by recreating the new version
+/// from them in a temporary folder and building against that.
+fn build_from_xdelta(legacy: &FromXdeltaArgs, options: &mut BuildOptions) -> Result<()> {
+    let mut rebuilt = rebuild_new_version(&legacy.old_dir, &legacy.xdelta_dir, &legacy.files)
+        .with_context(|| format!("Applying the patches in {}", legacy.xdelta_dir.display()))?;
+    options.imported = std::mem::take(&mut rebuilt.deltas);
+    build_patch(
+        &legacy.old_dir,
+        &rebuilt.dir,
+        &legacy.output,
+        &legacy.from_version,
+        &legacy.to_version,
+        &legacy.build,
+        options,
+    )
+}
+
+/// Versions a matrix build patches from: `--from`, or every other subfolder of the versions folder.
+fn matrix_from_versions(matrix: &MatrixArgs) -> Result<Vec<String>> {
+    Ok(if matrix.from.is_empty() {
+        let mut found = Vec::new();
+        for entry in fs::read_dir(&matrix.versions_dir)
+            .with_context(|| format!("Reading {}", matrix.versions_dir.display()))?
+        {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_dir() && name != matrix.latest {
+                found.push(name);
+            }
+        }
+        found.sort();
+        found
+    } else {
+        matrix.from.clone()
+    })
+}
+
+/// Parses `--metadata KEY=VALUE`. Required keys are reserved for later formats of the patch.
+fn parse_metadata(value: &str) -> Result<(String, String), String> {
+    let (key, value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got {value}"))?;
+    if key.is_empty() {
+        return Err("the key is empty".into());
+    }
+    if key.starts_with(REQUIRED_METADATA_PREFIX) {
+        return Err(format!(
+            "keys starting with {REQUIRED_METADATA_PREFIX} are reserved for the patch format"
+        ));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Parses `--fingerprint VERSION=DIR`.
+fn parse_version_dir(value: &str) -> Result<(String, PathBuf), String> {
+    let (version, dir) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected VERSION=DIR, got {value}"))?;
+    Ok((version.to_string(), PathBuf::from(dir)))
+}
+
+/// Splits `HKCU\Software\...` into its hive and the key path below it.
+fn parse_registry_key(key: &str) -> Result<(RegistryHive, String)> {
+    let (hive, path) = key
+        .split_once('\\')
+        .ok_or_else(|| anyhow::anyhow!("Registry key {key} has no path below the hive"))?;
+    let hive = match hive.to_ascii_uppercase().as_str() {
+        "HKCU" | "HKEY_CURRENT_USER" => RegistryHive::CurrentUser,
+        "HKLM" | "HKEY_LOCAL_MACHINE" => RegistryHive::LocalMachine,
+        other => anyhow::bail!("Unsupported registry hive {other}; use HKCU or HKLM"),
+    };
+    Ok((hive, path.to_string()))
+}
+
+/// POSIX permission bits of a new file, so executables and scripts keep their executable bit.
+#[cfg(unix)]
+fn file_mode(path: &Path) -> Result<Option<u32>> {
+    use std::os::unix::fs::PermissionsExt;
+    let meta =
+        fs::metadata(path).with_context(|| format!("Reading permissions of {}", path.display()))?;
+    Ok(Some(meta.permissions().mode() & 0o7777))
+}
+
+#[cfg(not(unix))]
+fn file_mode(_path: &Path) -> Result<Option<u32>> {
+    Ok(None)
+}
+
+/// Whether the file at `path` has holes: fewer blocks allocated than its length needs.
+#[cfg(unix)]
+fn is_sparse(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    // st_blocks counts 512-byte units whatever the filesystem's block size
+    fs::metadata(path).is_ok_and(|meta| meta.blocks() * 512 < meta.len())
+}
+
+/// Whether the file at `path` is marked sparse.
+#[cfg(windows)]
+fn is_sparse(path: &Path) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_SPARSE_FILE: u32 = 0x200;
+    fs::metadata(path).is_ok_and(|meta| meta.file_attributes() & FILE_ATTRIBUTE_SPARSE_FILE != 0)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_sparse(_path: &Path) -> bool {
+    false
+}
+
+/// Size used to weight progress; unreadable files count as empty.
+fn file_len(path: &Path) -> u64 {
+    std::fs::metadata(path).map_or(0, |m| m.len())
+}
+
+fn hash_file(
+    path: &Path,
+    display: &dyn ProgressDisplay,
+    normalization: Normalization,
+) -> Result<[u8; 32]> {
+    let _span = info_span!("hash").entered();
     // Identify worker
-    let idx = current_thread_index().unwrap_or(0);
-    let bar = &worker_bars[idx];
+    let worker = current_thread_index().unwrap_or(0);
 
     let len = std::fs::metadata(path)?.len();
 
-    bar.set_length(len);
-    bar.set_position(0);
+    display.file_started(worker, &path.to_string_lossy(), len);
 
     let mut hasher = blake3::Hasher::new();
     let mut file = File::open(path)?;
-    let mut buffer = [0u8; 8192];
+    let mut buffer = vec![0u8; buffers::buffer_len(len)];
     let mut read_total = 0u64;
 
-    loop {
-        let n = file.read(&mut buffer)?;
-        if n == 0 {
-            break;
+    let mut n = normalization.read_first(&mut file, &mut buffer[..buffers::head_len(len)])?;
+    while n > 0 {
+        if len >= schedule::LARGE_FILE {
+            // Lets workers that ran out of files help with the last big ones
+            hasher.update_rayon(&buffer[..n]);
+        } else {
+            hasher.update(&buffer[..n]);
         }
-        hasher.update(&buffer[..n]);
         read_total += n as u64;
-        bar.set_position(read_total);
+        display.file_progress(worker, read_total);
+        n = file.read(&mut buffer)?;
     }
 
     Ok(*hasher.finalize().as_bytes())
 }
 
+/// Hash identifying the exact bytes of a file for its store entry. A normalized file's
+/// manifest hash also matches other builds of it, whose deltas and copies differ.
+fn entry_hash(
+    path: &Path,
+    hash: [u8; 32],
+    normalization: Normalization,
+    display: &dyn ProgressDisplay,
+) -> Result<[u8; 32]> {
+    match normalization {
+        Normalization::None => Ok(hash),
+        _ => hash_file(path, display, Normalization::None),
+    }
+}
+
+/// Compressed full copy of the new file at `path`, which the patcher writes when the file's
+/// base does not verify.
+fn build_fallback(
+    store: Option<&EntryStore>,
+    path: &Path,
+    rel: &str,
+    new: [u8; 32],
+) -> Result<Fallback> {
+    let key = EntryKey::Fallback {
+        new,
+        level: FALLBACK_ZSTD_LEVEL,
+    };
+    let data = build_entry(store, &key, || {
+        let _span = info_span!("fallback").entered();
+        let file = File::open(path)?;
+        zstd::encode_all(file, FALLBACK_ZSTD_LEVEL)
+            .with_context(|| format!("Compressing fallback for {rel}"))
+    })?;
+    Ok((key, data))
+}
+
 fn build_bundle(
     old_dir: &Path,
     new_dir: &Path,
     product: &str,
     from_version: &str,
     to_version: &str,
-    delete_extra: bool,
+    extra_files: ExtraFiles,
+    options: &BuildOptions,
 ) -> Result<PatchBundle> {
     // Collect file lists
-    let mut old_files = Vec::<FileRec>::new();
-    for entry in WalkDir::new(old_dir)
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|e| e.file_type().is_file())
-    {
-        let rel = entry.path().strip_prefix(old_dir)?;
-        let rel_str = rel.to_slash().unwrap().to_string();
-        old_files.push(FileRec {
-            rel: rel_str,
-            path: entry.into_path(),
-        });
+    let mut skipped = SkipCounts::default();
+    // With an index, old_dir holds only the old files deltas are computed from
+    let index = options
+        .old_index
+        .as_ref()
+        .filter(|index| index.dir == old_dir);
+    let scan = info_span!("scan").entered();
+    let old_scan = match index {
+        Some(index) => index.scan(&options.scan, &mut skipped),
+        None => scan_dir(old_dir, &options.scan, &mut skipped)?,
+    };
+    let new_scan = scan_dir(new_dir, &options.scan, &mut skipped)?;
+    drop(scan);
+    let (old_files, new_files) = (old_scan.files, new_scan.files);
+    if skipped.total() > 0 {
+        eprintln!(
+            "Skipped {} entries ({} hidden, {} system, {} empty, {} outside size limits)",
+            skipped.total(),
+            skipped.hidden,
+            skipped.system,
+            skipped.empty,
+            skipped.size
+        );
     }
 
-    let mut new_files = Vec::<FileRec>::new();
-    for entry in WalkDir::new(new_dir)
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|e| e.file_type().is_file())
-    {
-        let rel = entry.path().strip_prefix(new_dir)?;
-        let rel_str = rel.to_slash().unwrap().to_string();
-        new_files.push(FileRec {
-            rel: rel_str,
-            path: entry.into_path(),
-        });
-    }
+    let old_len = |rec: &FileRec| {
+        index
+            .and_then(|index| index.len(&rec.rel))
+            .unwrap_or_else(|| file_len(&rec.path))
+    };
 
-    // Index old files & record new paths
-    let old_map: HashMap<String, PathBuf> = old_files
+    // Index old files & record new paths, by path key
+    let old_map: HashMap<String, &FileRec> = old_files
         .iter()
-        .map(|r| (r.rel.clone(), r.path.clone()))
+        .map(|r| (options.path_key(&r.rel), r))
         .collect();
-    let new_set: HashSet<String> = new_files.iter().map(|r| r.rel.clone()).collect();
-
-    // Progress bars
-    let total_tasks = new_files.len()
-        + if delete_extra {
-            old_files
-                .iter()
-                .filter(|rec| !new_set.contains(&rec.rel))
-                .count()
+    let new_set: HashSet<String> = new_files.iter().map(|r| options.path_key(&r.rel)).collect();
+    let old_only = |rec: &&FileRec| !new_set.contains(&options.path_key(&rec.rel));
+
+    // Paths differing only by case collide on Windows and other case-insensitive filesystems
+    let collisions = case_collisions(
+        new_files
+            .iter()
+            .chain(old_files.iter().filter(old_only))
+            .map(|rec| rec.rel.as_str()),
+    );
+    if !collisions.is_empty() {
+        let listing = collisions
+            .iter()
+            .map(|group| format!("  {}", group.join(" <-> ")))
+            .collect::<Vec<_>>()
+            .join("\n");
+        if options.allow_case_collisions {
+            eprintln!("Warning: paths differ only by case:\n{listing}");
         } else {
-            0
-        };
+            anyhow::bail!(
+                "Paths differ only by case and would collide on case-insensitive filesystems \
+                 (use --allow-case-collisions to ignore):\n{listing}"
+            );
+        }
+    }
 
-    let mp = Arc::new(MultiProgress::new());
+    // Old files the new version lacks, apart from protected ones, which stay on disk
+    let deleted_recs: Vec<&FileRec> = match extra_files {
+        ExtraFiles::Keep => Vec::new(),
+        ExtraFiles::Delete | ExtraFiles::Revert => old_files
+            .iter()
+            .filter(old_only)
+            .filter(|rec| !options.deletion_guard.is_protected(&rec.rel))
+            .collect(),
+    };
+    if extra_files == ExtraFiles::Delete {
+        let deleted: Vec<(&str, u64)> = deleted_recs
+            .iter()
+            .map(|rec| (rec.rel.as_str(), old_len(rec)))
+            .collect();
+        options.deletion_guard.check(&deleted, old_files.len())?;
+    }
 
-    let overall_pb = mp.add(ProgressBar::new(total_tasks as u64));
-    overall_pb.set_style(
-        ProgressStyle::with_template(
-            "[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg}",
-        )?
-            .progress_chars("##-"),
-    );
+    // Progress bars, weighted by the bytes each file needs read so rate and ETA stay honest
+    let total_bytes = new_files
+        .iter()
+        .map(|rec| {
+            file_len(&rec.path)
+                + old_map
+                    .get(&options.path_key(&rec.rel))
+                    .map_or(0, |old| old_len(old))
+        })
+        .sum::<u64>()
+        + deleted_recs.iter().map(|rec| old_len(rec)).sum::<u64>();
 
-    let num_workers = current_num_threads();
+    let display = options
+        .progress
+        .open(total_bytes, current_num_threads(), "")?;
+    let display = &*display;
+    let advance = |bytes| display.advance(bytes);
+    let hash_old = |rec: &FileRec, normalization: Normalization| match index
+        .and_then(|index| index.hash(&rec.rel, normalization))
+    {
+        Some(hash) => Ok(hash),
+        None => hash_file(&rec.path, display, normalization),
+    };
 
-    let mut worker_vec = Vec::with_capacity(num_workers);
-    for i in 0..num_workers {
-        let pb = mp.add(ProgressBar::new(0));
+    // Delete extra files if --delete-extra was used
+    let deleted_entries: Vec<FileEntry> = if !deleted_recs.is_empty() {
+        let phase = info_span!("deleted");
+        let _phase = phase.enter();
+        largest_first(
+            &deleted_recs,
+            |rec| old_len(rec),
+            |_, rec| {
+                // Workers start outside the phase's span
+                let _phase = phase.enter();
+                let normalization = options.normalization(&rec.rel);
+                let old_hash = hash_old(rec, normalization)?;
+                let old_size = old_len(rec);
+                advance(old_size);
 
-        let template = format!("  [W{:02}] {{bar:30.green/black}} {{bytes}}/{{total_bytes}}", i);
-        pb.set_style(
-            ProgressStyle::with_template(&template)?
-                .with_key("bytes", |st: &ProgressState, w: &mut dyn std::fmt::Write| {
-                    write!(w, "{}", indicatif::HumanBytes(st.pos())).ok();
-                })
-                .with_key("total_bytes", |st: &ProgressState, w: &mut dyn std::fmt::Write| {
-                    write!(w, "{}", indicatif::HumanBytes(st.len().unwrap_or(0))).ok();
+                Ok::<FileEntry, anyhow::Error>(FileEntry {
+                    path: rec.rel.clone(),
+                    kind: PatchKind::Deleted,
+                    original_hash: old_hash,
+                    new_hash: [0u8; 32],
+                    old_size,
+                    new_size: 0,
+                    mtime: None,
+                    normalization,
+                    mode: None,
+                    sparse: false,
+                    full_copy: None,
                 })
-                .progress_chars("##-"),
-        );
-        worker_vec.push(pb);
+            },
+        )?
+    } else {
+        Vec::new()
+    };
+
+    // Files that disappear, by content, so identical new files can be emitted as renames
+    let mut moved_sources = HashMap::<[u8; 32], Vec<String>>::new();
+    for entry in &deleted_entries {
+        moved_sources
+            .entry(entry.original_hash)
+            .or_default()
+            .push(entry.path.clone());
+    }
+    let moved_sources = Mutex::new(moved_sources);
+
+    // Old files by content, so a new file identical to one is copied from it on the user's
+    // disk. Only old files the size of some added file can match, so only those are hashed
+    let mut copy_sources = HashMap::<[u8; 32], String>::new();
+    if options.copy_from_old {
+        let added_sizes: HashSet<u64> = new_files
+            .iter()
+            .filter(|rec| !old_map.contains_key(&options.path_key(&rec.rel)))
+            .map(|rec| file_len(&rec.path))
+            .collect();
+        let candidates: Vec<&FileRec> = old_files
+            .iter()
+            .filter(|rec| added_sizes.contains(&old_len(rec)))
+            .collect();
+        let phase = info_span!("copy_sources");
+        let _phase = phase.enter();
+        let hashes = largest_first(
+            &candidates,
+            |rec| old_len(rec),
+            |_, rec| {
+                let _phase = phase.enter();
+                hash_old(rec, Normalization::None)
+            },
+        )?;
+        for (rec, hash) in candidates.iter().zip(hashes) {
+            copy_sources.entry(hash).or_insert_with(|| rec.rel.clone());
+        }
     }
-    let worker_bars = Arc::new(worker_vec);
 
     // Process new files
     let old_map_arc = Arc::new(old_map);
-    let overall_pb = overall_pb.clone();
-    let worker_bars_clone = worker_bars.clone();
 
-    let temp_results: Result<Vec<TempResult>> = new_files
-        .par_iter()
-        .map(|rec| {
-            let overall_pb = overall_pb.clone();
-            let old_map = old_map_arc.clone();
-            let worker_bars = worker_bars_clone.clone();
-
-            let new_hash = hash_file(&rec.path, &worker_bars)?;
-
-            let res = if let Some(old_path) = old_map.get(&rec.rel) {
-                let old_hash = hash_file(old_path, &worker_bars)?;
-
-                if old_hash == new_hash {
-                    // unchanged
-                    TempResult {
-                        path: rec.rel.clone(),
-                        original_hash: old_hash,
-                        new_hash,
-                        kind: TempKind::Unchanged,
-                    }
-                } else {
-                    // changed
-                    let patch_data = create_patch(old_path, &rec.path)?;
-                    TempResult {
-                        path: rec.rel.clone(),
-                        original_hash: old_hash,
-                        new_hash,
-                        kind: TempKind::Patched(PatchData::Xdelta(patch_data)),
-                    }
+    // Biggest pairs first, so a huge file is not left to one core at the end
+    let pair_size = |rec: &FileRec| {
+        file_len(&rec.path)
+            + old_map_arc
+                .get(&options.path_key(&rec.rel))
+                .map_or(0, |old| old_len(old))
+    };
+    let phase = info_span!("files");
+    let files_phase = phase.enter();
+    let temp_results = largest_first(&new_files, pair_size, |_, rec| {
+        let _phase = phase.enter();
+        let old_map = old_map_arc.clone();
+        let new_size = file_len(&rec.path);
+        let old = old_map.get(&options.path_key(&rec.rel)).copied();
+        let old_size = old.map_or(0, old_len);
+        let normalization = options.normalization(&rec.rel);
+        let mode = file_mode(&rec.path)?;
+        let old_path = old.map(|old| old.path.as_path());
+        if let Some(checkpoint) = &options.checkpoint
+            && let Some(res) = checkpoint.resume(rec, old_path, options)?
+        {
+            advance(new_size + old_size);
+            return Ok(res);
+        }
+
+        let res = if let Some(old) = old {
+            let old_path = &old.path;
+            // Hash both sides of the pair at once so a big file keeps two cores busy
+            let (old_hash, new_hash) = rayon::join(
+                || hash_old(old, normalization),
+                || {
+                    options
+                        .new_hashes
+                        .get_or_hash(&rec.path, display, normalization)
+                },
+            );
+            let (old_hash, new_hash) = (old_hash?, new_hash?);
+
+            if old_hash == new_hash {
+                // unchanged
+                TempResult {
+                    path: rec.rel.clone(),
+                    original_hash: old_hash,
+                    new_hash,
+                    old_size,
+                    new_size,
+                    mtime: None,
+                    normalization,
+                    mode,
+                    // Same content under another casing: renamed into the new casing
+                    kind: if old.rel == rec.rel {
+                        TempKind::Unchanged
+                    } else {
+                        TempKind::Moved(old.rel.clone())
+                    },
+                    fallback: None,
+                    chunks: None,
+                }
+            } else if new_size > 0
+                && options.is_segmented(old_size.max(new_size), &rec.rel, normalization)
+            {
+                // diffed segment by segment, so the patcher applies it in parallel and a
+                // failure costs only the segments left
+                let (chunks, entries) = build_segmented(
+                    old_path,
+                    &rec.path,
+                    options.store.as_ref(),
+                    options.xdelta_level,
+                )?;
+                TempResult {
+                    path: rec.rel.clone(),
+                    original_hash: old_hash,
+                    new_hash,
+                    old_size,
+                    new_size,
+                    mtime: options.mtime(&rec.path)?,
+                    normalization,
+                    mode,
+                    kind: TempKind::Segmented(entries),
+                    fallback: None,
+                    chunks: Some(chunks),
+                }
+            } else if new_size > 0 && old_size.max(new_size) >= STREAMED_MIN {
+                // too large to diff or for a patcher to hold in memory: stored as the chunks
+                // the old file does not already hold
+                let (chunks, entries) = build_streamed(
+                    Some(old_path),
+                    &rec.path,
+                    options.full_fallback.is_match(&rec.rel),
+                    normalization,
+                    STREAMED_ZSTD_LEVEL,
+                    options.store.as_ref(),
+                )?;
+                TempResult {
+                    path: rec.rel.clone(),
+                    original_hash: old_hash,
+                    new_hash,
+                    old_size,
+                    new_size,
+                    mtime: options.mtime(&rec.path)?,
+                    normalization,
+                    mode,
+                    kind: TempKind::Streamed(entries),
+                    fallback: None,
+                    chunks: Some(chunks),
+                }
+            } else if old_size == 0 || new_size == 0 {
+                // changed from or to an empty file: a delta would be all overhead, so the new
+                // content is stored as it is, and the old file need not be read
+                let key = EntryKey::Full {
+                    new: entry_hash(&rec.path, new_hash, normalization, display)?,
+                };
+                let data = build_entry(options.store.as_ref(), &key, || {
+                    let _span = info_span!("read").entered();
+                    Ok(fs::read(&rec.path)?)
+                })?;
+                TempResult {
+                    path: rec.rel.clone(),
+                    original_hash: old_hash,
+                    new_hash,
+                    old_size,
+                    new_size,
+                    mtime: options.mtime(&rec.path)?,
+                    normalization,
+                    mode,
+                    kind: TempKind::Patched(key, data, None),
+                    fallback: None,
+                    chunks: None,
                 }
             } else {
-                // added
-                let mut buffer = Vec::new();
-                File::open(&rec.path)?.read_to_end(&mut buffer)?;
+                // changed
+                if !old_path.is_file() {
+                    anyhow::bail!(
+                        "{} changed, but {} holds no copy of it",
+                        rec.rel,
+                        old_dir.display()
+                    );
+                }
+                let store = options.store.as_ref();
+                let transform = options.transforms.find(&rec.rel);
+                let (old_entry, new_entry) = rayon::join(
+                    || entry_hash(old_path, old_hash, normalization, display),
+                    || entry_hash(&rec.path, new_hash, normalization, display),
+                );
+                let (old_entry, new_entry) = (old_entry?, new_entry?);
+                let key = EntryKey::Delta {
+                    old: old_entry,
+                    new: new_entry,
+                    transform: transform.map(|t| options.transforms.id(t)),
+                    level: options.xdelta_level,
+                };
+                let wants_fallback = options.full_fallback.is_match(&rec.rel);
+                let chunked = old_size.max(new_size) >= CHUNKED_MIN;
+                let imported = options
+                    .imported
+                    .get(&(old_entry, new_entry))
+                    .filter(|_| transform.is_none());
+                let (patch_data, fallback) = rayon::join(
+                    || match imported {
+                        // Stored as made, and kept out of the store it was not built for
+                        Some(delta) => Ok(PatchData::Xdelta(
+                            fs::read(delta)
+                                .with_context(|| format!("Reading {}", delta.display()))?,
+                        )),
+                        None => build_entry(store, &key, || match transform {
+                            Some(t) => create_transformed_patch(
+                                old_path,
+                                &rec.path,
+                                options.transforms.get(t),
+                                options.xdelta_level,
+                            ),
+                            None => create_patch(old_path, &rec.path, options.xdelta_level),
+                        }),
+                    },
+                    || -> Result<(Option<Fallback>, Option<Chunks>)> {
+                        if chunked {
+                            let level = wants_fallback.then_some(FALLBACK_ZSTD_LEVEL);
+                            return Ok((
+                                None,
+                                Some(build_chunks(old_path, &rec.path, level, store)?),
+                            ));
+                        }
+                        if !wants_fallback {
+                            return Ok((None, None));
+                        }
+                        Ok((
+                            Some(build_fallback(store, &rec.path, &rec.rel, new_entry)?),
+                            None,
+                        ))
+                    },
+                );
+                let (patch_data, (fallback, chunks)) = (patch_data?, fallback?);
                 TempResult {
                     path: rec.rel.clone(),
-                    original_hash: [0u8; 32],
+                    original_hash: old_hash,
                     new_hash,
-                    kind: TempKind::Added(PatchData::Full(buffer)),
+                    old_size,
+                    new_size,
+                    mtime: options.mtime(&rec.path)?,
+                    normalization,
+                    mode,
+                    kind: TempKind::Patched(key, patch_data, transform),
+                    fallback,
+                    chunks,
                 }
+            }
+        } else {
+            // added
+            let new_hash = options
+                .new_hashes
+                .get_or_hash(&rec.path, display, normalization)?;
+            let moved_from = moved_sources
+                .lock()
+                .unwrap()
+                .get_mut(&new_hash)
+                .and_then(|sources| sources.pop());
+            if let Some(from) = moved_from {
+                advance(new_size);
+                return Ok(TempResult {
+                    path: rec.rel.clone(),
+                    original_hash: new_hash,
+                    new_hash,
+                    old_size: new_size,
+                    new_size,
+                    mtime: None,
+                    normalization,
+                    mode,
+                    kind: TempKind::Moved(from),
+                    fallback: None,
+                    chunks: None,
+                });
+            }
+            // Entries are made between raw bytes, so a normalized file is always stored
+            let copied_from = copy_sources
+                .get(&new_hash)
+                .filter(|_| normalization == Normalization::None);
+            if let Some(from) = copied_from {
+                advance(new_size);
+                return Ok(TempResult {
+                    path: rec.rel.clone(),
+                    original_hash: new_hash,
+                    new_hash,
+                    old_size: new_size,
+                    new_size,
+                    mtime: options.mtime(&rec.path)?,
+                    normalization,
+                    mode,
+                    kind: TempKind::Copied(from.clone()),
+                    fallback: None,
+                    chunks: None,
+                });
+            }
+            let (kind, chunks) = if new_size >= STREAMED_MIN {
+                let (chunks, entries) = build_streamed(
+                    None,
+                    &rec.path,
+                    true,
+                    normalization,
+                    STREAMED_ZSTD_LEVEL,
+                    options.store.as_ref(),
+                )?;
+                (TempKind::Streamed(entries), Some(chunks))
+            } else {
+                let key = EntryKey::Full {
+                    new: entry_hash(&rec.path, new_hash, normalization, display)?,
+                };
+                let data = build_entry(options.store.as_ref(), &key, || {
+                    let _span = info_span!("read").entered();
+                    let mut buffer = Vec::new();
+                    File::open(&rec.path)?.read_to_end(&mut buffer)?;
+                    Ok(buffer)
+                })?;
+                (TempKind::Added(key, data), None)
             };
+            TempResult {
+                path: rec.rel.clone(),
+                original_hash: [0u8; 32],
+                new_hash,
+                old_size,
+                new_size,
+                mtime: options.mtime(&rec.path)?,
+                normalization,
+                mode,
+                kind,
+                fallback: None,
+                chunks,
+            }
+        };
 
-            overall_pb.inc(1);
-            Ok::<TempResult, anyhow::Error>(res)
-        })
-        .collect();
-
-    let temp_results = temp_results?;
+        if let Some(checkpoint) = &options.checkpoint {
+            checkpoint.record(rec, old_path, &res)?;
+        }
+        advance(new_size + old_size);
+        Ok::<TempResult, anyhow::Error>(res)
+    })?;
+    drop(files_phase);
 
-    // Delete extra files if --delete-extra was used
-    let deleted_entries: Vec<FileEntry> = if delete_extra {
-        let worker_bars = worker_bars.clone();
-        old_files
-            .par_iter()
-            .filter(|rec| !new_set.contains(&rec.rel))
-            .map(|rec| {
-                let worker_bars = worker_bars.clone();
+    // Changed files matched across a difference in case are patched under the new casing,
+    // which only a filesystem that ignores case finds their base under
+    if options.ignore_path_case {
+        let recased: Vec<String> = temp_results
+            .iter()
+            .filter(|r| {
+                matches!(
+                    r.kind,
+                    TempKind::Patched(..) | TempKind::Streamed(_) | TempKind::Segmented(_)
+                )
+            })
+            .filter_map(|r| {
+                let old = old_map_arc.get(&options.path_key(&r.path))?;
+                (old.rel != r.path).then(|| format!("  {} -> {}", old.rel, r.path))
+            })
+            .collect();
+        if !recased.is_empty() {
+            eprintln!(
+                "Warning: changed files differ from their old paths only by case; patchers \
+                 refuse to apply this patch on case-sensitive filesystems:\n{}",
+                recased.join("\n")
+            );
+        }
+    }
 
-                let old_hash = hash_file(&rec.path, &worker_bars)?;
-                overall_pb.inc(1);
+    // A moved file's old path is consumed by the rename, so it no longer needs deleting
+    let moved: HashSet<&str> = temp_results
+        .iter()
+        .filter_map(|r| match &r.kind {
+            TempKind::Moved(from) => Some(from.as_str()),
+            _ => None,
+        })
+        .collect();
+    let deleted_entries: Vec<FileEntry> = deleted_entries
+        .into_iter()
+        .filter(|entry| !moved.contains(entry.path.as_str()))
+        .collect();
 
-                Ok::<FileEntry, anyhow::Error>(FileEntry {
-                    path: rec.rel.clone(),
-                    kind: PatchKind::Deleted,
-                    original_hash: old_hash,
-                    new_hash: [0u8; 32],
-                })
-            })
-            .collect::<Result<Vec<_>>>()?
-    } else {
-        Vec::new()
-    };
+    // A full install also needs the files it would otherwise take from the old installation,
+    // and those changed from or to an empty file, which have no fallback of their own
+    let mut temp_results = temp_results;
+    if options.full_install {
+        let _span = info_span!("full_copies").entered();
+        temp_results.par_iter_mut().try_for_each(|r| -> Result<()> {
+            if matches!(r.kind, TempKind::Added(..)) || r.fallback.is_some() || r.chunks.is_some() {
+                return Ok(());
+            }
+            let path = new_dir.join(&r.path);
+            if r.new_size >= STREAMED_MIN {
+                // Too large for one full copy, so an unchanged file is streamed from its chunks
+                if !matches!(r.kind, TempKind::Unchanged) {
+                    anyhow::bail!(
+                        "{} is {} bytes: --full-install cannot restore a moved or copied file of {} or more",
+                        r.path,
+                        r.new_size,
+                        indicatif::HumanBytes(STREAMED_MIN)
+                    );
+                }
+                let level = STREAMED_ZSTD_LEVEL;
+                let (chunks, entries) =
+                    build_streamed(None, &path, true, r.normalization, level, options.store.as_ref())?;
+                r.kind = TempKind::Streamed(entries);
+                r.chunks = Some(chunks);
+                return Ok(());
+            }
+            let new = entry_hash(&path, r.new_hash, r.normalization, display)?;
+            r.fallback = Some(build_fallback(options.store.as_ref(), &path, &r.path, new)?);
+            Ok(())
+        })?;
+    }
 
-    // Final assembly
+    // Final assembly; files with identical content share one entry
     let mut entries_vec = Vec::<PatchData>::new();
+    let mut entry_index = HashMap::<EntryKey, usize>::new();
     let mut files_vec = Vec::<FileEntry>::new();
+    let mut chunked_vec = Vec::<ChunkedFile>::new();
+    let mut add_entry = |key: EntryKey, data: PatchData| {
+        *entry_index.entry(key).or_insert_with(|| {
+            entries_vec.push(data);
+            entries_vec.len() - 1
+        })
+    };
 
     for r in temp_results {
+        // Only files the patcher writes can keep their holes
+        let sparse = !matches!(r.kind, TempKind::Unchanged | TempKind::Moved(_))
+            && options.sparse.keeps_holes(&new_dir.join(&r.path));
         match r.kind {
             TempKind::Unchanged => {
+                let full_copy = r.fallback.map(|(key, data)| add_entry(key, data));
                 files_vec.push(FileEntry {
                     path: r.path,
                     kind: PatchKind::Unchanged,
                     original_hash: r.original_hash,
                     new_hash: r.new_hash,
+                    old_size: r.old_size,
+                    new_size: r.new_size,
+                    mtime: r.mtime,
+                    normalization: r.normalization,
+                    mode: r.mode,
+                    sparse: false,
+                    full_copy,
                 });
             }
-            TempKind::Added(patch_data) => {
-                let idx = entries_vec.len();
-                entries_vec.push(patch_data);
+            TempKind::Moved(from) => {
+                let full_copy = r.fallback.map(|(key, data)| add_entry(key, data));
+                files_vec.push(FileEntry {
+                    path: r.path,
+                    kind: PatchKind::Moved { from },
+                    original_hash: r.original_hash,
+                    new_hash: r.new_hash,
+                    old_size: r.old_size,
+                    new_size: r.new_size,
+                    mtime: r.mtime,
+                    normalization: r.normalization,
+                    mode: r.mode,
+                    sparse: false,
+                    full_copy,
+                });
+            }
+            TempKind::Copied(from) => {
+                let full_copy = r.fallback.map(|(key, data)| add_entry(key, data));
+                files_vec.push(FileEntry {
+                    path: r.path,
+                    kind: PatchKind::Copied { from },
+                    original_hash: r.original_hash,
+                    new_hash: r.new_hash,
+                    old_size: r.old_size,
+                    new_size: r.new_size,
+                    mtime: r.mtime,
+                    normalization: r.normalization,
+                    mode: r.mode,
+                    sparse,
+                    full_copy,
+                });
+            }
+            TempKind::Streamed(entries) => {
+                let chunks = entries
+                    .into_iter()
+                    .map(|entry| entry.map(|(key, data)| add_entry(key, data)))
+                    .collect();
+                if let Some(chunks) = r.chunks {
+                    chunked_vec.push(ChunkedFile {
+                        path: r.path.clone(),
+                        old_chunks: chunks.old,
+                        new_chunks: chunks.new,
+                        chunk_entries: Vec::new(),
+                    });
+                }
+                files_vec.push(FileEntry {
+                    path: r.path,
+                    kind: PatchKind::Streamed { chunks },
+                    original_hash: r.original_hash,
+                    new_hash: r.new_hash,
+                    old_size: r.old_size,
+                    new_size: r.new_size,
+                    mtime: r.mtime,
+                    normalization: r.normalization,
+                    mode: r.mode,
+                    sparse,
+                    full_copy: None,
+                });
+            }
+            TempKind::Segmented(entries) => {
+                let segments = entries
+                    .into_iter()
+                    .map(|entry| entry.map(|(key, data)| add_entry(key, data)))
+                    .collect();
+                if let Some(chunks) = r.chunks {
+                    chunked_vec.push(ChunkedFile {
+                        path: r.path.clone(),
+                        old_chunks: chunks.old,
+                        new_chunks: chunks.new,
+                        chunk_entries: Vec::new(),
+                    });
+                }
+                files_vec.push(FileEntry {
+                    path: r.path,
+                    kind: PatchKind::Segmented { segments },
+                    original_hash: r.original_hash,
+                    new_hash: r.new_hash,
+                    old_size: r.old_size,
+                    new_size: r.new_size,
+                    mtime: r.mtime,
+                    normalization: r.normalization,
+                    mode: r.mode,
+                    sparse,
+                    full_copy: None,
+                });
+            }
+            TempKind::Added(key, patch_data) => {
+                let idx = add_entry(key, patch_data);
                 files_vec.push(FileEntry {
                     path: r.path,
                     kind: PatchKind::Added { idx },
                     original_hash: r.original_hash,
                     new_hash: r.new_hash,
+                    old_size: r.old_size,
+                    new_size: r.new_size,
+                    mtime: r.mtime,
+                    normalization: r.normalization,
+                    mode: r.mode,
+                    sparse,
+                    full_copy: None,
                 });
             }
-            TempKind::Patched(patch_data) => {
-                let idx = entries_vec.len();
-                entries_vec.push(patch_data);
+            TempKind::Patched(key, patch_data, transform) => {
+                let idx = add_entry(key, patch_data);
+                let fallback = r.fallback.map(|(key, data)| add_entry(key, data));
+                if let Some(chunks) = r.chunks {
+                    chunked_vec.push(ChunkedFile {
+                        path: r.path.clone(),
+                        old_chunks: chunks.old,
+                        new_chunks: chunks.new,
+                        chunk_entries: chunks
+                            .entries
+                            .into_iter()
+                            .map(|(key, data)| add_entry(key, data))
+                            .collect(),
+                    });
+                }
                 files_vec.push(FileEntry {
                     path: r.path,
-                    kind: PatchKind::Patched { idx },
+                    kind: PatchKind::Patched {
+                        idx,
+                        fallback,
+                        transform,
+                    },
                     original_hash: r.original_hash,
                     new_hash: r.new_hash,
+                    old_size: r.old_size,
+                    new_size: r.new_size,
+                    mtime: r.mtime,
+                    normalization: r.normalization,
+                    mode: r.mode,
+                    sparse,
+                    full_copy: None,
                 });
             }
         }
@@ -303,17 +2142,49 @@ fn build_bundle(
 
     files_vec.extend(deleted_entries);
 
-    overall_pb.finish_with_message("Bundle build complete");
-
-    for (i, wb) in worker_bars.iter().enumerate() {
-        wb.finish_with_message(format!("Worker {i}: done"));
-    }
+    display.finish("Bundle build complete");
 
+    let cost = apply_cost(&files_vec);
+    let diffs = if options.store_diffs {
+        text_diffs(&files_vec, old_dir, new_dir)?
+    } else {
+        Vec::new()
+    };
     let manifest = Manifest {
+        min_stub_version: options.format_version,
         product: product.to_string(),
         from_version: from_version.to_string(),
         to_version: to_version.to_string(),
         files: files_vec,
+        entries: Vec::new(),
+        markers: VersionMarkers::default(),
+        compression: Compression::None,
+        transforms: options.transforms.transforms().to_vec(),
+        created_dirs: new_scan.empty_dirs.clone(),
+        deleted_dirs: if extra_files != ExtraFiles::Keep {
+            old_scan
+                .empty_dirs
+                .into_iter()
+                .filter(|dir| {
+                    !new_dir.join(dir).is_dir() && !options.deletion_guard.is_protected(dir)
+                })
+                .collect()
+        } else {
+            Vec::new()
+        },
+        fingerprints: Vec::new(),
+        eula: None,
+        cost,
+        main_exe: None,
+        chunked: chunked_vec,
+        wizard: None,
+        mirrors: Vec::new(),
+        metadata: BTreeMap::new(),
+        release: None,
+        dictionary: None,
+        stub_hash: None,
+        frames: Vec::new(),
+        diffs,
     };
 
     Ok(PatchBundle {
@@ -322,13 +2193,49 @@ fn build_bundle(
     })
 }
 
-fn create_patch(old_path: &Path, new_path: &Path) -> Result<Vec<u8>> {
+/// What applying `files` to the old version reads, writes and decodes, for the estimate the
+/// stub shows before it starts.
+fn apply_cost(files: &[FileEntry]) -> ApplyCost {
+    let mut cost = ApplyCost::default();
+    for file in files {
+        // Every file the patch reads or keeps is hashed first; moves and copies hash their source
+        cost.verify_bytes += file.old_size;
+        match file.kind {
+            PatchKind::Unchanged | PatchKind::Deleted | PatchKind::Moved { .. } => {}
+            PatchKind::Added { .. } => cost.write_bytes += file.new_size,
+            PatchKind::Copied { .. } => {
+                cost.read_bytes += file.new_size;
+                cost.write_bytes += file.new_size;
+            }
+            PatchKind::Patched { .. } => {
+                cost.read_bytes += file.old_size;
+                cost.decode_bytes += file.new_size;
+                cost.write_bytes += file.new_size;
+            }
+            PatchKind::Streamed { .. } => {
+                cost.read_bytes += file.old_size;
+                cost.write_bytes += file.new_size;
+            }
+            PatchKind::Segmented { ref segments } => {
+                // Each delta also reads the chunks on either side of its own
+                let deltas = segments.iter().flatten().count() as u64;
+                cost.read_bytes += file.old_size + 2 * deltas * CHUNK_SIZE;
+                cost.decode_bytes += deltas * CHUNK_SIZE;
+                cost.write_bytes += file.new_size;
+            }
+        }
+    }
+    cost
+}
+
+fn create_patch(old_path: &Path, new_path: &Path, level: Option<u8>) -> Result<Vec<u8>> {
+    let _span = info_span!("diff").entered();
     let mut old = Vec::new();
     let mut new_ = Vec::new();
     File::open(old_path)?.read_to_end(&mut old)?;
     File::open(new_path)?.read_to_end(&mut new_)?;
 
-    let patch = xdelta3::encode(&new_, &old).context("xdelta encode failed")?;
+    let patch = xdelta::encode(&new_, &old, level).context("xdelta encode failed")?;
     Ok(patch)
 }
 
@@ -438,4 +2345,4 @@ fn create_patch(old_path: &Path, new_path: &Path) -> Result<Vec<u8>> {
 //         hasher.update(&buffer[..n]);
 //     }
 //     Ok(*hasher.finalize().as_bytes())
-// }
\ No newline at end of file
+// }
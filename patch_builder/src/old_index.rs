@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use patch_apply::hash_file;
+use patch_types::PatchKind;
+use patch_types::hex_hash;
+use patch_types::normalize::Normalization;
+use patch_types::schedule::largest_first;
+
+use crate::audit::load_manifest;
+use crate::scan::{FileRec, Scan, ScanFilter, SkipCounts, is_system_name, scan_dir};
+
+/// Sizes and hashes of every file of a release, written by `patch_builder index`.
+#[derive(Serialize, Deserialize)]
+struct TreeIndex {
+    files: Vec<IndexedFile>,
+    /// Slash-separated relative paths of directories with nothing in them
+    empty_dirs: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexedFile {
+    path: String,
+    size: u64,
+    #[serde(with = "hex_hash")]
+    hash: [u8; 32],
+    normalization: Normalization,
+}
+
+/// An old release known from an index instead of its files. `dir` holds copies of only some
+/// of them; a file is read from there only when a delta against it has to be computed.
+pub struct OldIndex {
+    pub dir: PathBuf,
+    files: HashMap<String, IndexedFile>,
+    empty_dirs: Vec<String>,
+}
+
+impl OldIndex {
+    /// Reads the index of the release whose partial copy is in `dir`: a file written by
+    /// `patch_builder index`, or a patch (or its manifest.json) to that release, whose new
+    /// hashes describe it.
+    pub fn load(path: &Path, dir: &Path) -> Result<OldIndex> {
+        let tree = fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<TreeIndex>(&bytes).ok());
+        let tree = match tree {
+            Some(tree) => tree,
+            None => {
+                let manifest = load_manifest(path).with_context(|| {
+                    format!("{} is neither an index nor a patch", path.display())
+                })?;
+                TreeIndex {
+                    files: manifest
+                        .files
+                        .into_iter()
+                        .filter(|file| !matches!(file.kind, PatchKind::Deleted))
+                        .map(|file| IndexedFile {
+                            path: file.path,
+                            size: file.new_size,
+                            hash: file.new_hash,
+                            normalization: file.normalization,
+                        })
+                        .collect(),
+                    empty_dirs: manifest.created_dirs,
+                }
+            }
+        };
+        Ok(OldIndex {
+            dir: dir.to_path_buf(),
+            files: tree
+                .files
+                .into_iter()
+                .map(|file| (file.path.clone(), file))
+                .collect(),
+            empty_dirs: tree.empty_dirs,
+        })
+    }
+
+    /// The indexed files as a scan of `dir` would list them. The size filters apply as in a
+    /// scan; hidden and system files are recognized by name only.
+    pub fn scan(&self, filter: &ScanFilter, skipped: &mut SkipCounts) -> Scan {
+        let mut files: Vec<FileRec> = Vec::new();
+        for (rel, file) in &self.files {
+            let names = || rel.split('/');
+            if filter.skip_hidden && names().any(|name| name.starts_with('.')) {
+                skipped.hidden += 1;
+            } else if filter.skip_system && names().any(is_system_name) {
+                skipped.system += 1;
+            } else if filter.skip_empty && file.size == 0 {
+                skipped.empty += 1;
+            } else if filter.min_size.is_some_and(|min| file.size < min)
+                || filter.max_size.is_some_and(|max| file.size > max)
+            {
+                skipped.size += 1;
+            } else {
+                files.push(FileRec {
+                    rel: rel.clone(),
+                    path: self.dir.join(rel),
+                });
+            }
+        }
+        files.sort_by(|a, b| a.rel.cmp(&b.rel));
+        Scan {
+            files,
+            empty_dirs: self.empty_dirs.clone(),
+        }
+    }
+
+    pub fn len(&self, rel: &str) -> Option<u64> {
+        self.files.get(rel).map(|file| file.size)
+    }
+
+    /// Indexed hash of `rel`, if it was recorded with `normalization`.
+    pub fn hash(&self, rel: &str, normalization: Normalization) -> Option<[u8; 32]> {
+        self.files
+            .get(rel)
+            .filter(|file| file.normalization == normalization)
+            .map(|file| file.hash)
+    }
+}
+
+/// Hashes every file in `dir` and writes the index `--old-index` reads.
+pub fn write_index(dir: &Path, output: &Path) -> Result<()> {
+    let scan = scan_dir(dir, &ScanFilter::default(), &mut SkipCounts::default())?;
+    let files = largest_first(
+        &scan.files,
+        |rec| fs::metadata(&rec.path).map_or(0, |meta| meta.len()),
+        |_, rec| -> Result<IndexedFile> {
+            Ok(IndexedFile {
+                path: rec.rel.clone(),
+                size: fs::metadata(&rec.path)?.len(),
+                hash: hash_file(&rec.path, Normalization::None)
+                    .with_context(|| format!("Hashing {}", rec.rel))?,
+                normalization: Normalization::None,
+            })
+        },
+    )?;
+    let count = files.len();
+    let index = TreeIndex {
+        files,
+        empty_dirs: scan.empty_dirs,
+    };
+    let out = File::create(output).with_context(|| format!("Creating {}", output.display()))?;
+    serde_json::to_writer(out, &index).with_context(|| format!("Writing {}", output.display()))?;
+    println!(
+        "Indexed {count} files of {} to {}",
+        dir.display(),
+        output.display()
+    );
+    Ok(())
+}
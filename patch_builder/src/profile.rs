@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use tracing::Subscriber;
+use tracing::span::{Attributes, Id};
+use tracing_subscriber::layer::{Context as LayerContext, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Time spent in the builder's `tracing` spans (scan, hash, diff, compress, serialize,
+/// write...), collected for `--profile`. Spans that run on several workers at once add up
+/// their time, so a stage can take longer in total than the build did.
+#[derive(Clone, Default)]
+pub struct Profile {
+    /// By stack of span names from the root, e.g. `build;files;diff`
+    stacks: Arc<Mutex<HashMap<String, Totals>>>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Totals {
+    calls: u64,
+    total: Duration,
+    /// Time not spent in child spans
+    own: Duration,
+}
+
+/// When a span started, and how long its closed children took.
+struct Timing {
+    started: Instant,
+    children: Duration,
+}
+
+impl Profile {
+    /// Records the spans of the whole process from now on.
+    pub fn install() -> Result<Self> {
+        let profile = Profile::default();
+        tracing::subscriber::set_global_default(
+            tracing_subscriber::registry().with(profile.clone()),
+        )
+        .context("Installing the profiler")?;
+        Ok(profile)
+    }
+
+    /// Writes the spans to `path` in the folded stack format flame graph tools read (one
+    /// `root;child;leaf <microseconds>` line per stack, with the time not spent in children),
+    /// and prints where the time went by span name.
+    pub fn report(&self, path: &Path) -> Result<()> {
+        let stacks = self.stacks.lock().unwrap();
+        let mut folded: Vec<String> = stacks
+            .iter()
+            .map(|(stack, totals)| format!("{stack} {}", totals.own.as_micros()))
+            .collect();
+        folded.sort();
+        fs::write(path, folded.join("\n") + "\n")
+            .with_context(|| format!("Writing profile {}", path.display()))?;
+
+        let mut by_name = HashMap::<&str, Totals>::new();
+        for (stack, totals) in stacks.iter() {
+            let name = stack.rsplit(';').next().unwrap_or(stack);
+            let sum = by_name.entry(name).or_default();
+            sum.calls += totals.calls;
+            sum.total += totals.total;
+            sum.own += totals.own;
+        }
+        let mut by_name: Vec<(&str, Totals)> = by_name.into_iter().collect();
+        by_name.sort_by_key(|(_, totals)| std::cmp::Reverse(totals.total));
+        eprintln!("Time by stage (summed over workers):");
+        for (name, totals) in by_name {
+            eprintln!(
+                "  {name:<12} {:>10.2}s total {:>10.2}s own {:>8} calls",
+                totals.total.as_secs_f64(),
+                totals.own.as_secs_f64(),
+                totals.calls
+            );
+        }
+        eprintln!(
+            "Flame graph stacks written to {} (e.g. inferno-flamegraph < it > profile.svg)",
+            path.display()
+        );
+        Ok(())
+    }
+}
+
+impl<S> Layer<S> for Profile
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _attrs: &Attributes<'_>, id: &Id, ctx: LayerContext<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(Timing {
+                started: Instant::now(),
+                children: Duration::ZERO,
+            });
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: LayerContext<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some((elapsed, children)) = span
+            .extensions()
+            .get::<Timing>()
+            .map(|timing| (timing.started.elapsed(), timing.children))
+        else {
+            return;
+        };
+        if let Some(parent) = span.parent()
+            && let Some(timing) = parent.extensions_mut().get_mut::<Timing>()
+        {
+            timing.children += elapsed;
+        }
+        let stack: Vec<&str> = span.scope().from_root().map(|span| span.name()).collect();
+        let mut stacks = self.stacks.lock().unwrap();
+        let totals = stacks.entry(stack.join(";")).or_default();
+        totals.calls += 1;
+        totals.total += elapsed;
+        totals.own += elapsed.saturating_sub(children);
+    }
+}
@@ -0,0 +1,61 @@
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use patch_types::Manifest;
+use patch_types::hex_hash;
+
+use crate::output::{Destination, OutputDigest};
+
+/// Release metadata of one patcher, as read by the release page generator.
+#[derive(Serialize)]
+struct Metadata<'a> {
+    product: &'a str,
+    from_version: &'a str,
+    to_version: &'a str,
+    file_name: String,
+    url: Option<&'a str>,
+    size: u64,
+    sha256: String,
+    blake3: String,
+}
+
+/// Writes `<output>.sha256`, in the format `sha256sum -c` checks, and `<output>.metadata.json`
+/// next to the patch.
+pub fn write_metadata(
+    manifest: &Manifest,
+    digest: &OutputDigest,
+    output: &Destination,
+    url: Option<&str>,
+) -> Result<()> {
+    let file_name = output.file_name();
+    let sha256 = hex_hash::encode(&digest.sha256);
+    write_sidecar(
+        output,
+        ".sha256",
+        format!("{sha256}  {file_name}\n").as_bytes(),
+    )?;
+
+    let metadata = Metadata {
+        product: &manifest.product,
+        from_version: &manifest.from_version,
+        to_version: &manifest.to_version,
+        file_name,
+        url,
+        size: digest.size,
+        sha256,
+        blake3: hex_hash::encode(&digest.blake3),
+    };
+    let mut json = serde_json::to_vec_pretty(&metadata)?;
+    json.push(b'\n');
+    write_sidecar(output, ".metadata.json", &json)
+}
+
+/// Writes `bytes` to `<output><suffix>`, next to the patch.
+pub fn write_sidecar(output: &Destination, suffix: &str, bytes: &[u8]) -> Result<()> {
+    let dest = output.with_suffix(suffix);
+    let mut out = dest.open(false)?;
+    out.write_all(bytes)?;
+    out.finish().with_context(|| format!("Writing {dest}"))
+}
@@ -0,0 +1,147 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use path_slash::PathExt as _;
+use walkdir::{DirEntry, WalkDir};
+
+/// OS metadata files that are treated as system files on every platform.
+const SYSTEM_FILE_NAMES: &[&str] = &["thumbs.db", "ehthumbs.db", "desktop.ini", ".ds_store"];
+
+#[derive(Clone)]
+pub struct FileRec {
+    pub rel: String,
+    pub path: PathBuf,
+}
+
+/// Result of walking a tree.
+pub struct Scan {
+    pub files: Vec<FileRec>,
+    /// Slash-separated relative paths of directories with nothing in them
+    pub empty_dirs: Vec<String>,
+}
+
+/// Which files to leave out of the scan.
+#[derive(Default)]
+pub struct ScanFilter {
+    pub skip_hidden: bool,
+    pub skip_system: bool,
+    pub skip_empty: bool,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+/// Number of entries left out of a scan, by reason.
+#[derive(Default)]
+pub struct SkipCounts {
+    pub hidden: usize,
+    pub system: usize,
+    pub empty: usize,
+    pub size: usize,
+}
+
+impl SkipCounts {
+    pub fn total(&self) -> usize {
+        self.hidden + self.system + self.empty + self.size
+    }
+}
+
+/// Walks `dir` and returns its files and empty directories with slash-separated paths relative to `dir`.
+pub fn scan_dir(dir: &Path, filter: &ScanFilter, skipped: &mut SkipCounts) -> Result<Scan> {
+    let mut files = Vec::new();
+    let mut empty_dirs = Vec::new();
+    let mut walker = WalkDir::new(dir).into_iter();
+
+    while let Some(entry) = walker.next() {
+        let Ok(entry) = entry else { continue };
+        if entry.depth() == 0 {
+            continue;
+        }
+
+        if filter.skip_hidden && is_hidden(&entry) {
+            skipped.hidden += 1;
+            if entry.file_type().is_dir() {
+                walker.skip_current_dir();
+            }
+            continue;
+        }
+        if filter.skip_system && is_system(&entry) {
+            skipped.system += 1;
+            if entry.file_type().is_dir() {
+                walker.skip_current_dir();
+            }
+            continue;
+        }
+        if entry.file_type().is_dir() {
+            if std::fs::read_dir(entry.path())?.next().is_none() {
+                empty_dirs.push(
+                    entry
+                        .path()
+                        .strip_prefix(dir)?
+                        .to_slash()
+                        .unwrap()
+                        .to_string(),
+                );
+            }
+            continue;
+        }
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let len = entry.metadata()?.len();
+        if filter.skip_empty && len == 0 {
+            skipped.empty += 1;
+            continue;
+        }
+        if filter.min_size.is_some_and(|min| len < min)
+            || filter.max_size.is_some_and(|max| len > max)
+        {
+            skipped.size += 1;
+            continue;
+        }
+
+        let rel = entry.path().strip_prefix(dir)?;
+        let rel_str = rel.to_slash().unwrap().to_string();
+        files.push(FileRec {
+            rel: rel_str,
+            path: entry.into_path(),
+        });
+    }
+
+    Ok(Scan { files, empty_dirs })
+}
+
+/// Whether `name` is one of the files operating systems leave in folders, such as Thumbs.db.
+pub fn is_system_name(name: &str) -> bool {
+    SYSTEM_FILE_NAMES.contains(&name.to_lowercase().as_str())
+}
+
+fn is_hidden(entry: &DirEntry) -> bool {
+    if entry.file_name().to_string_lossy().starts_with('.') {
+        return true;
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        if let Ok(meta) = entry.metadata() {
+            return meta.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0;
+        }
+    }
+    false
+}
+
+fn is_system(entry: &DirEntry) -> bool {
+    if is_system_name(&entry.file_name().to_string_lossy()) {
+        return true;
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_SYSTEM: u32 = 0x4;
+        if let Ok(meta) = entry.metadata() {
+            return meta.file_attributes() & FILE_ATTRIBUTE_SYSTEM != 0;
+        }
+    }
+    false
+}
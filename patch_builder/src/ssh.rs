@@ -0,0 +1,179 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{Context, Result};
+use ring::digest;
+
+use patch_types::buffers;
+
+use crate::output::hex;
+
+/// Status ssh exits with when it could not connect or authenticate, rather than passing on
+/// the remote command's.
+const SSH_FAILED: i32 = 255;
+
+/// Status the remote script exits with when the patcher it received has the wrong size or hash.
+const NOT_RECEIVED: i32 = 253;
+
+/// Status the remote script exits with in place of the patcher's own 255, which would read as
+/// [`SSH_FAILED`].
+const PATCHER_255: i32 = 254;
+
+/// Applies `patcher` to `target` on every host, `parallel` hosts at a time. Each host is sent
+/// the patcher over the ssh connection's input, checks its size and SHA-256, runs it from a
+/// temporary file that is removed afterwards, and its output comes back line by line, prefixed with the host. The patcher runs
+/// with `--accessible`, so its progress arrives as plain sentences, and `--no-launch`; any
+/// `patcher_args` follow. Fails naming every host that was not patched.
+pub fn deploy(
+    patcher: &Path,
+    hosts: &[String],
+    target: &str,
+    parallel: usize,
+    ssh_args: &[String],
+    patcher_args: &[String],
+) -> Result<()> {
+    let (size, sha256) = hash(patcher).with_context(|| format!("Reading {}", patcher.display()))?;
+    let mut command = format!("--target {} --accessible --no-launch", quote(target));
+    for arg in patcher_args {
+        command.push(' ');
+        command.push_str(&quote(arg));
+    }
+    let script = format!(
+        "f=$(mktemp \"${{TMPDIR:-/tmp}}/patcher.XXXXXX\") || exit 1\n\
+         trap 'rm -f \"$f\"' EXIT\n\
+         cat > \"$f\" || exit 1\n\
+         [ \"$(wc -c < \"$f\" | tr -d ' ')\" = {size} ] || exit {NOT_RECEIVED}\n\
+         h=$({{ sha256sum \"$f\" 2>/dev/null || shasum -a 256 \"$f\"; }} | cut -d ' ' -f 1)\n\
+         [ \"$h\" = {sha256} ] || exit {NOT_RECEIVED}\n\
+         chmod +x \"$f\" || exit 1\n\
+         \"$f\" {command}\n\
+         s=$?\n\
+         [ $s -eq {SSH_FAILED} ] && s={PATCHER_255}\n\
+         exit $s"
+    );
+
+    let next = AtomicUsize::new(0);
+    let failed = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..parallel.clamp(1, hosts.len().max(1)) {
+            scope.spawn(|| {
+                while let Some(host) = hosts.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    println!("{host}: patching {target}");
+                    match run_on(host, patcher, &script, ssh_args) {
+                        Ok(()) => println!("{host}: done"),
+                        Err(e) => {
+                            println!("{host}: failed: {e:#}");
+                            failed.lock().unwrap().push((host.as_str(), e));
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    let failed = failed.into_inner().unwrap();
+    println!(
+        "Patched {} of {} servers",
+        hosts.len() - failed.len(),
+        hosts.len()
+    );
+    if failed.is_empty() {
+        return Ok(());
+    }
+    let listing = failed
+        .iter()
+        .map(|(host, e)| format!("  {host}: {e:#}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    anyhow::bail!(
+        "{} of {} servers were not patched:\n{listing}",
+        failed.len(),
+        hosts.len()
+    )
+}
+
+/// Streams `patcher` to `host` and runs `script` there, echoing its output.
+fn run_on(host: &str, patcher: &Path, script: &str, ssh_args: &[String]) -> Result<()> {
+    let mut child = Command::new("ssh")
+        // Fails instead of waiting for a password nobody is there to type
+        .args(["-o", "BatchMode=yes"])
+        .args(ssh_args)
+        .arg(host)
+        .arg(script)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Starting ssh")?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+
+    let sent = std::thread::scope(|scope| {
+        scope.spawn(|| echo(host, stdout));
+        scope.spawn(|| echo(host, stderr));
+        let sent = File::open(patcher).and_then(|mut file| io::copy(&mut file, &mut stdin));
+        if sent.is_err() {
+            // Closing the input would end the remote `cat` as if the whole patcher had arrived
+            let _ = child.kill();
+        }
+        // Ends the remote `cat`, so the patcher starts
+        drop(stdin);
+        sent
+    });
+    let status = child.wait().context("Waiting for ssh")?;
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(SSH_FAILED) => {
+            anyhow::bail!("could not connect or log in (ssh exited with {SSH_FAILED})")
+        }
+        Some(NOT_RECEIVED) => {
+            anyhow::bail!("the patcher did not arrive intact (wrong size or SHA-256)")
+        }
+        Some(PATCHER_255) => anyhow::bail!("the patcher exited with exit status: 255"),
+        _ => {
+            sent.with_context(|| format!("Sending {}", patcher.display()))?;
+            anyhow::bail!("the patcher exited with {status}")
+        }
+    }
+}
+
+/// Size and hex SHA-256 of `path`, for the remote script to check what it received.
+fn hash(path: &Path) -> io::Result<(u64, String)> {
+    let mut file = File::open(path)?;
+    let mut sha256 = digest::Context::new(&digest::SHA256);
+    let mut size = 0;
+    let mut buffer = vec![0u8; buffers::STREAM_BUFFER];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        sha256.update(&buffer[..n]);
+        size += n as u64;
+    }
+    Ok((size, hex(sha256.finish().as_ref())))
+}
+
+/// Prints each line `from` gives, prefixed with `host`.
+fn echo(host: &str, from: impl Read) {
+    for line in BufReader::new(from).split(b'\n') {
+        let Ok(line) = line else {
+            break;
+        };
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end_matches('\r');
+        if !line.is_empty() {
+            let _ = writeln!(io::stdout().lock(), "{host}: {line}");
+        }
+    }
+}
+
+/// `text` as a single-quoted POSIX shell word.
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
@@ -0,0 +1,116 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use patch_types::PatchData;
+use patch_types::hex_hash::to_hex;
+
+/// Identifies an entry by the content it was built from, so the same delta or full copy is
+/// only computed and stored once.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EntryKey {
+    /// `transform` identifies the transform the delta was made through, if any, and `level`
+    /// the xdelta compression level it was made at, `None` for xdelta's default
+    Delta {
+        old: [u8; 32],
+        new: [u8; 32],
+        transform: Option<[u8; 32]>,
+        #[serde(default)]
+        level: Option<u8>,
+    },
+    Full {
+        new: [u8; 32],
+    },
+    Fallback {
+        new: [u8; 32],
+        level: i32,
+    },
+}
+
+impl EntryKey {
+    fn file_name(&self) -> String {
+        match self {
+            EntryKey::Delta {
+                old,
+                new,
+                transform,
+                level,
+            } => {
+                let mut name = format!("delta-{}-{}", to_hex(old), to_hex(new));
+                if let Some(id) = transform {
+                    name += &format!("-t{}", to_hex(id));
+                }
+                if let Some(level) = level {
+                    name += &format!("-x{level}");
+                }
+                name
+            }
+            EntryKey::Full { new } => format!("full-{}", to_hex(new)),
+            EntryKey::Fallback { new, level } => format!("fallback-{}-{level}", to_hex(new)),
+        }
+    }
+
+    fn wrap(&self, bytes: Vec<u8>) -> PatchData {
+        match self {
+            EntryKey::Delta { .. } => PatchData::Xdelta(bytes),
+            EntryKey::Full { .. } => PatchData::Full(bytes),
+            EntryKey::Fallback { .. } => PatchData::CompressedFull(bytes),
+        }
+    }
+}
+
+/// Folder of previously built entries, shared between builder runs (e.g. every from-version
+/// of a nightly patch matrix).
+pub struct EntryStore {
+    dir: PathBuf,
+}
+
+impl EntryStore {
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Creating entry store {}", dir.display()))?;
+        Ok(EntryStore {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// Returns the stored entry for `key`, if an earlier build stored it.
+    pub fn get(&self, key: &EntryKey) -> Option<PatchData> {
+        fs::read(self.dir.join(key.file_name()))
+            .ok()
+            .map(|bytes| key.wrap(bytes))
+    }
+
+    /// Returns the stored entry for `key`, or builds it and stores it for the next run.
+    pub fn get_or_build(
+        &self,
+        key: &EntryKey,
+        build: impl FnOnce() -> Result<Vec<u8>>,
+    ) -> Result<PatchData> {
+        if let Some(data) = self.get(key) {
+            return Ok(data);
+        }
+        let path = self.dir.join(key.file_name());
+
+        let bytes = build()?;
+        // Parallel builds may share a store: write privately, then publish with a rename
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&tmp, &bytes).with_context(|| format!("Writing {}", tmp.display()))?;
+        fs::rename(&tmp, &path).with_context(|| format!("Storing {}", path.display()))?;
+        Ok(key.wrap(bytes))
+    }
+}
+
+/// Builds the entry through the store when one is configured, directly otherwise.
+pub fn build_entry(
+    store: Option<&EntryStore>,
+    key: &EntryKey,
+    build: impl FnOnce() -> Result<Vec<u8>>,
+) -> Result<PatchData> {
+    match store {
+        Some(store) => store.get_or_build(key, build),
+        None => Ok(key.wrap(build()?)),
+    }
+}
@@ -0,0 +1,78 @@
+use std::env::consts::EXE_SUFFIX;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result};
+
+/// Workspace the builder was compiled from, where `--stub-target` builds the stub.
+const WORKSPACE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/..");
+
+/// Reads the stub executable patchers are built from: `stub` if given, a fresh build for
+/// `target` if given, and otherwise the one cargo builds next to this executable.
+pub fn load_stub(stub: Option<&Path>, target: Option<&str>) -> Result<Vec<u8>> {
+    let path = match (stub, target) {
+        (Some(path), _) => path.to_path_buf(),
+        (None, Some(target)) => build_stub(target)?,
+        (None, None) => {
+            let exe = std::env::current_exe()?;
+            let path = exe.with_file_name(format!("patch_stub{EXE_SUFFIX}"));
+            if !path.is_file() {
+                anyhow::bail!(
+                    "No stub at {}; build it with `cargo build --release -p patch_stub`, or pass \
+                     --stub or --stub-target",
+                    path.display()
+                );
+            }
+            path
+        }
+    };
+    fs::read(&path).with_context(|| format!("Reading stub {}", path.display()))
+}
+
+/// Builds patch_stub in release mode for the `target` triple and returns the executable's
+/// path, as reported by cargo.
+fn build_stub(target: &str) -> Result<PathBuf> {
+    let manifest = Path::new(WORKSPACE).join("Cargo.toml");
+    if !manifest.is_file() {
+        anyhow::bail!(
+            "--stub-target needs the workspace this builder was compiled from ({}); use --stub instead",
+            Path::new(WORKSPACE).display()
+        );
+    }
+    eprintln!("Building patch_stub for {target}");
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+    let output = Command::new(&cargo)
+        .args([
+            "build",
+            "--release",
+            "-p",
+            "patch_stub",
+            "--message-format=json",
+            "--target",
+            target,
+        ])
+        .arg("--manifest-path")
+        .arg(&manifest)
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("Running {cargo}"))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "Building patch_stub for {target} failed ({})",
+            output.status
+        );
+    }
+
+    // The last artifact line names the executable, wherever the target dir is
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .rev()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|message| {
+            message["reason"] == "compiler-artifact" && message["target"]["name"] == "patch_stub"
+        })
+        .filter_map(|message| message["executable"].as_str().map(PathBuf::from))
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("cargo built no patch_stub executable for {target}"))
+}
@@ -0,0 +1,103 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use globset::{Glob, GlobMatcher};
+use serde::Deserialize;
+
+use patch_types::{Transform, run_filter};
+
+use crate::xdelta;
+
+/// One entry of the `--transforms` file.
+#[derive(Deserialize)]
+struct Rule {
+    glob: String,
+    #[serde(flatten)]
+    transform: Transform,
+}
+
+/// Transforms to apply before diffing, matched by glob; the first matching rule wins.
+#[derive(Default)]
+pub struct TransformRules {
+    matchers: Vec<GlobMatcher>,
+    transforms: Vec<Transform>,
+    ids: Vec<[u8; 32]>,
+}
+
+impl TransformRules {
+    /// Reads a JSON array of `{"glob": ..., "normalize": [...], "restore": [...]}` rules.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Reading transforms {}", path.display()))?;
+        let rules: Vec<Rule> = serde_json::from_str(&text)
+            .with_context(|| format!("Parsing transforms {}", path.display()))?;
+
+        let mut loaded = TransformRules::default();
+        for rule in rules {
+            if rule.transform.normalize.is_empty() || rule.transform.restore.is_empty() {
+                anyhow::bail!(
+                    "Transform for {} needs both a normalize and a restore command",
+                    rule.glob
+                );
+            }
+            loaded.matchers.push(
+                Glob::new(&rule.glob)
+                    .with_context(|| format!("Invalid glob {}", rule.glob))?
+                    .compile_matcher(),
+            );
+            // Identifies the transform in the entry store, independent of rule order
+            let id = *blake3::hash(serde_json::to_string(&rule.transform)?.as_bytes()).as_bytes();
+            loaded.ids.push(id);
+            loaded.transforms.push(rule.transform);
+        }
+        Ok(loaded)
+    }
+
+    /// Index of the first rule matching `rel`.
+    pub fn find(&self, rel: &str) -> Option<usize> {
+        self.matchers.iter().position(|m| m.is_match(rel))
+    }
+
+    pub fn id(&self, idx: usize) -> [u8; 32] {
+        self.ids[idx]
+    }
+
+    pub fn get(&self, idx: usize) -> &Transform {
+        &self.transforms[idx]
+    }
+
+    pub fn transforms(&self) -> &[Transform] {
+        &self.transforms
+    }
+}
+
+/// Diffs the normalized forms of two files at xdelta compression `level`. Fails unless
+/// `restore` turns the normalized new file back into the exact original, since the stub relies
+/// on that to reach the new hash.
+pub fn create_transformed_patch(
+    old_path: &Path,
+    new_path: &Path,
+    transform: &Transform,
+    level: Option<u8>,
+) -> Result<Vec<u8>> {
+    let _span = tracing::info_span!("diff").entered();
+    let normalize = |path: &Path| -> Result<Vec<u8>> {
+        let bytes = fs::read(path)?;
+        run_filter(&transform.normalize, &bytes)
+            .with_context(|| format!("Normalizing {}", path.display()))
+    };
+    let old = normalize(old_path)?;
+    let new_ = normalize(new_path)?;
+
+    let restored = run_filter(&transform.restore, &new_)
+        .with_context(|| format!("Restoring {}", new_path.display()))?;
+    if restored != fs::read(new_path)? {
+        anyhow::bail!(
+            "The restore command does not reproduce {} exactly; it cannot be patched through this transform",
+            new_path.display()
+        );
+    }
+
+    xdelta::encode(&new_, &old, level).context("xdelta encode failed")
+}
@@ -0,0 +1,90 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+
+use patch_types::hex_hash;
+use patch_types::{Manifest, PayloadRef, Release, SignedUpdateInfo, UpdateInfo};
+
+use crate::output::Destination;
+
+/// Writes the descriptor of the patch at `output` to `<output>.update.json`, signed with the
+/// Ed25519 key in `key` (PKCS#8, as written by `keygen`) when given.
+pub fn write_update_info(
+    manifest: Manifest,
+    payload: PayloadRef,
+    output: &Destination,
+    key: Option<&Path>,
+) -> Result<()> {
+    let disk_bytes = manifest.cost.write_bytes;
+    let info = serde_json::to_string(&UpdateInfo {
+        manifest,
+        payload,
+        disk_bytes,
+    })?;
+    let signature = match key {
+        Some(path) => Some(hex_hash::encode(
+            load_key(path)?.sign(info.as_bytes()).as_ref(),
+        )),
+        None => None,
+    };
+
+    let dest = output.with_suffix(".update.json");
+    let mut out = dest.open(false)?;
+    serde_json::to_writer_pretty(&mut out, &SignedUpdateInfo { info, signature })?;
+    out.flush()?;
+    out.finish().with_context(|| format!("Writing {dest}"))
+}
+
+/// The release record of `manifest`: its `counter`, signed with the key in `key` when given.
+/// `None` when there is neither.
+pub fn sign_release(
+    manifest: &Manifest,
+    counter: Option<u64>,
+    key: Option<&Path>,
+) -> Result<Option<Release>> {
+    let key = key.map(load_key).transpose()?;
+    if counter.is_none() && key.is_none() {
+        return Ok(None);
+    }
+    let text = Release::signed_text(&manifest.product, &manifest.to_version, counter);
+    Ok(Some(Release {
+        counter,
+        public_key: key
+            .as_ref()
+            .map(|key| hex_hash::encode(key.public_key().as_ref())),
+        signature: key
+            .as_ref()
+            .map(|key| hex_hash::encode(key.sign(text.as_bytes()).as_ref())),
+    }))
+}
+
+fn load_key(path: &Path) -> Result<Ed25519KeyPair> {
+    let pkcs8 =
+        fs::read(path).with_context(|| format!("Reading signing key {}", path.display()))?;
+    Ed25519KeyPair::from_pkcs8(&pkcs8)
+        .map_err(|e| anyhow::anyhow!("{} is not an Ed25519 PKCS#8 key: {e}", path.display()))
+}
+
+/// Creates a signing key for update descriptors and prints the public key that launchers and
+/// the stub's `--public-key` check signatures against.
+pub fn generate_key(path: &Path) -> Result<()> {
+    if path.exists() {
+        anyhow::bail!(
+            "{} already exists; refusing to overwrite a signing key",
+            path.display()
+        );
+    }
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map_err(|_| anyhow::anyhow!("Generating a signing key failed"))?;
+    fs::write(path, pkcs8.as_ref()).with_context(|| format!("Writing {}", path.display()))?;
+    let key = load_key(path)?;
+    println!(
+        "Public key: {}",
+        hex_hash::encode(key.public_key().as_ref())
+    );
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use patch_types::Manifest;
+
+/// Must match `FIELD_WIDTH` in the stub's build.rs.
+const FIELD_WIDTH: usize = 128;
+
+/// Signature that starts the VS_FIXEDFILEINFO block of a version resource.
+const FIXED_INFO_SIGNATURE: [u8; 4] = 0xFEEF04BDu32.to_le_bytes();
+
+/// Returns a copy of the stub whose version resource describes this patch, so installers can
+/// be told apart from their file properties. Stubs built without the placeholder resource are
+/// returned unchanged.
+pub fn stamp_version(stub: &[u8], manifest: &Manifest) -> Vec<u8> {
+    let mut stamped = stub.to_vec();
+    let description = format!(
+        "{} update {} to {}",
+        manifest.product, manifest.from_version, manifest.to_version
+    );
+    let comments = format!(
+        "Updates {} from version {}",
+        manifest.product, manifest.from_version
+    );
+    for (field, value) in [
+        ("ProductName", manifest.product.as_str()),
+        ("FileDescription", description.as_str()),
+        ("FileVersion", manifest.to_version.as_str()),
+        ("ProductVersion", manifest.to_version.as_str()),
+        ("Comments", comments.as_str()),
+    ] {
+        stamp_string(&mut stamped, field, value);
+    }
+
+    if let Some(version) = numeric_version(&manifest.to_version)
+        && let Some(pos) = find(&stamped, &FIXED_INFO_SIGNATURE)
+    {
+        // dwSignature and dwStrucVersion, then the file and product versions as MS/LS pairs
+        let ms = ((version[0] as u32) << 16 | version[1] as u32).to_le_bytes();
+        let ls = ((version[2] as u32) << 16 | version[3] as u32).to_le_bytes();
+        for offset in [8, 16] {
+            stamped[pos + offset..pos + offset + 4].copy_from_slice(&ms);
+            stamped[pos + offset + 4..pos + offset + 8].copy_from_slice(&ls);
+        }
+    }
+    stamped
+}
+
+/// Overwrites the UTF-16 placeholder for `field` with `value`, padded with NULs.
+fn stamp_string(stub: &mut [u8], field: &str, value: &str) {
+    let placeholder = utf16(&format!("{:_<FIELD_WIDTH$}", format!("${field}$")));
+    let Some(pos) = find(stub, &placeholder) else {
+        return;
+    };
+    let mut replacement = utf16(value);
+    replacement.truncate(placeholder.len());
+    replacement.resize(placeholder.len(), 0);
+    stub[pos..pos + placeholder.len()].copy_from_slice(&replacement);
+}
+
+/// `1.2.3` or `v1.2.3-beta` as the four 16-bit parts of a Windows version number.
+fn numeric_version(version: &str) -> Option<[u16; 4]> {
+    let digits = version.trim_start_matches(['v', 'V']);
+    let mut parts = [0u16; 4];
+    for (part, text) in parts.iter_mut().zip(digits.split('.')) {
+        let end = text
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(text.len());
+        *part = text[..end].parse().ok()?;
+        if end < text.len() {
+            break;
+        }
+    }
+    Some(parts)
+}
+
+fn utf16(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
@@ -0,0 +1,134 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use globset::{Glob, GlobSetBuilder};
+use serde::Deserialize;
+
+use patch_types::wizard::{Component, Wizard, WizardPage};
+use patch_types::{Manifest, PatchKind};
+
+/// A `--wizard` file: the pages in order, each tagged with its `page` kind.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WizardFile {
+    pages: Vec<PageDef>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "page", rename_all = "snake_case", deny_unknown_fields)]
+enum PageDef {
+    Welcome { title: String, text: String },
+    Eula,
+    Directory,
+    Components { components: Vec<ComponentDef> },
+    Confirm,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ComponentDef {
+    id: String,
+    name: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default = "default_true")]
+    default: bool,
+    /// Globs over new-version paths
+    files: Vec<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Reads the wizard definition at `path`, resolving each component's globs against the files
+/// of `manifest`.
+pub fn load_wizard(path: &Path, manifest: &Manifest) -> Result<Wizard> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Reading wizard {}", path.display()))?;
+    let def: WizardFile = serde_json::from_str(&text)
+        .with_context(|| format!("Parsing wizard {}", path.display()))?;
+    let mut seen = HashSet::new();
+    let mut pages = Vec::new();
+    for page in def.pages {
+        let kind = match &page {
+            PageDef::Welcome { .. } => "welcome",
+            PageDef::Eula => "eula",
+            PageDef::Directory => "directory",
+            PageDef::Components { .. } => "components",
+            PageDef::Confirm => "confirm",
+        };
+        if !seen.insert(kind) {
+            anyhow::bail!("{}: the {kind} page appears more than once", path.display());
+        }
+        // Which components are installed already depends on the folder chosen
+        if kind == "directory" && seen.contains("components") {
+            anyhow::bail!(
+                "{}: the directory page must come before the components page",
+                path.display()
+            );
+        }
+        pages.push(match page {
+            PageDef::Welcome { title, text } => WizardPage::Welcome { title, text },
+            PageDef::Eula if manifest.eula.is_none() => {
+                anyhow::bail!(
+                    "{}: the eula page needs a license, given with --eula",
+                    path.display()
+                )
+            }
+            PageDef::Eula => WizardPage::Eula,
+            PageDef::Directory => WizardPage::Directory,
+            PageDef::Components { components } => WizardPage::Components(
+                components
+                    .into_iter()
+                    .map(|component| resolve_component(component, manifest))
+                    .collect::<Result<_>>()
+                    .with_context(|| format!("In wizard {}", path.display()))?,
+            ),
+            PageDef::Confirm => WizardPage::Confirm,
+        });
+    }
+    let wizard = Wizard { pages };
+    let mut ids = HashSet::new();
+    if let Some(duplicate) = wizard
+        .components()
+        .iter()
+        .find(|component| !ids.insert(&component.id))
+    {
+        anyhow::bail!(
+            "{}: component id {} is used twice",
+            path.display(),
+            duplicate.id
+        );
+    }
+    Ok(wizard)
+}
+
+fn resolve_component(def: ComponentDef, manifest: &Manifest) -> Result<Component> {
+    let mut globs = GlobSetBuilder::new();
+    for pattern in &def.files {
+        globs.add(
+            Glob::new(pattern)
+                .with_context(|| format!("Invalid glob {pattern} in component {}", def.id))?,
+        );
+    }
+    let globs = globs.build()?;
+    let files: Vec<String> = manifest
+        .files
+        .iter()
+        .filter(|file| !matches!(file.kind, PatchKind::Deleted) && globs.is_match(&file.path))
+        .map(|file| file.path.clone())
+        .collect();
+    if files.is_empty() {
+        anyhow::bail!("Component {} matches no file of the new version", def.id);
+    }
+    Ok(Component {
+        id: def.id,
+        name: def.name,
+        description: def.description,
+        default: def.default,
+        files,
+    })
+}
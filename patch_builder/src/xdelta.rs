@@ -0,0 +1,87 @@
+//! xdelta3 encoding at a chosen compression level. The `xdelta3` crate's `encode` always runs
+//! xdelta at its default level, so other levels call the C function it wraps directly, with
+//! the level in its flags.
+//!
+//! The in-memory encoder cuts the new file into windows of up to 8 MiB and matches each one
+//! against the whole old file, the widest source window xdelta has, so every level sees all of
+//! the source; the level sets how hard the string matcher searches it. Deltas decode the same
+//! whatever level made them, so patchers need nothing new.
+
+use std::os::raw::{c_int, c_uint};
+
+/// Bit offset of the compression level in the encoder's flags (`XD3_COMPLEVEL_SHIFT`).
+const COMPLEVEL_SHIFT: u32 = 20;
+
+unsafe extern "C" {
+    /// From the xdelta3 C library the `xdelta3` crate builds and links.
+    fn xd3_encode_memory(
+        input: *const u8,
+        input_size: c_uint,
+        source: *const u8,
+        source_size: c_uint,
+        output: *mut u8,
+        output_size: *mut c_uint,
+        avail_output: c_uint,
+        flags: c_int,
+    ) -> c_int;
+}
+
+/// Encodes `input` as a delta from `source` at xdelta compression `level`, 1 (fastest) to 9
+/// (smallest), or at xdelta's default of 6 for `None`. `None` when xdelta fails.
+pub fn encode(input: &[u8], source: &[u8], level: Option<u8>) -> Option<Vec<u8>> {
+    let Some(level) = level else {
+        return xdelta3::encode(input, source);
+    };
+    let input_len = c_uint::try_from(input.len()).ok()?;
+    let source_len = c_uint::try_from(source.len()).ok()?;
+    // The room the crate's `encode` gives a delta
+    let capacity = input_len.saturating_add(source_len).saturating_mul(2);
+    let mut output = Vec::with_capacity(capacity as usize);
+    let mut written: c_uint = 0;
+    // SAFETY: the pointers and lengths describe live buffers; xdelta writes at most `capacity`
+    // bytes to `output` and how many it wrote to `written`.
+    let result = unsafe {
+        xd3_encode_memory(
+            input.as_ptr(),
+            input_len,
+            source.as_ptr(),
+            source_len,
+            output.as_mut_ptr(),
+            &mut written,
+            capacity,
+            c_int::from(level.clamp(1, 9)) << COMPLEVEL_SHIFT,
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+    // SAFETY: xdelta initialized the first `written` bytes, no more than `capacity`.
+    unsafe { output.set_len(written as usize) };
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_level_decodes_to_the_input() {
+        let source: Vec<u8> = (0..200_000u32)
+            .flat_map(|i| (i * 7).to_le_bytes())
+            .collect();
+        let mut input = source.clone();
+        input[1000..1100].fill(0xaa);
+        input.extend_from_slice(b"appended");
+        let default = encode(&input, &source, None).unwrap();
+        assert_eq!(xdelta3::decode(&default, &source).unwrap(), input);
+        for level in 1..=9 {
+            let delta = encode(&input, &source, Some(level)).unwrap();
+            assert_eq!(
+                xdelta3::decode(&delta, &source).unwrap(),
+                input,
+                "level {level}"
+            );
+        }
+        assert_eq!(encode(&input, &source, Some(6)).unwrap(), default);
+    }
+}
@@ -0,0 +1,398 @@
+//! Progress display shared by the builder and the patcher: an overall total weighted in bytes
+//! and a line per worker for the file it is on, rendered as console bars, JSON lines, to a
+//! pipe another process reads, or not at all. Every binary picks one with [`Frontend`], so
+//! they all show progress the same way.
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Context, Result};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressState, ProgressStyle};
+use serde::Serialize;
+
+pub mod throttle;
+
+use throttle::{Batch, Ticker};
+
+/// Where progress is shown. Calls come from the worker threads, so a display is shared
+/// between them. Displays throttle what they draw, so workers can report every buffer.
+pub trait ProgressDisplay: Send + Sync {
+    /// Replaces the message beside the overall total.
+    fn message(&self, message: &str);
+    /// `worker` starts a file that reads or writes `bytes`.
+    fn file_started(&self, worker: usize, path: &str, bytes: u64);
+    /// `worker` has processed `done` bytes of its current file.
+    fn file_progress(&self, worker: usize, done: u64);
+    /// Adds `bytes` to the overall total done.
+    fn advance(&self, bytes: u64);
+    /// Completes the total with `message` beside it.
+    fn finish(&self, message: &str);
+}
+
+/// How a binary shows progress, parsed from `bars`, `json`, `none` or `pipe:<PATH>`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Frontend {
+    /// Animated bars on the terminal, hidden when stderr is not one
+    #[default]
+    Bars,
+    /// One JSON object per event on stderr
+    Json,
+    Silent,
+    /// JSON lines written into a named pipe (or FIFO) that another process created and reads
+    Pipe(PathBuf),
+}
+
+impl FromStr for Frontend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bars" => Ok(Frontend::Bars),
+            "json" => Ok(Frontend::Json),
+            "none" => Ok(Frontend::Silent),
+            _ => match s.strip_prefix("pipe:") {
+                Some(path) if !path.is_empty() => Ok(Frontend::Pipe(PathBuf::from(path))),
+                _ => Err(format!(
+                    "expected bars, json, none or pipe:<PATH>, not {s:?}"
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Frontend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Frontend::Bars => write!(f, "bars"),
+            Frontend::Json => write!(f, "json"),
+            Frontend::Silent => write!(f, "none"),
+            Frontend::Pipe(path) => write!(f, "pipe:{}", path.display()),
+        }
+    }
+}
+
+impl Frontend {
+    /// Opens a display of `total` bytes, with `message` beside it and a line for each of
+    /// `workers` (none for a single transfer such as a download).
+    pub fn open(
+        &self,
+        total: u64,
+        workers: usize,
+        message: &str,
+    ) -> Result<Box<dyn ProgressDisplay>> {
+        let display: Box<dyn ProgressDisplay> = match self {
+            Frontend::Bars => Box::new(Bars::new(total, workers, message)),
+            Frontend::Json => Box::new(JsonLines::new(
+                Box::new(io::stderr()),
+                total,
+                workers,
+                message,
+            )),
+            Frontend::Silent => Box::new(Silent),
+            Frontend::Pipe(path) => {
+                // Not created: a pipe nobody reads would block the first write forever
+                let pipe = OpenOptions::new()
+                    .write(true)
+                    .open(path)
+                    .with_context(|| format!("Opening progress pipe {}", path.display()))?;
+                Box::new(JsonLines::new(Box::new(pipe), total, workers, message))
+            }
+        };
+        Ok(display)
+    }
+}
+
+/// Console bars: the overall total, then one bar per worker.
+struct Bars {
+    overall: ProgressBar,
+    /// Added in batches, so thousands of small files do not redraw the bar for each
+    finished: Batch,
+    workers: Vec<ProgressBar>,
+    /// Position updates of each worker's bar
+    tickers: Vec<Ticker>,
+}
+
+impl Bars {
+    fn new(total: u64, workers: usize, message: &str) -> Self {
+        let mp = MultiProgress::new();
+        let overall = mp.add(ProgressBar::new(total));
+        overall.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] {bar:40.cyan/blue} {binary_bytes}/{binary_total_bytes} \
+                 {binary_bytes_per_sec} ETA {eta} {msg}",
+            )
+            .expect("valid template")
+            .progress_chars("##-"),
+        );
+        overall.set_message(message.to_string());
+        let tickers = (0..workers).map(|_| Ticker::default()).collect();
+        let workers = (0..workers)
+            .map(|i| {
+                let pb = mp.add(ProgressBar::new(0));
+                let template = format!(
+                    "  [W{:02}] {{bar:30.green/black}} {{bytes}}/{{total_bytes}}",
+                    i
+                );
+                pb.set_style(
+                    ProgressStyle::with_template(&template)
+                        .expect("valid template")
+                        .with_key("bytes", |st: &ProgressState, w: &mut dyn fmt::Write| {
+                            write!(w, "{}", HumanBytes(st.pos())).ok();
+                        })
+                        .with_key(
+                            "total_bytes",
+                            |st: &ProgressState, w: &mut dyn fmt::Write| {
+                                write!(w, "{}", HumanBytes(st.len().unwrap_or(0))).ok();
+                            },
+                        )
+                        .progress_chars("##-"),
+                );
+                pb
+            })
+            .collect();
+        Bars {
+            overall,
+            finished: Batch::default(),
+            workers,
+            tickers,
+        }
+    }
+}
+
+impl ProgressDisplay for Bars {
+    fn message(&self, message: &str) {
+        self.overall.inc(self.finished.take());
+        self.overall.set_message(message.to_string());
+    }
+
+    fn file_started(&self, worker: usize, _path: &str, bytes: u64) {
+        if let (Some(pb), Some(ticker)) = (self.workers.get(worker), self.tickers.get(worker)) {
+            pb.set_length(bytes);
+            pb.set_position(0);
+            ticker.restart();
+        }
+    }
+
+    fn file_progress(&self, worker: usize, done: u64) {
+        if let (Some(pb), Some(ticker)) = (self.workers.get(worker), self.tickers.get(worker))
+            && ticker.due()
+        {
+            pb.set_position(done);
+        }
+    }
+
+    fn advance(&self, bytes: u64) {
+        if let Some(bytes) = self.finished.add(bytes) {
+            self.overall.inc(bytes);
+        }
+    }
+
+    fn finish(&self, message: &str) {
+        self.overall.inc(self.finished.take());
+        self.overall.finish_with_message(message.to_string());
+        for (i, wb) in self.workers.iter().enumerate() {
+            wb.finish_with_message(format!("Worker {i}: done"));
+        }
+    }
+}
+
+/// A line of [`JsonLines`] output, tagged by `event`.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    Start {
+        total: u64,
+        workers: usize,
+        message: &'a str,
+    },
+    Message {
+        message: &'a str,
+    },
+    FileStarted {
+        worker: usize,
+        path: &'a str,
+        bytes: u64,
+    },
+    FileProgress {
+        worker: usize,
+        done: u64,
+    },
+    /// Overall bytes done so far
+    Progress {
+        done: u64,
+    },
+    Finish {
+        done: u64,
+        message: &'a str,
+    },
+}
+
+/// One JSON object per line, for frontends in other processes or languages.
+struct JsonLines {
+    out: Mutex<Box<dyn Write + Send>>,
+    finished: Batch,
+    done: AtomicU64,
+    /// `file_progress` lines of each worker
+    tickers: Vec<Ticker>,
+}
+
+impl JsonLines {
+    fn new(out: Box<dyn Write + Send>, total: u64, workers: usize, message: &str) -> Self {
+        let lines = JsonLines {
+            out: Mutex::new(out),
+            finished: Batch::default(),
+            done: AtomicU64::new(0),
+            tickers: (0..workers).map(|_| Ticker::default()).collect(),
+        };
+        lines.send(&Event::Start {
+            total,
+            workers,
+            message,
+        });
+        lines
+    }
+
+    /// Writes `event` as a line. Write errors are ignored: a reader that went away does not
+    /// stop the work it was watching.
+    fn send(&self, event: &Event) {
+        let Ok(mut line) = serde_json::to_vec(event) else {
+            return;
+        };
+        line.push(b'\n');
+        let mut out = self.out.lock().unwrap();
+        let _ = out.write_all(&line).and_then(|_| out.flush());
+    }
+
+    /// Adds the batch still pending to the total done, returning the new total.
+    fn flush_done(&self) -> u64 {
+        let pending = self.finished.take();
+        self.done.fetch_add(pending, Ordering::Relaxed) + pending
+    }
+}
+
+impl ProgressDisplay for JsonLines {
+    fn message(&self, message: &str) {
+        let done = self.flush_done();
+        self.send(&Event::Progress { done });
+        self.send(&Event::Message { message });
+    }
+
+    fn file_started(&self, worker: usize, path: &str, bytes: u64) {
+        if let Some(ticker) = self.tickers.get(worker) {
+            ticker.restart();
+        }
+        self.send(&Event::FileStarted {
+            worker,
+            path,
+            bytes,
+        });
+    }
+
+    fn file_progress(&self, worker: usize, done: u64) {
+        if self.tickers.get(worker).is_none_or(Ticker::due) {
+            self.send(&Event::FileProgress { worker, done });
+        }
+    }
+
+    fn advance(&self, bytes: u64) {
+        if let Some(bytes) = self.finished.add(bytes) {
+            let done = self.done.fetch_add(bytes, Ordering::Relaxed) + bytes;
+            self.send(&Event::Progress { done });
+        }
+    }
+
+    fn finish(&self, message: &str) {
+        let done = self.flush_done();
+        self.send(&Event::Finish { done, message });
+    }
+}
+
+/// Shows nothing.
+struct Silent;
+
+impl ProgressDisplay for Silent {
+    fn message(&self, _message: &str) {}
+    fn file_started(&self, _worker: usize, _path: &str, _bytes: u64) {}
+    fn file_progress(&self, _worker: usize, _done: u64) {}
+    fn advance(&self, _bytes: u64) {}
+    fn finish(&self, _message: &str) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// A writer whose lines the test reads back.
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_totals_add_up_across_threads() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let lines = JsonLines::new(Box::new(Shared(out.clone())), 8000, 8, "Patching");
+        std::thread::scope(|scope| {
+            for worker in 0..8 {
+                let lines = &lines;
+                scope.spawn(move || {
+                    lines.file_started(worker, "file", 1000);
+                    for done in 1..=1000 {
+                        lines.file_progress(worker, done);
+                        lines.advance(1);
+                    }
+                });
+            }
+        });
+        lines.message("Committing");
+        lines.finish("Done");
+
+        let out = out.lock().unwrap();
+        let events: Vec<serde_json::Value> = out
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(events[0]["event"], "start");
+        assert_eq!(events[0]["total"], 8000);
+        let done: Vec<u64> = events
+            .iter()
+            .filter(|event| event["event"] == "progress")
+            .map(|event| event["done"].as_u64().unwrap())
+            .collect();
+        assert!(done.is_sorted() && done.iter().all(|&done| done <= 8000));
+        // Throttled: a line takes an interval to come due, not one per update
+        assert!(done.len() < 100, "{} progress lines", done.len());
+        let file_lines = events
+            .iter()
+            .filter(|event| event["event"] == "file_progress")
+            .count();
+        assert!(file_lines < 100, "{file_lines} file_progress lines");
+        // Everything pending is passed on before the message
+        let message = events
+            .iter()
+            .position(|event| event["event"] == "message")
+            .unwrap();
+        assert_eq!(events[message - 1]["done"], 8000);
+        let last = events.last().unwrap();
+        assert_eq!(
+            (&last["event"], &last["done"]),
+            (&"finish".into(), &8000.into())
+        );
+    }
+}
@@ -0,0 +1,111 @@
+//! Rate limits for progress updates. Workers process a file a buffer at a time, and redrawing
+//! per chunk of thousands of small files costs more CPU than the work it shows, so updates are
+//! passed on at most once per [`REDRAW_INTERVAL`]: by each display, and by the patcher's
+//! library before its observers see them.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Least time between two updates a display passes on.
+pub const REDRAW_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Limits a stream of updates from any number of threads to one per [`REDRAW_INTERVAL`].
+pub struct Ticker {
+    started: Instant,
+    /// Nanoseconds after `started` when the next update is due
+    next: AtomicU64,
+}
+
+impl Default for Ticker {
+    fn default() -> Self {
+        Ticker {
+            started: Instant::now(),
+            next: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Ticker {
+    /// A ticker whose first update is due an interval from now, for a file just started.
+    pub fn from_now() -> Self {
+        let ticker = Ticker::default();
+        ticker.restart();
+        ticker
+    }
+
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_nanos() as u64
+    }
+
+    /// Makes the next update due an interval from now, as when a worker starts a file, so a
+    /// small file passes on none at all.
+    pub fn restart(&self) {
+        let next = self.now() + REDRAW_INTERVAL.as_nanos() as u64;
+        self.next.store(next, Ordering::Relaxed);
+    }
+
+    /// Whether an update is due now; if so, the next one is due an interval later. Only the
+    /// thread that moves the deadline forward is told it is due.
+    pub fn due(&self) -> bool {
+        let now = self.now();
+        let next = self.next.load(Ordering::Relaxed);
+        now >= next
+            && self
+                .next
+                .compare_exchange(
+                    next,
+                    now + REDRAW_INTERVAL.as_nanos() as u64,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+    }
+}
+
+/// Increments of a shared progress total from many threads, passed on in batches at most
+/// once per [`REDRAW_INTERVAL`]. Call [`Batch::take`] when done for what is still pending.
+#[derive(Default)]
+pub(crate) struct Batch {
+    pending: AtomicU64,
+    ticker: Ticker,
+}
+
+impl Batch {
+    /// Adds `n`, and returns the amount to pass on when a batch is due.
+    pub fn add(&self, n: u64) -> Option<u64> {
+        self.pending.fetch_add(n, Ordering::Relaxed);
+        self.ticker.due().then(|| self.take())
+    }
+
+    /// Everything added and not yet passed on.
+    pub fn take(&self) -> u64 {
+        self.pending.swap(0, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_restarted_ticker_waits_an_interval() {
+        let ticker = Ticker::default();
+        assert!(ticker.due());
+        assert!(!ticker.due());
+        std::thread::sleep(REDRAW_INTERVAL);
+        ticker.restart();
+        assert!(!ticker.due());
+        std::thread::sleep(REDRAW_INTERVAL);
+        assert!(ticker.due());
+    }
+
+    #[test]
+    fn batches_lose_nothing() {
+        let batch = Batch::default();
+        assert_eq!(batch.add(5), Some(5));
+        assert_eq!(batch.add(3), None);
+        assert_eq!(batch.add(4), None);
+        assert_eq!(batch.take(), 7);
+        assert_eq!(batch.take(), 0);
+    }
+}
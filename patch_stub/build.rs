@@ -0,0 +1,24 @@
+/// Width every stamped string is padded to; longer values are cut off when stamping.
+const FIELD_WIDTH: usize = 128;
+
+/// The version strings are fixed-width placeholders that patch_builder overwrites with each
+/// patch's product and versions when it builds an installer. Keep in sync with the builder's
+/// `version_info` module.
+fn placeholder(field: &str) -> String {
+    format!("{:_<FIELD_WIDTH$}", format!("${field}$"))
+}
+
+fn main() {
+    let mut res = winres::WindowsResource::new();
+    for field in [
+        "ProductName",
+        "FileDescription",
+        "FileVersion",
+        "ProductVersion",
+        "Comments",
+    ] {
+        res.set(field, &placeholder(field));
+    }
+    res.set("LegalCopyright", "JJayRex");
+    res.compile().unwrap();
+}
@@ -0,0 +1,56 @@
+use std::time::{Duration, Instant};
+
+use indicatif::HumanDuration;
+
+/// Shortest time between two progress sentences, so a screen reader is not kept talking.
+const EVERY: Duration = Duration::from_secs(15);
+
+/// Progress told as plain sentences for `--accessible`, such as "42 percent complete, about
+/// 3 minutes remaining", printed on their own line at most every [`EVERY`] and only when the
+/// percentage moved.
+pub struct Announcer {
+    /// What is progressing, e.g. "Patching", for the sentence once it is done
+    what: &'static str,
+    total: u64,
+    started: Instant,
+    last: Instant,
+    last_percent: u64,
+}
+
+impl Announcer {
+    pub fn new(what: &'static str, total: u64) -> Self {
+        let now = Instant::now();
+        Announcer {
+            what,
+            total,
+            started: now,
+            last: now,
+            last_percent: 0,
+        }
+    }
+
+    /// `done` of the total bytes are done.
+    pub fn set(&mut self, done: u64) {
+        let percent = (done.saturating_mul(100) / self.total.max(1)).min(100);
+        // The end is told by `finish`
+        if percent == self.last_percent || percent == 100 || self.last.elapsed() < EVERY {
+            return;
+        }
+        self.last = Instant::now();
+        self.last_percent = percent;
+        // Assumes the rest goes as fast as what is done so far
+        let left = self.total.saturating_sub(done) as f64 / done as f64;
+        let remaining = self.started.elapsed().mul_f64(left);
+        println!(
+            "{percent} percent complete, about {} remaining",
+            HumanDuration(remaining)
+        );
+    }
+
+    /// Says the work is done, unless nothing was done.
+    pub fn finish(&self) {
+        if self.total > 0 {
+            println!("{} complete", self.what);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::serve::{Progress, Status};
+use patch_apply::throttle;
+
+/// How often the heartbeat file is rewritten and the watchdog looks for progress.
+const EVERY: Duration = Duration::from_secs(5);
+/// How long the watchdog waits for a stalled patch to stop by itself before exiting anyway,
+/// for when it is stuck inside a read or write that never returns.
+const GRACE: Duration = Duration::from_secs(60);
+/// Exit status when the watchdog ends the process, as `timeout` uses.
+pub const STALLED_EXIT: i32 = 124;
+
+/// Contents of the `--heartbeat-file`: the `/status.json` fields, and when and by whom it was
+/// last written.
+#[derive(Serialize)]
+struct Heartbeat {
+    #[serde(flatten)]
+    status: Status,
+    pid: u32,
+    /// When the file was written, in seconds since the Unix epoch
+    updated_at: u64,
+    /// Seconds since the patcher last read, wrote or downloaded anything or moved on a stage
+    idle_secs: u64,
+}
+
+/// Starts a background thread that rewrites `heartbeat` every few seconds while the patcher
+/// runs and, with a `watchdog` limit, stops a patch that makes no progress for that long: first
+/// by asking it to stop as cancelling does, then, if it is still stuck [`GRACE`] later, by
+/// calling `abandon` with how long it has been idle, which is expected to exit.
+pub fn start(
+    heartbeat: Option<PathBuf>,
+    watchdog: Option<Duration>,
+    progress: Arc<Progress>,
+    abandon: impl Fn(Duration) + Send + 'static,
+) {
+    let activity = |progress: &Progress| (throttle::bytes_moved(), progress.events());
+    std::thread::spawn(move || {
+        let mut last = activity(&progress);
+        let mut since = Instant::now();
+        let mut stalled_at = None;
+        let mut warned = false;
+        loop {
+            let now = activity(&progress);
+            if now != last {
+                last = now;
+                since = Instant::now();
+            }
+            let idle = since.elapsed();
+            if let Some(path) = &heartbeat
+                && let Err(e) = write(path, &progress, idle)
+                && !warned
+            {
+                eprintln!("Warning: could not write the heartbeat file: {e:#}");
+                warned = true;
+            }
+            if let Some(limit) = watchdog
+                && idle >= limit
+                && !progress.is_over()
+            {
+                match stalled_at {
+                    None => {
+                        progress.stall();
+                        stalled_at = Some(Instant::now());
+                    }
+                    Some(at) if Instant::now().duration_since(at) >= GRACE => abandon(idle),
+                    Some(_) => {}
+                }
+            }
+            std::thread::sleep(EVERY);
+        }
+    });
+}
+
+/// Writes the heartbeat to `path` through a temporary file, so a reader never sees half of it.
+pub fn write(path: &Path, progress: &Progress, idle: Duration) -> Result<()> {
+    let heartbeat = Heartbeat {
+        status: progress.status(),
+        pid: std::process::id(),
+        updated_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        idle_secs: idle.as_secs(),
+    };
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, serde_json::to_vec_pretty(&heartbeat)?)
+        .with_context(|| format!("Writing {}", Path::new(&temp).display()))?;
+    fs::rename(&temp, path).with_context(|| format!("Replacing {}", path.display()))
+}
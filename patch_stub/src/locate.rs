@@ -0,0 +1,209 @@
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use patch_apply::hash_file;
+use patch_types::{FileEntry, Manifest, PatchKind};
+
+/// Number of manifest files hashed to decide whether a folder holds the product.
+const SAMPLE_FILES: usize = 3;
+
+/// Lists auto-detected install folders that pass validation and lets the user pick one
+/// or type a path, which is validated the same way.
+pub fn choose_target(manifest: &Manifest) -> Result<PathBuf> {
+    // Without a file to hash every folder would pass, so nothing is detected
+    let checkable = !samples(manifest).is_empty();
+    if !checkable {
+        println!(
+            "Warning: this patch changes no existing file, so the folder cannot be checked \
+             against {} {}",
+            manifest.product, manifest.from_version
+        );
+    }
+    let valid: Vec<PathBuf> = candidates(&manifest.product)
+        .into_iter()
+        .filter(|dir| checkable && validate(manifest, dir))
+        .collect();
+
+    println!("Where is {} installed?", manifest.product);
+    for (i, dir) in valid.iter().enumerate() {
+        println!("  [{}] {}", i + 1, dir.display());
+    }
+    if valid.is_empty() {
+        println!("  (no installation of {} was detected)", manifest.product);
+    }
+
+    let stdin = std::io::stdin();
+    loop {
+        if valid.is_empty() {
+            print!("Enter the install folder: ");
+        } else {
+            print!("Choose 1-{} or enter a folder: ", valid.len());
+        }
+        std::io::stdout().flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            anyhow::bail!("No install folder selected");
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Ok(n) = line.parse::<usize>()
+            && (1..=valid.len()).contains(&n)
+        {
+            return Ok(valid[n - 1].clone());
+        }
+
+        let dir = PathBuf::from(line);
+        if validate(manifest, &dir) {
+            return Ok(dir);
+        }
+        println!(
+            "{} does not look like a {} {} installation",
+            dir.display(),
+            manifest.product,
+            manifest.from_version
+        );
+    }
+}
+
+/// Checks a few manifest hashes, accepting files at either their old or new state. A patch
+/// with no existing file to hash cannot be checked, so any existing folder passes.
+pub fn validate(manifest: &Manifest, dir: &Path) -> bool {
+    if !dir.is_dir() {
+        return false;
+    }
+
+    samples(manifest).iter().all(|file| {
+        let path = dir.join(&file.path);
+        path.is_file()
+            && hash_file(&path, file.normalization)
+                .is_ok_and(|hash| hash == file.original_hash || hash == file.new_hash)
+    })
+}
+
+/// Up to [`SAMPLE_FILES`] files the patch expects to exist, spread evenly over the manifest
+/// so one damaged or optional folder doesn't decide the check.
+fn samples(manifest: &Manifest) -> Vec<&FileEntry> {
+    let existing: Vec<_> = manifest
+        .files
+        .iter()
+        .filter(|f| {
+            !matches!(
+                f.kind,
+                PatchKind::Added { .. } | PatchKind::Moved { .. } | PatchKind::Copied { .. }
+            ) && f.original_hash != [0u8; 32]
+        })
+        .collect();
+    let count = existing.len().min(SAMPLE_FILES);
+    (0..count)
+        .map(|i| existing[i * existing.len() / count])
+        .collect()
+}
+
+/// Possible install folders: the working directory and its siblings, Steam libraries,
+/// and on Windows the uninstall registry entries matching the product name.
+fn candidates(product: &str) -> BTreeSet<PathBuf> {
+    let mut dirs = BTreeSet::new();
+
+    if let Ok(cwd) = std::env::current_dir() {
+        if let Some(parent) = cwd.parent() {
+            dirs.extend(subdirs(parent));
+        }
+        dirs.insert(cwd);
+    }
+
+    for library in steam_libraries() {
+        dirs.extend(subdirs(&library.join("steamapps").join("common")));
+    }
+
+    dirs.extend(registry_install_locations(product));
+    dirs
+}
+
+fn subdirs(dir: &Path) -> Vec<PathBuf> {
+    std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|e| e.path())
+                .filter(|p| p.is_dir())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Library roots listed in Steam's `libraryfolders.vdf`.
+fn steam_libraries() -> Vec<PathBuf> {
+    let mut roots = Vec::new();
+    if cfg!(windows) {
+        roots.push(PathBuf::from(r"C:\Program Files (x86)\Steam"));
+    } else if let Some(home) = std::env::var_os("HOME") {
+        let home = PathBuf::from(home);
+        roots.push(home.join(".steam/steam"));
+        roots.push(home.join(".local/share/Steam"));
+    }
+
+    let mut libraries = Vec::new();
+    for root in roots {
+        let Ok(vdf) = std::fs::read_to_string(root.join("steamapps").join("libraryfolders.vdf"))
+        else {
+            continue;
+        };
+        libraries.push(root);
+        for line in vdf.lines() {
+            let parts: Vec<&str> = line.split('"').collect();
+            // Lines look like: "path"		"D:\\SteamLibrary"
+            if parts.len() >= 4 && parts[1] == "path" {
+                libraries.push(PathBuf::from(parts[3].replace("\\\\", "\\")));
+            }
+        }
+    }
+    libraries
+}
+
+#[cfg(windows)]
+fn registry_install_locations(product: &str) -> Vec<PathBuf> {
+    use winreg::RegKey;
+    use winreg::enums::{HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE};
+
+    const UNINSTALL_KEYS: &[&str] = &[
+        r"SOFTWARE\Microsoft\Windows\CurrentVersion\Uninstall",
+        r"SOFTWARE\WOW6432Node\Microsoft\Windows\CurrentVersion\Uninstall",
+    ];
+
+    let product = product.to_lowercase();
+    let mut dirs = Vec::new();
+    for hive in [HKEY_LOCAL_MACHINE, HKEY_CURRENT_USER] {
+        for key in UNINSTALL_KEYS {
+            let Ok(uninstall) = RegKey::predef(hive).open_subkey(key) else {
+                continue;
+            };
+            for name in uninstall.enum_keys().filter_map(Result::ok) {
+                let Ok(app) = uninstall.open_subkey(&name) else {
+                    continue;
+                };
+                let display_name: String = app.get_value("DisplayName").unwrap_or_default();
+                if !display_name.to_lowercase().contains(&product) {
+                    continue;
+                }
+                if let Ok(location) = app.get_value::<String, _>("InstallLocation")
+                    && !location.is_empty()
+                {
+                    dirs.push(PathBuf::from(location));
+                }
+            }
+        }
+    }
+    dirs
+}
+
+#[cfg(not(windows))]
+fn registry_install_locations(_product: &str) -> Vec<PathBuf> {
+    Vec::new()
+}
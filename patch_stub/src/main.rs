@@ -1,251 +1,871 @@
-use std::fs::{self, File};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::Path;
+mod announce;
+mod heartbeat;
+mod locate;
+mod report;
+mod serve;
+mod tui;
+mod wizard;
+
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Context, Result};
-use bincode;
-use blake3;
-use indicatif::{ProgressBar, ProgressStyle, MultiProgress, ProgressState};
-use rayon::prelude::*;
-use rayon::{current_num_threads, current_thread_index};
-use xdelta3;
+use clap::{Parser, ValueEnum};
+use indicatif::{HumanBytes, HumanDuration};
+use serde::Serialize;
+
+use crate::announce::Announcer;
+use crate::report::{ErrorReport, send_report};
+use crate::serve::{Progress, serve_progress};
+use crate::tui::Tui;
+use crate::wizard::Choices;
+use patch_apply::chaos::Chaos;
+use patch_apply::net::{HttpOptions, build_agent};
+use patch_apply::probe::probe;
+use patch_apply::update::{check_update, read_update_info};
+use patch_apply::{
+    ApplyOptions, Bundle, EulaAcceptance, MirrorOrder, PatchError, PatchObserver, Remedy,
+    Selection, Stage, Summary, VerifyMode, history, throttle,
+};
+use patch_progress::{Frontend, ProgressDisplay};
+use patch_types::Manifest;
+use patch_types::hex_hash;
+
+#[derive(Parser)]
+struct Args {
+    /// Fetch the payload from this URL with HTTP range requests instead of reading it from this
+    /// executable; repeat for mirrors, tried in turn when one fails
+    #[arg(long, conflicts_with = "bundle")]
+    url: Vec<String>,
+    /// In download mode, try the mirror that answers fastest first instead of going in order
+    #[arg(long, requires = "url")]
+    fastest_mirror: bool,
+    /// Apply a zip-format patch (or another patcher executable) instead of the payload in this executable
+    #[arg(long, value_name = "PATH")]
+    bundle: Option<PathBuf>,
+    /// Proxy for download mode, e.g. http://proxy:8080 (defaults to HTTPS_PROXY/HTTP_PROXY/ALL_PROXY)
+    #[arg(long, requires = "url")]
+    proxy: Option<String>,
+    /// PEM file with root certificates to trust in download mode, e.g. a corporate proxy CA
+    #[arg(long, value_name = "PEM", requires = "url")]
+    ca_bundle: Option<PathBuf>,
+    /// Write a JSON report of the outcome and performance summary to this path
+    #[arg(long)]
+    result_json: Option<PathBuf>,
+    /// Flush every written file and its directory to disk before moving on
+    #[arg(long)]
+    durable: bool,
+    /// Wait for antivirus scanners to finish with written files before checking and moving them
+    #[arg(long)]
+    av_safe: bool,
+    /// Risky: patch files of 1 GiB or more where they are, a journaled chunk at a time, instead of
+    /// writing a copy first, for disks too full to hold one. Interrupted, such a file stays half
+    /// patched until the patcher is run again
+    #[arg(long)]
+    in_place: bool,
+    /// Folder to patch instead of the current directory
+    #[arg(long, value_name = "DIR")]
+    target: Option<PathBuf>,
+    /// Pick the folder to patch from auto-detected installations, validated against the patch
+    #[arg(long, conflicts_with = "target")]
+    choose_target: bool,
+    /// Inject random write failures, hash mismatches and simulated power loss from this seed (testing only)
+    #[arg(long, hide = true, value_name = "SEED")]
+    chaos: Option<u64>,
+    /// Serve a progress page and JSON status on this address, e.g. 127.0.0.1:8080
+    #[arg(long, value_name = "ADDR")]
+    serve_progress: Option<SocketAddr>,
+    /// Rewrite this file every few seconds with the JSON status and the time, so a deployment
+    /// tool can tell a running patcher from a stuck or vanished one
+    #[arg(long, value_name = "PATH")]
+    heartbeat_file: Option<PathBuf>,
+    /// Stop, with the installation untouched where possible, when the patcher has read, written
+    /// or downloaded nothing for this many minutes, e.g. on a hung network drive
+    #[arg(long, value_name = "MINUTES", value_parser = clap::value_parser!(u64).range(1..))]
+    watchdog: Option<u64>,
+    /// Run at low priority on a single worker, with disk and download limits, while the user keeps working
+    #[arg(long)]
+    background: bool,
+    /// Limit disk reads and writes to this many bytes per second (default in --background: 32 MiB/s)
+    #[arg(long, value_name = "BYTES_PER_SEC")]
+    io_limit: Option<u64>,
+    /// Hold at most this many bytes of file data in memory, decoding fewer files at once instead
+    #[arg(long, value_name = "BYTES")]
+    max_memory: Option<u64>,
+    /// Limit downloads to this many bytes per second (default in --background: 2 MiB/s)
+    #[arg(long, value_name = "BYTES_PER_SEC", requires = "url")]
+    download_limit: Option<u64>,
+    /// On failure, show an error report (error, OS, free disk space, versions) and POST it to this URL
+    #[arg(long, value_name = "URL")]
+    report_errors: Option<String>,
+    /// Which files to hash before patching: all, only those the patch changes, or those plus a sample of the rest
+    #[arg(long, value_enum, default_value_t = Verify::Full)]
+    verify: Verify,
+    /// Hash every file even if unchanged since the last patch recorded its hash, and read
+    /// patched files back from disk to check them
+    #[arg(long)]
+    paranoid: bool,
+    /// Verify the installation and print what patching would do, without changing anything
+    #[arg(long)]
+    plan: bool,
+    /// Also write the plan as JSON to this path (implies --plan)
+    #[arg(long, value_name = "PATH")]
+    plan_json: Option<PathBuf>,
+    /// Print the diffs the patch stores of the text files it changes, such as configs, without
+    /// changing anything
+    #[arg(long, conflicts_with_all = ["plan", "plan_json", "download_only", "apply_cached", "history"])]
+    preview: bool,
+    /// Print as JSON whether the folder is at the release the patch updates, has the space it
+    /// needs and holds no files that are open or that the user may not write, without hashing
+    /// it or changing anything; for launchers deciding what to ask the user first
+    #[arg(long, conflicts_with_all = ["plan", "plan_json", "preview", "download_only", "check_update", "history"])]
+    probe: bool,
+    /// Download and check the whole patch into the cache without applying it, e.g. overnight
+    #[arg(long, requires = "url", conflicts_with_all = ["plan", "plan_json"])]
+    download_only: bool,
+    /// Apply the patch downloaded earlier with --download-only, then delete it
+    #[arg(long, conflicts_with_all = ["url", "bundle"])]
+    apply_cached: bool,
+    /// Where --download-only stores the patch (default: <this executable>.download)
+    #[arg(long, value_name = "PATH")]
+    cache: Option<PathBuf>,
+    /// Show a full-screen view with every worker, the files done and messages, with keys to
+    /// pause and cancel
+    #[arg(long, conflicts_with_all = ["plan", "plan_json", "choose_target", "check_update", "download_only"])]
+    tui: bool,
+    /// Report progress as plain sentences every few seconds instead of animated bars, for
+    /// screen readers
+    #[arg(long, conflicts_with = "tui")]
+    accessible: bool,
+    /// How to show progress: bars, json (one event per line on stderr), none, or pipe:<PATH>
+    /// for JSON lines into a named pipe a launcher reads
+    #[arg(long, value_name = "FRONTEND", default_value_t = Frontend::Bars, conflicts_with_all = ["tui", "accessible"])]
+    progress: Frontend,
+    /// Start the product once patched without asking
+    #[arg(long, conflicts_with = "no_launch")]
+    launch: bool,
+    /// Do not offer to start the product once patched
+    #[arg(long)]
+    no_launch: bool,
+    /// Accept the patch's license agreement without being asked, for unattended installs
+    #[arg(long)]
+    accept_eula: bool,
+    /// Snapshot the folder's filesystem (Btrfs, ZFS or a VSS shadow copy) before patching, to
+    /// roll back to; patching stops if no snapshot can be taken
+    #[arg(long, conflicts_with_all = ["plan", "plan_json"])]
+    snapshot: bool,
+    /// Install the patch even over a newer release than it installs, as recorded in the receipt
+    #[arg(long)]
+    allow_downgrade: bool,
+    /// Install these optional components (comma-separated ids) instead of asking
+    #[arg(long, value_name = "IDS", value_delimiter = ',')]
+    components: Option<Vec<String>>,
+    /// Patch only the files matching this glob, e.g. 'server/**', and leave the rest as they
+    /// are; repeatable. The receipt records the installation as partially patched
+    #[arg(long, value_name = "GLOB")]
+    only: Vec<String>,
+    /// Leave the files matching this glob as they are; repeatable
+    #[arg(long, value_name = "GLOB")]
+    skip: Vec<String>,
+    /// Leave the files the patch deletes in place, and list them once patched
+    #[arg(long)]
+    no_delete: bool,
+    /// Once patched, remove every file in the folder the patch does not know, as the builder's
+    /// --delete-extra does; --plan lists them
+    #[arg(long)]
+    purge_unknown: bool,
+    /// Read an update descriptor (<patch>.update.json) from this path or URL and print as JSON
+    /// whether the folder needs it, without downloading the patch
+    #[arg(long, value_name = "PATH_OR_URL", conflicts_with_all = ["plan", "plan_json", "download_only", "apply_cached"])]
+    check_update: Option<String>,
+    /// Hex Ed25519 public key (from `patch_builder keygen`) the descriptor must be signed with
+    #[arg(long, value_name = "HEX", requires = "check_update")]
+    public_key: Option<String>,
+    /// Print every patch applied to the folder, with when, how long it took and how it ended,
+    /// without applying this one
+    #[arg(long, conflicts_with_all = ["plan", "plan_json", "download_only", "apply_cached", "check_update"])]
+    history: bool,
+}
 
-use patch_types::{PatchBundle, PatchData, PatchKind};
+/// How much of the installation is hashed before patching.
+#[derive(Clone, Copy, ValueEnum)]
+enum Verify {
+    Full,
+    ChangedOnly,
+    Sampled,
+}
 
-fn main() -> Result<()> {
-    let bundle = load_bundle()?;
-    let cwd = std::env::current_dir()?;
+impl From<Verify> for VerifyMode {
+    fn from(verify: Verify) -> Self {
+        match verify {
+            Verify::Full => VerifyMode::Full,
+            Verify::ChangedOnly => VerifyMode::ChangedOnly,
+            Verify::Sampled => VerifyMode::Sampled,
+        }
+    }
+}
 
-    verify_base_folder(&bundle, &cwd)?;
-    apply_bundle(&bundle, &cwd)?;
-    Ok(())
+/// Outcome written to `--result-json`.
+#[derive(Serialize)]
+struct ApplyResult {
+    success: bool,
+    error: Option<String>,
+    /// Kind of the [`PatchError`] behind the error, such as `hash_mismatch`, when there is one
+    error_kind: Option<&'static str>,
+    /// Suggestions for the user drawn from the error
+    remedies: Vec<String>,
+    summary: Option<Summary>,
 }
 
-fn load_bundle() -> Result<PatchBundle> {
-    let exe = std::env::current_exe()?;
-    let mut file = File::open(exe)?;
-    let len = file.metadata()?.len();
-    if len < 8 {
-        anyhow::bail!("Invalid patch exe (too small)");
+fn main() -> Result<()> {
+    let args = Args::parse();
+    if args.background {
+        throttle::lower_priority().context("Lowering process priority")?;
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build_global()?;
+    }
+    let background_limit = |limit| args.background.then_some(limit);
+    if let Some(limit) = args
+        .io_limit
+        .or(background_limit(throttle::BACKGROUND_IO_LIMIT))
+    {
+        throttle::limit_io(limit);
+    }
+    if let Some(limit) = args
+        .download_limit
+        .or(background_limit(throttle::BACKGROUND_DOWNLOAD_LIMIT))
+    {
+        throttle::limit_download(limit);
     }
 
-    // Read footer
-    file.seek(SeekFrom::End(-8))?;
-    let mut footer = [0u8; 8];
-    file.read_exact(&mut footer)?;
-    let bundle_len = u64::from_le_bytes(footer);
-    if bundle_len + 8 > len {
-        anyhow::bail!("Invalid bundle length");
+    let progress = Arc::new(Progress::default());
+    if let Some(addr) = args.serve_progress {
+        serve_progress(addr, progress.clone())?;
+    }
+    let watchdog = args
+        .watchdog
+        .map(|minutes| Duration::from_secs(minutes * 60));
+    if args.heartbeat_file.is_some() || watchdog.is_some() {
+        let (heartbeat, result_json) = (args.heartbeat_file.clone(), args.result_json.clone());
+        let stuck = progress.clone();
+        heartbeat::start(heartbeat.clone(), watchdog, progress.clone(), move |idle| {
+            // The patch could not be stopped, so the process ends around it
+            let message = format!(
+                "No progress for {}; ended by --watchdog",
+                HumanDuration(idle)
+            );
+            stuck.fail(message.clone());
+            if let Some(path) = &heartbeat {
+                let _ = heartbeat::write(path, &stuck, idle);
+            }
+            if let Some(path) = &result_json {
+                let _ = write_result(path, &Err(anyhow::anyhow!(message.clone())));
+            }
+            eprintln!("Error: {message}");
+            std::process::exit(heartbeat::STALLED_EXIT);
+        });
     }
 
-    // Read bundle
-    file.seek(SeekFrom::Start(len - 8 - bundle_len))?;
-    let mut buffer = vec![0u8; bundle_len as usize];
-    file.read_exact(&mut buffer)?;
+    let result = run(&args, &progress).map_err(|e| match watchdog {
+        Some(limit) if progress.stalled() => e.context(format!(
+            "No progress for {}; stopped by --watchdog",
+            HumanDuration(limit)
+        )),
+        _ => e,
+    });
+    match &result {
+        Ok(_) => progress.complete(),
+        Err(e) => progress.fail(format!("{e:#}")),
+    }
+    if let Some(path) = &args.heartbeat_file {
+        heartbeat::write(path, &progress, Duration::ZERO)?;
+    }
+    if let (Err(e), Some(url)) = (&result, &args.report_errors) {
+        let target = match &args.target {
+            Some(dir) => dir.clone(),
+            None => std::env::current_dir()?,
+        };
+        let report = ErrorReport::new(format!("{e:#}"), progress.patch(), &target);
+        let sent = build_agent(&HttpOptions {
+            proxy: args.proxy.clone(),
+            ca_bundle: args.ca_bundle.clone(),
+        })
+        .and_then(|agent| send_report(&report, &agent, url));
+        if let Err(report_err) = sent {
+            eprintln!("Warning: could not send error report: {report_err:#}");
+        }
+    }
 
-    let bundle: PatchBundle =
-        bincode::borrow_decode_from_slice(&buffer, bincode::config::standard())?.0;
-    Ok(bundle)
-}
+    if let Some(path) = &args.result_json {
+        write_result(path, &result)?;
+    }
 
-fn hash_file(path: &Path) -> Result<[u8; 32]> {
-    let mut hasher = blake3::Hasher::new();
-    let mut file = File::open(path)?;
-    let mut buffer = [0u8; 8192];
-    loop {
-        let n = file.read(&mut buffer)?;
-        if n == 0 {
-            break;
+    if args.serve_progress.is_some() {
+        std::thread::sleep(serve::LINGER);
+    }
+    if let Err(e) = &result {
+        let remedies = remedies(e);
+        if !remedies.is_empty() {
+            // Printed here rather than returned, so the suggestions come after the error
+            eprintln!("Error: {e:?}\n\nWhat to try:");
+            for remedy in remedies {
+                eprintln!("  - {remedy}");
+            }
+            std::process::exit(1);
         }
-        hasher.update(&buffer[..n]);
     }
-    Ok(*hasher.finalize().as_bytes())
+    result.map(|_| ())
 }
 
-fn verify_base_folder(bundle: &PatchBundle, cwd: &Path) -> Result<()> {
-    for file in &bundle.manifest.files {
-        match file.kind {
-            PatchKind::Unchanged | PatchKind::Patched { .. } | PatchKind::Deleted => {
-                if file.original_hash != [0u8; 32] {
-                    let path = cwd.join(&file.path);
-                    if !path.exists() {
-                        anyhow::bail!("Expected file missing: {}", file.path);
-                    }
-                    let hash =
-                        hash_file(&path).with_context(|| format!("Hashing {}", file.path))?;
-                    if hash != file.original_hash {
-                        anyhow::bail!("File {} hash mismatch", file.path);
-                    }
-                }
-            }
-            PatchKind::Added { .. } => {}
+/// Writes the `--result-json` report of `result` to `path`.
+fn write_result(path: &Path, result: &Result<Option<Summary>>) -> Result<()> {
+    let report = match result {
+        Ok(summary) => ApplyResult {
+            success: true,
+            error: None,
+            error_kind: None,
+            remedies: Vec::new(),
+            summary: summary.clone(),
+        },
+        Err(e) => ApplyResult {
+            success: false,
+            error: Some(format!("{e:#}")),
+            error_kind: e
+                .chain()
+                .find_map(|e| e.downcast_ref::<PatchError>())
+                .map(PatchError::kind),
+            remedies: remedies(e).iter().map(ToString::to_string).collect(),
+            summary: None,
+        },
+    };
+    fs::write(path, serde_json::to_vec_pretty(&report)?)
+        .with_context(|| format!("Writing {}", path.display()))
+}
+
+/// Distinct suggestions from the file errors in `err`'s chain.
+fn remedies(err: &anyhow::Error) -> Vec<Remedy> {
+    let mut found = Vec::new();
+    let file_errors = err
+        .chain()
+        .filter_map(|e| match e.downcast_ref::<PatchError>()? {
+            PatchError::Io(file) => Some(file),
+            _ => None,
+        });
+    for remedy in file_errors.filter_map(|file| file.remedy) {
+        if !found.contains(&remedy) {
+            found.push(remedy);
         }
     }
-    Ok(())
+    found
 }
 
-fn apply_bundle(bundle: &PatchBundle, cwd: &Path) -> Result<()> {
-    let total_files = bundle.manifest.files.len() as u64;
+/// Opens the patch and applies it, or only reports the plan with `--plan`. Returns the
+/// performance summary when files were patched.
+fn run(args: &Args, progress: &Progress) -> Result<Option<Summary>> {
+    progress.set_stage("Opening patch");
+    let cache = match &args.cache {
+        Some(path) => path.clone(),
+        None => std::env::current_exe()?.with_extension("download"),
+    };
+    let agent = || {
+        build_agent(&HttpOptions {
+            proxy: args.proxy.clone(),
+            ca_bundle: args.ca_bundle.clone(),
+        })
+    };
+    if let Some(source) = &args.check_update {
+        let bytes = if source.starts_with("http://") || source.starts_with("https://") {
+            agent()?
+                .get(source)
+                .call()
+                .and_then(|mut response| response.body_mut().read_to_vec())
+                .with_context(|| format!("Downloading {source}"))?
+        } else {
+            fs::read(source).with_context(|| format!("Reading {source}"))?
+        };
+        let public_key = match &args.public_key {
+            Some(hex) => Some(hex_hash::decode(hex).context("--public-key is not a hex string")?),
+            None => None,
+        };
+        let info = read_update_info(&bytes, public_key.as_deref())?;
+        let target = match &args.target {
+            Some(dir) => dir.clone(),
+            None => std::env::current_dir()?,
+        };
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&check_update(&info, &target))?
+        );
+        return Ok(None);
+    }
+    if args.history {
+        let target = match &args.target {
+            Some(dir) => dir.clone(),
+            None => std::env::current_dir()?,
+        };
+        print_history(&target)?;
+        return Ok(None);
+    }
+    let view = match (args.tui, args.accessible) {
+        (true, _) => View::Tui,
+        (_, true) => View::Sentences,
+        _ => View::Display(args.progress.clone()),
+    };
+    let order = if args.fastest_mirror {
+        MirrorOrder::Latency
+    } else {
+        MirrorOrder::Listed
+    };
+    let bundle = match args.url.as_slice() {
+        [_, ..] if args.download_only => {
+            progress.set_stage("Downloading");
+            let bundle = Bundle::download(
+                &agent()?,
+                &args.url,
+                order,
+                &cache,
+                &mut CliObserver::new(progress, view.clone())?,
+            )?;
+            let manifest = bundle.manifest();
+            println!(
+                "Downloaded {} {} -> {} to {}; apply it with --apply-cached",
+                manifest.product,
+                manifest.from_version,
+                manifest.to_version,
+                cache.display()
+            );
+            return Ok(None);
+        }
+        [_, ..] => Bundle::open_remote(agent()?, &args.url, order)?,
+        [] if args.apply_cached => {
+            if !cache.is_file() {
+                anyhow::bail!(
+                    "No downloaded patch at {}; run with --download-only first",
+                    cache.display()
+                );
+            }
+            Bundle::open(&cache)?
+        }
+        [] => match &args.bundle {
+            Some(path) => Bundle::open(path)?,
+            None => Bundle::open(&std::env::current_exe()?)?,
+        },
+    };
+    let manifest = bundle.manifest();
+    progress.set_patch(manifest);
+    if args.preview {
+        let target = match &args.target {
+            Some(dir) => dir.clone(),
+            None => std::env::current_dir()?,
+        };
+        print_preview(manifest, &target)?;
+        return Ok(None);
+    }
+    if args.probe {
+        let target = match &args.target {
+            Some(dir) => dir.clone(),
+            None => std::env::current_dir()?,
+        };
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&probe(manifest, &target))?
+        );
+        return Ok(None);
+    }
+    let plan_only = args.plan || args.plan_json.is_some();
+    let mut choices = Choices {
+        target: args.target.clone(),
+        eula: None,
+        components: args.components.clone(),
+        summarized: false,
+    };
+    if let Some(wizard) = manifest.wizard.as_ref().filter(|_| !plan_only) {
+        wizard::run(manifest, wizard, args.accept_eula, &mut choices)?;
+    }
+    let cwd = match choices.target {
+        Some(dir) => dir,
+        None if args.choose_target => locate::choose_target(manifest)?,
+        None => std::env::current_dir()?,
+    };
+    let components = match choices.components {
+        Some(ids) => Some(wizard::check_components(manifest, ids)?),
+        None => wizard::preselected(manifest, &cwd),
+    };
+    let mut options = ApplyOptions {
+        durable: args.durable,
+        av_safe: args.av_safe,
+        verify: args.verify.into(),
+        chaos: args.chaos.map(Chaos::new),
+        max_memory: args.max_memory,
+        paranoid: args.paranoid,
+        eula: choices.eula,
+        components,
+        allow_downgrade: args.allow_downgrade,
+        snapshot: args.snapshot,
+        selection: if args.only.is_empty() && args.skip.is_empty() {
+            None
+        } else {
+            Some(Selection::new(args.only.clone(), args.skip.clone())?)
+        },
+        keep_deleted: args.no_delete,
+        purge_unknown: args.purge_unknown,
+        in_place: args.in_place,
+    };
+
+    if plan_only {
+        let plan = patch_apply::plan(
+            &bundle,
+            &cwd,
+            &options,
+            &mut CliObserver::new(progress, view.clone())?,
+        )?;
+        plan.print();
+        if let Some(path) = &args.plan_json {
+            plan.write_json(path)?;
+        }
+        if !plan.conflicts.is_empty() {
+            anyhow::bail!(
+                "Patching would fail: {} conflicts found",
+                plan.conflicts.len()
+            );
+        }
+        return Ok(None);
+    }
 
-    let mp = Arc::new(MultiProgress::new());
+    if options.eula.is_none()
+        && let Some(text) = &manifest.eula
+    {
+        options.eula = Some(accept_eula(text, args.accept_eula)?);
+    }
+    if !choices.summarized {
+        print_estimate(manifest);
+    }
+    // Started only now, so the license prompt is not drawn over
+    let mut observer = CliObserver::new(progress, view)?;
+    let summary = patch_apply::apply_bundle_with_options(&bundle, &cwd, &options, &mut observer)?;
+    if observer.finish() {
+        summary.print();
+    }
+    if args.apply_cached {
+        fs::remove_file(&cache).with_context(|| format!("Removing {}", cache.display()))?;
+    }
+    if manifest.main_exe.is_some()
+        && !args.no_launch
+        && (args.launch || ask_launch(&manifest.product)?)
+    {
+        patch_apply::launch::start(manifest, &cwd)?;
+    }
+    Ok(Some(summary))
+}
 
-    let overall_pb = mp.add(ProgressBar::new(total_files));
-    overall_pb.set_style(
-        ProgressStyle::with_template(
-            "[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg}",
-        )?
-            .progress_chars("##-"),
-    );
-    overall_pb.set_message("Patching files");
-
-    let num_workers = current_num_threads();
-    let mut worker_vec = Vec::with_capacity(num_workers);
-
-    for i in 0..num_workers {
-        let pb = mp.add(ProgressBar::new(0));
-
-        let template = format!("  [W{:02}] {{bar:30.green/black}} {{bytes}}/{{total_bytes}}", i);
-        pb.set_style(
-            ProgressStyle::with_template(&template)?
-                .with_key("bytes", |st: &ProgressState, w: &mut dyn std::fmt::Write| {
-                    write!(w, "{}", indicatif::HumanBytes(st.pos())).ok();
-                })
-                .with_key("total_bytes", |st: &ProgressState, w: &mut dyn std::fmt::Write| {
-                    write!(w, "{}", indicatif::HumanBytes(st.len().unwrap_or(0))).ok();
-                })
-                .progress_chars("##-"),
+/// Lists the applies recorded in `target`, oldest first.
+fn print_history(target: &Path) -> Result<()> {
+    let entries = history::read(target)?;
+    if entries.is_empty() {
+        println!("No patches have been applied to {}", target.display());
+        return Ok(());
+    }
+    for entry in entries {
+        println!(
+            "{}  {} {} -> {}: {} in {} (patcher {}, format {})",
+            entry.started_at_utc(),
+            entry.product,
+            entry.from_version,
+            entry.to_version,
+            entry.outcome,
+            HumanDuration(Duration::from_millis(entry.duration_ms)),
+            entry.patcher_version,
+            entry.format_version
         );
-        worker_vec.push(pb);
+        if let Some(error) = &entry.error {
+            println!("    {error}");
+        }
     }
-    let worker_bars = Arc::new(worker_vec);
+    Ok(())
+}
 
-    let base_dir = cwd.to_path_buf();
-    let entries = &bundle.entries;
-    let files = &bundle.manifest.files;
+/// Prints the stored diffs, noting the files in `target` that are at neither version, which the
+/// diff does not describe.
+fn print_preview(manifest: &Manifest, target: &Path) -> Result<()> {
+    if manifest.diffs.is_empty() {
+        println!("This patch stores no diffs to preview");
+        return Ok(());
+    }
+    println!(
+        "{} {} -> {}: {} text files change",
+        manifest.product,
+        manifest.from_version,
+        manifest.to_version,
+        manifest.diffs.len()
+    );
+    for diff in &manifest.diffs {
+        let path = target.join(&diff.path);
+        if let Some(file) = manifest.files.iter().find(|file| file.path == diff.path)
+            && path.is_file()
+        {
+            let hash = patch_apply::hash_file(&path, file.normalization)?;
+            if hash == file.new_hash {
+                println!("\n{} is already at {}", diff.path, manifest.to_version);
+                continue;
+            }
+            if hash != file.original_hash {
+                println!(
+                    "\nWarning: {} differs from {}; patching it will fail as a conflict",
+                    path.display(),
+                    manifest.from_version
+                );
+            }
+        }
+        println!();
+        print!("{}", diff.unified);
+    }
+    Ok(())
+}
 
-    files.par_iter().try_for_each(|file| {
-        let base = base_dir.clone();
-        let entries = entries;
-        let overall_pb = overall_pb.clone();
-        let worker_bars = worker_bars.clone();
+fn print_estimate(manifest: &Manifest) {
+    println!(
+        "This update needs up to {} of free space and takes about {}",
+        HumanBytes(manifest.cost.write_bytes),
+        HumanDuration(manifest.cost.estimate())
+    );
+}
 
-        let idx = current_thread_index().unwrap_or(0);
-        let worker_pb = &worker_bars[idx];
+/// Asks whether to start the product now; never when nobody is at the terminal.
+fn ask_launch(product: &str) -> Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    print!("Launch {product} now? [Y/n] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(
+        answer.trim().to_ascii_lowercase().as_str(),
+        "" | "y" | "yes"
+    ))
+}
 
-        let target = base.join(&file.path);
+/// Shows the license and asks the user to accept it, unless `--accept-eula` already did.
+fn accept_eula(text: &str, accepted: bool) -> Result<EulaAcceptance> {
+    if accepted {
+        return Ok(EulaAcceptance::Unattended);
+    }
+    if !io::stdin().is_terminal() {
+        anyhow::bail!(
+            "This patch requires accepting its license agreement; pass --accept-eula to accept it unattended"
+        );
+    }
+    println!("{}\n", text.trim_end());
+    print!("Do you accept the license agreement? [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    if !answer.trim().eq_ignore_ascii_case("y") {
+        anyhow::bail!("License agreement declined; nothing was changed");
+    }
+    Ok(EulaAcceptance::Interactive)
+}
 
-        match file.kind {
-            PatchKind::Unchanged => {
-                worker_pb.set_length(1);
-                worker_pb.set_position(1);
-            }
-            PatchKind::Deleted => {
-                let len = std::fs::metadata(&target).map(|m| m.len()).unwrap_or(1);
-                worker_pb.set_length(len);
-                if target.exists() {
-                    fs::remove_file(&target).with_context(|| format!("Removing {}", file.path))?;
-                }
-                worker_pb.set_position(len);
-            }
-            PatchKind::Added { idx } => {
-                let data = entries
-                    .get(idx)
-                    .ok_or_else(|| anyhow::anyhow!("Invalid entry index for {}", file.path))?;
-
-                let bytes = match data {
-                    PatchData::Full(b) => b,
-                    _ => anyhow::bail!("'Added' has wrong PatchData type for {}", file.path),
-                };
-
-                if let Some(parent) = target.parent() {
-                    fs::create_dir_all(parent)
-                        .with_context(|| format!("Creating dir for {}", file.path))?;
-                }
+/// How the console shows progress.
+#[derive(Clone, PartialEq, Eq)]
+enum View {
+    /// Bars, or what `--progress` picked instead
+    Display(Frontend),
+    /// The full-screen `--tui` view
+    Tui,
+    /// Plain sentences for `--accessible`
+    Sentences,
+}
 
-                let total = bytes.len() as u64;
-                worker_pb.set_length(total);
+/// Shows apply progress as terminal bars (or the `--tui` screen, sentences with `--accessible`
+/// or another `--progress` frontend) and on the `--serve-progress` page.
+struct CliObserver<'a> {
+    progress: &'a Progress,
+    /// Frontend of the download and file displays, unless the view replaces them
+    frontend: Option<Frontend>,
+    download: Option<Download>,
+    /// Created once files start, so a run with nothing to do prints no bars
+    display: Option<Box<dyn ProgressDisplay>>,
+    /// Replaces the bars and printed notices while it is shown
+    tui: Option<Tui>,
+    /// Replaces the bars with sentences, for the download and then the files
+    announcer: Option<Announcer>,
+    /// Weight of the files finished so far, for the sentences
+    done: u64,
+    accessible: bool,
+    /// Whether any files were patched
+    patching: bool,
+}
 
-                let mut tmp = target.clone();
-                tmp.set_extension("tmp");
+struct Download {
+    display: Box<dyn ProgressDisplay>,
+    /// Bytes already passed on to `display`
+    shown: u64,
+}
 
+impl<'a> CliObserver<'a> {
+    fn new(progress: &'a Progress, view: View) -> Result<Self> {
+        Ok(CliObserver {
+            progress,
+            download: None,
+            display: None,
+            tui: if view == View::Tui {
+                Some(Tui::start()?)
+            } else {
+                None
+            },
+            announcer: None,
+            done: 0,
+            accessible: view == View::Sentences,
+            patching: false,
+            frontend: match view {
+                View::Display(frontend) => Some(frontend),
+                View::Tui | View::Sentences => None,
+            },
+        })
+    }
 
-                let mut out = File::create(&tmp)
-                    .with_context(|| format!("Creating temp for {}", file.path))?;
+    /// Completes the bars or closes the full-screen view, returning whether any files were
+    /// patched.
+    fn finish(self) -> bool {
+        if let Some(announcer) = &self.announcer {
+            announcer.finish();
+        }
+        if let Some(display) = self.display {
+            display.finish("Patching complete");
+        }
+        self.patching
+    }
+}
 
-                let mut written: u64 = 0;
-                for chunk in bytes.chunks(8192) {
-                    out.write_all(chunk).with_context(|| format!("Writing {}", file.path))?;
-                    written += chunk.len() as u64;
-                    worker_pb.set_position(written);
-                }
+impl PatchObserver for CliObserver<'_> {
+    fn stage(&mut self, stage: Stage) {
+        self.progress.set_stage(&stage.to_string());
+        if let Some(tui) = &self.tui {
+            tui.stage(&stage.to_string());
+        }
+        if self.accessible {
+            println!("{stage}");
+        }
+        if let (Some(display), Stage::VerifyingOutput | Stage::Committing) = (&self.display, stage)
+        {
+            display.message(&stage.to_string());
+        }
+    }
 
-                fs::rename(&tmp, &target).with_context(|| format!("Renaming {}", file.path))?;
+    fn totals(&mut self, files: u64, bytes: u64) {
+        self.progress.set_totals(files, bytes);
+        self.patching = true;
+        if let Some(tui) = &self.tui {
+            tui.totals(files, bytes);
+            return;
+        }
+        if self.accessible {
+            println!("Patching {files} files");
+            self.announcer = Some(Announcer::new("Patching", bytes));
+            return;
+        }
+        if let Some(frontend) = &self.frontend {
+            match frontend.open(bytes, rayon::current_num_threads(), "Patching files") {
+                Ok(display) => self.display = Some(display),
+                Err(e) => println!("{e:#}; patching without showing progress"),
             }
-            PatchKind::Patched { idx } => {
-                let data = entries
-                    .get(idx)
-                    .ok_or_else(|| anyhow::anyhow!("Invalid entry index for {}", file.path))?;
-
-                let patch = match data {
-                    PatchData::Xdelta(p) => p,
-                    _ => anyhow::bail!("Patched has wrong PatchData type for {}", file.path),
-                };
-
-                let org_len = std::fs::metadata(&target).with_context(|| format!("Metadata for {}", file.path))?.len();
-                worker_pb.set_length(org_len);
-
-                let mut org_bytes = Vec::with_capacity(org_len as usize);
-                let mut org_file = File::open(&target).with_context(|| format!("Opening {}", file.path))?;
-                let mut buffer = [0u8; 8192];
-                let mut read_total: u64 = 0;
-
-                loop {
-                    let n = org_file.read(&mut buffer)
-                        .with_context(|| format!("Reading original {}", file.path))?;
-                    if n == 0 {
-                        break;
-                    }
-                    org_bytes.extend_from_slice(&buffer[..n]);
-                    read_total += n as u64;
-                    worker_pb.set_position(read_total);
-                }
-
-                let new_bytes = xdelta3::decode(patch, &org_bytes)
-                    .with_context(|| format!("xdelta decode failed for {}", file.path))?;
-
-                let new_len = new_bytes.len() as u64;
-                let total = org_len + new_len;
+        }
+    }
 
-                worker_pb.set_length(total);
-                let mut pos = read_total;
+    fn file_started(&mut self, worker: usize, path: &str, bytes: u64) {
+        if let Some(tui) = &self.tui {
+            tui.file_started(worker, path, bytes);
+        }
+        if let Some(display) = &self.display {
+            display.file_started(worker, path, bytes);
+        }
+    }
 
-                let mut tmp = target.clone();
-                tmp.set_extension("tmp");
+    fn file_progress(&mut self, worker: usize, done: u64) {
+        if let Some(tui) = &self.tui {
+            tui.file_progress(worker, done);
+            // Pauses mid-file as well as between files
+            tui.wait_if_paused();
+        }
+        if let Some(display) = &self.display {
+            display.file_progress(worker, done);
+        }
+    }
 
-                let mut out = File::create(&tmp).with_context(|| format!("Creating temp for {}", file.path))?;
+    fn file_finished(&mut self, path: &str, weight: u64) {
+        if let Some(tui) = &self.tui {
+            tui.file_finished(path, weight);
+        }
+        if let Some(display) = &self.display {
+            display.advance(weight);
+        }
+        self.done += weight;
+        if let Some(announcer) = &mut self.announcer {
+            announcer.set(self.done);
+        }
+        self.progress.file_done(weight);
+    }
 
-                for chunk in new_bytes.chunks(8192) {
-                    out.write_all(chunk).with_context(|| format!("Writing {}", file.path))?;
-                    pos += chunk.len() as u64;
-                    worker_pb.set_position(pos);
+    fn downloaded(&mut self, done: u64, total: u64) {
+        if let Some(tui) = &self.tui {
+            tui.stage(&format!(
+                "Downloading {} / {}",
+                indicatif::HumanBytes(done),
+                indicatif::HumanBytes(total)
+            ));
+            return;
+        }
+        if self.accessible {
+            let announcer = self.announcer.get_or_insert_with(|| {
+                println!("Downloading {}", HumanBytes(total));
+                Announcer::new("Download", total)
+            });
+            announcer.set(done);
+            if done == total {
+                announcer.finish();
+            }
+            return;
+        }
+        let Some(frontend) = &self.frontend else {
+            return;
+        };
+        if self.download.is_none() {
+            match frontend.open(total, 0, "Downloading") {
+                Ok(display) => self.download = Some(Download { display, shown: 0 }),
+                Err(e) => {
+                    println!("{e:#}; downloading without showing progress");
+                    self.frontend = None;
+                    return;
                 }
-
-                fs::rename(&tmp, &target).with_context(|| format!("Renaming {}", file.path))?;
             }
         }
+        if let Some(download) = &mut self.download {
+            download
+                .display
+                .advance(done.saturating_sub(download.shown));
+            download.shown = done;
+            if done == total {
+                download.display.finish("Downloaded");
+            }
+        }
+    }
 
-        overall_pb.inc(1);
-        Ok::<(), anyhow::Error>(())
-    })?;
-
-    overall_pb.finish_with_message("Patching complete");
-
-    for (i, wb) in worker_bars.iter().enumerate() {
-        wb.finish_with_message(format!("Worker {i}: done"));
+    fn notice(&mut self, message: &str) {
+        match &self.tui {
+            Some(tui) => tui.message(message),
+            None => println!("{message}"),
+        }
     }
 
-    Ok(())
+    fn cancelled(&mut self) -> bool {
+        self.progress.stalled() || self.tui.as_ref().is_some_and(Tui::wait_if_paused)
+    }
 }
 
 // fn apply_bundle(bundle: &PatchBundle, cwd: &Path) -> Result<()> {
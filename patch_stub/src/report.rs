@@ -0,0 +1,67 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use ureq::Agent;
+
+use patch_types::FORMAT_VERSION;
+
+use crate::serve::PatchInfo;
+use patch_apply::disk::free_space;
+use patch_apply::net::explain;
+
+/// What `--report-errors` uploads after a failed apply.
+#[derive(Serialize)]
+pub struct ErrorReport {
+    pub error: String,
+    pub patch: Option<PatchInfo>,
+    pub os: &'static str,
+    pub arch: &'static str,
+    pub patcher_version: &'static str,
+    pub format_version: u32,
+    /// Free space on the volume being patched, in bytes
+    pub free_space: Option<u64>,
+}
+
+impl ErrorReport {
+    pub fn new(error: String, patch: Option<PatchInfo>, target: &Path) -> Self {
+        ErrorReport {
+            error,
+            patch,
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            patcher_version: env!("CARGO_PKG_VERSION"),
+            format_version: FORMAT_VERSION,
+            free_space: free_space(target),
+        }
+    }
+}
+
+/// Shows the report and, once the user agrees, POSTs it as JSON to `url`. Without a terminal
+/// to ask on, passing `--report-errors` is taken as consent.
+pub fn send_report(report: &ErrorReport, agent: &Agent, url: &str) -> Result<()> {
+    let body = serde_json::to_vec_pretty(report)?;
+    println!("The following error report will be sent to {url}:");
+    println!("{}", String::from_utf8_lossy(&body));
+
+    if io::stdin().is_terminal() {
+        print!("Send it? [y/N] ");
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().lock().read_line(&mut answer)?;
+        if !answer.trim().eq_ignore_ascii_case("y") {
+            println!("Report not sent");
+            return Ok(());
+        }
+    }
+
+    agent
+        .post(url)
+        .header("Content-Type", "application/json")
+        .send(&body[..])
+        .map_err(|e| explain(e, url))
+        .with_context(|| format!("Sending error report to {url}"))?;
+    println!("Report sent");
+    Ok(())
+}
@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use patch_types::Manifest;
+
+/// How long the endpoint stays up after the patch finishes, so a polling page sees the outcome.
+pub const LINGER: Duration = Duration::from_secs(5);
+
+const PAGE: &str = r#"<!doctype html>
+<html><head><meta charset="utf-8"><title>Patch progress</title>
+<style>body{font-family:sans-serif;margin:2em}progress{width:100%;height:1.5em}</style></head>
+<body><h1 id="stage">Connecting...</h1><progress id="bar" max="1" value="0"></progress>
+<p id="detail"></p>
+<script>
+async function poll() {
+  try {
+    const s = await (await fetch('/status.json')).json();
+    document.getElementById('stage').textContent = s.error ? 'Failed: ' + s.error : s.stage;
+    if (s.patch) document.title = s.patch.product + ' ' + s.patch.from_version + ' \u2192 ' + s.patch.to_version;
+    const bar = document.getElementById('bar');
+    bar.max = Math.max(s.bytes_total, 1); bar.value = s.bytes_done;
+    document.getElementById('detail').textContent =
+      s.files_done + ' / ' + s.files_total + ' files, ' +
+      (s.bytes_done / 1048576).toFixed(1) + ' / ' + (s.bytes_total / 1048576).toFixed(1) + ' MiB';
+  } catch (e) {
+    document.getElementById('stage').textContent = 'Patcher has exited';
+    return;
+  }
+  setTimeout(poll, 1000);
+}
+poll();
+</script></body></html>
+"#;
+
+/// Which patch is being applied.
+#[derive(Serialize, Clone)]
+pub struct PatchInfo {
+    pub product: String,
+    pub from_version: String,
+    pub to_version: String,
+    /// Key-values the publisher attached to the patch
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// Live apply state shared between the patching threads and the progress endpoint.
+#[derive(Default)]
+pub struct Progress {
+    patch: Mutex<Option<PatchInfo>>,
+    stage: Mutex<String>,
+    error: Mutex<Option<String>>,
+    files_done: AtomicU64,
+    files_total: AtomicU64,
+    bytes_done: AtomicU64,
+    bytes_total: AtomicU64,
+    /// Stages entered and files finished so far
+    events: AtomicU64,
+    /// Set once the run has completed or failed
+    over: AtomicBool,
+    /// Set by the watchdog, asking the patch to stop
+    stalled: AtomicBool,
+}
+
+/// JSON body of `/status.json`, also written to the `--heartbeat-file`.
+#[derive(Serialize)]
+pub struct Status {
+    patch: Option<PatchInfo>,
+    stage: String,
+    error: Option<String>,
+    files_done: u64,
+    files_total: u64,
+    bytes_done: u64,
+    bytes_total: u64,
+}
+
+impl Progress {
+    pub fn set_patch(&self, manifest: &Manifest) {
+        *self.patch.lock().unwrap() = Some(PatchInfo {
+            product: manifest.product.clone(),
+            from_version: manifest.from_version.clone(),
+            to_version: manifest.to_version.clone(),
+            metadata: manifest.metadata.clone(),
+        });
+    }
+
+    pub fn patch(&self) -> Option<PatchInfo> {
+        self.patch.lock().unwrap().clone()
+    }
+
+    pub fn set_stage(&self, stage: &str) {
+        *self.stage.lock().unwrap() = stage.to_string();
+        self.events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn complete(&self) {
+        self.set_stage("Complete");
+        self.over.store(true, Ordering::Relaxed);
+    }
+
+    pub fn fail(&self, error: String) {
+        self.set_stage("Failed");
+        *self.error.lock().unwrap() = Some(error);
+        self.over.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the run has completed or failed.
+    pub fn is_over(&self) -> bool {
+        self.over.load(Ordering::Relaxed)
+    }
+
+    /// Asks the patch to stop at the next file, as cancelling does.
+    pub fn stall(&self) {
+        self.stalled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn stalled(&self) -> bool {
+        self.stalled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_totals(&self, files: u64, bytes: u64) {
+        self.files_total.store(files, Ordering::Relaxed);
+        self.bytes_total.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn file_done(&self, bytes: u64) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+        self.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+        self.events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count of stages entered and files finished, which moves on whenever patching does.
+    pub fn events(&self) -> u64 {
+        self.events.load(Ordering::Relaxed)
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            patch: self.patch(),
+            stage: self.stage.lock().unwrap().clone(),
+            error: self.error.lock().unwrap().clone(),
+            files_done: self.files_done.load(Ordering::Relaxed),
+            files_total: self.files_total.load(Ordering::Relaxed),
+            bytes_done: self.bytes_done.load(Ordering::Relaxed),
+            bytes_total: self.bytes_total.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Serves `/` (an HTML page) and `/status.json` on `addr` from a background thread.
+pub fn serve_progress(addr: SocketAddr, progress: Arc<Progress>) -> Result<()> {
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("Binding progress endpoint {addr}"))?;
+    println!("Serving progress on http://{}/", listener.local_addr()?);
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // A misbehaving client only loses its own response
+            let _ = respond(stream, &progress);
+        }
+    });
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream, progress: &Progress) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Drain the headers so closing the socket does not reset the connection
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+
+    let (status, content_type, body) = match path {
+        "/" | "/index.html" => (
+            "200 OK",
+            "text/html; charset=utf-8",
+            PAGE.as_bytes().to_vec(),
+        ),
+        "/status.json" => (
+            "200 OK",
+            "application/json",
+            serde_json::to_vec(&progress.status())?,
+        ),
+        _ => ("404 Not Found", "text/plain", b"Not found".to_vec()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    Ok(())
+}
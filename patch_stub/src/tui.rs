@@ -0,0 +1,280 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::Result;
+use indicatif::HumanBytes;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, List, ListItem, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+
+/// How often the screen is redrawn and the keyboard polled.
+const FRAME: Duration = Duration::from_millis(100);
+/// Most worker rows shown; the rest scroll off the bottom of their panel.
+const MAX_WORKER_ROWS: u16 = 8;
+
+/// What the screen shows, updated by the observer from the worker threads.
+#[derive(Default)]
+struct State {
+    stage: String,
+    total_files: u64,
+    total_bytes: u64,
+    done_bytes: u64,
+    workers: Vec<Option<Worker>>,
+    files: Vec<(String, bool)>,
+    messages: Vec<String>,
+}
+
+struct Worker {
+    path: String,
+    done: u64,
+    len: u64,
+}
+
+/// Keyboard commands, read by the workers between files.
+#[derive(Default)]
+struct Control {
+    paused: AtomicBool,
+    cancelled: AtomicBool,
+    stop: AtomicBool,
+}
+
+/// Full-screen view of the apply for `--tui`: overall and per-worker progress, the files done
+/// so far, and messages. `p` pauses and resumes, `c` cancels. The terminal is restored when
+/// this is dropped.
+pub struct Tui {
+    state: Arc<Mutex<State>>,
+    control: Arc<Control>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Tui {
+    pub fn start() -> Result<Tui> {
+        let terminal = ratatui::try_init()?;
+        let state = Arc::new(Mutex::new(State::default()));
+        let control = Arc::new(Control::default());
+        let thread = thread::spawn({
+            let state = state.clone();
+            let control = control.clone();
+            move || {
+                let _ = draw_loop(terminal, &state, &control);
+                ratatui::restore();
+            }
+        });
+        Ok(Tui {
+            state,
+            control,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn stage(&self, stage: &str) {
+        self.state.lock().unwrap().stage = stage.to_string();
+    }
+
+    pub fn totals(&self, files: u64, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.total_files = files;
+        state.total_bytes = bytes;
+        state.workers = (0..rayon::current_num_threads()).map(|_| None).collect();
+    }
+
+    pub fn file_started(&self, worker: usize, path: &str, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(slot) = state.workers.get_mut(worker) {
+            *slot = Some(Worker {
+                path: path.to_string(),
+                done: 0,
+                len: bytes,
+            });
+        }
+    }
+
+    pub fn file_progress(&self, worker: usize, done: u64) {
+        if let Some(Some(current)) = self.state.lock().unwrap().workers.get_mut(worker) {
+            current.done = done;
+        }
+    }
+
+    pub fn file_finished(&self, path: &str, weight: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.done_bytes += weight;
+        state.files.push((path.to_string(), weight > 0));
+        for slot in &mut state.workers {
+            if slot.as_ref().is_some_and(|current| current.path == path) {
+                *slot = None;
+            }
+        }
+    }
+
+    pub fn message(&self, message: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .messages
+            .push(message.to_string());
+    }
+
+    /// Blocks while the user has paused, and returns whether they asked to cancel.
+    pub fn wait_if_paused(&self) -> bool {
+        while self.control.paused.load(Ordering::Relaxed)
+            && !self.control.cancelled.load(Ordering::Relaxed)
+        {
+            thread::sleep(FRAME);
+        }
+        self.control.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        self.control.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn draw_loop(mut terminal: DefaultTerminal, state: &Mutex<State>, control: &Control) -> Result<()> {
+    // Index of the last file row shown; None follows the newest
+    let mut scroll: Option<usize> = None;
+    while !control.stop.load(Ordering::Relaxed) {
+        terminal.draw(|frame| draw(frame, &state.lock().unwrap(), control, scroll))?;
+        if !event::poll(FRAME)? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        let files = state.lock().unwrap().files.len();
+        match key.code {
+            KeyCode::Char('p') | KeyCode::Char(' ') => {
+                control.paused.fetch_xor(true, Ordering::Relaxed);
+            }
+            KeyCode::Char('c') | KeyCode::Char('q') | KeyCode::Esc => {
+                control.cancelled.store(true, Ordering::Relaxed);
+            }
+            KeyCode::Up => {
+                scroll = Some(scroll.unwrap_or(files.saturating_sub(1)).saturating_sub(1))
+            }
+            KeyCode::Down => scroll = scroll.map(|last| last + 1).filter(|&last| last + 1 < files),
+            KeyCode::End => scroll = None,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn draw(frame: &mut Frame, state: &State, control: &Control, scroll: Option<usize>) {
+    let worker_rows = (state.workers.len() as u16).clamp(1, MAX_WORKER_ROWS);
+    let [header, overall, files, messages, workers, help] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Length(3),
+        Constraint::Min(3),
+        Constraint::Length(5),
+        Constraint::Length(worker_rows + 2),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let status = if control.cancelled.load(Ordering::Relaxed) {
+        " (cancelling)"
+    } else if control.paused.load(Ordering::Relaxed) {
+        " (paused)"
+    } else {
+        ""
+    };
+    frame.render_widget(Line::from(format!("{}{status}", state.stage)), header);
+
+    let ratio = match state.total_bytes {
+        0 => 0.0,
+        total => (state.done_bytes as f64 / total as f64).min(1.0),
+    };
+    let gauge = Gauge::default()
+        .block(Block::bordered().title("Overall"))
+        .gauge_style(Style::default().fg(Color::Cyan))
+        .ratio(ratio)
+        .label(format!(
+            "{} / {}   {} of {} files",
+            HumanBytes(state.done_bytes),
+            HumanBytes(state.total_bytes),
+            state.files.len(),
+            state.total_files
+        ));
+    frame.render_widget(gauge, overall);
+
+    draw_files(frame, state, scroll, files);
+
+    let shown = messages.height.saturating_sub(2) as usize;
+    let recent = &state.messages[state.messages.len().saturating_sub(shown)..];
+    let panel = Paragraph::new(
+        recent
+            .iter()
+            .map(|m| Line::from(m.as_str()))
+            .collect::<Vec<_>>(),
+    )
+    .style(Style::default().fg(Color::Yellow))
+    .wrap(Wrap { trim: true })
+    .block(Block::bordered().title("Messages"));
+    frame.render_widget(panel, messages);
+
+    let rows: Vec<ListItem> = state
+        .workers
+        .iter()
+        .enumerate()
+        .map(|(i, slot)| match slot {
+            Some(current) => ListItem::new(format!(
+                "W{i:02} {:>3}%  {} / {}  {}",
+                (current.done * 100)
+                    .checked_div(current.len)
+                    .unwrap_or(100)
+                    .min(100),
+                HumanBytes(current.done),
+                HumanBytes(current.len),
+                current.path
+            )),
+            None => {
+                ListItem::new(format!("W{i:02} idle")).style(Style::default().fg(Color::DarkGray))
+            }
+        })
+        .collect();
+    frame.render_widget(
+        List::new(rows).block(Block::bordered().title("Workers")),
+        workers,
+    );
+
+    frame.render_widget(
+        Line::from("p pause/resume   c cancel   Up/Down scroll files   End follow newest")
+            .style(Style::default().fg(Color::DarkGray)),
+        help,
+    );
+}
+
+/// Files done so far, scrolled to `scroll` or following the newest.
+fn draw_files(frame: &mut Frame, state: &State, scroll: Option<usize>, area: Rect) {
+    let rows = area.height.saturating_sub(2) as usize;
+    let end = scroll
+        .map_or(state.files.len(), |last| last + 1)
+        .min(state.files.len());
+    let start = end.saturating_sub(rows);
+    let items: Vec<ListItem> = state.files[start..end]
+        .iter()
+        .map(|(path, written)| {
+            if *written {
+                ListItem::new(format!("written  {path}")).style(Style::default().fg(Color::Green))
+            } else {
+                ListItem::new(format!("         {path}"))
+                    .style(Style::default().fg(Color::DarkGray))
+            }
+        })
+        .collect();
+    let title = format!("Files ({}/{})", state.files.len(), state.total_files);
+    frame.render_widget(List::new(items).block(Block::bordered().title(title)), area);
+}
@@ -0,0 +1,181 @@
+use std::collections::HashSet;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use patch_apply::EulaAcceptance;
+use patch_types::Manifest;
+use patch_types::wizard::{Component, Wizard, WizardPage};
+
+use crate::{accept_eula, locate, print_estimate};
+
+/// What the install flow settled, from the command line or from the wizard's pages.
+pub struct Choices {
+    pub target: Option<PathBuf>,
+    pub eula: Option<EulaAcceptance>,
+    /// Ids of the components to install
+    pub components: Option<Vec<String>>,
+    /// Whether the confirm page already showed the size and time of the update
+    pub summarized: bool,
+}
+
+/// Shows the pages of `wizard` in order. Pages answered on the command line are passed over,
+/// and so is every page but the license when nobody is at the terminal.
+pub fn run(
+    manifest: &Manifest,
+    wizard: &Wizard,
+    accept: bool,
+    choices: &mut Choices,
+) -> Result<()> {
+    let interactive = io::stdin().is_terminal();
+    for page in &wizard.pages {
+        match page {
+            WizardPage::Welcome { title, text } if interactive => {
+                println!("{title}\n\n{}\n", text.trim_end());
+                ask("Press Enter to continue")?;
+            }
+            WizardPage::Eula => {
+                if let Some(text) = &manifest.eula {
+                    choices.eula = Some(accept_eula(text, accept)?);
+                }
+            }
+            WizardPage::Directory if interactive && choices.target.is_none() => {
+                choices.target = Some(locate::choose_target(manifest)?);
+            }
+            WizardPage::Components(components) if interactive && choices.components.is_none() => {
+                let target = match &choices.target {
+                    Some(dir) => dir.clone(),
+                    None => std::env::current_dir()?,
+                };
+                choices.components = Some(choose_components(components, &target)?);
+            }
+            WizardPage::Confirm if interactive => {
+                confirm(manifest, wizard, choices)?;
+                choices.summarized = true;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `ids` name components of the patch.
+pub fn check_components(manifest: &Manifest, ids: Vec<String>) -> Result<Vec<String>> {
+    let components = manifest.wizard.as_ref().map_or(&[][..], Wizard::components);
+    if components.is_empty() {
+        anyhow::bail!("This patch has no optional components to choose from");
+    }
+    if let Some(unknown) = ids
+        .iter()
+        .find(|id| !components.iter().any(|component| &component.id == *id))
+    {
+        let known: Vec<&str> = components
+            .iter()
+            .map(|component| component.id.as_str())
+            .collect();
+        anyhow::bail!(
+            "No component {unknown} in this patch; it has {}",
+            known.join(", ")
+        );
+    }
+    Ok(ids)
+}
+
+/// Components picked when the user does not choose: those already installed in `target`,
+/// and the others that are picked by default. `None` for a patch without components.
+pub fn preselected(manifest: &Manifest, target: &Path) -> Option<Vec<String>> {
+    let components = manifest.wizard.as_ref()?.components();
+    (!components.is_empty()).then(|| {
+        components
+            .iter()
+            .filter(|component| component.default || installed(component, target))
+            .map(|component| component.id.clone())
+            .collect()
+    })
+}
+
+fn installed(component: &Component, target: &Path) -> bool {
+    component
+        .files
+        .iter()
+        .any(|path| target.join(path).exists())
+}
+
+/// Lists the components with their picks and lets the user toggle them by number.
+fn choose_components(components: &[Component], target: &Path) -> Result<Vec<String>> {
+    let mut picked: HashSet<usize> = (0..components.len())
+        .filter(|&i| components[i].default || installed(&components[i], target))
+        .collect();
+    loop {
+        println!("Components:");
+        for (i, component) in components.iter().enumerate() {
+            let mark = if picked.contains(&i) { 'x' } else { ' ' };
+            println!("  [{mark}] {}. {}", i + 1, component.name);
+            if !component.description.is_empty() {
+                println!("        {}", component.description);
+            }
+        }
+        let answer = ask("Enter numbers to toggle, or press Enter to continue")?;
+        if answer.is_empty() {
+            break;
+        }
+        for word in answer.split([',', ' ']).filter(|word| !word.is_empty()) {
+            match word.parse::<usize>() {
+                Ok(n) if (1..=components.len()).contains(&n) => {
+                    if !picked.remove(&(n - 1)) {
+                        picked.insert(n - 1);
+                    }
+                }
+                _ => println!("{word} is not one of 1-{}", components.len()),
+            }
+        }
+    }
+    Ok((0..components.len())
+        .filter(|i| picked.contains(i))
+        .map(|i| components[i].id.clone())
+        .collect())
+}
+
+/// Sums up the update and asks to go ahead.
+fn confirm(manifest: &Manifest, wizard: &Wizard, choices: &Choices) -> Result<()> {
+    let target = match &choices.target {
+        Some(dir) => dir.display().to_string(),
+        None => "the current folder".to_string(),
+    };
+    println!(
+        "Ready to update {} from {} to {} in {target}",
+        manifest.product, manifest.from_version, manifest.to_version
+    );
+    if let Some(ids) = &choices.components {
+        let names: Vec<&str> = wizard
+            .components()
+            .iter()
+            .filter(|component| ids.contains(&component.id))
+            .map(|component| component.name.as_str())
+            .collect();
+        println!(
+            "Components: {}",
+            if names.is_empty() {
+                "none".to_string()
+            } else {
+                names.join(", ")
+            }
+        );
+    }
+    print_estimate(manifest);
+    let answer = ask("Continue? [Y/n]")?;
+    if !matches!(answer.to_ascii_lowercase().as_str(), "" | "y" | "yes") {
+        anyhow::bail!("Cancelled; nothing was changed");
+    }
+    Ok(())
+}
+
+/// Prints `prompt` and reads a trimmed line; end of input reads as an empty line.
+fn ask(prompt: &str) -> Result<String> {
+    print!("{prompt} ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(answer.trim().to_string())
+}
@@ -0,0 +1,144 @@
+//! What Authenticode signing changes in an installer. Signing the finished installer fills in
+//! the PE checksum and the security directory entry in the stub's headers and appends a
+//! certificate table after the footer; the rest of the file stays as the builder wrote it.
+//! The stub hash leaves out the two header fields and the payload is found before the table,
+//! so a signed patcher still checks its own code and finds its patch.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Bytes read from the start of an executable to reach its data directories.
+pub const HEADER_LEN: usize = 4096;
+
+/// Index of the security directory, which locates the certificate table, among the data
+/// directories.
+const SECURITY_DIRECTORY: usize = 4;
+
+/// Certificate tables start at a multiple of this many bytes in the file.
+pub const ALIGNMENT: u64 = 8;
+
+/// Offsets in `head` of the CheckSum field and the security directory entry of a PE image,
+/// or `None` when `head` does not hold the headers of one.
+fn signed_fields(head: &[u8]) -> Option<(usize, usize)> {
+    if head.get(..2)? != b"MZ" {
+        return None;
+    }
+    let pe = u32::from_le_bytes(head.get(0x3c..0x40)?.try_into().ok()?) as usize;
+    if head.get(pe..pe + 4)? != b"PE\0\0" {
+        return None;
+    }
+    let optional = pe + 24;
+    // Data directories follow the NumberOfRvaAndSizes field, which PE32+ moves 16 bytes on
+    let count_at = match u16::from_le_bytes(head.get(optional..optional + 2)?.try_into().ok()?) {
+        0x10b => optional + 92,
+        0x20b => optional + 108,
+        _ => return None,
+    };
+    let count = u32::from_le_bytes(head.get(count_at..count_at + 4)?.try_into().ok()?) as usize;
+    let security = count_at + 4 + SECURITY_DIRECTORY * 8;
+    (count > SECURITY_DIRECTORY && head.len() >= security + 8).then_some((optional + 64, security))
+}
+
+/// Hash of an installer's stub, the `len` bytes `stub` reads: BLAKE3 of them with the PE
+/// checksum and the security directory entry zeroed, so signing the installer leaves it as
+/// the builder recorded it. Any other change to the stub changes the hash.
+pub fn stub_hash(stub: &mut impl Read, len: u64) -> io::Result<[u8; 32]> {
+    let mut stub = stub.take(len);
+    let mut head = Vec::with_capacity(HEADER_LEN);
+    (&mut stub).take(HEADER_LEN as u64).read_to_end(&mut head)?;
+    if let Some((checksum, security)) = signed_fields(&head) {
+        head[checksum..checksum + 4].fill(0);
+        head[security..security + 8].fill(0);
+    }
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&head);
+    io::copy(&mut stub, &mut hasher)?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// Length of the `len`-byte installer `file` without the certificate table signing appended,
+/// which is where its footer ends; `len` when it is not signed. Only the first
+/// [`HEADER_LEN`] bytes are read, so `file` may hold no more than those.
+pub fn unsigned_len(file: &mut (impl Read + Seek), len: u64) -> io::Result<u64> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut head = Vec::with_capacity(HEADER_LEN);
+    file.take(HEADER_LEN as u64).read_to_end(&mut head)?;
+    let Some((_, security)) = signed_fields(&head) else {
+        return Ok(len);
+    };
+    let offset = u64::from(u32::from_le_bytes(
+        head[security..security + 4].try_into().unwrap(),
+    ));
+    let size = u64::from(u32::from_le_bytes(
+        head[security + 4..security + 8].try_into().unwrap(),
+    ));
+    // A table that ends before the file does was the stub's own, signed before the build
+    Ok(if size > 0 && offset + size == len {
+        offset
+    } else {
+        len
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// A PE32+ image of `len` bytes whose certificate table is at `table`.
+    fn image(len: usize, checksum: u32, table: (u32, u32)) -> Vec<u8> {
+        let mut image = vec![0x90; len];
+        image[..0x40].fill(0);
+        image[..2].copy_from_slice(b"MZ");
+        image[0x3c..0x40].copy_from_slice(&0x80u32.to_le_bytes());
+        image[0x80..0x84].copy_from_slice(b"PE\0\0");
+        let optional = 0x80 + 24;
+        image[optional..optional + 2].copy_from_slice(&0x20bu16.to_le_bytes());
+        image[optional + 64..optional + 68].copy_from_slice(&checksum.to_le_bytes());
+        image[optional + 108..optional + 112].copy_from_slice(&16u32.to_le_bytes());
+        let security = optional + 112 + SECURITY_DIRECTORY * 8;
+        image[security..security + 4].copy_from_slice(&table.0.to_le_bytes());
+        image[security + 4..security + 8].copy_from_slice(&table.1.to_le_bytes());
+        image
+    }
+
+    fn hash(bytes: &[u8]) -> [u8; 32] {
+        stub_hash(&mut Cursor::new(bytes), bytes.len() as u64).unwrap()
+    }
+
+    #[test]
+    fn signing_keeps_the_stub_hash() {
+        let built = image(8192, 0, (0, 0));
+        let mut signed = image(8192, 0x1234_5678, (16_384, 2048));
+        assert_eq!(hash(&built), hash(&signed));
+        signed[5000] ^= 1;
+        assert_ne!(hash(&built), hash(&signed));
+    }
+
+    #[test]
+    fn other_header_changes_change_the_stub_hash() {
+        let built = image(8192, 0, (0, 0));
+        let mut patched = built.clone();
+        // TimeDateStamp, which signing leaves alone
+        patched[0x80 + 8] = 1;
+        assert_ne!(hash(&built), hash(&patched));
+    }
+
+    #[test]
+    fn payload_ends_before_the_certificate_table() {
+        let mut installer = image(8192, 0x1234_5678, (8192, 512));
+        installer.resize(8192 + 512, 0xcc);
+        let len = installer.len() as u64;
+        assert_eq!(
+            unsigned_len(&mut Cursor::new(&installer), len).unwrap(),
+            8192
+        );
+        // The stub's own table from before the build, followed by the payload
+        installer.resize(9000, 0xdd);
+        assert_eq!(
+            unsigned_len(&mut Cursor::new(&installer), 9000).unwrap(),
+            9000
+        );
+        assert_eq!(unsigned_len(&mut Cursor::new(b"payload"), 7).unwrap(), 7);
+    }
+}
@@ -0,0 +1,47 @@
+//! Buffer sizes for reading, hashing and writing files, shared by the builder and the stub.
+//! Requests of a megabyte or more keep an NVMe drive close to its full speed, where the 8 KiB
+//! `std::io` uses by default leaves it waiting on system calls most of the time.
+
+use crate::error::PatchError;
+use crate::schedule::LARGE_FILE;
+
+/// Smallest buffer, used for files up to its size
+const MIN_BUFFER: usize = 64 * 1024;
+/// Largest buffer for a file one worker reads alone
+const MAX_BUFFER: usize = 4 * 1024 * 1024;
+/// Buffer for large files, whose hashing is split across idle workers and wants long slices
+const LARGE_BUFFER: usize = 8 * 1024 * 1024;
+
+/// Buffer for a stream of unknown length, such as a patch being written.
+pub const STREAM_BUFFER: usize = 1024 * 1024;
+
+/// Buffer for reading, hashing or writing a file of `len` bytes: the whole file when it is
+/// small, a few megabytes when it is not, so small files do not pay for a big allocation.
+pub fn buffer_len(len: u64) -> usize {
+    if len >= LARGE_FILE {
+        return LARGE_BUFFER;
+    }
+    (len as usize)
+        .next_power_of_two()
+        .clamp(MIN_BUFFER, MAX_BUFFER)
+}
+
+/// Bytes at the start of a file of `len` bytes that are normalized before it is hashed. It
+/// decides the hashes of normalized files, which the manifest records, so it stays fixed
+/// whatever [`buffer_len`] reads at a time; it is never larger.
+pub fn head_len(len: u64) -> usize {
+    if len >= LARGE_FILE {
+        LARGE_BUFFER
+    } else {
+        MIN_BUFFER
+    }
+}
+
+/// `len` as the length of a buffer holding `what` whole, failing where it is larger than any
+/// allocation can be on this platform (2 GiB for a 32-bit process) instead of truncating it.
+pub fn memory_len(len: u64, what: impl FnOnce() -> String) -> Result<usize, PatchError> {
+    usize::try_from(len)
+        .ok()
+        .filter(|&len| len <= isize::MAX as usize)
+        .ok_or_else(|| PatchError::TooLarge { what: what(), len })
+}
@@ -0,0 +1,192 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::{CHUNK_SIZE, hex_hash};
+
+/// A failure tools can branch on, carried in the error chains the patcher and builder return.
+/// Find it with `err.chain().find_map(|e| e.downcast_ref::<PatchError>())`; [`PatchError::kind`]
+/// names it for JSON reports.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum PatchError {
+    /// A file the patch reads is not there
+    MissingFile {
+        path: String,
+        /// File the missing one is moved or copied to, when it is needed as a source
+        needed_for: Option<String>,
+    },
+    /// A file does not have the content the patch expects
+    HashMismatch {
+        path: String,
+        expected: [u8; 32],
+        /// `None` when the file's size already ruled it out, so it was not hashed
+        actual: Option<[u8; 32]>,
+    },
+    /// A large file differs from the content the patch expects in these chunks of
+    /// [`CHUNK_SIZE`] bytes, counted from 0
+    CorruptChunks { path: String, chunks: Vec<u64> },
+    /// The manifest refers to an entry or transform that is not in the patch
+    InvalidIndex { what: &'static str, index: usize },
+    /// A file operation failed
+    Io(FileError),
+    /// Stored or decoded data for a file is not what the manifest describes
+    Decode { path: String, reason: String },
+    /// Data that has to be held in memory whole is larger than this process can address
+    TooLarge { what: String, len: u64 },
+    /// Several files conflict with the patch; the first is shown
+    Conflicts(Vec<PatchError>),
+}
+
+impl PatchError {
+    /// Stable name of the variant, such as `hash_mismatch`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PatchError::MissingFile { .. } => "missing_file",
+            PatchError::HashMismatch { .. } => "hash_mismatch",
+            PatchError::CorruptChunks { .. } => "corrupt_chunks",
+            PatchError::InvalidIndex { .. } => "invalid_index",
+            PatchError::Io(_) => "io",
+            PatchError::Decode { .. } => "decode",
+            PatchError::TooLarge { .. } => "too_large",
+            PatchError::Conflicts(_) => "conflicts",
+        }
+    }
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::MissingFile {
+                path,
+                needed_for: None,
+            } => write!(f, "Expected file missing: {path}"),
+            PatchError::MissingFile {
+                path,
+                needed_for: Some(to),
+            } => {
+                write!(f, "Expected file missing: {path} (needed for {to})")
+            }
+            PatchError::HashMismatch {
+                path,
+                expected,
+                actual: Some(actual),
+            } => write!(
+                f,
+                "File {path} hash mismatch (expected {}, found {})",
+                hex_hash::to_hex(expected),
+                hex_hash::to_hex(actual)
+            ),
+            PatchError::HashMismatch {
+                path, actual: None, ..
+            } => {
+                write!(f, "File {path} hash mismatch (its size matches no version)")
+            }
+            PatchError::CorruptChunks { path, chunks } => {
+                let offsets: Vec<String> = chunks
+                    .iter()
+                    .take(4)
+                    .map(|c| format!("{}", c * CHUNK_SIZE))
+                    .collect();
+                write!(
+                    f,
+                    "File {path} is corrupt in {} of its {} MiB chunks, starting at byte {}{}",
+                    chunks.len(),
+                    CHUNK_SIZE >> 20,
+                    offsets.join(", "),
+                    if chunks.len() > offsets.len() {
+                        ", ..."
+                    } else {
+                        ""
+                    }
+                )
+            }
+            PatchError::InvalidIndex { what, index } => write!(f, "Invalid {what} index {index}"),
+            PatchError::Io(err) => err.fmt(f),
+            PatchError::Decode { path, reason } => write!(f, "Decoding {path} failed: {reason}"),
+            PatchError::TooLarge { what, len } => write!(
+                f,
+                "{what} is {len} bytes, more than a {}-bit process can hold in memory",
+                usize::BITS
+            ),
+            PatchError::Conflicts(errors) => match errors.as_slice() {
+                [] => f.write_str("No conflicts"),
+                [only] => only.fmt(f),
+                [first, rest @ ..] => write!(f, "{first} (and {} more conflicts)", rest.len()),
+            },
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+impl From<FileError> for PatchError {
+    fn from(err: FileError) -> Self {
+        PatchError::Io(err)
+    }
+}
+
+/// Something the user can do about a failed file operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Remedy {
+    /// The file is open in another program
+    CloseProgram,
+    FreeSpace,
+    RunAsAdmin,
+    /// A file the installation needs is gone
+    Repair,
+    /// An antivirus program is holding or removing files the patcher wrote
+    Antivirus,
+}
+
+impl fmt::Display for Remedy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Remedy::CloseProgram => "Close the game and any program using its files, then run the patcher again",
+            Remedy::FreeSpace => "Free up space on the drive, then run the patcher again",
+            Remedy::RunAsAdmin => {
+                "Run the patcher as an administrator or as the account that owns the installation, \
+                 or grant your account Modify permission on the folder"
+            }
+            Remedy::Repair => "Repair or reinstall the game, then run the patcher again",
+            Remedy::Antivirus => {
+                "Add the game folder to your antivirus program's exclusions and restore any files it \
+                 quarantined, or run the patcher again with --av-safe"
+            }
+        })
+    }
+}
+
+/// A failed operation on a file of the installation, with the [`Remedy`] to show the user.
+#[derive(Clone, Debug)]
+pub struct FileError {
+    pub path: PathBuf,
+    /// What was being done to the file, such as "replacing"
+    pub action: String,
+    pub os_code: Option<i32>,
+    pub remedy: Option<Remedy>,
+    message: String,
+}
+
+impl FileError {
+    pub fn new(
+        path: PathBuf,
+        action: &str,
+        os_code: Option<i32>,
+        remedy: Option<Remedy>,
+        message: String,
+    ) -> Self {
+        FileError {
+            path,
+            action: action.to_string(),
+            os_code,
+            remedy,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
@@ -1,33 +1,388 @@
-use bincode::{Encode, Decode};
+pub mod authenticode;
+pub mod buffers;
+pub mod error;
+pub mod normalize;
+pub mod schedule;
+pub mod versions;
+pub mod wizard;
 
-#[derive(Encode, Decode)]
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+/// Size of the trailer appended after the payload: payload length, then manifest length.
+pub const FOOTER_LEN: u64 = 16;
+
+/// Version of the bundle format written by this builder and understood by this stub.
+/// Bump it whenever an older stub would misread a newer bundle, keeping the previous manifest
+/// layout readable in [`versions`].
+pub const FORMAT_VERSION: u32 = 27;
+
+#[derive(Encode, Decode, Serialize, Deserialize, Clone)]
 pub struct Manifest {
+    /// Oldest stub format that can apply this bundle, and the format whose layout the rest of
+    /// the manifest is encoded in. Kept as the first field so any stub can read it before
+    /// decoding the rest.
+    pub min_stub_version: u32,
     pub product: String,
     pub from_version: String,
     pub to_version: String,
     pub files: Vec<FileEntry>,
+    /// Location of each entry in the payload, filled in when the installer is written
+    pub entries: Vec<EntryRange>,
+    /// Where to record `to_version` after a successful apply
+    pub markers: VersionMarkers,
+    /// Secondary compression applied to every encoded entry
+    pub compression: Compression,
+    /// External transforms referenced by `PatchKind::Patched::transform`
+    pub transforms: Vec<Transform>,
+    /// Empty directories that must exist after patching
+    pub created_dirs: Vec<String>,
+    /// Empty directories of the old version that are gone from the new one
+    pub deleted_dirs: Vec<String>,
+    /// Releases the builder knew about, so the stub can name the version it finds installed
+    pub fingerprints: Vec<VersionFingerprint>,
+    /// License text the user must accept before the patch is applied
+    pub eula: Option<String>,
+    /// Work applying the patch to the from-version takes, for estimates shown before starting
+    pub cost: ApplyCost,
+    /// Executable the stub offers to start after patching
+    pub main_exe: Option<MainExecutable>,
+    /// Chunk hashes of the large patched files
+    pub chunked: Vec<ChunkedFile>,
+    /// Install flow the stub shows instead of its default prompts
+    pub wizard: Option<wizard::Wizard>,
+    /// URLs of hosted copies of this patcher, tried in download mode when the one given fails
+    pub mirrors: Vec<String>,
+    /// Free-form key-values attached by the publisher, such as a build id or branch. The
+    /// patcher passes them on without interpreting them, except for keys starting with
+    /// [`REQUIRED_METADATA_PREFIX`], which a patcher that does not know them refuses; later
+    /// formats can add settings there without breaking older patchers that may ignore them.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    /// Place of `to_version` among the product's releases, recorded in the receipt
+    #[serde(default)]
+    pub release: Option<Release>,
+    /// Location in the payload of the zstd dictionary that entries marked
+    /// [`EntryRange::dictionary`] are compressed against
+    #[serde(default)]
+    pub dictionary: Option<EntryRange>,
+    /// BLAKE3 hash of the executable the payload is appended to, which the patcher checks
+    /// itself against before running; `None` for zip patches
+    #[serde(default, with = "hex_hash::option")]
+    pub stub_hash: Option<[u8; 32]>,
+    /// Frames holding several small entries compressed together (`--solid-frame`)
+    #[serde(default)]
+    pub frames: Vec<SolidFrame>,
+    /// Readable diffs of small patched text files, for review before applying (`--store-diffs`)
+    #[serde(default)]
+    pub diffs: Vec<FileDiff>,
 }
 
-#[derive(Encode, Decode)]
+impl Manifest {
+    /// The stored diff of the file at `path`, if the patch has one.
+    pub fn diff(&self, path: &str) -> Option<&FileDiff> {
+        self.diffs.iter().find(|diff| diff.path == path)
+    }
+}
+
+/// Unified diff of a patched text file, from its old version to its new one. Only shown to
+/// people; patching uses the file's entry.
+#[derive(Encode, Decode, Serialize, Deserialize, Clone)]
+pub struct FileDiff {
+    pub path: String,
+    pub unified: String,
+}
+
+/// Where a release stands among the product's releases, vouched for by its publisher. The
+/// receipt keeps it, so a later patch to an older release can be recognized as a downgrade.
+#[derive(Encode, Decode, Serialize, Deserialize, Clone, Debug)]
+pub struct Release {
+    /// Grows with every release of the product
+    pub counter: Option<u64>,
+    /// Hex Ed25519 public key of the publisher, and its signature over [`Release::signed_text`]
+    pub public_key: Option<String>,
+    pub signature: Option<String>,
+}
+
+impl Release {
+    /// The text signed for `version` of `product`: what the receipt can prove was installed.
+    pub fn signed_text(product: &str, version: &str, counter: Option<u64>) -> String {
+        let counter = counter
+            .map(|counter| counter.to_string())
+            .unwrap_or_default();
+        format!("{product}\n{version}\n{counter}")
+    }
+}
+
+/// Marks a metadata key the patcher must understand to apply the patch correctly.
+pub const REQUIRED_METADATA_PREFIX: char = '!';
+/// Required metadata keys this patcher understands.
+pub const KNOWN_REQUIRED_METADATA: &[&str] = &[];
+
+impl Manifest {
+    /// Required metadata keys this patcher does not understand.
+    pub fn unknown_required_metadata(&self) -> Vec<&str> {
+        self.metadata
+            .keys()
+            .filter(|key| {
+                key.starts_with(REQUIRED_METADATA_PREFIX)
+                    && !KNOWN_REQUIRED_METADATA.contains(&key.as_str())
+            })
+            .map(String::as_str)
+            .collect()
+    }
+
+    pub fn chunked_file(&self, path: &str) -> Option<&ChunkedFile> {
+        self.chunked.iter().find(|chunked| chunked.path == path)
+    }
+}
+
+/// Size of the pieces large files are hashed (and their fallbacks stored) in.
+pub const CHUNK_SIZE: u64 = 4 << 20;
+/// Smallest patched file given chunk hashes.
+pub const CHUNKED_MIN: u64 = 16 * CHUNK_SIZE;
+/// Smallest file stored and written chunk by chunk ([`PatchKind::Streamed`]) rather than as one
+/// entry. xdelta takes 32-bit lengths, and applying a delta holds the old and new file in memory
+/// at once, which a 32-bit patcher's address space cannot for files of a few GiB.
+pub const STREAMED_MIN: u64 = 1 << 30;
+
+/// Bytes of an old file of `old_size` that segment `index` of a [`PatchKind::Segmented`] file
+/// is diffed against: the chunk at the same place and the chunks on either side, so data that
+/// moved by less than a chunk is still found.
+pub fn segment_base(index: u64, old_size: u64) -> Range<u64> {
+    let end = ((index + 2) * CHUNK_SIZE).min(old_size);
+    (index.saturating_sub(1) * CHUNK_SIZE).min(end)..end
+}
+
+/// BLAKE3 hashes of each [`CHUNK_SIZE`] piece of a large patched file, so a corrupt file can
+/// be narrowed down to the chunks that differ and repaired from those alone.
+#[derive(Encode, Decode, Serialize, Deserialize, Clone)]
+pub struct ChunkedFile {
+    pub path: String,
+    #[serde(with = "hex_hash::list")]
+    pub old_chunks: Vec<[u8; 32]>,
+    #[serde(with = "hex_hash::list")]
+    pub new_chunks: Vec<[u8; 32]>,
+    /// Entry holding each chunk of the new file zstd-compressed, when the file has a full
+    /// fallback; it then takes the place of the `fallback` entry
+    pub chunk_entries: Vec<usize>,
+}
+
+/// Throughputs [`ApplyCost::estimate`] assumes, in bytes per second: a modest hard disk, and
+/// xdelta on one core.
+const READ_RATE: u64 = 100 << 20;
+const WRITE_RATE: u64 = 80 << 20;
+const DECODE_RATE: u64 = 60 << 20;
+
+/// Bytes applying a patch reads, writes and decodes, measured by the builder.
+#[derive(Encode, Decode, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct ApplyCost {
+    /// Installation files hashed before patching
+    pub verify_bytes: u64,
+    /// Installation files read while patching: the bases of deltas and the sources of copies
+    pub read_bytes: u64,
+    /// Files written; also the free space patching needs, since each is staged in full while
+    /// the originals are still in place
+    pub write_bytes: u64,
+    /// Output of xdelta decoding
+    pub decode_bytes: u64,
+}
+
+impl ApplyCost {
+    /// Rough time applying takes on a modest machine.
+    pub fn estimate(&self) -> std::time::Duration {
+        let secs = (self.verify_bytes + self.read_bytes) as f64 / READ_RATE as f64
+            + self.write_bytes as f64 / WRITE_RATE as f64
+            + self.decode_bytes as f64 / DECODE_RATE as f64;
+        std::time::Duration::from_secs_f64(secs)
+    }
+}
+
+/// A few sentinel files whose hashes tell one release apart from the others.
+#[derive(Encode, Decode, Serialize, Deserialize, Clone)]
+pub struct VersionFingerprint {
+    pub version: String,
+    pub files: Vec<SentinelFile>,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Clone)]
+pub struct SentinelFile {
+    pub path: String,
+    /// All zeros when the release does not have the file
+    #[serde(with = "hex_hash")]
+    pub hash: [u8; 32],
+}
+
+/// Pair of external commands that normalize a file before diffing (e.g. unpacking a container
+/// so xdelta sees similar content) and restore the normalized form afterwards. Both read the
+/// input on stdin and write the result to stdout.
+#[derive(Encode, Decode, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct Transform {
+    /// Program and arguments turning a stored file into the form that is diffed
+    pub normalize: Vec<String>,
+    /// Inverse of `normalize`, run by the stub on the patched result
+    pub restore: Vec<String>,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Clone, Copy, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+/// The product's main executable in the new version.
+#[derive(Encode, Decode, Serialize, Deserialize, Clone)]
+pub struct MainExecutable {
+    /// File relative to the install directory, e.g. `bin/game.exe`
+    pub path: String,
+    /// Its path in the old version when it was renamed; Windows shortcuts to that file are
+    /// pointed at the new one
+    pub previous: Option<String>,
+}
+
+/// Places updated with `to_version` once a patch has been applied.
+#[derive(Encode, Decode, Serialize, Deserialize, Default, Clone)]
+pub struct VersionMarkers {
+    /// File relative to the install directory, e.g. `version.txt`
+    pub version_file: Option<String>,
+    /// Registry value set on Windows
+    pub registry: Option<RegistryMarker>,
+    /// Add/Remove Programs entry registered on Windows
+    pub uninstall: Option<UninstallEntry>,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Clone)]
+pub struct RegistryMarker {
+    pub hive: RegistryHive,
+    /// Key path below the hive, e.g. `Software\Company\Product`
+    pub key: String,
+    pub value: String,
+}
+
+/// An entry under `Software\Microsoft\Windows\CurrentVersion\Uninstall`, so inventory tools
+/// see the installed version.
+#[derive(Encode, Decode, Serialize, Deserialize, Clone)]
+pub struct UninstallEntry {
+    pub hive: RegistryHive,
+    /// Name of the entry's key, e.g. `ExampleGame`
+    pub name: String,
+    pub display_name: String,
+    pub publisher: Option<String>,
+    /// Reverting patcher in the install directory that the entry's uninstall command runs.
+    /// `None` in the reverting patcher itself, which removes the entry.
+    pub uninstaller: Option<String>,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Clone, Copy)]
+pub enum RegistryHive {
+    CurrentUser,
+    LocalMachine,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Clone)]
 pub struct FileEntry {
     pub path: String,
     pub kind: PatchKind,
+    #[serde(with = "hex_hash")]
     pub original_hash: [u8; 32],
+    #[serde(with = "hex_hash")]
     pub new_hash: [u8; 32],
+    /// Size of the file before patching; 0 for added files
+    pub old_size: u64,
+    /// Size of the file after patching; 0 for deleted files
+    pub new_size: u64,
+    /// Modification time the patcher gives the written file, in nanoseconds since the Unix
+    /// epoch; `None` leaves it at the time of writing
+    pub mtime: Option<u64>,
+    /// Normalization applied before computing `original_hash` and `new_hash`
+    pub normalization: normalize::Normalization,
+    /// POSIX permission bits the patcher gives the file, recorded when the builder runs on a
+    /// Unix system; `None` leaves them as they are
+    pub mode: Option<u32>,
+    /// The patcher leaves the file's runs of zeros unwritten, as holes, so a sparse file
+    /// (a pre-allocated cache, a disk image) is not materialized in full
+    #[serde(default)]
+    pub sparse: bool,
+    /// Entry holding a compressed full copy of an unchanged, moved or copied file, written in
+    /// its place when its base is missing or corrupt; set by full-install patches, which
+    /// then also install into an empty folder
+    #[serde(default)]
+    pub full_copy: Option<usize>,
 }
 
-#[derive(Encode, Decode)]
+#[derive(Encode, Decode, Serialize, Deserialize, Clone)]
 pub enum PatchKind {
     Unchanged,
-    Patched { idx: usize },
-    Added { idx: usize },
+    /// `fallback` points at a compressed full copy used when the base file does not verify
+    /// `transform` indexes `Manifest::transforms` when the delta was made between normalized files
+    Patched {
+        idx: usize,
+        fallback: Option<usize>,
+        transform: Option<usize>,
+    },
+    Added {
+        idx: usize,
+    },
     Deleted,
+    /// Pure rename: the file at `from` already has the new content and is moved into place
+    Moved {
+        from: String,
+    },
+    /// New file identical to the old file at `from`, copied from it on disk before anything
+    /// in the installation changes
+    Copied {
+        from: String,
+    },
+    /// File of at least [`STREAMED_MIN`] bytes, written chunk by chunk: each of the new chunks
+    /// in its `Manifest::chunked` record is taken from the same chunk of the file on disk when
+    /// that already holds it, or from its entry in `chunks` otherwise. `None` marks a chunk the
+    /// old version has at the same place; a new file has an entry for every chunk
+    Streamed {
+        chunks: Vec<Option<usize>>,
+    },
+    /// Large changed file split into [`CHUNK_SIZE`] segments, each diffed on its own so the
+    /// patcher applies them in parallel and resumes a file cut short one segment at a time.
+    /// `segments` holds an xdelta delta of each new chunk in its `Manifest::chunked` record
+    /// from the old file's [`segment_base`], or `None` where the old file holds the chunk at
+    /// the same place
+    Segmented {
+        segments: Vec<Option<usize>>,
+    },
 }
 
 #[derive(Encode, Decode)]
 pub enum PatchData {
-    Xdelta(Vec<u8>), // xdelta diff
-    Full(Vec<u8>),   // full file
+    Xdelta(Vec<u8>),         // xdelta diff
+    Full(Vec<u8>),           // full file
+    CompressedFull(Vec<u8>), // zstd-compressed full file
+}
+
+/// Byte range of an encoded `PatchData`, relative to the start of the payload.
+#[derive(Encode, Decode, Serialize, Deserialize, Clone, Copy)]
+pub struct EntryRange {
+    pub offset: u64,
+    pub len: u64,
+    /// BLAKE3 hash of the stored bytes, checked whichever mirror they come from
+    #[serde(with = "hex_hash")]
+    pub hash: [u8; 32],
+    /// Compressed against `Manifest::dictionary` rather than on its own
+    #[serde(default)]
+    pub dictionary: bool,
+}
+
+/// Small entries compressed together as one zstd frame, so each is compressed with what the
+/// others before it hold. The [`EntryRange`] of an entry in a frame locates its encoded bytes
+/// within the decompressed frame rather than in the payload, and hashes those bytes.
+#[derive(Encode, Decode, Serialize, Deserialize, Clone)]
+pub struct SolidFrame {
+    /// Location of the compressed frame in the payload
+    pub range: EntryRange,
+    /// Entries the frame holds, in the order they follow each other in it
+    pub entries: Vec<usize>,
 }
 
 #[derive(Encode, Decode)]
@@ -35,3 +390,209 @@ pub struct PatchBundle {
     pub manifest: Manifest,
     pub entries: Vec<PatchData>,
 }
+
+/// `manifest.json` stored in zip-format patches.
+#[derive(Serialize, Deserialize)]
+pub struct ZipManifest {
+    pub manifest: Manifest,
+    /// Archive member holding each entry, indexed like `PatchKind` indices
+    pub entry_files: Vec<String>,
+}
+
+/// Name of the manifest member in zip-format patches.
+pub const ZIP_MANIFEST_NAME: &str = "manifest.json";
+
+/// Descriptor published next to a patch, so launchers can tell whether an update is needed and
+/// how big it is before downloading the patch itself.
+#[derive(Serialize, Deserialize)]
+pub struct UpdateInfo {
+    pub manifest: Manifest,
+    pub payload: PayloadRef,
+    /// Free space applying the patch to the from-version needs
+    pub disk_bytes: u64,
+}
+
+/// The patch an [`UpdateInfo`] describes.
+#[derive(Serialize, Deserialize)]
+pub struct PayloadRef {
+    pub url: Option<String>,
+    pub size: u64,
+    #[serde(with = "hex_hash")]
+    pub blake3: [u8; 32],
+}
+
+/// An [`UpdateInfo`] as published: its JSON text, and a hex Ed25519 signature over exactly
+/// those bytes when the builder was given a key.
+#[derive(Serialize, Deserialize)]
+pub struct SignedUpdateInfo {
+    pub info: String,
+    pub signature: Option<String>,
+}
+
+/// Serializes hashes as lowercase hex strings in JSON.
+pub mod hex_hash {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn to_hex(hash: &[u8; 32]) -> String {
+        encode(hash)
+    }
+
+    /// Lowercase hex of any byte string, such as a key or signature.
+    pub fn encode(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    pub fn decode(text: &str) -> Option<Vec<u8>> {
+        if !text.len().is_multiple_of(2) {
+            return None;
+        }
+        (0..text.len() / 2)
+            .map(|i| u8::from_str_radix(text.get(i * 2..i * 2 + 2)?, 16).ok())
+            .collect()
+    }
+
+    pub fn from_hex(text: &str) -> Option<[u8; 32]> {
+        if text.len() != 64 {
+            return None;
+        }
+        let mut hash = [0u8; 32];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(text.get(i * 2..i * 2 + 2)?, 16).ok()?;
+        }
+        Some(hash)
+    }
+
+    pub fn serialize<S: Serializer>(hash: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&to_hex(hash))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 32], D::Error> {
+        let text = String::deserialize(deserializer)?;
+        from_hex(&text).ok_or_else(|| D::Error::custom(format!("invalid hash {text}")))
+    }
+
+    /// The same for an optional hash.
+    pub mod option {
+        use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+        pub fn serialize<S: Serializer>(
+            hash: &Option<[u8; 32]>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match hash {
+                Some(hash) => serializer.serialize_some(&super::to_hex(hash)),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<[u8; 32]>, D::Error> {
+            Option::<String>::deserialize(deserializer)?
+                .map(|text| {
+                    super::from_hex(&text)
+                        .ok_or_else(|| D::Error::custom(format!("invalid hash {text}")))
+                })
+                .transpose()
+        }
+    }
+
+    /// The same for a list of hashes.
+    pub mod list {
+        use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+        pub fn serialize<S: Serializer>(
+            hashes: &[[u8; 32]],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(hashes.iter().map(super::to_hex))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<[u8; 32]>, D::Error> {
+            Vec::<String>::deserialize(deserializer)?
+                .iter()
+                .map(|text| {
+                    super::from_hex(text)
+                        .ok_or_else(|| D::Error::custom(format!("invalid hash {text}")))
+                })
+                .collect()
+        }
+    }
+}
+
+/// Trailer at the very end of an installer, used to locate the payload and manifest.
+///
+/// Layout: `[stub][entry 0]..[entry n][manifest][payload_len: u64 LE][manifest_len: u64 LE]`
+pub struct Footer {
+    pub payload_len: u64,
+    pub manifest_len: u64,
+}
+
+impl Footer {
+    pub fn to_bytes(&self) -> [u8; FOOTER_LEN as usize] {
+        let mut out = [0u8; FOOTER_LEN as usize];
+        out[..8].copy_from_slice(&self.payload_len.to_le_bytes());
+        out[8..].copy_from_slice(&self.manifest_len.to_le_bytes());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8; FOOTER_LEN as usize]) -> Self {
+        let mut payload_len = [0u8; 8];
+        let mut manifest_len = [0u8; 8];
+        payload_len.copy_from_slice(&bytes[..8]);
+        manifest_len.copy_from_slice(&bytes[8..]);
+        Footer {
+            payload_len: u64::from_le_bytes(payload_len),
+            manifest_len: u64::from_le_bytes(manifest_len),
+        }
+    }
+}
+
+/// Pipes `input` through the external command `cmd` (program followed by its arguments).
+pub fn run_filter(cmd: &[String], input: &[u8]) -> std::io::Result<Vec<u8>> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let (program, args) = cmd
+        .split_first()
+        .ok_or_else(|| std::io::Error::other("empty transform command"))?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    // Feed stdin from another thread so a command that streams its output cannot deadlock
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let (output, written) = std::thread::scope(|scope| {
+        let writer = scope.spawn(move || stdin.write_all(input));
+        let output = child.wait_with_output();
+        (output, writer.join().expect("stdin writer panicked"))
+    });
+
+    let output = output?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "{program} exited with {}",
+            output.status
+        )));
+    }
+    written?;
+    Ok(output.stdout)
+}
+
+/// Groups of paths that differ only by letter case and would collide on a case-insensitive filesystem.
+pub fn case_collisions<'a>(paths: impl IntoIterator<Item = &'a str>) -> Vec<Vec<&'a str>> {
+    let mut groups: HashMap<String, Vec<&'a str>> = HashMap::new();
+    for path in paths {
+        groups.entry(path.to_lowercase()).or_default().push(path);
+    }
+    let mut collisions: Vec<Vec<&str>> = groups.into_values().filter(|g| g.len() > 1).collect();
+    for group in &mut collisions {
+        group.sort_unstable();
+    }
+    collisions.sort_unstable();
+    collisions
+}
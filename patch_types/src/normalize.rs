@@ -0,0 +1,64 @@
+//! Normalization applied to a file's bytes before hashing, so rebuilds that differ only in
+//! build metadata hash the same.
+
+use std::io::{self, Read};
+
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+/// How a file is normalized before it is hashed. The file itself is never changed.
+#[derive(Encode, Decode, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Normalization {
+    #[default]
+    None,
+    /// Zero the link timestamp and checksum in the headers of a PE executable or DLL.
+    /// Files that are not PE images hash as they are.
+    PeHeader,
+}
+
+impl Normalization {
+    /// Normalizes the start of a file in place. Fields beyond `head` are left alone, so
+    /// `head` must hold the headers; [`read_first`](Self::read_first) reads enough.
+    pub fn apply(self, head: &mut [u8]) {
+        match self {
+            Normalization::None => {}
+            Normalization::PeHeader => zero_pe_header(head),
+        }
+    }
+
+    /// Fills `buffer` from the start of `file` as far as it can and normalizes it, for hashing
+    /// the first chunk. Returns the number of bytes read.
+    pub fn read_first(self, file: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            let n = file.read(&mut buffer[filled..])?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        self.apply(&mut buffer[..filled]);
+        Ok(filled)
+    }
+}
+
+fn zero_pe_header(head: &mut [u8]) {
+    if head.len() < 0x40 || &head[..2] != b"MZ" {
+        return;
+    }
+    let pe = u32::from_le_bytes([head[0x3c], head[0x3d], head[0x3e], head[0x3f]]) as usize;
+    if head.get(pe..pe + 4) != Some(b"PE\0\0".as_slice()) {
+        return;
+    }
+    // TimeDateStamp in the COFF header, after Machine and NumberOfSections
+    zero(head, pe + 8);
+    // CheckSum in the optional header, at the same offset in PE32 and PE32+
+    zero(head, pe + 24 + 64);
+}
+
+fn zero(head: &mut [u8], at: usize) {
+    if let Some(field) = head.get_mut(at..at + 4) {
+        field.fill(0);
+    }
+}
@@ -0,0 +1,61 @@
+//! Size-aware scheduling shared by the builder and the stub.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use rayon::prelude::*;
+
+/// Files at least this big are read in large chunks and hashed across idle workers.
+pub const LARGE_FILE: u64 = 64 * 1024 * 1024;
+
+/// Runs `work` on every item across the current rayon pool, biggest first: each worker takes
+/// the largest item left, so a huge file starts straight away instead of being left for the
+/// end while the other cores sit idle. Results come back in the original order. After a
+/// failure no new items are started and the first error is returned.
+pub fn largest_first<T, R, E>(
+    items: &[T],
+    size: impl Fn(&T) -> u64,
+    work: impl Fn(usize, &T) -> Result<R, E> + Sync,
+) -> Result<Vec<R>, E>
+where
+    T: Sync,
+    R: Send,
+    E: Send,
+{
+    let mut order: Vec<usize> = (0..items.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(size(&items[i])));
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let workers = rayon::current_num_threads().min(items.len());
+    let done: Vec<Result<Vec<(usize, R)>, E>> = (0..workers)
+        .into_par_iter()
+        .with_max_len(1)
+        .map(|_| {
+            let mut done = Vec::new();
+            while !failed.load(Ordering::Relaxed) {
+                let Some(&i) = order.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                    break;
+                };
+                match work(i, &items[i]) {
+                    Ok(result) => done.push((i, result)),
+                    Err(e) => {
+                        failed.store(true, Ordering::Relaxed);
+                        return Err(e);
+                    }
+                }
+            }
+            Ok(done)
+        })
+        .collect();
+
+    let mut results: Vec<Option<R>> = std::iter::repeat_with(|| None).take(items.len()).collect();
+    for worker in done {
+        for (i, result) in worker? {
+            results[i] = Some(result);
+        }
+    }
+    Ok(results
+        .into_iter()
+        .map(|r| r.expect("every item runs unless one fails"))
+        .collect())
+}
@@ -0,0 +1,360 @@
+//! Manifest layouts of earlier formats. Patchers read manifests of any format from
+//! [`OLDEST_FORMAT`] to [`FORMAT_VERSION`], and builders write any of them as long as the patch
+//! uses nothing a later format added, so patchers and patches within that window work with
+//! each other in both directions. The manifest's `min_stub_version` names its layout.
+//!
+//! The window holds the four newest formats. A new format adds a struct or tuple here for the
+//! previous layout when it changes it, with a conversion each way, and moves [`OLDEST_FORMAT`]
+//! up by one, dropping the layout only the format that falls out used. Nothing else moves the
+//! window. The README's format section states the same policy for users.
+
+use std::collections::BTreeMap;
+
+use bincode::error::{DecodeError, EncodeError};
+use bincode::{Decode, Encode};
+
+use crate::{
+    ApplyCost, ChunkedFile, Compression, EntryRange, FORMAT_VERSION, FileEntry, MainExecutable,
+    Manifest, PatchKind, Release, SolidFrame, Transform, VersionFingerprint, VersionMarkers,
+};
+
+/// Oldest format patchers read and builders write.
+pub const OLDEST_FORMAT: u32 = 24;
+/// Added files written chunk by chunk ([`PatchKind::Streamed`]); the layout is format 23's.
+pub const STREAMED_FILES: u32 = 24;
+/// Added small entries compressed together in shared frames ([`Manifest::frames`]).
+pub const SOLID_FRAMES: u32 = 25;
+/// Added readable diffs of text files ([`Manifest::diffs`]).
+pub const TEXT_DIFFS: u32 = 26;
+/// Added large files diffed segment by segment ([`PatchKind::Segmented`]); the layout is
+/// format 26's.
+pub const SEGMENTED_FILES: u32 = 27;
+
+/// The fields of [`Manifest`] that formats 24 and 25 lay out first; their layouts append to it.
+#[derive(Encode, Decode)]
+struct ManifestV20 {
+    min_stub_version: u32,
+    product: String,
+    from_version: String,
+    to_version: String,
+    files: Vec<FileEntry>,
+    entries: Vec<EntryRange>,
+    markers: VersionMarkers,
+    compression: Compression,
+    transforms: Vec<Transform>,
+    created_dirs: Vec<String>,
+    deleted_dirs: Vec<String>,
+    fingerprints: Vec<VersionFingerprint>,
+    eula: Option<String>,
+    cost: ApplyCost,
+    main_exe: Option<MainExecutable>,
+    chunked: Vec<ChunkedFile>,
+    wizard: Option<crate::wizard::Wizard>,
+    mirrors: Vec<String>,
+    metadata: BTreeMap<String, String>,
+    release: Option<Release>,
+    dictionary: Option<EntryRange>,
+}
+
+/// [`Manifest`] as format 24 lays it out: [`ManifestV20`], then `stub_hash`.
+type ManifestV24 = (ManifestV20, Option<[u8; 32]>);
+
+/// [`Manifest`] as format 25 lays it out: format 24's, then `frames`.
+type ManifestV25 = (ManifestV20, Option<[u8; 32]>, Vec<SolidFrame>);
+
+impl ManifestV20 {
+    fn from_current(manifest: &Manifest) -> Self {
+        ManifestV20 {
+            min_stub_version: manifest.min_stub_version,
+            product: manifest.product.clone(),
+            from_version: manifest.from_version.clone(),
+            to_version: manifest.to_version.clone(),
+            files: manifest.files.clone(),
+            entries: manifest.entries.clone(),
+            markers: manifest.markers.clone(),
+            compression: manifest.compression,
+            transforms: manifest.transforms.clone(),
+            created_dirs: manifest.created_dirs.clone(),
+            deleted_dirs: manifest.deleted_dirs.clone(),
+            fingerprints: manifest.fingerprints.clone(),
+            eula: manifest.eula.clone(),
+            cost: manifest.cost,
+            main_exe: manifest.main_exe.clone(),
+            chunked: manifest.chunked.clone(),
+            wizard: manifest.wizard.clone(),
+            mirrors: manifest.mirrors.clone(),
+            metadata: manifest.metadata.clone(),
+            release: manifest.release.clone(),
+            dictionary: manifest.dictionary,
+        }
+    }
+
+    fn upgrade(self, stub_hash: Option<[u8; 32]>) -> Manifest {
+        Manifest {
+            min_stub_version: self.min_stub_version,
+            product: self.product,
+            from_version: self.from_version,
+            to_version: self.to_version,
+            files: self.files,
+            entries: self.entries,
+            markers: self.markers,
+            compression: self.compression,
+            transforms: self.transforms,
+            created_dirs: self.created_dirs,
+            deleted_dirs: self.deleted_dirs,
+            fingerprints: self.fingerprints,
+            eula: self.eula,
+            cost: self.cost,
+            main_exe: self.main_exe,
+            chunked: self.chunked,
+            wizard: self.wizard,
+            mirrors: self.mirrors,
+            metadata: self.metadata,
+            release: self.release,
+            dictionary: self.dictionary,
+            stub_hash,
+            frames: Vec::new(),
+            diffs: Vec::new(),
+        }
+    }
+}
+
+/// Decodes a manifest in the layout of the format its `min_stub_version` names, filling in
+/// what later formats added with what an older patch means by leaving it out.
+pub fn decode_manifest(bytes: &[u8]) -> Result<Manifest, DecodeError> {
+    let config = bincode::config::standard();
+    let format: u32 = bincode::decode_from_slice(bytes, config)?.0;
+    Ok(match format {
+        FORMAT_VERSION | TEXT_DIFFS => bincode::decode_from_slice(bytes, config)?.0,
+        SOLID_FRAMES => {
+            let (manifest, stub_hash, frames): ManifestV25 =
+                bincode::decode_from_slice(bytes, config)?.0;
+            Manifest {
+                frames,
+                ..manifest.upgrade(stub_hash)
+            }
+        }
+        STREAMED_FILES => {
+            let (manifest, stub_hash): ManifestV24 = bincode::decode_from_slice(bytes, config)?.0;
+            manifest.upgrade(stub_hash)
+        }
+        _ => return Err(DecodeError::OtherString(out_of_window(format))),
+    })
+}
+
+/// Encodes `manifest` in the layout of the format its `min_stub_version` names. Fails when it
+/// uses something that format does not have, rather than leaving it out.
+pub fn encode_manifest(manifest: &Manifest) -> Result<Vec<u8>, EncodeError> {
+    let config = bincode::config::standard();
+    let format = manifest.min_stub_version;
+    if let Some((feature, _)) = unsupported_feature(manifest, format) {
+        return Err(EncodeError::OtherString(format!(
+            "format {format} cannot hold {feature}"
+        )));
+    }
+    match format {
+        FORMAT_VERSION | TEXT_DIFFS => bincode::encode_to_vec(manifest, config),
+        SOLID_FRAMES => {
+            let manifest: ManifestV25 = (
+                ManifestV20::from_current(manifest),
+                manifest.stub_hash,
+                manifest.frames.clone(),
+            );
+            bincode::encode_to_vec(manifest, config)
+        }
+        STREAMED_FILES => {
+            let manifest: ManifestV24 = (ManifestV20::from_current(manifest), manifest.stub_hash);
+            bincode::encode_to_vec(manifest, config)
+        }
+        _ => Err(EncodeError::OtherString(out_of_window(format))),
+    }
+}
+
+/// The newest thing `manifest` uses that patchers of `format` do not know, if any, with the
+/// format that added it.
+pub fn unsupported_feature(manifest: &Manifest, format: u32) -> Option<(&'static str, u32)> {
+    let feature = if manifest
+        .files
+        .iter()
+        .any(|file| matches!(file.kind, PatchKind::Segmented { .. }))
+    {
+        (
+            "files diffed segment by segment (--segment)",
+            SEGMENTED_FILES,
+        )
+    } else if !manifest.diffs.is_empty() {
+        ("diffs of text files (--store-diffs)", TEXT_DIFFS)
+    } else if !manifest.frames.is_empty() {
+        (
+            "small entries compressed together (--solid-frame)",
+            SOLID_FRAMES,
+        )
+    } else {
+        return None;
+    };
+    (feature.1 > format).then_some(feature)
+}
+
+fn out_of_window(format: u32) -> String {
+    format!(
+        "format {format} is outside the formats {OLDEST_FORMAT} to {FORMAT_VERSION} this version handles"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalize::Normalization;
+
+    fn entry(path: &str, kind: PatchKind) -> FileEntry {
+        FileEntry {
+            path: path.to_string(),
+            kind,
+            original_hash: [1; 32],
+            new_hash: [2; 32],
+            old_size: 10,
+            new_size: 0,
+            mtime: Some(1_700_000_000_000_000_000),
+            normalization: Normalization::None,
+            mode: Some(0o755),
+            sparse: true,
+            full_copy: Some(3),
+        }
+    }
+
+    /// A manifest using everything format `format` can hold.
+    fn manifest(format: u32) -> Manifest {
+        let range = |offset| EntryRange {
+            offset,
+            len: 5,
+            hash: [7; 32],
+            dictionary: false,
+        };
+        let mut files = vec![
+            entry(
+                "bin/app.exe",
+                PatchKind::Patched {
+                    idx: 0,
+                    fallback: Some(1),
+                    transform: None,
+                },
+            ),
+            entry(
+                "data/big.pak",
+                PatchKind::Streamed {
+                    chunks: vec![None, Some(2)],
+                },
+            ),
+            entry("old.txt", PatchKind::Deleted),
+        ];
+        if format >= SEGMENTED_FILES {
+            files.push(entry(
+                "data/huge.pak",
+                PatchKind::Segmented {
+                    segments: vec![Some(4), None],
+                },
+            ));
+        }
+        Manifest {
+            min_stub_version: format,
+            product: "App".to_string(),
+            from_version: "1.0".to_string(),
+            to_version: "1.1".to_string(),
+            files,
+            entries: vec![range(0), range(5), range(10), range(15), range(20)],
+            markers: VersionMarkers::default(),
+            compression: Compression::default(),
+            transforms: Vec::new(),
+            created_dirs: vec!["data".to_string()],
+            deleted_dirs: vec!["logs".to_string()],
+            fingerprints: Vec::new(),
+            eula: Some("Terms".to_string()),
+            cost: ApplyCost::default(),
+            main_exe: None,
+            chunked: Vec::new(),
+            wizard: None,
+            mirrors: vec!["https://cdn.example.com/patch.bin".to_string()],
+            metadata: BTreeMap::from([("build".to_string(), "1234".to_string())]),
+            release: None,
+            dictionary: Some(range(25)),
+            stub_hash: Some([9; 32]),
+            frames: if format >= SOLID_FRAMES {
+                vec![SolidFrame {
+                    range: range(30),
+                    entries: vec![0, 1],
+                }]
+            } else {
+                Vec::new()
+            },
+            diffs: if format >= TEXT_DIFFS {
+                vec![crate::FileDiff {
+                    path: "readme.txt".to_string(),
+                    unified: "-a\n+b\n".to_string(),
+                }]
+            } else {
+                Vec::new()
+            },
+        }
+    }
+
+    #[test]
+    fn every_format_round_trips() {
+        for format in OLDEST_FORMAT..=FORMAT_VERSION {
+            let original = manifest(format);
+            let bytes = encode_manifest(&original).unwrap();
+            let decoded = decode_manifest(&bytes).unwrap();
+            assert_eq!(decoded.min_stub_version, format);
+            assert_eq!(decoded.files.len(), original.files.len(), "format {format}");
+            assert_eq!(decoded.stub_hash, original.stub_hash, "format {format}");
+            assert_eq!(
+                decoded.frames.len(),
+                original.frames.len(),
+                "format {format}"
+            );
+            assert_eq!(decoded.diffs.len(), original.diffs.len(), "format {format}");
+            assert_eq!(encode_manifest(&decoded).unwrap(), bytes, "format {format}");
+        }
+    }
+
+    #[test]
+    fn older_layouts_differ_from_the_current_one() {
+        let current = bincode::encode_to_vec(manifest(STREAMED_FILES), bincode::config::standard());
+        assert_ne!(
+            encode_manifest(&manifest(STREAMED_FILES)).unwrap(),
+            current.unwrap()
+        );
+    }
+
+    #[test]
+    fn encoding_refuses_what_the_format_lacks() {
+        for (format, later) in [
+            (STREAMED_FILES, SOLID_FRAMES),
+            (SOLID_FRAMES, TEXT_DIFFS),
+            (TEXT_DIFFS, SEGMENTED_FILES),
+        ] {
+            let manifest = Manifest {
+                min_stub_version: format,
+                ..manifest(later)
+            };
+            assert_eq!(
+                unsupported_feature(&manifest, format).map(|(_, added)| added),
+                Some(later)
+            );
+            assert!(encode_manifest(&manifest).is_err(), "format {format}");
+        }
+        assert!(unsupported_feature(&manifest(FORMAT_VERSION), FORMAT_VERSION).is_none());
+    }
+
+    #[test]
+    fn formats_outside_the_window_are_refused() {
+        for format in [OLDEST_FORMAT - 1, FORMAT_VERSION + 1] {
+            let manifest = Manifest {
+                min_stub_version: format,
+                ..manifest(OLDEST_FORMAT)
+            };
+            assert!(encode_manifest(&manifest).is_err());
+            let bytes = bincode::encode_to_vec(&manifest, bincode::config::standard()).unwrap();
+            assert!(decode_manifest(&bytes).is_err());
+        }
+    }
+}
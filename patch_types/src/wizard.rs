@@ -0,0 +1,50 @@
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+/// Pages the stub walks the user through before patching, in order, so a product can shape
+/// its install flow without a stub of its own.
+#[derive(Encode, Decode, Serialize, Deserialize, Clone, Default)]
+pub struct Wizard {
+    pub pages: Vec<WizardPage>,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Clone)]
+pub enum WizardPage {
+    /// A heading and a few paragraphs introducing the update
+    Welcome { title: String, text: String },
+    /// The patch's license agreement, `Manifest::eula`
+    Eula,
+    /// Asks which installation to patch, offering the detected ones
+    Directory,
+    /// Optional parts of the new version for the user to pick from
+    Components(Vec<Component>),
+    /// Sums up what patching will do and asks to go ahead
+    Confirm,
+}
+
+/// An optional part of the product. Files of a component the user leaves out are not
+/// installed, but those already present are still patched.
+#[derive(Encode, Decode, Serialize, Deserialize, Clone)]
+pub struct Component {
+    /// Name chosen with `--components` and recorded in the receipt
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    /// Whether it is picked when it is not installed yet
+    pub default: bool,
+    /// New-version paths of its files
+    pub files: Vec<String>,
+}
+
+impl Wizard {
+    /// The components page's components, if there is one.
+    pub fn components(&self) -> &[Component] {
+        self.pages
+            .iter()
+            .find_map(|page| match page {
+                WizardPage::Components(components) => Some(components.as_slice()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+}